@@ -0,0 +1,383 @@
+//! PocketIC environment for end-to-end tests of the currency crate.
+//!
+//! Installs the ICP ledger at its mainnet id, ICRC-1 ledgers for ckBTC, ckETH and
+//! ckUSDC with a mock minter each, and the test canister configured through a
+//! [`NetworkConfig`] that points it at the local ledgers and minters.
+//!
+//! Wasm paths default to the files written by `scripts/download.pocket_ic.sh` and can
+//! be overridden with `ICP_LEDGER_WASM`, `ICRC1_LEDGER_WASM`, `MOCK_MINTER_WASM` and
+//! `TEST_CANISTER_WASM`.
+
+use std::path::PathBuf;
+
+use candid::{
+    decode_one, encode_args, encode_one, utils::ArgumentEncoder, CandidType, Nat, Principal,
+};
+use currency::{
+    icrc_ledger_canister_interface::{
+        Account, ApproveArgs, ArchiveOptions, FeatureFlags, InitArgs, LedgerArgument, Result2,
+    },
+    types::{
+        currency::{CKTokenConfig, CKTokenSymbol},
+        network_config::NetworkConfig,
+    },
+    Currency,
+};
+use ic_ledger_types::{AccountIdentifier, Tokens, DEFAULT_SUBACCOUNT, MAINNET_LEDGER_CANISTER_ID};
+use pocket_ic::{PocketIc, PocketIcBuilder};
+use serde::{de::DeserializeOwned, Deserialize};
+
+pub const INITIAL_BALANCE: u128 = 1_000_000_000_000_000_000;
+pub const ICP_FEE: u128 = 10_000;
+pub const CKBTC_FEE: u128 = 10;
+pub const CKETH_FEE: u128 = 2_000_000_000_000;
+pub const CKUSDC_FEE: u128 = 10_000;
+
+const CYCLES: u128 = 10_000_000_000_000;
+
+/// Principal of test user `n`, funded on every ledger
+pub fn user(n: u8) -> Principal {
+    Principal::from_slice(&[0x10, n])
+}
+
+fn controller() -> Principal {
+    Principal::from_slice(&[0x01])
+}
+
+fn wasm(var: &str, default: &str) -> Vec<u8> {
+    let path = std::env::var(var).map(PathBuf::from).unwrap_or_else(|_| {
+        PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("../..")
+            .join(default)
+    });
+    std::fs::read(&path).unwrap_or_else(|e| {
+        panic!(
+            "cannot read {} ({}): {}, run scripts/download.pocket_ic.sh",
+            path.display(),
+            var,
+            e
+        )
+    })
+}
+
+#[derive(CandidType)]
+struct IcpFeatureFlags {
+    icrc2: bool,
+}
+
+#[derive(CandidType)]
+struct IcpInitArgs {
+    minting_account: String,
+    initial_values: Vec<(String, Tokens)>,
+    send_whitelist: Vec<Principal>,
+    transfer_fee: Option<Tokens>,
+    token_symbol: Option<String>,
+    token_name: Option<String>,
+    feature_flags: Option<IcpFeatureFlags>,
+}
+
+#[derive(CandidType)]
+enum IcpLedgerArgument {
+    Init(IcpInitArgs),
+}
+
+#[derive(CandidType, Deserialize)]
+struct MockMinterInit {
+    ledger_id: Principal,
+    token_symbol: String,
+    required_confirmations: u32,
+    deposit_address: Option<String>,
+}
+
+/// Withdrawal states the mock minter's `set_withdrawal_status` moves a withdrawal to
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum MockWithdrawalStatus {
+    Pending,
+    TxCreated,
+    TxSent {
+        transaction_hash: String,
+    },
+    Finalized {
+        transaction_hash: String,
+    },
+    PendingReimbursement {
+        transaction_hash: String,
+    },
+    Reimbursed {
+        transaction_hash: String,
+        reimbursed_amount: u128,
+        reimbursed_in_block: u128,
+    },
+}
+
+/// A local ckToken ledger and the mock minter minting to it
+#[derive(Debug, Clone, Copy)]
+pub struct CkToken {
+    pub ledger: Principal,
+    pub minter: Principal,
+    pub config: CKTokenConfig,
+}
+
+pub struct Env {
+    pub pic: PocketIc,
+    pub canister: Principal,
+    pub icp_ledger: Principal,
+    pub ckbtc: CkToken,
+    pub cketh: CkToken,
+    pub ckusdc: CkToken,
+}
+
+impl Env {
+    pub fn new() -> Self {
+        let pic = PocketIcBuilder::new()
+            .with_nns_subnet()
+            .with_application_subnet()
+            .build();
+
+        let icp_ledger = install_icp_ledger(&pic);
+        let ckbtc = install_ck_token(&pic, Currency::BTC, "ckBTC", 8, CKBTC_FEE);
+        let cketh = install_ck_token(
+            &pic,
+            Currency::CKETHToken(CKTokenSymbol::ETH),
+            "ckETH",
+            18,
+            CKETH_FEE,
+        );
+        let ckusdc = install_ck_token(
+            &pic,
+            Currency::CKETHToken(CKTokenSymbol::USDC),
+            "ckUSDC",
+            6,
+            CKUSDC_FEE,
+        );
+
+        let network = NetworkConfig::mainnet()
+            .with_ck_token(ckbtc.config)
+            .with_ck_token(cketh.config)
+            .with_ck_token(ckusdc.config);
+        let canister = create_canister(&pic);
+        pic.install_canister(
+            canister,
+            wasm(
+                "TEST_CANISTER_WASM",
+                "integration/target/wasm32-unknown-unknown/release/test_canister.wasm",
+            ),
+            encode_one(network).unwrap(),
+            Some(controller()),
+        );
+
+        Self {
+            pic,
+            canister,
+            icp_ledger,
+            ckbtc,
+            cketh,
+            ckusdc,
+        }
+    }
+
+    pub fn update<R: CandidType + DeserializeOwned>(
+        &self,
+        canister: Principal,
+        sender: Principal,
+        method: &str,
+        args: impl ArgumentEncoder,
+    ) -> R {
+        let reply = self
+            .pic
+            .update_call(canister, sender, method, encode_args(args).unwrap())
+            .unwrap_or_else(|e| panic!("{} was rejected: {:?}", method, e));
+        decode_one(&reply).unwrap_or_else(|e| panic!("cannot decode the {} reply: {}", method, e))
+    }
+
+    pub fn query<R: CandidType + DeserializeOwned>(
+        &self,
+        canister: Principal,
+        method: &str,
+        args: impl ArgumentEncoder,
+    ) -> R {
+        let reply = self
+            .pic
+            .query_call(
+                canister,
+                Principal::anonymous(),
+                method,
+                encode_args(args).unwrap(),
+            )
+            .unwrap_or_else(|e| panic!("{} was rejected: {:?}", method, e));
+        decode_one(&reply).unwrap_or_else(|e| panic!("cannot decode the {} reply: {}", method, e))
+    }
+
+    /// Call the test canister as `sender`
+    pub fn call<R: CandidType + DeserializeOwned>(
+        &self,
+        sender: Principal,
+        method: &str,
+        args: impl ArgumentEncoder,
+    ) -> R {
+        self.update(self.canister, sender, method, args)
+    }
+
+    pub fn balance(
+        &self,
+        ledger: Principal,
+        owner: Principal,
+        subaccount: Option<Vec<u8>>,
+    ) -> u128 {
+        let account = Account {
+            owner,
+            subaccount: subaccount.map(serde_bytes::ByteBuf::from),
+        };
+        let balance: Nat = self.query(ledger, "icrc1_balance_of", (account,));
+        u128::try_from(balance.0).unwrap()
+    }
+
+    /// `owner` approves the test canister to spend `amount` on `ledger`
+    pub fn approve_canister(&self, ledger: Principal, owner: Principal, amount: u128) {
+        let args = ApproveArgs {
+            fee: None,
+            memo: None,
+            from_subaccount: None,
+            created_at_time: None,
+            amount: Nat::from(amount),
+            expected_allowance: None,
+            expires_at: None,
+            spender: Account {
+                owner: self.canister,
+                subaccount: None,
+            },
+        };
+        match self.update::<Result2>(ledger, owner, "icrc2_approve", (args,)) {
+            Result2::Ok(_) => {}
+            Result2::Err(_) => panic!("{} rejected the approval of {}", ledger, owner),
+        }
+    }
+}
+
+impl Default for Env {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn create_canister(pic: &PocketIc) -> Principal {
+    let canister = pic.create_canister_with_settings(Some(controller()), None);
+    pic.add_cycles(canister, CYCLES);
+    canister
+}
+
+fn install_icp_ledger(pic: &PocketIc) -> Principal {
+    let ledger = pic
+        .create_canister_with_id(Some(controller()), None, MAINNET_LEDGER_CANISTER_ID)
+        .expect("the NNS subnet hosts the ICP ledger id");
+    pic.add_cycles(ledger, CYCLES);
+
+    let account =
+        |owner: Principal| AccountIdentifier::new(&owner, &DEFAULT_SUBACCOUNT).to_string();
+    let args = IcpLedgerArgument::Init(IcpInitArgs {
+        minting_account: account(controller()),
+        initial_values: (0..4)
+            .map(|n| {
+                (
+                    account(user(n)),
+                    Tokens::from_e8s(INITIAL_BALANCE as u64 / 1_000_000),
+                )
+            })
+            .collect(),
+        send_whitelist: Vec::new(),
+        transfer_fee: Some(Tokens::from_e8s(ICP_FEE as u64)),
+        token_symbol: Some("ICP".to_string()),
+        token_name: Some("Internet Computer".to_string()),
+        feature_flags: Some(IcpFeatureFlags { icrc2: true }),
+    });
+    pic.install_canister(
+        ledger,
+        wasm("ICP_LEDGER_WASM", "target/ic/icp_ledger.wasm.gz"),
+        encode_one(args).unwrap(),
+        Some(controller()),
+    );
+    ledger
+}
+
+fn install_ck_token(
+    pic: &PocketIc,
+    currency: Currency,
+    symbol: &str,
+    decimals: u8,
+    fee: u128,
+) -> CkToken {
+    let ledger = create_canister(pic);
+    let minter = create_canister(pic);
+
+    let args = LedgerArgument::Init(InitArgs {
+        decimals: Some(decimals),
+        token_symbol: symbol.to_string(),
+        transfer_fee: Nat::from(fee),
+        metadata: Vec::new(),
+        minting_account: Account {
+            owner: minter,
+            subaccount: None,
+        },
+        initial_balances: (0..4)
+            .map(|n| {
+                (
+                    Account {
+                        owner: user(n),
+                        subaccount: None,
+                    },
+                    Nat::from(INITIAL_BALANCE),
+                )
+            })
+            .collect(),
+        maximum_number_of_accounts: None,
+        accounts_overflow_trim_quantity: None,
+        fee_collector_account: None,
+        archive_options: ArchiveOptions {
+            num_blocks_to_archive: 1_000,
+            max_transactions_per_response: None,
+            trigger_threshold: 2_000,
+            more_controller_ids: None,
+            max_message_size_bytes: None,
+            cycles_for_archive_creation: None,
+            node_max_memory_size_bytes: None,
+            controller_id: controller(),
+        },
+        max_memo_length: None,
+        token_name: symbol.to_string(),
+        feature_flags: Some(FeatureFlags { icrc2: true }),
+    });
+    pic.install_canister(
+        ledger,
+        wasm("ICRC1_LEDGER_WASM", "target/ic/icrc1_ledger.wasm.gz"),
+        encode_one(args).unwrap(),
+        Some(controller()),
+    );
+
+    let init = MockMinterInit {
+        ledger_id: ledger,
+        token_symbol: symbol.to_string(),
+        required_confirmations: 6,
+        deposit_address: Some("0x2d39863d30716aaf2b7fffd85dd03dda2bfc2e38".to_string()),
+    };
+    pic.install_canister(
+        minter,
+        wasm(
+            "MOCK_MINTER_WASM",
+            "integration/target/wasm32-unknown-unknown/release/mock_minter.wasm",
+        ),
+        encode_one(init).unwrap(),
+        Some(controller()),
+    );
+
+    CkToken {
+        ledger,
+        minter,
+        config: CKTokenConfig {
+            minter_id: minter,
+            ledger_id: ledger,
+            token_symbol: currency,
+            decimals,
+            fee,
+        },
+    }
+}
@@ -0,0 +1,227 @@
+use currency::{
+    currency_error::CurrencyError,
+    deposit_subaccount::deposit_subaccount,
+    ledger_client::DepositCheck,
+    types::{
+        canister_wallets::{
+            btc_token_wallet::BtcDepositUpdate, ckerc20_token_wallet::CKTokenWithdrawalStatus,
+        },
+        currency::CKTokenSymbol,
+    },
+    Currency,
+};
+use harness::{user, Env, MockWithdrawalStatus, CKUSDC_FEE, ICP_FEE, INITIAL_BALANCE};
+
+#[test]
+fn icp_deposit_and_withdraw() {
+    let env = Env::new();
+    let alice = user(0);
+    env.approve_canister(env.icp_ledger, alice, 1_000_000);
+
+    let deposit: Result<(), CurrencyError> =
+        env.call(alice, "deposit", (Currency::ICP, 500_000u64));
+    deposit.unwrap();
+    assert_eq!(env.balance(env.icp_ledger, env.canister, None), 500_000);
+
+    let withdraw: Result<(), CurrencyError> =
+        env.call(alice, "withdraw", (Currency::ICP, 200_000u64));
+    withdraw.unwrap();
+    assert_eq!(env.balance(env.icp_ledger, env.canister, None), 300_000);
+}
+
+#[test]
+fn ckusdc_deposit_without_allowance_is_rejected() {
+    let env = Env::new();
+    let usdc = Currency::CKETHToken(CKTokenSymbol::USDC);
+    let added: Result<(), CurrencyError> = env.call(user(0), "add_currency", (usdc,));
+    added.unwrap();
+
+    let deposit: Result<(), CurrencyError> = env.call(user(1), "deposit", (usdc, 1_000_000u64));
+    assert_eq!(deposit, Err(CurrencyError::InsufficientAllowance));
+    assert_eq!(
+        env.balance(env.ckusdc.ledger, user(1), None),
+        INITIAL_BALANCE
+    );
+}
+
+#[test]
+fn ckusdc_deposit_check_asks_for_amount_plus_fee() {
+    let env = Env::new();
+    let usdc = Currency::CKETHToken(CKTokenSymbol::USDC);
+    let alice = user(0);
+    let added: Result<(), CurrencyError> = env.call(alice, "add_currency", (usdc,));
+    added.unwrap();
+    env.approve_canister(env.ckusdc.ledger, alice, 1_000_000);
+
+    let check: Result<DepositCheck, CurrencyError> =
+        env.call(alice, "check_deposit", (usdc, 1_000_000u64));
+    let check = check.unwrap();
+    assert_eq!(check.allowance, 1_000_000);
+    assert_eq!(check.required_allowance, 1_000_000 + CKUSDC_FEE);
+    assert!(!check.will_succeed);
+
+    env.approve_canister(env.ckusdc.ledger, alice, check.required_allowance);
+    let check: Result<DepositCheck, CurrencyError> =
+        env.call(alice, "check_deposit", (usdc, 1_000_000u64));
+    assert!(check.unwrap().will_succeed);
+
+    let deposit: Result<(), CurrencyError> = env.call(alice, "deposit", (usdc, 1_000_000u64));
+    deposit.unwrap();
+}
+
+#[test]
+fn ckusdc_withdraw_deducts_fee() {
+    let env = Env::new();
+    let usdc = Currency::CKETHToken(CKTokenSymbol::USDC);
+    let alice = user(0);
+    let added: Result<(), CurrencyError> = env.call(alice, "add_currency", (usdc,));
+    added.unwrap();
+    env.approve_canister(env.ckusdc.ledger, alice, 10_000_000);
+
+    let deposit: Result<(), CurrencyError> = env.call(alice, "deposit", (usdc, 5_000_000u64));
+    deposit.unwrap();
+    let withdraw: Result<(), CurrencyError> = env.call(alice, "withdraw", (usdc, 1_000_000u64));
+    withdraw.unwrap();
+
+    // Approve fee, transfer_from fee, then the withdrawn amount minus its fee
+    assert_eq!(
+        env.balance(env.ckusdc.ledger, alice, None),
+        INITIAL_BALANCE - CKUSDC_FEE - 5_000_000 - CKUSDC_FEE + 1_000_000 - CKUSDC_FEE
+    );
+    assert_eq!(
+        env.balance(env.ckusdc.ledger, env.canister, None),
+        4_000_000
+    );
+}
+
+#[test]
+fn ckusdc_withdrawal_to_eth_is_followed_until_finalized() {
+    let env = Env::new();
+    let usdc = Currency::CKETHToken(CKTokenSymbol::USDC);
+    let alice = user(0);
+    let added: Result<(), CurrencyError> = env.call(alice, "add_currency", (usdc,));
+    added.unwrap();
+    env.approve_canister(env.ckusdc.ledger, alice, 10_000_000);
+    let deposit: Result<(), CurrencyError> = env.call(alice, "deposit", (usdc, 5_000_000u64));
+    deposit.unwrap();
+
+    let approved: Result<u128, CurrencyError> = env.call(
+        alice,
+        "approve_minter",
+        (CKTokenSymbol::USDC, 1_000_000u128 + CKUSDC_FEE),
+    );
+    approved.unwrap();
+    let withdrawal: Result<u128, CurrencyError> = env.call(
+        alice,
+        "withdraw_to_eth",
+        (
+            CKTokenSymbol::USDC,
+            "0x2d39863d30716aaf2b7fffd85dd03dda2bfc2e38".to_string(),
+            1_000_000u64,
+        ),
+    );
+    let withdrawal_id = withdrawal.unwrap() as u64;
+
+    // The approval fee, then the burn of the withdrawn amount
+    assert_eq!(
+        env.balance(env.ckusdc.ledger, env.canister, None),
+        5_000_000 - CKUSDC_FEE - 1_000_000
+    );
+    let status: Result<CKTokenWithdrawalStatus, CurrencyError> = env.call(
+        alice,
+        "eth_withdrawal_status",
+        (CKTokenSymbol::USDC, withdrawal_id),
+    );
+    assert!(matches!(status, Ok(CKTokenWithdrawalStatus::Pending)));
+
+    let moved: bool = env.update(
+        env.ckusdc.minter,
+        alice,
+        "set_withdrawal_status",
+        (
+            withdrawal_id,
+            MockWithdrawalStatus::Finalized {
+                transaction_hash: "0xab12".to_string(),
+            },
+        ),
+    );
+    assert!(moved);
+    let status: Result<CKTokenWithdrawalStatus, CurrencyError> = env.call(
+        alice,
+        "eth_withdrawal_status",
+        (CKTokenSymbol::USDC, withdrawal_id),
+    );
+    match status.unwrap() {
+        CKTokenWithdrawalStatus::TxFinalized {
+            transaction_hash,
+            effective_fee,
+        } => {
+            assert_eq!(transaction_hash, "0xab12");
+            assert_eq!(effective_fee, None);
+        }
+        status => panic!("unexpected withdrawal status {:?}", status),
+    }
+}
+
+#[test]
+fn cketh_mint_is_found_in_minter_events() {
+    let env = Env::new();
+    let eth = Currency::CKETHToken(CKTokenSymbol::ETH);
+    let added: Result<(), CurrencyError> = env.call(user(0), "add_currency", (eth,));
+    added.unwrap();
+
+    let hash = "0xAB12".to_string();
+    let mint_block: u128 = env.update(
+        env.cketh.minter,
+        user(0),
+        "deposit_eth",
+        (
+            env.canister,
+            1_000_000_000_000_000u128,
+            hash.clone(),
+            None::<String>,
+        ),
+    );
+
+    let found: Result<u64, CurrencyError> = env.call(
+        user(0),
+        "eth_mint_block",
+        (CKTokenSymbol::ETH, hash.to_lowercase()),
+    );
+    assert_eq!(found, Ok(mint_block as u64));
+}
+
+#[test]
+fn ckbtc_deposit_waits_for_confirmations() {
+    let env = Env::new();
+    let alice = user(0);
+    let subaccount = deposit_subaccount(&alice, None).to_vec();
+    let _: () = env.update(
+        env.ckbtc.minter,
+        alice,
+        "add_utxo",
+        (env.canister, Some(subaccount), 100_000u64, 1u32),
+    );
+
+    let pending: Result<BtcDepositUpdate, CurrencyError> =
+        env.call(alice, "update_btc_deposits", ());
+    let pending = pending.unwrap();
+    assert_eq!(pending.pending.len(), 1);
+    assert_eq!(pending.minted_amount, 0);
+
+    let _: () = env.update(env.ckbtc.minter, alice, "confirm_utxos", (5u32,));
+    let minted: Result<BtcDepositUpdate, CurrencyError> =
+        env.call(alice, "update_btc_deposits", ());
+    let minted = minted.unwrap();
+    assert_eq!(minted.minted_amount, 100_000);
+    assert!(minted.sweep.is_some());
+}
+
+#[test]
+fn icp_withdraw_above_balance_is_rejected() {
+    let env = Env::new();
+    let withdraw: Result<(), CurrencyError> =
+        env.call(user(0), "withdraw", (Currency::ICP, 10 * ICP_FEE as u64));
+    assert!(withdraw.unwrap_err().is_user_error());
+    assert_eq!(env.balance(env.icp_ledger, env.canister, None), 0);
+}
@@ -0,0 +1,494 @@
+//! Stand-in for the ckBTC and ckETH minters.
+//!
+//! Implements the minter methods the currency crate calls, minting and burning on a
+//! local ICRC-1 ledger whose minting account is this canister. Bitcoin UTXOs and
+//! Ethereum deposits are injected by the tests, and withdrawals move between states
+//! only when a test says so.
+
+use std::cell::RefCell;
+
+use candid::{CandidType, Nat, Principal};
+use currency::{
+    ckbtc_minter_canister_interface::{
+        GetBtcAddressArg, PendingUtxo, PendingUtxoOutpoint, UpdateBalanceArg, UpdateBalanceError,
+        UpdateBalanceRet, Utxo, UtxoOutpoint, UtxoStatus,
+    },
+    cketh_minter_canister_interface::{
+        EthTransaction, Event, EventPayload, EventSource, GetEventsArg, GetEventsRet, LedgerError,
+        MinterInfo, RetrieveErc20Request, TxFinalizedStatus, WithdrawErc20Arg, WithdrawErc20Error,
+        WithdrawErc20Ret, WithdrawalDetail, WithdrawalSearchParameter, WithdrawalStatus,
+    },
+    currency_error::{CurrencyError, LedgerRejection},
+    icrc1_types::{Account, TransferArg, TransferFromArg},
+    ledger_client::{IcLedgerClient, LedgerClient},
+};
+use serde::Deserialize;
+use serde_bytes::ByteBuf;
+
+#[derive(CandidType, Deserialize)]
+pub struct MockMinterInit {
+    /// Ledger this minter mints to, it must be the ledger's minting account
+    pub ledger_id: Principal,
+    pub token_symbol: String,
+    pub required_confirmations: u32,
+    /// Returned by `smart_contract_address` and `get_minter_info`
+    pub deposit_address: Option<String>,
+}
+
+/// Withdrawal states a test can move a withdrawal to
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum MockWithdrawalStatus {
+    Pending,
+    TxCreated,
+    TxSent {
+        transaction_hash: String,
+    },
+    Finalized {
+        transaction_hash: String,
+    },
+    PendingReimbursement {
+        transaction_hash: String,
+    },
+    Reimbursed {
+        transaction_hash: String,
+        reimbursed_amount: u128,
+        reimbursed_in_block: u128,
+    },
+}
+
+struct MockUtxo {
+    owner: Principal,
+    subaccount: Option<Vec<u8>>,
+    txid: Vec<u8>,
+    value: u64,
+    confirmations: u32,
+    minted: bool,
+}
+
+struct MockWithdrawal {
+    id: u64,
+    from: Principal,
+    from_subaccount: Option<Vec<u8>>,
+    amount: u128,
+    recipient: String,
+    status: MockWithdrawalStatus,
+}
+
+struct MockDeposit {
+    timestamp: u64,
+    transaction_hash: String,
+    mint_block_index: u128,
+    erc20_contract_address: Option<String>,
+}
+
+struct State {
+    init: MockMinterInit,
+    utxos: Vec<MockUtxo>,
+    withdrawals: Vec<MockWithdrawal>,
+    deposits: Vec<MockDeposit>,
+    blocked_addresses: Vec<String>,
+}
+
+thread_local! {
+    static STATE: RefCell<Option<State>> = const { RefCell::new(None) };
+}
+
+fn with_state<R>(f: impl FnOnce(&mut State) -> R) -> R {
+    STATE.with(|s| f(s.borrow_mut().as_mut().expect("minter is initialized")))
+}
+
+fn ledger() -> IcLedgerClient {
+    IcLedgerClient::new(with_state(|s| s.init.ledger_id))
+}
+
+async fn mint(to: Account, amount: u128) -> Result<u128, CurrencyError> {
+    ledger()
+        .transfer(TransferArg {
+            to,
+            fee: None,
+            memo: None,
+            from_subaccount: None,
+            created_at_time: None,
+            amount,
+        })
+        .await
+}
+
+#[ic_cdk::init]
+fn init(init: MockMinterInit) {
+    STATE.with(|s| {
+        *s.borrow_mut() = Some(State {
+            init,
+            utxos: Vec::new(),
+            withdrawals: Vec::new(),
+            deposits: Vec::new(),
+            blocked_addresses: Vec::new(),
+        })
+    });
+}
+
+// ckBTC minter
+
+#[ic_cdk::query]
+fn get_btc_address(arg: GetBtcAddressArg) -> String {
+    let owner = arg.owner.unwrap_or_else(ic_cdk::api::msg_caller);
+    let subaccount = arg.subaccount.map(|s| s.into_vec()).unwrap_or_default();
+    format!(
+        "bcrt1mock{}{}",
+        owner.to_text().replace('-', ""),
+        hex(&subaccount)
+    )
+}
+
+#[ic_cdk::update]
+async fn update_balance(arg: UpdateBalanceArg) -> UpdateBalanceRet {
+    let owner = arg.owner.unwrap_or_else(ic_cdk::api::msg_caller);
+    let subaccount = arg.subaccount.map(|s| s.into_vec());
+    let required_confirmations = with_state(|s| s.init.required_confirmations);
+
+    let (ready, pending): (Vec<_>, Vec<_>) = with_state(|s| {
+        s.utxos
+            .iter_mut()
+            .filter(|u| u.owner == owner && u.subaccount == subaccount && !u.minted)
+            .map(|u| {
+                let ready = u.confirmations >= required_confirmations;
+                // Marked before minting so a concurrent call cannot mint it again
+                u.minted = ready;
+                (ready, u.txid.clone(), u.value, u.confirmations)
+            })
+            .partition(|(ready, ..)| *ready)
+    });
+
+    if ready.is_empty() {
+        return UpdateBalanceRet::Err(UpdateBalanceError::NoNewUtxos {
+            suspended_utxos: None,
+            required_confirmations,
+            pending_utxos: Some(
+                pending
+                    .into_iter()
+                    .map(|(_, txid, value, confirmations)| PendingUtxo {
+                        confirmations,
+                        value,
+                        outpoint: PendingUtxoOutpoint {
+                            txid: ByteBuf::from(txid),
+                            vout: 0,
+                        },
+                    })
+                    .collect(),
+            ),
+            current_confirmations: None,
+        });
+    }
+
+    let mut statuses = Vec::new();
+    for (_, txid, value, _) in ready {
+        let to = Account {
+            owner,
+            subaccount: subaccount.clone(),
+        };
+        let block_index = match mint(to, value as u128).await {
+            Ok(block_index) => block_index,
+            Err(e) => ic_cdk::trap(format!("mint failed: {}", e)),
+        };
+        statuses.push(UtxoStatus::Minted {
+            minted_amount: value,
+            block_index: block_index as u64,
+            utxo: Utxo {
+                height: 0,
+                value,
+                outpoint: UtxoOutpoint {
+                    txid: ByteBuf::from(txid),
+                    vout: 0,
+                },
+            },
+        });
+    }
+    UpdateBalanceRet::Ok(statuses)
+}
+
+// ckETH minter
+
+#[ic_cdk::query]
+fn smart_contract_address() -> Option<String> {
+    with_state(|s| s.init.deposit_address.clone())
+}
+
+#[ic_cdk::query]
+fn get_minter_info() -> MinterInfo {
+    let (ledger_id, deposit_address) =
+        with_state(|s| (s.init.ledger_id, s.init.deposit_address.clone()));
+    MinterInfo {
+        deposit_with_subaccount_helper_contract_address: deposit_address.clone(),
+        eth_balance: None,
+        eth_helper_contract_address: deposit_address.clone(),
+        last_observed_block_number: None,
+        evm_rpc_id: None,
+        erc20_helper_contract_address: None,
+        last_erc20_scraped_block_number: None,
+        supported_ckerc20_tokens: None,
+        last_gas_fee_estimate: None,
+        cketh_ledger_id: Some(ledger_id),
+        smart_contract_address: deposit_address,
+        last_eth_scraped_block_number: None,
+        minimum_withdrawal_amount: None,
+        erc20_balances: None,
+        minter_address: None,
+        last_deposit_with_subaccount_scraped_block_number: None,
+        ethereum_block_height: None,
+    }
+}
+
+#[ic_cdk::query]
+fn is_address_blocked(address: String) -> bool {
+    with_state(|s| s.blocked_addresses.contains(&address.to_lowercase()))
+}
+
+/// Burns the tokens with the allowance the caller granted this minter
+#[ic_cdk::update]
+async fn withdraw_erc20(arg: WithdrawErc20Arg) -> WithdrawErc20Ret {
+    let caller = ic_cdk::api::msg_caller();
+    if is_address_blocked(arg.recipient.clone()) {
+        return WithdrawErc20Ret::Err(WithdrawErc20Error::RecipientAddressBlocked {
+            address: arg.recipient,
+        });
+    }
+
+    let amount = u128::try_from(arg.amount.0.clone()).unwrap_or(u128::MAX);
+    let from_subaccount = arg.from_ckerc20_subaccount.map(|s| s.into_vec());
+    let burn = IcLedgerClient::new(arg.ckerc20_ledger_id)
+        .transfer_from(TransferFromArg {
+            spender_subaccount: None,
+            from: Account {
+                owner: caller,
+                subaccount: from_subaccount.clone(),
+            },
+            to: Account::from(ic_cdk::api::canister_self()),
+            amount,
+            fee: None,
+            memo: None,
+            created_at_time: None,
+        })
+        .await;
+
+    let block_index = match burn {
+        Ok(block_index) => block_index,
+        Err(e) => return WithdrawErc20Ret::Err(burn_error(e, arg.ckerc20_ledger_id, &arg.amount)),
+    };
+
+    let id = block_index as u64;
+    with_state(|s| {
+        s.withdrawals.push(MockWithdrawal {
+            id,
+            from: caller,
+            from_subaccount,
+            amount,
+            recipient: arg.recipient,
+            status: MockWithdrawalStatus::Pending,
+        })
+    });
+
+    WithdrawErc20Ret::Ok(RetrieveErc20Request {
+        ckerc20_block_index: Nat::from(block_index),
+        cketh_block_index: Nat::from(block_index),
+    })
+}
+
+fn burn_error(error: CurrencyError, ledger_id: Principal, amount: &Nat) -> WithdrawErc20Error {
+    let token_symbol = with_state(|s| s.init.token_symbol.clone());
+    let error = match error {
+        CurrencyError::LedgerRejected(LedgerRejection::InsufficientAllowance { allowance }) => {
+            LedgerError::InsufficientAllowance {
+                token_symbol,
+                ledger_id,
+                allowance: Nat::from(allowance),
+                failed_burn_amount: amount.clone(),
+            }
+        }
+        CurrencyError::LedgerRejected(LedgerRejection::InsufficientFunds { balance }) => {
+            LedgerError::InsufficientFunds {
+                balance: Nat::from(balance),
+                token_symbol,
+                ledger_id,
+                failed_burn_amount: amount.clone(),
+            }
+        }
+        e => return WithdrawErc20Error::TemporarilyUnavailable(e.to_string()),
+    };
+    WithdrawErc20Error::CkErc20LedgerError {
+        error,
+        cketh_block_index: Nat::from(0u8),
+    }
+}
+
+#[ic_cdk::query]
+fn withdrawal_status(parameter: WithdrawalSearchParameter) -> Vec<WithdrawalDetail> {
+    let token_symbol = with_state(|s| s.init.token_symbol.clone());
+    with_state(|s| {
+        s.withdrawals
+            .iter()
+            .filter(|w| match &parameter {
+                WithdrawalSearchParameter::ByWithdrawalId(id) => w.id == *id,
+                WithdrawalSearchParameter::ByRecipient(recipient) => w.recipient == *recipient,
+                WithdrawalSearchParameter::BySenderAccount(account) => {
+                    w.from == account.owner
+                        && w.from_subaccount == account.subaccount.clone().map(|s| s.into_vec())
+                }
+            })
+            .map(|w| WithdrawalDetail {
+                status: withdrawal_status_of(&w.status),
+                token_symbol: token_symbol.clone(),
+                withdrawal_amount: Nat::from(w.amount),
+                withdrawal_id: w.id,
+                from: w.from,
+                from_subaccount: w.from_subaccount.clone().map(ByteBuf::from),
+                max_transaction_fee: None,
+                recipient_address: w.recipient.clone(),
+            })
+            .collect()
+    })
+}
+
+fn withdrawal_status_of(status: &MockWithdrawalStatus) -> WithdrawalStatus {
+    let tx = |hash: &String| EthTransaction {
+        transaction_hash: hash.clone(),
+    };
+    match status {
+        MockWithdrawalStatus::Pending => WithdrawalStatus::Pending,
+        MockWithdrawalStatus::TxCreated => WithdrawalStatus::TxCreated,
+        MockWithdrawalStatus::TxSent { transaction_hash } => {
+            WithdrawalStatus::TxSent(tx(transaction_hash))
+        }
+        MockWithdrawalStatus::Finalized { transaction_hash } => {
+            WithdrawalStatus::TxFinalized(TxFinalizedStatus::Success {
+                transaction_hash: transaction_hash.clone(),
+                effective_transaction_fee: None,
+            })
+        }
+        MockWithdrawalStatus::PendingReimbursement { transaction_hash } => {
+            WithdrawalStatus::TxFinalized(TxFinalizedStatus::PendingReimbursement(tx(
+                transaction_hash,
+            )))
+        }
+        MockWithdrawalStatus::Reimbursed {
+            transaction_hash,
+            reimbursed_amount,
+            reimbursed_in_block,
+        } => WithdrawalStatus::TxFinalized(TxFinalizedStatus::Reimbursed {
+            transaction_hash: transaction_hash.clone(),
+            reimbursed_amount: Nat::from(*reimbursed_amount),
+            reimbursed_in_block: Nat::from(*reimbursed_in_block),
+        }),
+    }
+}
+
+#[ic_cdk::query]
+fn get_events(arg: GetEventsArg) -> GetEventsRet {
+    with_state(|s| {
+        let total_event_count = s.deposits.len() as u64;
+        let start = arg.start.min(total_event_count) as usize;
+        let end = arg.start.saturating_add(arg.length).min(total_event_count) as usize;
+        let events = s.deposits[start..end]
+            .iter()
+            .map(|d| {
+                let event_source = EventSource {
+                    transaction_hash: d.transaction_hash.clone(),
+                    log_index: Nat::from(0u8),
+                };
+                let mint_block_index = Nat::from(d.mint_block_index);
+                let payload = match &d.erc20_contract_address {
+                    Some(erc20_contract_address) => EventPayload::MintedCkErc20 {
+                        event_source,
+                        erc20_contract_address: erc20_contract_address.clone(),
+                        mint_block_index,
+                        ckerc20_token_symbol: s.init.token_symbol.clone(),
+                    },
+                    None => EventPayload::MintedCkEth {
+                        event_source,
+                        mint_block_index,
+                    },
+                };
+                Event {
+                    timestamp: d.timestamp,
+                    payload,
+                }
+            })
+            .collect();
+        GetEventsRet {
+            total_event_count,
+            events,
+        }
+    })
+}
+
+// Test controls
+
+/// A BTC transaction paying `value` satoshi to the deposit address of `owner`/`subaccount`
+#[ic_cdk::update]
+fn add_utxo(owner: Principal, subaccount: Option<Vec<u8>>, value: u64, confirmations: u32) {
+    with_state(|s| {
+        let txid = (s.utxos.len() as u64).to_be_bytes().to_vec();
+        s.utxos.push(MockUtxo {
+            owner,
+            subaccount,
+            txid,
+            value,
+            confirmations,
+            minted: false,
+        })
+    });
+}
+
+/// Add confirmations to every UTXO not minted yet
+#[ic_cdk::update]
+fn confirm_utxos(confirmations: u32) {
+    with_state(|s| {
+        for utxo in s.utxos.iter_mut().filter(|u| !u.minted) {
+            utxo.confirmations += confirmations;
+        }
+    });
+}
+
+/// An Ethereum deposit minted to `to`, recorded in the event log. Returns the mint block.
+#[ic_cdk::update]
+async fn deposit_eth(
+    to: Principal,
+    amount: u128,
+    transaction_hash: String,
+    erc20_contract_address: Option<String>,
+) -> u128 {
+    let mint_block_index = match mint(Account::from(to), amount).await {
+        Ok(block_index) => block_index,
+        Err(e) => ic_cdk::trap(format!("mint failed: {}", e)),
+    };
+    with_state(|s| {
+        s.deposits.push(MockDeposit {
+            timestamp: ic_cdk::api::time(),
+            transaction_hash,
+            mint_block_index,
+            erc20_contract_address,
+        })
+    });
+    mint_block_index
+}
+
+#[ic_cdk::update]
+fn set_withdrawal_status(withdrawal_id: u64, status: MockWithdrawalStatus) -> bool {
+    with_state(
+        |s| match s.withdrawals.iter_mut().find(|w| w.id == withdrawal_id) {
+            Some(withdrawal) => {
+                withdrawal.status = status;
+                true
+            }
+            None => false,
+        },
+    )
+}
+
+#[ic_cdk::update]
+fn block_address(address: String) {
+    with_state(|s| s.blocked_addresses.push(address.to_lowercase()));
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
@@ -0,0 +1,145 @@
+//! Canister exposing the currency crate's flows to the PocketIC tests.
+//!
+//! Every endpoint acts for the caller. The manager and transaction state are copied
+//! out of their cells before awaiting, so no borrow is held across a call.
+
+use std::cell::RefCell;
+
+use candid::Principal;
+use currency::{
+    currency_error::CurrencyError,
+    icrc1_types::{Account, ApproveArg},
+    ledger_client::DepositCheck,
+    state::TransactionState,
+    types::{
+        canister_wallets::{
+            btc_token_wallet::BtcDepositUpdate,
+            ckerc20_token_wallet::{CKERC20TokenWallet, CKTokenWithdrawalStatus},
+        },
+        currency::CKTokenSymbol,
+        currency_manager::CurrencyManager,
+        network_config::NetworkConfig,
+    },
+    Currency,
+};
+
+thread_local! {
+    static MANAGER: RefCell<CurrencyManager> = RefCell::new(CurrencyManager::new());
+    static TRANSACTIONS: RefCell<TransactionState> = RefCell::new(TransactionState::new());
+}
+
+fn manager() -> CurrencyManager {
+    MANAGER.with(|m| m.borrow().clone())
+}
+
+fn ckerc20_wallet(token: CKTokenSymbol) -> Result<CKERC20TokenWallet, CurrencyError> {
+    manager()
+        .ckerc20_tokens
+        .into_iter()
+        .find(|w| w.config.token_symbol == Currency::CKETHToken(token))
+        .ok_or(CurrencyError::WalletNotSet)
+}
+
+#[ic_cdk::init]
+fn init(network: NetworkConfig) {
+    MANAGER.with(|m| m.borrow_mut().set_network_config(network));
+}
+
+#[ic_cdk::update]
+async fn add_currency(currency: Currency) -> Result<(), CurrencyError> {
+    let mut manager = manager();
+    manager.add_currency(currency).await?;
+    MANAGER.with(|m| *m.borrow_mut() = manager);
+    Ok(())
+}
+
+#[ic_cdk::update]
+async fn deposit(currency: Currency, amount: u64) -> Result<(), CurrencyError> {
+    let mut transactions = TRANSACTIONS.with(|t| t.borrow().clone());
+    manager()
+        .deposit(
+            &mut transactions,
+            &currency,
+            ic_cdk::api::msg_caller(),
+            amount,
+        )
+        .await?;
+    TRANSACTIONS.with(|t| *t.borrow_mut() = transactions);
+    Ok(())
+}
+
+#[ic_cdk::update]
+async fn check_deposit(currency: Currency, amount: u64) -> Result<DepositCheck, CurrencyError> {
+    manager()
+        .check_deposit(&currency, ic_cdk::api::msg_caller(), amount)
+        .await
+}
+
+#[ic_cdk::update]
+async fn withdraw(currency: Currency, amount: u64) -> Result<(), CurrencyError> {
+    manager()
+        .withdraw(&currency, ic_cdk::api::msg_caller(), amount)
+        .await
+}
+
+#[ic_cdk::update]
+async fn get_balance(currency: Currency, principal: Principal) -> Result<u128, CurrencyError> {
+    manager().get_balance(&currency, principal).await
+}
+
+#[ic_cdk::update]
+async fn btc_deposit_address() -> Result<String, CurrencyError> {
+    manager()
+        .get_btc_deposit_address(ic_cdk::api::msg_caller(), None)
+        .await
+}
+
+#[ic_cdk::update]
+async fn update_btc_deposits() -> Result<BtcDepositUpdate, CurrencyError> {
+    manager()
+        .update_btc_deposits(ic_cdk::api::msg_caller(), None)
+        .await
+}
+
+/// Let the token's minter burn `amount` of the canister's tokens, which `withdraw_to_eth` needs
+#[ic_cdk::update]
+async fn approve_minter(token: CKTokenSymbol, amount: u128) -> Result<u128, CurrencyError> {
+    let minter = ckerc20_wallet(token)?.config.minter_id;
+    manager()
+        .approve(
+            &Currency::CKETHToken(token),
+            ApproveArg::new(Account::from(minter), amount),
+        )
+        .await
+}
+
+#[ic_cdk::update]
+async fn withdraw_to_eth(
+    token: CKTokenSymbol,
+    eth_address: String,
+    amount: u64,
+) -> Result<u128, CurrencyError> {
+    ckerc20_wallet(token)?
+        .request_eth_withdrawal(eth_address, amount)
+        .await
+}
+
+#[ic_cdk::update]
+async fn eth_withdrawal_status(
+    token: CKTokenSymbol,
+    withdrawal_id: u64,
+) -> Result<CKTokenWithdrawalStatus, CurrencyError> {
+    ckerc20_wallet(token)?
+        .check_withdrawal_status(withdrawal_id)
+        .await
+}
+
+#[ic_cdk::update]
+async fn eth_mint_block(
+    token: CKTokenSymbol,
+    transaction_hash: String,
+) -> Result<u64, CurrencyError> {
+    ckerc20_wallet(token)?
+        .get_mint_block_number(transaction_hash)
+        .await
+}
@@ -0,0 +1,28 @@
+#!/bin/bash
+
+# Download the ledgers and build the canisters used by the PocketIC tests.
+# The pocket-ic crate downloads the PocketIC server itself.
+
+DIR=target/ic
+
+if [ ! -d "$DIR" ]; then
+  mkdir -p "$DIR"
+fi
+
+IC_COMMIT="03dd6ee6de80c2202f66948692c69c61eb6af54d"
+
+download_if_not_exists() {
+  local url=$1
+  local output=$2
+  if [ ! -f "$output" ]; then
+    curl -sSL "$url" -o "$output"
+  else
+    echo "File $output already exists, skipping download."
+  fi
+}
+
+download_if_not_exists "https://download.dfinity.systems/ic/$IC_COMMIT/canisters/ledger-canister.wasm.gz" "$DIR/icp_ledger.wasm.gz"
+
+download_if_not_exists "https://download.dfinity.systems/ic/$IC_COMMIT/canisters/ic-icrc1-ledger.wasm.gz" "$DIR/icrc1_ledger.wasm.gz"
+
+cargo build --manifest-path integration/Cargo.toml --target wasm32-unknown-unknown --release -p mock_minter -p test_canister
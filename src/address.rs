@@ -0,0 +1,284 @@
+//! Validation of Ethereum and Bitcoin withdrawal destinations.
+//!
+//! Withdrawals to L1 are checked before any ledger or minter is called, so a typo
+//! returns an [`AddressError`] instead of burning tokens for a failing withdrawal.
+
+use bech32::primitives::decode::SegwitHrpstringError;
+use candid::CandidType;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sha3::Keccak256;
+use thiserror::Error;
+
+use crate::{
+    currency_error::CurrencyError,
+    ledger_client::{IcMinterClient, MinterClient},
+    types::{
+        canister_wallets::ckerc20_token_wallet::CKERC20TokenWallet,
+        currency_manager::CurrencyManager,
+    },
+    Currency,
+};
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, CandidType, PartialEq, Eq, Hash)]
+pub enum BitcoinNetwork {
+    Mainnet,
+    Testnet,
+    Regtest,
+}
+
+impl BitcoinNetwork {
+    /// Human readable part of segwit addresses
+    fn hrp(&self) -> &'static str {
+        match self {
+            BitcoinNetwork::Mainnet => "bc",
+            BitcoinNetwork::Testnet => "tb",
+            BitcoinNetwork::Regtest => "bcrt",
+        }
+    }
+
+    /// Version bytes of base58 P2PKH and P2SH addresses
+    fn base58_versions(&self) -> (u8, u8) {
+        match self {
+            BitcoinNetwork::Mainnet => (0x00, 0x05),
+            BitcoinNetwork::Testnet | BitcoinNetwork::Regtest => (0x6f, 0xc4),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, CandidType, PartialEq, Eq, Hash)]
+pub enum BitcoinAddressType {
+    P2pkh,
+    P2sh,
+    P2wpkh,
+    P2wsh,
+    P2tr,
+}
+
+#[derive(Error, Debug, Clone, CandidType, Serialize, Deserialize, PartialEq, Eq)]
+pub enum AddressError {
+    #[error("address is empty")]
+    Empty,
+
+    #[error("address is malformed: {0}")]
+    Malformed(String),
+
+    #[error("address has {found} bytes, expected {expected}")]
+    InvalidLength { expected: u32, found: u32 },
+
+    #[error("address checksum is invalid")]
+    InvalidChecksum,
+
+    #[error("address is for {found:?}, expected {expected:?}")]
+    WrongNetwork {
+        expected: BitcoinNetwork,
+        found: BitcoinNetwork,
+    },
+
+    #[error("unsupported address type: {0}")]
+    UnsupportedAddressType(String),
+
+    #[error("address {0} is blocked by the minter")]
+    Blocked(String),
+}
+
+/// EIP-55 checksummed form of an Ethereum address
+pub fn eth_checksum_address(address: &[u8; 20]) -> String {
+    let lower = hex::encode(address);
+    let hash = Keccak256::digest(lower.as_bytes());
+
+    let checksummed: String = lower
+        .chars()
+        .enumerate()
+        .map(|(i, c)| {
+            let nibble = (hash[i / 2] >> (if i % 2 == 0 { 4 } else { 0 })) & 0x0f;
+            if nibble >= 8 {
+                c.to_ascii_uppercase()
+            } else {
+                c
+            }
+        })
+        .collect();
+    format!("0x{}", checksummed)
+}
+
+/// Validate a `0x` prefixed Ethereum address and return its bytes.
+///
+/// All-lowercase and all-uppercase addresses carry no checksum and are accepted as is,
+/// mixed-case addresses must match their EIP-55 checksum.
+pub fn validate_eth_address(address: &str) -> Result<[u8; 20], AddressError> {
+    if address.is_empty() {
+        return Err(AddressError::Empty);
+    }
+    let hex_part = address
+        .strip_prefix("0x")
+        .ok_or_else(|| AddressError::Malformed("missing 0x prefix".to_string()))?;
+
+    let bytes = hex::decode(hex_part)
+        .map_err(|_| AddressError::Malformed("not hexadecimal".to_string()))?;
+    let bytes: [u8; 20] =
+        bytes
+            .try_into()
+            .map_err(|bytes: Vec<u8>| AddressError::InvalidLength {
+                expected: 20,
+                found: bytes.len() as u32,
+            })?;
+
+    let has_lower = hex_part.chars().any(|c| c.is_ascii_lowercase());
+    let has_upper = hex_part.chars().any(|c| c.is_ascii_uppercase());
+    if has_lower && has_upper && eth_checksum_address(&bytes)[2..] != *hex_part {
+        return Err(AddressError::InvalidChecksum);
+    }
+
+    Ok(bytes)
+}
+
+fn validate_segwit_address(
+    address: &str,
+    network: BitcoinNetwork,
+) -> Result<BitcoinAddressType, AddressError> {
+    // The checksum also tells bech32 (version 0) from bech32m (version 1 and up)
+    let (hrp, version, program) = bech32::segwit::decode(address).map_err(|e| match e.0 {
+        SegwitHrpstringError::Checksum(_) => AddressError::InvalidChecksum,
+        e => AddressError::Malformed(e.to_string()),
+    })?;
+
+    // Segwit addresses may be written in uppercase
+    let hrp = hrp.as_str().to_lowercase();
+    if hrp != network.hrp() {
+        let found = [
+            BitcoinNetwork::Mainnet,
+            BitcoinNetwork::Testnet,
+            BitcoinNetwork::Regtest,
+        ]
+        .into_iter()
+        .find(|n| n.hrp() == hrp);
+        return Err(match found {
+            Some(found) => AddressError::WrongNetwork {
+                expected: network,
+                found,
+            },
+            None => AddressError::Malformed(format!("unknown prefix {}", hrp)),
+        });
+    }
+
+    match (version.to_u8(), program.len()) {
+        (0, 20) => Ok(BitcoinAddressType::P2wpkh),
+        (0, 32) => Ok(BitcoinAddressType::P2wsh),
+        (1, 32) => Ok(BitcoinAddressType::P2tr),
+        (version, length) => Err(AddressError::UnsupportedAddressType(format!(
+            "witness version {} with a {} byte program",
+            version, length
+        ))),
+    }
+}
+
+fn validate_base58_address(
+    address: &str,
+    network: BitcoinNetwork,
+) -> Result<BitcoinAddressType, AddressError> {
+    let bytes = bs58::decode(address)
+        .into_vec()
+        .map_err(|e| AddressError::Malformed(e.to_string()))?;
+    if bytes.len() != 25 {
+        return Err(AddressError::InvalidLength {
+            expected: 25,
+            found: bytes.len() as u32,
+        });
+    }
+
+    let (payload, checksum) = bytes.split_at(21);
+    if Sha256::digest(Sha256::digest(payload))[..4] != *checksum {
+        return Err(AddressError::InvalidChecksum);
+    }
+
+    let (p2pkh, p2sh) = network.base58_versions();
+    match payload[0] {
+        version if version == p2pkh => Ok(BitcoinAddressType::P2pkh),
+        version if version == p2sh => Ok(BitcoinAddressType::P2sh),
+        0x00 | 0x05 => Err(AddressError::WrongNetwork {
+            expected: network,
+            found: BitcoinNetwork::Mainnet,
+        }),
+        0x6f | 0xc4 => Err(AddressError::WrongNetwork {
+            expected: network,
+            found: BitcoinNetwork::Testnet,
+        }),
+        version => Err(AddressError::UnsupportedAddressType(format!(
+            "version byte {:#04x}",
+            version
+        ))),
+    }
+}
+
+/// Validate a Bitcoin address for `network` and return its type
+pub fn validate_btc_address(
+    address: &str,
+    network: BitcoinNetwork,
+) -> Result<BitcoinAddressType, AddressError> {
+    if address.is_empty() {
+        return Err(AddressError::Empty);
+    }
+
+    // Segwit addresses are `<hrp>1<data>`, base58 never contains a `0` but may contain `1`
+    let is_segwit = ["bc1", "tb1", "bcrt1"]
+        .iter()
+        .any(|prefix| address.to_lowercase().starts_with(prefix));
+    if is_segwit {
+        validate_segwit_address(address, network)
+    } else {
+        validate_base58_address(address, network)
+    }
+}
+
+/// Validate an Ethereum destination and ask `minter` whether it is blocked
+pub async fn check_eth_address(
+    minter: &impl MinterClient,
+    address: &str,
+) -> Result<(), CurrencyError> {
+    validate_eth_address(address).map_err(CurrencyError::InvalidAddress)?;
+
+    if minter.is_address_blocked(address).await? {
+        return Err(CurrencyError::InvalidAddress(AddressError::Blocked(
+            address.to_string(),
+        )));
+    }
+    Ok(())
+}
+
+impl CKERC20TokenWallet {
+    /// Validate an Ethereum destination and ask the minter whether it is blocked
+    pub async fn check_eth_destination(&self, address: &str) -> Result<(), CurrencyError> {
+        check_eth_address(&IcMinterClient::new(self.config.minter_id), address).await
+    }
+}
+
+impl CurrencyManager {
+    /// Check a withdrawal destination on L1 before any funds move.
+    ///
+    /// BTC addresses are validated for the configured Bitcoin network, Ethereum
+    /// addresses are also checked against the ckETH minter's blocklist.
+    pub async fn validate_withdrawal_address(
+        &self,
+        currency: &Currency,
+        address: &str,
+    ) -> Result<(), CurrencyError> {
+        match currency {
+            Currency::BTC => validate_btc_address(address, self.network_config().bitcoin_network())
+                .map(|_| ())
+                .map_err(CurrencyError::InvalidAddress),
+            Currency::CKETHToken(_) => {
+                let wallet = self
+                    .ckerc20_tokens
+                    .iter()
+                    .find(|w| w.config.token_symbol == *currency)
+                    .ok_or(CurrencyError::WalletNotSet)?;
+                wallet.check_eth_destination(address).await
+            }
+            _ => Err(CurrencyError::OperationNotSupported(format!(
+                "{} has no L1 withdrawal address",
+                currency
+            ))),
+        }
+    }
+}
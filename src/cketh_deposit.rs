@@ -0,0 +1,364 @@
+//! ckETH / ckERC20 deposits attributed to users.
+//!
+//! Users deposit through the minter's "deposit with subaccount" helper contract,
+//! passing the canister's principal and their deposit subaccount. The watcher follows
+//! the minter event stream, remembers the accepted deposits addressed to this canister
+//! and reports them once the minter minted them, exactly once like all stream events.
+
+use std::{borrow::Cow, cell::RefCell, time::Duration};
+
+use candid::{CandidType, Decode, Encode, Principal};
+use ic_cdk_timers::TimerId;
+use ic_stable_structures::{
+    memory_manager::VirtualMemory, storable::Bound, DefaultMemoryImpl, StableBTreeMap, Storable,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    address::validate_eth_address,
+    cketh_minter_canister_interface::MinterInfo,
+    currency_error::{call_error, CurrencyError},
+    deposit_subaccount::{deposit_subaccount, user_from_deposit_subaccount},
+    minter_events::{
+        start_minter_event_stream, MinterEvent, MinterEventCategory, MinterEventSubscribers,
+    },
+    retry::{retry_policy, retry_query},
+    types::{
+        canister_wallets::ckerc20_token_wallet::CKERC20TokenWallet, currency::CKTokenSymbol,
+        currency_manager::CurrencyManager,
+    },
+    Currency,
+};
+
+pub type EthDepositMemory = VirtualMemory<DefaultMemoryImpl>;
+
+/// `depositEth(bytes32,bytes32)`
+const DEPOSIT_ETH_SELECTOR: [u8; 4] = [0x17, 0xc8, 0x19, 0xc4];
+/// `depositErc20(address,uint256,bytes32,bytes32)`
+const DEPOSIT_ERC20_SELECTOR: [u8; 4] = [0xdb, 0x97, 0x51, 0xaf];
+
+/// Encode a principal the way the helper contract expects it: length byte, bytes, zero padding
+pub fn principal_to_bytes32(principal: &Principal) -> [u8; 32] {
+    let bytes = principal.as_slice();
+    let mut encoded = [0u8; 32];
+    encoded[0] = bytes.len() as u8;
+    encoded[1..1 + bytes.len()].copy_from_slice(bytes);
+    encoded
+}
+
+fn abi_word_u128(value: u128) -> [u8; 32] {
+    let mut word = [0u8; 32];
+    word[16..].copy_from_slice(&value.to_be_bytes());
+    word
+}
+
+fn abi_word_address(address: &[u8; 20]) -> [u8; 32] {
+    let mut word = [0u8; 32];
+    word[12..].copy_from_slice(address);
+    word
+}
+
+/// Everything a wallet needs to send a deposit to the helper contract
+#[derive(Debug, Clone, Serialize, Deserialize, CandidType, PartialEq, Eq)]
+pub struct EthDepositInstructions {
+    pub helper_contract_address: String,
+    /// ERC-20 contract on which the user must first approve the helper contract
+    /// for `amount`, `None` for ETH deposits
+    pub erc20_contract_address: Option<String>,
+    /// Hex encoded call data, `0x` prefixed
+    pub call_data: String,
+    /// Wei to send along with the call, `None` for ERC-20 deposits
+    pub value: Option<u128>,
+    pub principal: String,
+    pub subaccount: String,
+}
+
+impl CKERC20TokenWallet {
+    /// Helper-contract call depositing `amount` into `user`'s deposit subaccount of this canister
+    pub async fn deposit_instructions(
+        &self,
+        user: Principal,
+        namespace: Option<u16>,
+        amount: u128,
+    ) -> Result<EthDepositInstructions, CurrencyError> {
+        let minter_id = self.config.minter_id;
+        let (minter_info,): (MinterInfo,) = retry_query(&retry_policy(), |_| async {
+            ic_cdk::call(minter_id, "get_minter_info", ())
+                .await
+                .map_err(call_error(minter_id, "get_minter_info"))
+        })
+        .await?;
+
+        let helper_contract_address = minter_info
+            .deposit_with_subaccount_helper_contract_address
+            .ok_or(CurrencyError::NoDepositAddress)?;
+
+        let principal = principal_to_bytes32(&ic_cdk::api::canister_self());
+        let subaccount = deposit_subaccount(&user, namespace);
+
+        let mut call_data = Vec::with_capacity(4 + 4 * 32);
+        let (erc20_contract_address, value) =
+            if self.config.token_symbol == Currency::CKETHToken(CKTokenSymbol::ETH) {
+                call_data.extend_from_slice(&DEPOSIT_ETH_SELECTOR);
+                (None, Some(amount))
+            } else {
+                let erc20_contract_address = minter_info
+                    .supported_ckerc20_tokens
+                    .unwrap_or_default()
+                    .into_iter()
+                    .find(|token| token.ledger_canister_id == self.config.ledger_id)
+                    .map(|token| token.erc20_contract_address)
+                    .ok_or_else(|| {
+                        CurrencyError::OperationNotSupported(format!(
+                            "The minter does not support {}",
+                            self.config.token_symbol
+                        ))
+                    })?;
+
+                call_data.extend_from_slice(&DEPOSIT_ERC20_SELECTOR);
+                call_data.extend_from_slice(&abi_word_address(
+                    &validate_eth_address(&erc20_contract_address)
+                        .map_err(CurrencyError::InvalidAddress)?,
+                ));
+                call_data.extend_from_slice(&abi_word_u128(amount));
+                (Some(erc20_contract_address), None)
+            };
+        call_data.extend_from_slice(&principal);
+        call_data.extend_from_slice(&subaccount);
+
+        Ok(EthDepositInstructions {
+            helper_contract_address,
+            erc20_contract_address,
+            call_data: format!("0x{}", hex::encode(call_data)),
+            value,
+            principal: format!("0x{}", hex::encode(principal)),
+            subaccount: format!("0x{}", hex::encode(subaccount)),
+        })
+    }
+}
+
+impl CurrencyManager {
+    /// Helper-contract call depositing `amount` of a ckETH/ckERC20 currency for `user`,
+    /// see [`CKERC20TokenWallet::deposit_instructions`]
+    pub async fn get_eth_deposit_instructions(
+        &self,
+        currency: &Currency,
+        user: Principal,
+        namespace: Option<u16>,
+        amount: u128,
+    ) -> Result<EthDepositInstructions, CurrencyError> {
+        let Currency::CKETHToken(_) = currency else {
+            return Err(CurrencyError::OperationNotSupported(format!(
+                "{} is not deposited through the ckETH minter",
+                currency
+            )));
+        };
+
+        let wallet = self
+            .ckerc20_tokens
+            .iter()
+            .find(|w| w.config.token_symbol == *currency)
+            .ok_or(CurrencyError::WalletNotSet)?;
+        wallet.deposit_instructions(user, namespace, amount).await
+    }
+}
+
+/// A deposit accepted by the minter but not minted yet
+#[derive(Debug, Clone, Serialize, Deserialize, CandidType, PartialEq, Eq)]
+pub struct PendingEthDeposit {
+    pub transaction_hash: String,
+    pub log_index: u128,
+    pub subaccount: Option<Vec<u8>>,
+    pub amount: u128,
+    pub from_address: String,
+    /// `None` for ETH deposits
+    pub erc20_contract_address: Option<String>,
+}
+
+impl Storable for PendingEthDeposit {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).expect("Failed to encode pending ETH deposit"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).expect("Failed to decode pending ETH deposit")
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+/// A deposit minted by the minter for this canister
+#[derive(Debug, Clone, Serialize, Deserialize, CandidType, PartialEq, Eq)]
+pub struct EthDepositEvent {
+    pub currency: Currency,
+    /// User owning the deposit subaccount, `None` for deposits to other subaccounts
+    pub user: Option<Principal>,
+    pub subaccount: Option<Vec<u8>>,
+    pub amount: u128,
+    pub mint_block_index: u128,
+    pub transaction_hash: String,
+    pub from_address: String,
+}
+
+/// Accepted deposits waiting to be minted, stored in a dedicated virtual memory
+pub struct EthDepositWatcher {
+    pending: StableBTreeMap<String, PendingEthDeposit, EthDepositMemory>,
+}
+
+impl EthDepositWatcher {
+    pub fn init(memory: EthDepositMemory) -> Self {
+        Self {
+            pending: StableBTreeMap::init(memory),
+        }
+    }
+
+    pub fn pending(&self) -> Vec<PendingEthDeposit> {
+        self.pending.iter().map(|(_, deposit)| deposit).collect()
+    }
+
+    /// Apply one minter event, returns the deposit if the event completed one
+    fn apply(
+        &mut self,
+        manager: &CurrencyManager,
+        canister_id: Principal,
+        event: &MinterEvent,
+    ) -> Option<EthDepositEvent> {
+        match event {
+            MinterEvent::EthDepositAccepted {
+                source,
+                principal,
+                subaccount,
+                amount,
+                from_address,
+                erc20_contract_address,
+            } if *principal == canister_id => {
+                self.pending.insert(
+                    source.key(),
+                    PendingEthDeposit {
+                        transaction_hash: source.transaction_hash.clone(),
+                        log_index: source.log_index,
+                        subaccount: subaccount.clone(),
+                        amount: *amount,
+                        from_address: from_address.clone(),
+                        erc20_contract_address: erc20_contract_address.clone(),
+                    },
+                );
+                None
+            }
+            MinterEvent::EthDepositMinted {
+                source,
+                mint_block_index,
+                ckerc20_token_symbol,
+            } => {
+                let deposit = self.pending.remove(&source.key())?;
+                let currency = match ckerc20_token_symbol {
+                    None => Some(Currency::CKETHToken(CKTokenSymbol::ETH)),
+                    Some(symbol) => manager
+                        .ckerc20_tokens
+                        .iter()
+                        .map(|wallet| wallet.config.token_symbol)
+                        .find(|currency| format!("ck{}", currency) == *symbol),
+                };
+
+                match currency {
+                    Some(currency) => Some(minted(currency, deposit, *mint_block_index)),
+                    None => {
+                        ic_cdk::println!(
+                            "Minted {:?} deposit {} for an unsupported token",
+                            ckerc20_token_symbol,
+                            deposit.transaction_hash
+                        );
+                        None
+                    }
+                }
+            }
+            // Deposits that will never be minted
+            MinterEvent::EthDepositQuarantined { source }
+            | MinterEvent::EthDepositInvalid { source, .. } => {
+                self.pending.remove(&source.key());
+                None
+            }
+            _ => None,
+        }
+    }
+}
+
+fn minted(
+    currency: Currency,
+    deposit: PendingEthDeposit,
+    mint_block_index: u128,
+) -> EthDepositEvent {
+    EthDepositEvent {
+        currency,
+        user: deposit
+            .subaccount
+            .as_deref()
+            .and_then(user_from_deposit_subaccount)
+            .map(|(user, _)| user),
+        subaccount: deposit.subaccount,
+        amount: deposit.amount,
+        mint_block_index,
+        transaction_hash: deposit.transaction_hash,
+        from_address: deposit.from_address,
+    }
+}
+
+thread_local! {
+    static WATCHER: RefCell<Option<EthDepositWatcher>> = const { RefCell::new(None) };
+}
+
+/// Initialise the watcher on a memory obtained from the canister's `MemoryManager`.
+/// Must be called from both `init` and `post_upgrade`.
+pub fn init_eth_deposit_watcher(memory: EthDepositMemory) {
+    WATCHER.with(|watcher| *watcher.borrow_mut() = Some(EthDepositWatcher::init(memory)));
+}
+
+/// Run a closure against the watcher
+pub fn with_eth_deposit_watcher<R>(
+    f: impl FnOnce(&mut EthDepositWatcher) -> R,
+) -> Result<R, CurrencyError> {
+    WATCHER.with(|watcher| match watcher.borrow_mut().as_mut() {
+        Some(watcher) => Ok(f(watcher)),
+        None => Err(CurrencyError::DepositWatcherNotInitialized),
+    })
+}
+
+/// Subscribe `on_deposit` to the minted ckETH/ckERC20 deposits of a minter event stream
+pub fn subscribe_eth_deposits(
+    subscribers: MinterEventSubscribers,
+    on_deposit: impl Fn(&EthDepositEvent) + 'static,
+) -> MinterEventSubscribers {
+    subscribers.subscribe(
+        &[
+            MinterEventCategory::Deposit,
+            MinterEventCategory::Quarantine,
+        ],
+        move |manager, record| {
+            let canister_id = ic_cdk::api::canister_self();
+            match with_eth_deposit_watcher(|watcher| {
+                watcher.apply(manager, canister_id, &record.event)
+            }) {
+                Ok(Some(deposit)) => on_deposit(&deposit),
+                Ok(None) => {}
+                // Trap so the cursor is not advanced past the event
+                Err(e) => ic_cdk::trap(e.to_string()),
+            }
+        },
+    )
+}
+
+/// Start a minter event stream that only reports ckETH/ckERC20 deposits.
+///
+/// To also handle other minter events, pass [`subscribe_eth_deposits`] to the
+/// canister's single [`start_minter_event_stream`] instead.
+pub fn start_eth_deposit_watcher(
+    interval: Duration,
+    manager: impl Fn() -> Option<CurrencyManager> + 'static,
+    on_deposit: impl Fn(&EthDepositEvent) + 'static,
+) -> TimerId {
+    start_minter_event_stream(
+        interval,
+        manager,
+        subscribe_eth_deposits(MinterEventSubscribers::new(), on_deposit),
+    )
+}
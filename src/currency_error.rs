@@ -1,9 +1,21 @@
-use candid::CandidType;
+use candid::{CandidType, Principal};
+use num_traits::ToPrimitive;
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
+use crate::{
+    address::AddressError,
+    ckbtc_minter_canister_interface::UpdateBalanceError,
+    cketh_minter_canister_interface::{LedgerError as CkEthLedgerError, WithdrawErc20Error},
+    icrc1_types::{TransferErrorIcrc1, TransferFromError},
+    icrc_ledger_canister_interface as icrc_ledger,
+};
+
 // Define a new encompassing error type that includes GameError and LockError
-#[derive(Error, Debug, CandidType, Serialize, Deserialize, PartialEq, Eq)]
+//
+// Variants are only ever appended and keep their payload, so frontends decoding
+// the candid encoding of older versions keep working.
+#[derive(Error, Debug, Clone, CandidType, Serialize, Deserialize, PartialEq, Eq)]
 pub enum CurrencyError {
     #[error("failed to acquire lock")]
     LockError,
@@ -70,4 +82,562 @@ pub enum CurrencyError {
 
     #[error("Operation not supported: {0}")]
     OperationNotSupported(String),
+
+    #[error("Withdrawal outbox not initialized")]
+    OutboxNotInitialized,
+
+    #[error("Invalid token metadata: {0}")]
+    InvalidTokenMetadata(String),
+
+    #[error("No index canister known for {0}")]
+    IndexCanisterNotFound(String),
+
+    #[error("Deposit watcher not initialized")]
+    DepositWatcherNotInitialized,
+
+    #[error("Invalid address: {0}")]
+    InvalidAddress(AddressError),
+
+    #[error("Minter event stream not initialized")]
+    MinterEventStreamNotInitialized,
+
+    #[error("Reimbursement tracker not initialized")]
+    ReimbursementTrackerNotInitialized,
+
+    #[error("Sweep log not initialized")]
+    SweepLogNotInitialized,
+
+    #[error("Ledger rejected the transaction: {0}")]
+    LedgerRejected(LedgerRejection),
+
+    #[error("Minter rejected the request: {0}")]
+    MinterRejected(MinterRejection),
+
+    /// The call was rejected before the callee changed any state
+    #[error("Call was rejected: {0}")]
+    CallRejected(CallError),
+
+    /// The callee may or may not have executed the call. Do not report this as a
+    /// failure to the user, reconcile it instead (e.g. through the outbox).
+    #[error("Call outcome is unknown: {0}")]
+    CallOutcomeUnknown(CallError),
+}
+
+impl CurrencyError {
+    /// Whether the same request may succeed when retried later
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            CurrencyError::LockError
+            | CurrencyError::CanisterCallFailed(_)
+            | CurrencyError::BlockQueryFailed(_)
+            | CurrencyError::QueryError(_)
+            | CurrencyError::GetBlockError(_) => true,
+            CurrencyError::CallRejected(error) => error.is_transient(),
+            // Replays carry the original created_at_time and are deduplicated by the ledger
+            CurrencyError::CallOutcomeUnknown(_) => true,
+            CurrencyError::LedgerRejected(rejection) => matches!(
+                rejection,
+                LedgerRejection::TemporarilyUnavailable | LedgerRejection::CreatedInFuture { .. }
+            ),
+            CurrencyError::MinterRejected(rejection) => matches!(
+                rejection,
+                MinterRejection::TemporarilyUnavailable(_) | MinterRejection::AlreadyProcessing
+            ),
+            _ => false,
+        }
+    }
+
+    /// Whether the request can only succeed after the user changes something,
+    /// e.g. their balance, allowance, amount or address
+    pub fn is_user_error(&self) -> bool {
+        match self {
+            CurrencyError::InsufficientFunds
+            | CurrencyError::InsufficientAllowance
+            | CurrencyError::DuplicateTransaction
+            | CurrencyError::InvalidAddress(_) => true,
+            CurrencyError::LedgerRejected(rejection) => matches!(
+                rejection,
+                LedgerRejection::InsufficientFunds { .. }
+                    | LedgerRejection::InsufficientAllowance { .. }
+                    | LedgerRejection::BadBurn { .. }
+                    | LedgerRejection::AllowanceChanged { .. }
+                    | LedgerRejection::Expired { .. }
+            ),
+            CurrencyError::MinterRejected(rejection) => matches!(
+                rejection,
+                MinterRejection::AmountTooLow { .. }
+                    | MinterRejection::InsufficientFunds { .. }
+                    | MinterRejection::InsufficientAllowance { .. }
+                    | MinterRejection::RecipientAddressBlocked { .. }
+                    | MinterRejection::NoNewUtxos { .. }
+            ),
+            _ => false,
+        }
+    }
+
+    /// Whether the call may have taken effect even though it returned an error
+    pub fn is_outcome_unknown(&self) -> bool {
+        matches!(self, CurrencyError::CallOutcomeUnknown(_))
+    }
+}
+
+/// Reject code of a failed inter-canister call
+#[derive(Debug, Clone, Copy, CandidType, Serialize, Deserialize, PartialEq, Eq)]
+pub enum CallRejectCode {
+    SysFatal,
+    SysTransient,
+    DestinationInvalid,
+    CanisterReject,
+    CanisterError,
+    /// The call timed out or its response was lost
+    SysUnknown,
+}
+
+/// A rejected inter-canister call
+#[derive(Error, Debug, Clone, CandidType, Serialize, Deserialize, PartialEq, Eq)]
+#[error("{method} on {canister_id} failed with {code:?}: {message}")]
+pub struct CallError {
+    pub canister_id: Principal,
+    pub method: String,
+    pub code: CallRejectCode,
+    pub message: String,
+}
+
+impl CallError {
+    /// Whether the callee certainly did not change its state.
+    ///
+    /// Like `ic_cdk::call::CallErrorExt::is_clean_reject` only system rejects are
+    /// trusted: a canister reject or error (including a reply that could not be
+    /// decoded) may come after the callee already executed the call.
+    pub fn is_clean_reject(&self) -> bool {
+        matches!(
+            self.code,
+            CallRejectCode::SysFatal
+                | CallRejectCode::SysTransient
+                | CallRejectCode::DestinationInvalid
+        )
+    }
+
+    /// Whether calling again may succeed without any change on either side
+    pub fn is_transient(&self) -> bool {
+        matches!(
+            self.code,
+            CallRejectCode::SysTransient | CallRejectCode::SysUnknown
+        )
+    }
+}
+
+impl From<CallError> for CurrencyError {
+    fn from(error: CallError) -> Self {
+        if error.is_clean_reject() {
+            CurrencyError::CallRejected(error)
+        } else {
+            CurrencyError::CallOutcomeUnknown(error)
+        }
+    }
+}
+
+impl CallRejectCode {
+    fn from_raw(code: u32) -> Self {
+        match code {
+            1 => CallRejectCode::SysFatal,
+            2 => CallRejectCode::SysTransient,
+            3 => CallRejectCode::DestinationInvalid,
+            4 => CallRejectCode::CanisterReject,
+            5 => CallRejectCode::CanisterError,
+            // Anything unexpected is treated as an unknown outcome
+            _ => CallRejectCode::SysUnknown,
+        }
+    }
+}
+
+/// Errors of the legacy and the current `ic_cdk` call APIs
+pub(crate) trait IntoCallReject {
+    fn into_call_reject(self) -> (CallRejectCode, String);
+}
+
+#[allow(deprecated)]
+impl IntoCallReject for (ic_cdk::api::call::RejectionCode, String) {
+    fn into_call_reject(self) -> (CallRejectCode, String) {
+        // The legacy code has no SysUnknown variant, it decodes as `Unknown`
+        (CallRejectCode::from_raw(self.0 as u32), self.1)
+    }
+}
+
+impl IntoCallReject for ic_cdk::call::Error {
+    fn into_call_reject(self) -> (CallRejectCode, String) {
+        use ic_cdk::call::Error;
+
+        let code = match &self {
+            // The call was never sent
+            Error::InsufficientLiquidCycleBalance(_) | Error::CallPerformFailed(_) => {
+                CallRejectCode::SysFatal
+            }
+            Error::CallRejected(rejected) => CallRejectCode::from_raw(rejected.raw_reject_code()),
+            // The callee replied, so it may have executed the call
+            Error::CandidDecodeFailed(_) => CallRejectCode::CanisterError,
+        };
+        (code, self.to_string())
+    }
+}
+
+/// Classify the reject of a call to `method` on `canister_id`.
+///
+/// Meant for `map_err`: `ic_cdk::call(id, "icrc1_balance_of", args).await.map_err(call_error(id, "icrc1_balance_of"))`
+pub(crate) fn call_error<E: IntoCallReject>(
+    canister_id: Principal,
+    method: &str,
+) -> impl FnOnce(E) -> CurrencyError + '_ {
+    move |error| {
+        let (code, message) = error.into_call_reject();
+        CallError {
+            canister_id,
+            method: method.to_string(),
+            code,
+            message,
+        }
+        .into()
+    }
+}
+
+/// Error returned by an ICP or ICRC ledger, with the fields the ledger reported
+#[derive(Error, Debug, Clone, CandidType, Serialize, Deserialize, PartialEq, Eq)]
+pub enum LedgerRejection {
+    #[error("bad fee, expected {expected_fee}")]
+    BadFee { expected_fee: u128 },
+
+    #[error("amount is below the minimum burn amount {min_burn_amount}")]
+    BadBurn { min_burn_amount: u128 },
+
+    #[error("insufficient funds, balance is {balance}")]
+    InsufficientFunds { balance: u128 },
+
+    #[error("insufficient allowance, allowance is {allowance}")]
+    InsufficientAllowance { allowance: u128 },
+
+    #[error("allowance changed, current allowance is {current_allowance}")]
+    AllowanceChanged { current_allowance: u128 },
+
+    #[error("approval expired at {ledger_time}")]
+    Expired { ledger_time: u64 },
+
+    #[error("transaction is too old")]
+    TooOld,
+
+    /// `ledger_time` is `None` for the ICP ledger, which does not report it
+    #[error("transaction was created in the future")]
+    CreatedInFuture { ledger_time: Option<u64> },
+
+    #[error("duplicate of block {duplicate_of}")]
+    Duplicate { duplicate_of: u128 },
+
+    #[error("ledger is temporarily unavailable")]
+    TemporarilyUnavailable,
+
+    #[error("error code {error_code}: {message}")]
+    GenericError { error_code: u128, message: String },
+}
+
+/// Error returned by the ckBTC or ckETH minter, with the fields the minter reported
+#[derive(Error, Debug, Clone, CandidType, Serialize, Deserialize, PartialEq, Eq)]
+pub enum MinterRejection {
+    #[error("minter is temporarily unavailable: {0}")]
+    TemporarilyUnavailable(String),
+
+    #[error("minter is already processing a request for this account")]
+    AlreadyProcessing,
+
+    #[error("no new UTXOs, {current_confirmations:?} of {required_confirmations} confirmations")]
+    NoNewUtxos {
+        required_confirmations: u32,
+        current_confirmations: Option<u32>,
+    },
+
+    #[error("{token_symbol} amount {amount} is below the minimum {minimum_amount}")]
+    AmountTooLow {
+        token_symbol: String,
+        minimum_amount: u128,
+        amount: u128,
+    },
+
+    #[error("insufficient {token_symbol}: balance is {balance}, need {amount}")]
+    InsufficientFunds {
+        token_symbol: String,
+        balance: u128,
+        amount: u128,
+    },
+
+    #[error("insufficient {token_symbol} allowance: allowance is {allowance}, need {amount}")]
+    InsufficientAllowance {
+        token_symbol: String,
+        allowance: u128,
+        amount: u128,
+    },
+
+    #[error("token not supported, supported tokens are {supported_tokens:?}")]
+    TokenNotSupported { supported_tokens: Vec<String> },
+
+    #[error("recipient address {address} is blocked")]
+    RecipientAddressBlocked { address: String },
+
+    #[error("error code {error_code}: {message}")]
+    GenericError { error_code: u64, message: String },
+}
+
+fn nat_to_u128(value: &candid::Nat) -> Result<u128, CurrencyError> {
+    value
+        .0
+        .to_u128()
+        .ok_or_else(|| CurrencyError::QueryError(format!("Ledger value {} is too large", value)))
+}
+
+impl From<TransferErrorIcrc1> for LedgerRejection {
+    fn from(e: TransferErrorIcrc1) -> Self {
+        match e {
+            TransferErrorIcrc1::GenericError {
+                message,
+                error_code,
+            } => LedgerRejection::GenericError {
+                error_code,
+                message,
+            },
+            TransferErrorIcrc1::TemporarilyUnavailable => LedgerRejection::TemporarilyUnavailable,
+            TransferErrorIcrc1::BadBurn { min_burn_amount } => {
+                LedgerRejection::BadBurn { min_burn_amount }
+            }
+            TransferErrorIcrc1::Duplicate { duplicate_of } => {
+                LedgerRejection::Duplicate { duplicate_of }
+            }
+            TransferErrorIcrc1::BadFee { expected_fee } => LedgerRejection::BadFee { expected_fee },
+            TransferErrorIcrc1::CreatedInFuture { ledger_time } => {
+                LedgerRejection::CreatedInFuture {
+                    ledger_time: Some(ledger_time),
+                }
+            }
+            TransferErrorIcrc1::TooOld => LedgerRejection::TooOld,
+            TransferErrorIcrc1::InsufficientFunds { balance } => {
+                LedgerRejection::InsufficientFunds { balance }
+            }
+        }
+    }
+}
+
+impl From<TransferFromError> for LedgerRejection {
+    fn from(e: TransferFromError) -> Self {
+        match e {
+            TransferFromError::BadFee { expected_fee } => LedgerRejection::BadFee { expected_fee },
+            TransferFromError::BadBurn { min_burn_amount } => {
+                LedgerRejection::BadBurn { min_burn_amount }
+            }
+            TransferFromError::InsufficientFunds { balance } => {
+                LedgerRejection::InsufficientFunds { balance }
+            }
+            TransferFromError::InsufficientAllowance { allowance } => {
+                LedgerRejection::InsufficientAllowance { allowance }
+            }
+            TransferFromError::TooOld => LedgerRejection::TooOld,
+            TransferFromError::CreatedInFuture { ledger_time } => {
+                LedgerRejection::CreatedInFuture {
+                    ledger_time: Some(ledger_time),
+                }
+            }
+            TransferFromError::Duplicate { duplicate_of } => {
+                LedgerRejection::Duplicate { duplicate_of }
+            }
+            TransferFromError::TemporarilyUnavailable => LedgerRejection::TemporarilyUnavailable,
+            TransferFromError::GenericError {
+                error_code,
+                message,
+            } => LedgerRejection::GenericError {
+                error_code,
+                message,
+            },
+        }
+    }
+}
+
+/// Fails on amounts that do not fit a `u128`
+impl TryFrom<icrc_ledger::TransferFromError> for LedgerRejection {
+    type Error = CurrencyError;
+
+    fn try_from(e: icrc_ledger::TransferFromError) -> Result<Self, CurrencyError> {
+        use icrc_ledger::TransferFromError as E;
+        Ok(match e {
+            E::GenericError {
+                message,
+                error_code,
+            } => LedgerRejection::GenericError {
+                error_code: nat_to_u128(&error_code)?,
+                message,
+            },
+            E::TemporarilyUnavailable => LedgerRejection::TemporarilyUnavailable,
+            E::InsufficientAllowance { allowance } => LedgerRejection::InsufficientAllowance {
+                allowance: nat_to_u128(&allowance)?,
+            },
+            E::BadBurn { min_burn_amount } => LedgerRejection::BadBurn {
+                min_burn_amount: nat_to_u128(&min_burn_amount)?,
+            },
+            E::Duplicate { duplicate_of } => LedgerRejection::Duplicate {
+                duplicate_of: nat_to_u128(&duplicate_of)?,
+            },
+            E::BadFee { expected_fee } => LedgerRejection::BadFee {
+                expected_fee: nat_to_u128(&expected_fee)?,
+            },
+            E::CreatedInFuture { ledger_time } => LedgerRejection::CreatedInFuture {
+                ledger_time: Some(ledger_time),
+            },
+            E::TooOld => LedgerRejection::TooOld,
+            E::InsufficientFunds { balance } => LedgerRejection::InsufficientFunds {
+                balance: nat_to_u128(&balance)?,
+            },
+        })
+    }
+}
+
+/// Fails on amounts that do not fit a `u128`
+impl TryFrom<icrc_ledger::ApproveError> for LedgerRejection {
+    type Error = CurrencyError;
+
+    fn try_from(e: icrc_ledger::ApproveError) -> Result<Self, CurrencyError> {
+        use icrc_ledger::ApproveError as E;
+        Ok(match e {
+            E::GenericError {
+                message,
+                error_code,
+            } => LedgerRejection::GenericError {
+                error_code: nat_to_u128(&error_code)?,
+                message,
+            },
+            E::TemporarilyUnavailable => LedgerRejection::TemporarilyUnavailable,
+            E::Duplicate { duplicate_of } => LedgerRejection::Duplicate {
+                duplicate_of: nat_to_u128(&duplicate_of)?,
+            },
+            E::BadFee { expected_fee } => LedgerRejection::BadFee {
+                expected_fee: nat_to_u128(&expected_fee)?,
+            },
+            E::AllowanceChanged { current_allowance } => LedgerRejection::AllowanceChanged {
+                current_allowance: nat_to_u128(&current_allowance)?,
+            },
+            E::CreatedInFuture { ledger_time } => LedgerRejection::CreatedInFuture {
+                ledger_time: Some(ledger_time),
+            },
+            E::TooOld => LedgerRejection::TooOld,
+            E::Expired { ledger_time } => LedgerRejection::Expired { ledger_time },
+            E::InsufficientFunds { balance } => LedgerRejection::InsufficientFunds {
+                balance: nat_to_u128(&balance)?,
+            },
+        })
+    }
+}
+
+impl From<ic_ledger_types::TransferError> for LedgerRejection {
+    fn from(e: ic_ledger_types::TransferError) -> Self {
+        use ic_ledger_types::TransferError as E;
+        match e {
+            E::BadFee { expected_fee } => LedgerRejection::BadFee {
+                expected_fee: expected_fee.e8s() as u128,
+            },
+            E::InsufficientFunds { balance } => LedgerRejection::InsufficientFunds {
+                balance: balance.e8s() as u128,
+            },
+            E::TxTooOld { .. } => LedgerRejection::TooOld,
+            E::TxCreatedInFuture => LedgerRejection::CreatedInFuture { ledger_time: None },
+            E::TxDuplicate { duplicate_of } => LedgerRejection::Duplicate {
+                duplicate_of: duplicate_of as u128,
+            },
+        }
+    }
+}
+
+impl From<UpdateBalanceError> for MinterRejection {
+    fn from(e: UpdateBalanceError) -> Self {
+        match e {
+            UpdateBalanceError::GenericError {
+                error_message,
+                error_code,
+            } => MinterRejection::GenericError {
+                error_code,
+                message: error_message,
+            },
+            UpdateBalanceError::TemporarilyUnavailable(message) => {
+                MinterRejection::TemporarilyUnavailable(message)
+            }
+            UpdateBalanceError::AlreadyProcessing => MinterRejection::AlreadyProcessing,
+            UpdateBalanceError::NoNewUtxos {
+                required_confirmations,
+                current_confirmations,
+                ..
+            } => MinterRejection::NoNewUtxos {
+                required_confirmations,
+                current_confirmations,
+            },
+        }
+    }
+}
+
+impl TryFrom<CkEthLedgerError> for MinterRejection {
+    type Error = CurrencyError;
+
+    fn try_from(e: CkEthLedgerError) -> Result<Self, CurrencyError> {
+        Ok(match e {
+            CkEthLedgerError::TemporarilyUnavailable(message) => {
+                MinterRejection::TemporarilyUnavailable(message)
+            }
+            CkEthLedgerError::InsufficientAllowance {
+                token_symbol,
+                allowance,
+                failed_burn_amount,
+                ..
+            } => MinterRejection::InsufficientAllowance {
+                token_symbol,
+                allowance: nat_to_u128(&allowance)?,
+                amount: nat_to_u128(&failed_burn_amount)?,
+            },
+            CkEthLedgerError::AmountTooLow {
+                minimum_burn_amount,
+                token_symbol,
+                failed_burn_amount,
+                ..
+            } => MinterRejection::AmountTooLow {
+                token_symbol,
+                minimum_amount: nat_to_u128(&minimum_burn_amount)?,
+                amount: nat_to_u128(&failed_burn_amount)?,
+            },
+            CkEthLedgerError::InsufficientFunds {
+                balance,
+                token_symbol,
+                failed_burn_amount,
+                ..
+            } => MinterRejection::InsufficientFunds {
+                token_symbol,
+                balance: nat_to_u128(&balance)?,
+                amount: nat_to_u128(&failed_burn_amount)?,
+            },
+        })
+    }
+}
+
+impl TryFrom<WithdrawErc20Error> for MinterRejection {
+    type Error = CurrencyError;
+
+    fn try_from(e: WithdrawErc20Error) -> Result<Self, CurrencyError> {
+        Ok(match e {
+            WithdrawErc20Error::TokenNotSupported { supported_tokens } => {
+                MinterRejection::TokenNotSupported {
+                    supported_tokens: supported_tokens
+                        .into_iter()
+                        .map(|token| token.ckerc20_token_symbol)
+                        .collect(),
+                }
+            }
+            WithdrawErc20Error::TemporarilyUnavailable(message) => {
+                MinterRejection::TemporarilyUnavailable(message)
+            }
+            WithdrawErc20Error::CkErc20LedgerError { error, .. }
+            | WithdrawErc20Error::CkEthLedgerError { error } => error.try_into()?,
+            WithdrawErc20Error::RecipientAddressBlocked { address } => {
+                MinterRejection::RecipientAddressBlocked { address }
+            }
+        })
+    }
 }
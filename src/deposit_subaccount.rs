@@ -0,0 +1,330 @@
+//! Per-user deposit subaccounts.
+//!
+//! Users sending from an exchange cannot `approve`, so every user gets a subaccount
+//! of the canister to deposit to. The subaccount is derived from the user's principal
+//! without hashing (`[length][principal bytes][zero padding][namespace]`), so the user
+//! can be read back from the subaccount without keeping a mapping. Deposits are later
+//! swept into the canister's main account. A sweep is recorded before it is sent, so a
+//! sweep whose outcome is unknown is replayed with the same `created_at_time`.
+
+use std::{borrow::Cow, cell::RefCell};
+
+use candid::{CandidType, Decode, Encode, Principal};
+use ic_ledger_types::{AccountIdentifier, Subaccount};
+use ic_stable_structures::{
+    memory_manager::VirtualMemory, storable::Bound, DefaultMemoryImpl, StableBTreeMap, Storable,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    currency_error::CurrencyError,
+    deposit_watcher::DepositResolver,
+    guard::OperationGuard,
+    icrc1_types::Account,
+    ledger_client::{IcLedgerClient, LedgerClient},
+    transfer::{transfer_icp_at, transfer_icrc1_from_subaccount_at},
+    types::currency_manager::CurrencyManager,
+    Currency,
+};
+
+pub type SweepMemory = VirtualMemory<DefaultMemoryImpl>;
+
+/// Principals are at most 29 bytes long, leaving room for the length and the namespace
+const MAX_PRINCIPAL_LENGTH: usize = 29;
+
+/// Derive the deposit subaccount of `user`.
+///
+/// `namespace` separates independent sets of deposit accounts of the same canister,
+/// e.g. per game; `None` is the same as namespace 0.
+pub fn deposit_subaccount(user: &Principal, namespace: Option<u16>) -> [u8; 32] {
+    let bytes = user.as_slice();
+    let mut subaccount = [0u8; 32];
+    subaccount[0] = bytes.len() as u8;
+    subaccount[1..1 + bytes.len()].copy_from_slice(bytes);
+    subaccount[30..].copy_from_slice(&namespace.unwrap_or(0).to_be_bytes());
+    subaccount
+}
+
+/// The user and namespace a subaccount was derived from, `None` if it is not a deposit subaccount
+pub fn user_from_deposit_subaccount(subaccount: &[u8]) -> Option<(Principal, u16)> {
+    if subaccount.len() != 32 {
+        return None;
+    }
+
+    let length = subaccount[0] as usize;
+    if length == 0 || length > MAX_PRINCIPAL_LENGTH {
+        return None;
+    }
+    if subaccount[1 + length..30].iter().any(|byte| *byte != 0) {
+        return None;
+    }
+
+    let user = Principal::try_from_slice(&subaccount[1..1 + length]).ok()?;
+    let namespace = u16::from_be_bytes([subaccount[30], subaccount[31]]);
+    Some((user, namespace))
+}
+
+/// ICRC-1 textual encoding of an account (`<owner>-<checksum>.<subaccount>`)
+pub fn icrc1_account_text(owner: &Principal, subaccount: Option<&[u8; 32]>) -> String {
+    let subaccount = match subaccount {
+        Some(subaccount) if subaccount.iter().any(|byte| *byte != 0) => subaccount,
+        _ => return owner.to_text(),
+    };
+
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(owner.as_slice());
+    hasher.update(subaccount);
+    let checksum = data_encoding::BASE32_NOPAD
+        .encode(&hasher.finalize().to_be_bytes())
+        .to_lowercase();
+
+    let hex: String = subaccount
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+    format!(
+        "{}-{}.{}",
+        owner.to_text(),
+        checksum,
+        hex.trim_start_matches('0')
+    )
+}
+
+/// Where a user deposits, in the formats wallets and exchanges expect
+#[derive(Debug, Clone, Serialize, Deserialize, CandidType, PartialEq, Eq)]
+pub struct DepositAccount {
+    pub owner: Principal,
+    pub subaccount: Vec<u8>,
+    /// ICRC-1 textual account, for ICRC-1 ledgers
+    pub icrc1_account: String,
+    /// Hex account identifier, for the ICP ledger
+    pub icp_account_identifier: String,
+}
+
+/// Deposit account of `user` on this canister
+pub fn deposit_account(user: &Principal, namespace: Option<u16>) -> DepositAccount {
+    let owner = ic_cdk::api::canister_self();
+    let subaccount = deposit_subaccount(user, namespace);
+
+    DepositAccount {
+        owner,
+        subaccount: subaccount.to_vec(),
+        icrc1_account: icrc1_account_text(&owner, Some(&subaccount)),
+        icp_account_identifier: AccountIdentifier::new(&owner, &Subaccount(subaccount)).to_hex(),
+    }
+}
+
+/// Attributes deposits made to deposit subaccounts, for use with the deposit watcher
+#[derive(Debug, Clone, Default)]
+pub struct DepositSubaccountResolver {
+    pub namespace: Option<u16>,
+    /// Users whose ICP deposit accounts are watched, ICP blocks only carry account identifiers
+    pub icp_users: Vec<Principal>,
+}
+
+impl DepositResolver for DepositSubaccountResolver {
+    fn icp_subaccounts(&self) -> Vec<[u8; 32]> {
+        self.icp_users
+            .iter()
+            .map(|user| deposit_subaccount(user, self.namespace))
+            .collect()
+    }
+
+    fn resolve(&self, subaccount: Option<&[u8]>, _memo: Option<&[u8]>) -> Option<Principal> {
+        let (user, namespace) = user_from_deposit_subaccount(subaccount?)?;
+        (namespace == self.namespace.unwrap_or(0)).then_some(user)
+    }
+}
+
+/// A deposit moved from a user's deposit subaccount into the main account
+#[derive(Debug, Clone, Serialize, Deserialize, CandidType, PartialEq, Eq)]
+pub struct SweepResult {
+    pub currency: Currency,
+    pub user: Principal,
+    /// Amount that arrived in the main account, to be credited to the user
+    pub amount: u128,
+    pub fee: u128,
+    pub block_index: u128,
+}
+
+/// A sweep sent to the ledger, kept until its block index is known
+#[derive(Debug, Clone, Serialize, Deserialize, CandidType, PartialEq, Eq)]
+pub struct PendingSweep {
+    pub currency: Currency,
+    pub user: Principal,
+    pub namespace: u16,
+    /// Balance of the deposit subaccount, moved out in full including the fee
+    pub balance: u128,
+    pub fee: u128,
+    pub created_at_time: u64,
+}
+
+impl Storable for PendingSweep {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).expect("Failed to encode pending sweep"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).expect("Failed to decode pending sweep")
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+/// Sweeps of unknown outcome, keyed by currency, user and namespace, stored in a
+/// dedicated virtual memory
+pub struct SweepLog {
+    pending: StableBTreeMap<String, PendingSweep, SweepMemory>,
+}
+
+impl SweepLog {
+    pub fn init(memory: SweepMemory) -> Self {
+        Self {
+            pending: StableBTreeMap::init(memory),
+        }
+    }
+
+    pub fn pending(&self) -> Vec<PendingSweep> {
+        self.pending.iter().map(|(_, sweep)| sweep).collect()
+    }
+}
+
+fn sweep_key(currency: &Currency, user: &Principal, namespace: u16) -> String {
+    format!("{}:{}:{}", currency, user, namespace)
+}
+
+thread_local! {
+    static SWEEPS: RefCell<Option<SweepLog>> = const { RefCell::new(None) };
+}
+
+/// Initialise the sweep log on a memory obtained from the canister's `MemoryManager`.
+/// Must be called from both `init` and `post_upgrade`.
+pub fn init_sweep_log(memory: SweepMemory) {
+    SWEEPS.with(|log| *log.borrow_mut() = Some(SweepLog::init(memory)));
+}
+
+/// Run a closure against the sweep log
+pub fn with_sweep_log<R>(f: impl FnOnce(&mut SweepLog) -> R) -> Result<R, CurrencyError> {
+    SWEEPS.with(|log| match log.borrow_mut().as_mut() {
+        Some(log) => Ok(f(log)),
+        None => Err(CurrencyError::SweepLogNotInitialized),
+    })
+}
+
+impl CurrencyManager {
+    /// Balance of a user's deposit subaccount
+    pub async fn get_deposit_balance(
+        &self,
+        currency: &Currency,
+        user: Principal,
+        namespace: Option<u16>,
+    ) -> Result<u128, CurrencyError> {
+        let account = Account {
+            owner: ic_cdk::api::canister_self(),
+            subaccount: Some(deposit_subaccount(&user, namespace).to_vec()),
+        };
+
+        IcLedgerClient::new(self.get_ledger_id(currency)?)
+            .balance_of(&account)
+            .await
+    }
+
+    /// Move the balance of a user's deposit subaccount into the canister's main account.
+    ///
+    /// Returns `None` when the balance does not cover the fee. The returned amount is the
+    /// deposit minus the fee; credit it to the user unless the deposit watcher already
+    /// credited the deposit to the subaccount.
+    ///
+    /// The sweep is recorded with its `created_at_time` before it is sent. If its outcome
+    /// is unknown, the error is returned and the next call replays the recorded sweep
+    /// instead of reading the balance again, so the ledger deduplicates it and the
+    /// result still carries the block to credit from.
+    pub async fn sweep_deposit(
+        &self,
+        currency: &Currency,
+        user: Principal,
+        namespace: Option<u16>,
+    ) -> Result<Option<SweepResult>, CurrencyError> {
+        let _guard = OperationGuard::new(user, *currency)?;
+
+        let namespace = namespace.unwrap_or(0);
+        let key = sweep_key(currency, &user, namespace);
+        let sweep = match with_sweep_log(|log| log.pending.get(&key))? {
+            Some(sweep) => sweep,
+            None => {
+                let balance = self.get_deposit_balance(currency, user, Some(namespace)).await?;
+                let fee = self.get_fee(currency).await?;
+                if balance <= fee {
+                    return Ok(None);
+                }
+
+                let sweep = PendingSweep {
+                    currency: *currency,
+                    user,
+                    namespace,
+                    balance,
+                    fee,
+                    created_at_time: ic_cdk::api::time(),
+                };
+                with_sweep_log(|log| log.pending.insert(key.clone(), sweep.clone()))?;
+                sweep
+            }
+        };
+
+        let result = self.send_sweep(&sweep).await;
+        match &result {
+            // Kept for the next call to replay
+            Err(error) if error.is_outcome_unknown() => {}
+            _ => {
+                with_sweep_log(|log| log.pending.remove(&key))?;
+            }
+        }
+        let block_index = result?;
+
+        Ok(Some(SweepResult {
+            currency: sweep.currency,
+            user,
+            amount: sweep.balance - sweep.fee,
+            fee: sweep.fee,
+            block_index,
+        }))
+    }
+
+    /// Send a recorded sweep, a duplicate of an earlier attempt returns its block index
+    async fn send_sweep(&self, sweep: &PendingSweep) -> Result<u128, CurrencyError> {
+        let subaccount = deposit_subaccount(&sweep.user, Some(sweep.namespace));
+        let canister_id = ic_cdk::api::canister_self();
+
+        match sweep.currency {
+            // `transfer_icp_at` takes the gross amount and deducts the fee itself
+            Currency::ICP => {
+                let amount: u64 = sweep
+                    .balance
+                    .try_into()
+                    .map_err(|_| CurrencyError::LedgerError("Balance too large".to_string()))?;
+                let block_index = transfer_icp_at(
+                    amount,
+                    Subaccount(subaccount),
+                    canister_id,
+                    sweep.created_at_time,
+                )
+                .await?;
+                Ok(block_index as u128)
+            }
+            _ => {
+                transfer_icrc1_from_subaccount_at(
+                    self.get_ledger_id(&sweep.currency)?,
+                    sweep.balance - sweep.fee,
+                    subaccount,
+                    Account {
+                        owner: canister_id,
+                        subaccount: None,
+                    },
+                    Some(sweep.fee),
+                    sweep.created_at_time,
+                )
+                .await
+            }
+        }
+    }
+}
@@ -0,0 +1,337 @@
+//! Deposit detection by scanning ledger blocks.
+//!
+//! Instead of every user calling `deposit`, the watcher periodically reads the new
+//! blocks of every supported ledger from a cursor kept in stable memory and reports
+//! incoming transfers to the canister's accounts. Deposits are attributed to users
+//! by a [`DepositResolver`], typically by subaccount or memo.
+//!
+//! Deposits are reported exactly once: the callback runs and the cursor advances in
+//! the same message, after the last `await`. If the callback traps, the cursor update
+//! is rolled back with it and the blocks are scanned again on the next run.
+
+use std::{cell::RefCell, rc::Rc, time::Duration};
+
+use candid::{CandidType, Principal};
+use ic_cdk_timers::TimerId;
+use ic_ledger_types::{AccountIdentifier, Operation, Subaccount, DEFAULT_SUBACCOUNT};
+use ic_stable_structures::{memory_manager::VirtualMemory, DefaultMemoryImpl, StableBTreeMap};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    currency_error::CurrencyError,
+    icrc1_types::Account,
+    icrc3::{self, Icrc3Operation},
+    index::HistoryAccount,
+    query::query_block_range,
+    types::currency_manager::CurrencyManager,
+    Currency,
+};
+
+pub type WatcherMemory = VirtualMemory<DefaultMemoryImpl>;
+
+/// Blocks read per ledger and run
+pub const MAX_BLOCKS_PER_SCAN: u64 = 1_000;
+
+/// An incoming transfer to one of the canister's accounts
+#[derive(Debug, Clone, Serialize, Deserialize, CandidType, PartialEq, Eq)]
+pub struct DepositEvent {
+    pub currency: Currency,
+    pub block_index: u64,
+    pub from: Option<HistoryAccount>,
+    /// Canister subaccount that received the deposit, `None` for the default account
+    pub to_subaccount: Option<Vec<u8>>,
+    pub amount: u128,
+    pub memo: Option<Vec<u8>>,
+    pub timestamp: u64,
+    /// User the deposit was attributed to, `None` if the resolver did not recognise it
+    pub user: Option<Principal>,
+}
+
+/// Maps deposits to the canister's users
+pub trait DepositResolver {
+    /// Canister subaccounts watched on the ICP ledger.
+    ///
+    /// ICP blocks only contain account identifiers, so deposits to subaccounts that
+    /// are not listed here cannot be recognised. Defaults to the default account.
+    fn icp_subaccounts(&self) -> Vec<[u8; 32]> {
+        vec![[0u8; 32]]
+    }
+
+    /// User owning a deposit made to `subaccount` (`None` for the default account) with `memo`
+    fn resolve(&self, subaccount: Option<&[u8]>, memo: Option<&[u8]>) -> Option<Principal>;
+}
+
+/// Next block to scan per ledger, stored in a dedicated virtual memory
+pub struct DepositWatcher {
+    cursors: StableBTreeMap<Principal, u64, WatcherMemory>,
+}
+
+impl DepositWatcher {
+    pub fn init(memory: WatcherMemory) -> Self {
+        Self {
+            cursors: StableBTreeMap::init(memory),
+        }
+    }
+
+    pub fn cursor(&self, ledger_id: &Principal) -> Option<u64> {
+        self.cursors.get(ledger_id)
+    }
+
+    /// Start (or restart) scanning a ledger at `next_block`
+    pub fn set_cursor(&mut self, ledger_id: Principal, next_block: u64) {
+        self.cursors.insert(ledger_id, next_block);
+    }
+
+    /// Stop tracking a ledger; scanning restarts at the ledger's tip when it is seen again
+    pub fn remove_cursor(&mut self, ledger_id: &Principal) -> Option<u64> {
+        self.cursors.remove(ledger_id)
+    }
+}
+
+thread_local! {
+    static WATCHER: RefCell<Option<DepositWatcher>> = const { RefCell::new(None) };
+    static SCANNING: RefCell<bool> = const { RefCell::new(false) };
+}
+
+/// Initialise the watcher on a memory obtained from the canister's `MemoryManager`.
+/// Must be called from both `init` and `post_upgrade`.
+pub fn init_deposit_watcher(memory: WatcherMemory) {
+    WATCHER.with(|watcher| *watcher.borrow_mut() = Some(DepositWatcher::init(memory)));
+}
+
+/// Run a closure against the watcher
+pub fn with_deposit_watcher<R>(
+    f: impl FnOnce(&mut DepositWatcher) -> R,
+) -> Result<R, CurrencyError> {
+    WATCHER.with(|watcher| match watcher.borrow_mut().as_mut() {
+        Some(watcher) => Ok(f(watcher)),
+        None => Err(CurrencyError::DepositWatcherNotInitialized),
+    })
+}
+
+fn normalize_subaccount(subaccount: Option<Vec<u8>>) -> Option<Vec<u8>> {
+    subaccount.filter(|subaccount| subaccount.iter().any(|byte| *byte != 0))
+}
+
+/// Deposits found in a range of ICRC-3 blocks.
+///
+/// Transfers the canister made as a spender are its own `deposit` calls, which
+/// already credited the user, and are not reported again.
+pub fn icrc_deposits(
+    currency: Currency,
+    canister_id: Principal,
+    blocks: Vec<icrc3::Icrc3Block>,
+    resolver: &dyn DepositResolver,
+) -> Vec<DepositEvent> {
+    let is_canister = |account: &Account| account.owner == canister_id;
+
+    blocks
+        .into_iter()
+        .filter_map(|block| {
+            let (from, to, amount) = match block.operation {
+                Icrc3Operation::Transfer {
+                    from,
+                    to,
+                    spender,
+                    amount,
+                } if is_canister(&to)
+                    && !is_canister(&from)
+                    && !spender.as_ref().is_some_and(is_canister) =>
+                {
+                    (Some(from), to, amount)
+                }
+                Icrc3Operation::Mint { to, amount } if is_canister(&to) => (None, to, amount),
+                _ => return None,
+            };
+
+            let to_subaccount = normalize_subaccount(to.subaccount);
+            Some(DepositEvent {
+                currency,
+                block_index: block.index,
+                from: from.map(HistoryAccount::Account),
+                user: resolver.resolve(to_subaccount.as_deref(), block.memo.as_deref()),
+                to_subaccount,
+                amount,
+                memo: block.memo,
+                timestamp: block.timestamp,
+            })
+        })
+        .collect()
+}
+
+/// Deposits found in a range of ICP blocks, see [`icrc_deposits`]
+pub fn icp_deposits(
+    canister_id: Principal,
+    blocks: Vec<(u64, ic_ledger_types::Block)>,
+    resolver: &dyn DepositResolver,
+) -> Vec<DepositEvent> {
+    let watched: Vec<(AccountIdentifier, [u8; 32])> = resolver
+        .icp_subaccounts()
+        .into_iter()
+        .map(|subaccount| {
+            (
+                AccountIdentifier::new(&canister_id, &Subaccount(subaccount)),
+                subaccount,
+            )
+        })
+        .collect();
+    let find = |account: &AccountIdentifier| {
+        watched
+            .iter()
+            .find(|(id, _)| id == account)
+            .map(|(_, subaccount)| *subaccount)
+    };
+    let canister_account = AccountIdentifier::new(&canister_id, &DEFAULT_SUBACCOUNT);
+    let is_canister =
+        |account: &AccountIdentifier| *account == canister_account || find(account).is_some();
+
+    blocks
+        .into_iter()
+        .filter_map(|(index, block)| {
+            let (from, subaccount, amount) = match block.transaction.operation? {
+                Operation::Transfer {
+                    from, to, amount, ..
+                } if !is_canister(&from) => (Some(from), find(&to)?, amount),
+                Operation::TransferFrom {
+                    from,
+                    to,
+                    spender,
+                    amount,
+                    ..
+                } if !is_canister(&from) && !is_canister(&spender) => {
+                    (Some(from), find(&to)?, amount)
+                }
+                Operation::Mint { to, amount } => (None, find(&to)?, amount),
+                _ => return None,
+            };
+
+            let memo = match block.transaction.icrc1_memo {
+                Some(memo) => Some(memo.into_vec()),
+                None if block.transaction.memo.0 != 0 => {
+                    Some(block.transaction.memo.0.to_be_bytes().to_vec())
+                }
+                None => None,
+            };
+            let to_subaccount = normalize_subaccount(Some(subaccount.to_vec()));
+
+            Some(DepositEvent {
+                currency: Currency::ICP,
+                block_index: index,
+                from: from.map(|from| HistoryAccount::AccountIdentifier(from.to_hex())),
+                user: resolver.resolve(to_subaccount.as_deref(), memo.as_deref()),
+                to_subaccount,
+                amount: amount.e8s() as u128,
+                memo,
+                timestamp: block.timestamp.timestamp_nanos,
+            })
+        })
+        .collect()
+}
+
+/// Scan the next blocks of one currency's ledger.
+///
+/// A ledger without a cursor starts at its current tip, earlier blocks are not reported.
+/// Returns the number of deposits reported.
+pub async fn scan_ledger(
+    manager: &CurrencyManager,
+    currency: &Currency,
+    resolver: &dyn DepositResolver,
+    on_deposit: &dyn Fn(&DepositEvent),
+) -> Result<usize, CurrencyError> {
+    let ledger_id = manager.get_ledger_id(currency)?;
+    let canister_id = ic_cdk::api::canister_self();
+    let cursor = with_deposit_watcher(|watcher| watcher.cursor(&ledger_id))?;
+
+    let (next_block, deposits) = if *currency == Currency::ICP {
+        let start = match cursor {
+            Some(cursor) => cursor,
+            None => query_block_range(ledger_id, 0, 0).await?.0,
+        };
+        let (_, blocks) = query_block_range(ledger_id, start, MAX_BLOCKS_PER_SCAN).await?;
+        // The blocks are contiguous from `start`, missing ones are read on the next run
+        let next_block = blocks.last().map(|(index, _)| index + 1).unwrap_or(start);
+        (next_block, icp_deposits(canister_id, blocks, resolver))
+    } else {
+        let start = match cursor {
+            Some(cursor) => cursor,
+            None => icrc3::get_log_length(ledger_id).await?,
+        };
+        let range = icrc3::get_blocks(ledger_id, start, MAX_BLOCKS_PER_SCAN).await?;
+        let next_block = range
+            .blocks
+            .last()
+            .map(|block| block.index + 1)
+            .unwrap_or(start);
+        (
+            next_block,
+            icrc_deposits(*currency, canister_id, range.blocks, resolver),
+        )
+    };
+
+    // No await from here on: the callbacks and the cursor update commit together.
+    for deposit in deposits.iter() {
+        on_deposit(deposit);
+    }
+    with_deposit_watcher(|watcher| watcher.set_cursor(ledger_id, next_block))?;
+
+    Ok(deposits.len())
+}
+
+/// Scan every currency supported by the manager, see [`scan_ledger`]
+pub async fn scan_all_ledgers(
+    manager: &CurrencyManager,
+    resolver: &dyn DepositResolver,
+    on_deposit: &dyn Fn(&DepositEvent),
+) -> usize {
+    let mut found = 0;
+    for supported in manager.supported_currencies() {
+        match scan_ledger(manager, &supported.currency, resolver, on_deposit).await {
+            Ok(count) => found += count,
+            Err(e) => ic_cdk::println!(
+                "Failed to scan the {} ledger for deposits: {:?}",
+                supported.currency,
+                e
+            ),
+        }
+    }
+    found
+}
+
+/// Clears the scanning flag when dropped, also when a scan traps
+struct ScanGuard;
+
+impl Drop for ScanGuard {
+    fn drop(&mut self) {
+        SCANNING.with(|flag| *flag.borrow_mut() = false);
+    }
+}
+
+/// Start a timer that periodically scans all ledgers for deposits.
+///
+/// `manager` provides the current `CurrencyManager` for every run, `None` skips the run.
+pub fn start_deposit_watcher(
+    interval: Duration,
+    manager: impl Fn() -> Option<CurrencyManager> + 'static,
+    resolver: impl DepositResolver + 'static,
+    on_deposit: impl Fn(&DepositEvent) + 'static,
+) -> TimerId {
+    let manager = Rc::new(manager);
+    let resolver = Rc::new(resolver);
+    let on_deposit = Rc::new(on_deposit);
+
+    ic_cdk_timers::set_timer_interval(interval, move || {
+        if SCANNING.with(|flag| flag.replace(true)) {
+            return;
+        }
+
+        let manager = manager.clone();
+        let resolver = resolver.clone();
+        let on_deposit = on_deposit.clone();
+        ic_cdk::futures::spawn(async move {
+            let _guard = ScanGuard;
+            if let Some(manager) = manager() {
+                scan_all_ledgers(&manager, resolver.as_ref(), on_deposit.as_ref()).await;
+            }
+        });
+    })
+}
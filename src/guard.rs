@@ -0,0 +1,49 @@
+//! Per (principal, currency) guards preventing concurrent deposits and withdrawals.
+//!
+//! Deposits and withdrawals await several inter-canister calls, so two calls for the
+//! same user could otherwise interleave. A guard is released when it is dropped, which
+//! also happens during the cleanup of a call that trapped after an `await`.
+
+use std::{cell::RefCell, collections::HashSet};
+
+use candid::Principal;
+
+use crate::{currency_error::CurrencyError, Currency};
+
+thread_local! {
+    static LOCKS: RefCell<HashSet<(Principal, Currency)>> = RefCell::new(HashSet::new());
+}
+
+#[derive(Debug)]
+pub struct OperationGuard {
+    principal: Principal,
+    currency: Currency,
+}
+
+impl OperationGuard {
+    /// Acquire the guard, fails with `LockError` if an operation is already running
+    pub fn new(principal: Principal, currency: Currency) -> Result<Self, CurrencyError> {
+        LOCKS.with(|locks| {
+            if !locks.borrow_mut().insert((principal, currency)) {
+                return Err(CurrencyError::LockError);
+            }
+            Ok(Self {
+                principal,
+                currency,
+            })
+        })
+    }
+}
+
+impl Drop for OperationGuard {
+    fn drop(&mut self) {
+        LOCKS.with(|locks| {
+            locks.borrow_mut().remove(&(self.principal, self.currency));
+        });
+    }
+}
+
+/// Whether an operation is currently running for the principal and currency
+pub fn is_locked(principal: Principal, currency: Currency) -> bool {
+    LOCKS.with(|locks| locks.borrow().contains(&(principal, currency)))
+}
@@ -1,13 +1,26 @@
-use candid::{CandidType, Principal};
-use serde::Deserialize;
+use candid::{CandidType, Nat, Principal};
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
 
-#[derive(CandidType, Deserialize)]
+use crate::icrc_ledger_canister_interface::ApproveArgs;
+
+#[derive(CandidType, Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
 pub struct Account {
     pub owner: Principal,
     pub subaccount: Option<Vec<u8>>,
 }
 
-#[derive(CandidType, Deserialize)]
+/// The default account of a principal
+impl From<Principal> for Account {
+    fn from(owner: Principal) -> Self {
+        Self {
+            owner,
+            subaccount: None,
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone)]
 pub struct TransferArg {
     pub to: Account,
     pub fee: Option<u128>,
@@ -41,7 +54,7 @@ pub struct AllowanceArgs {
     pub spender: Account,
 }
 
-#[derive(CandidType, Deserialize)]
+#[derive(CandidType, Deserialize, Clone)]
 pub struct TransferFromArg {
     pub spender_subaccount: Option<Vec<u8>>,
     pub from: Account,
@@ -64,3 +77,53 @@ pub enum TransferFromError {
     TemporarilyUnavailable,
     GenericError { error_code: u128, message: String },
 }
+
+/// ICRC-2 approval of `spender` by the caller's `from_subaccount`.
+///
+/// `amount` replaces the current allowance. With `expected_allowance` set the ledger
+/// only applies the approval if the current allowance still has that value.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct ApproveArg {
+    pub from_subaccount: Option<Vec<u8>>,
+    pub spender: Account,
+    pub amount: u128,
+    pub expected_allowance: Option<u128>,
+    pub expires_at: Option<u64>,
+    pub fee: Option<u128>,
+    pub memo: Option<Vec<u8>>,
+    pub created_at_time: Option<u64>,
+}
+
+impl ApproveArg {
+    /// Approve `spender` for `amount` out of the default account, without expiry
+    pub fn new(spender: Account, amount: u128) -> Self {
+        Self {
+            from_subaccount: None,
+            spender,
+            amount,
+            expected_allowance: None,
+            expires_at: None,
+            fee: None,
+            memo: None,
+            created_at_time: None,
+        }
+    }
+}
+
+impl From<ApproveArg> for ApproveArgs {
+    fn from(args: ApproveArg) -> Self {
+        Self {
+            fee: args.fee.map(Nat::from),
+            memo: args.memo.map(ByteBuf::from),
+            from_subaccount: args.from_subaccount.map(ByteBuf::from),
+            created_at_time: args.created_at_time,
+            amount: Nat::from(args.amount),
+            expected_allowance: args.expected_allowance.map(Nat::from),
+            expires_at: args.expires_at,
+            spender: crate::icrc_ledger_canister_interface::Account {
+                owner: args.spender.owner,
+                subaccount: args.spender.subaccount.map(ByteBuf::from),
+            },
+        }
+    }
+}
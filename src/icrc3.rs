@@ -0,0 +1,393 @@
+//! ICRC-3 block reader.
+//!
+//! Reads blocks through `icrc3_get_blocks`, follows the archive callbacks returned
+//! by the ledger and decodes the generic `Icrc3Value` blocks into typed records.
+//! Works for every ledger implementing ICRC-3 (ckBTC, ckETH, ckERC20 and generic
+//! ICRC-1 tokens). The ICP ledger does not implement ICRC-3.
+
+use candid::{CandidType, Principal};
+use num_traits::ToPrimitive;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    currency_error::{call_error, CurrencyError},
+    icrc1_types::Account,
+    icrc_ledger_canister_interface::{
+        GetArchivesArgs, GetBlocksRequest, GetBlocksResult, Icrc3ArchiveInfo, Icrc3Value,
+    },
+    retry::{retry_policy, retry_query},
+    types::currency_manager::CurrencyManager,
+    Currency,
+};
+
+/// Upper bound on the number of blocks returned by a single [`get_blocks`] call
+pub const MAX_BLOCKS_PER_READ: u64 = 2_000;
+
+#[derive(Debug, Clone, Serialize, Deserialize, CandidType, PartialEq, Eq)]
+pub enum Icrc3Operation {
+    Mint {
+        to: Account,
+        amount: u128,
+    },
+    Burn {
+        from: Account,
+        spender: Option<Account>,
+        amount: u128,
+    },
+    Transfer {
+        from: Account,
+        to: Account,
+        spender: Option<Account>,
+        amount: u128,
+    },
+    Approve {
+        from: Account,
+        spender: Account,
+        amount: u128,
+        expected_allowance: Option<u128>,
+        expires_at: Option<u64>,
+    },
+    /// A block type this crate does not decode, e.g. one added by a later ICRC standard.
+    /// Holds the `btype`, or the `tx.op` of a legacy block.
+    Unknown {
+        btype: String,
+    },
+}
+
+/// A decoded ICRC-3 block
+#[derive(Debug, Clone, Serialize, Deserialize, CandidType, PartialEq, Eq)]
+pub struct Icrc3Block {
+    pub index: u64,
+    /// Time the block was created by the ledger
+    pub timestamp: u64,
+    pub operation: Icrc3Operation,
+    /// Fee charged for the operation, either set by the caller or by the ledger
+    pub fee: Option<u128>,
+    pub memo: Option<Vec<u8>>,
+    /// `created_at_time` set by the caller
+    pub created_at_time: Option<u64>,
+}
+
+/// Blocks read from a ledger together with the current length of its log
+#[derive(Debug, Clone, Serialize, Deserialize, CandidType, PartialEq, Eq)]
+pub struct Icrc3BlockRange {
+    pub log_length: u64,
+    pub blocks: Vec<Icrc3Block>,
+}
+
+/// Read up to `length` blocks starting at `start`, following archive callbacks.
+///
+/// Blocks are returned in ascending order. Fewer blocks than requested are returned
+/// when the end of the log is reached; `length` is capped at [`MAX_BLOCKS_PER_READ`].
+pub async fn get_blocks(
+    ledger: Principal,
+    start: u64,
+    length: u64,
+) -> Result<Icrc3BlockRange, CurrencyError> {
+    let end = start.saturating_add(length.min(MAX_BLOCKS_PER_READ));
+    let mut blocks: Vec<Icrc3Block> = Vec::new();
+    let mut next = start;
+    let mut log_length = 0;
+
+    while next < end {
+        let result = call_get_blocks(ledger, next, end - next).await?;
+        log_length = nat_to_u64(&result.log_length, "log_length")?;
+
+        let mut page = decode_blocks(result.blocks.into_iter())?;
+
+        for archived in result.archived_blocks {
+            let archive_id = archived.callback.0.principal;
+            let method = &archived.callback.0.method;
+            let (archive,): (GetBlocksResult,) = retry_query(&retry_policy(), |_| async {
+                ic_cdk::call(archive_id, method, (&archived.args,))
+                    .await
+                    .map_err(call_error(archive_id, method))
+            })
+            .await?;
+            page.extend(decode_blocks(archive.blocks.into_iter())?);
+        }
+
+        page.retain(|block| block.index >= next && block.index < end);
+        page.sort_by_key(|block| block.index);
+
+        // Only advance over a contiguous range so a truncated archive response
+        // cannot leave gaps.
+        let mut fetched = 0;
+        for block in page {
+            if block.index != next + fetched {
+                break;
+            }
+            fetched += 1;
+            blocks.push(block);
+        }
+
+        if fetched == 0 {
+            break;
+        }
+        next += fetched;
+
+        if next >= log_length {
+            break;
+        }
+    }
+
+    Ok(Icrc3BlockRange { log_length, blocks })
+}
+
+/// Read a single block, `None` if it does not exist (yet)
+pub async fn get_block(ledger: Principal, index: u64) -> Result<Option<Icrc3Block>, CurrencyError> {
+    Ok(get_blocks(ledger, index, 1)
+        .await?
+        .blocks
+        .into_iter()
+        .next())
+}
+
+/// Number of blocks in the ledger's log, i.e. the index of the next block
+pub async fn get_log_length(ledger: Principal) -> Result<u64, CurrencyError> {
+    let result = call_get_blocks(ledger, 0, 0).await?;
+    nat_to_u64(&result.log_length, "log_length")
+}
+
+/// List the archive canisters of a ledger and the block ranges they hold
+pub async fn get_archives(ledger: Principal) -> Result<Vec<Icrc3ArchiveInfo>, CurrencyError> {
+    let (archives,): (Vec<Icrc3ArchiveInfo>,) = retry_query(&retry_policy(), |_| async {
+        ic_cdk::call(
+            ledger,
+            "icrc3_get_archives",
+            (GetArchivesArgs { from: None },),
+        )
+        .await
+        .map_err(call_error(ledger, "icrc3_get_archives"))
+    })
+    .await?;
+    Ok(archives)
+}
+
+async fn call_get_blocks(
+    ledger: Principal,
+    start: u64,
+    length: u64,
+) -> Result<GetBlocksResult, CurrencyError> {
+    let args = vec![GetBlocksRequest {
+        start: candid::Nat::from(start),
+        length: candid::Nat::from(length),
+    }];
+
+    let (result,): (GetBlocksResult,) = retry_query(&retry_policy(), |_| async {
+        ic_cdk::call(ledger, "icrc3_get_blocks", (&args,))
+            .await
+            .map_err(call_error(ledger, "icrc3_get_blocks"))
+    })
+    .await?;
+    Ok(result)
+}
+
+fn decode_blocks(
+    blocks: impl Iterator<Item = crate::icrc_ledger_canister_interface::BlockWithId>,
+) -> Result<Vec<Icrc3Block>, CurrencyError> {
+    blocks
+        .map(|block| {
+            let index = nat_to_u64(&block.id, "block id")?;
+            decode_block(index, *block.block)
+        })
+        .collect()
+}
+
+/// Decode a generic ICRC-3 block.
+///
+/// Supports both the legacy layout (`tx.op` set to `mint`, `burn`, `xfer` or `approve`)
+/// and the typed layout with a top-level `btype` (`1mint`, `1burn`, `1xfer`, `2xfer`, `2approve`).
+/// Other block types are returned as [`Icrc3Operation::Unknown`] so they do not
+/// prevent reading the rest of the range.
+pub fn decode_block(index: u64, block: Icrc3Value) -> Result<Icrc3Block, CurrencyError> {
+    let invalid =
+        |reason: String| CurrencyError::GetBlockError(format!("Block {}: {}", index, reason));
+
+    let mut block = into_map(block).map_err(invalid)?;
+    let timestamp = take_u64(&mut block, "ts")
+        .map_err(invalid)?
+        .ok_or_else(|| invalid("missing ts".to_string()))?;
+    let block_fee = take_nat(&mut block, "fee").map_err(invalid)?;
+    let btype = take_text(&mut block, "btype").map_err(invalid)?;
+
+    let mut tx = match take(&mut block, "tx") {
+        Some(tx) => into_map(tx).map_err(invalid)?,
+        // Typed blocks of unknown types do not necessarily have a `tx`
+        None if btype.is_some() => ValueMap::new(),
+        None => return Err(invalid("missing tx".to_string())),
+    };
+
+    let op = match btype.as_deref() {
+        Some("1mint") => "mint".to_string(),
+        Some("1burn") => "burn".to_string(),
+        Some("1xfer") | Some("2xfer") => "xfer".to_string(),
+        Some("2approve") => "approve".to_string(),
+        Some(other) => other.to_string(),
+        None => take_text(&mut tx, "op")
+            .map_err(invalid)?
+            .ok_or_else(|| invalid("missing op".to_string()))?,
+    };
+
+    if !matches!(op.as_str(), "mint" | "burn" | "xfer" | "approve") {
+        return Ok(Icrc3Block {
+            index,
+            timestamp,
+            operation: Icrc3Operation::Unknown { btype: op },
+            fee: block_fee,
+            memo: None,
+            created_at_time: None,
+        });
+    }
+
+    let amount = take_nat(&mut tx, "amt")
+        .map_err(invalid)?
+        .ok_or_else(|| invalid("missing amt".to_string()))?;
+    let from = take_account(&mut tx, "from").map_err(invalid)?;
+    let to = take_account(&mut tx, "to").map_err(invalid)?;
+    let spender = take_account(&mut tx, "spender").map_err(invalid)?;
+    let required = |account: Option<Account>, field: &str| {
+        account.ok_or_else(|| invalid(format!("missing {}", field)))
+    };
+
+    let operation = match op.as_str() {
+        "mint" => Icrc3Operation::Mint {
+            to: required(to, "to")?,
+            amount,
+        },
+        "burn" => Icrc3Operation::Burn {
+            from: required(from, "from")?,
+            spender,
+            amount,
+        },
+        "xfer" => Icrc3Operation::Transfer {
+            from: required(from, "from")?,
+            to: required(to, "to")?,
+            spender,
+            amount,
+        },
+        "approve" => Icrc3Operation::Approve {
+            from: required(from, "from")?,
+            spender: required(spender, "spender")?,
+            amount,
+            expected_allowance: take_nat(&mut tx, "expected_allowance").map_err(invalid)?,
+            expires_at: take_u64(&mut tx, "expires_at").map_err(invalid)?,
+        },
+        other => Icrc3Operation::Unknown {
+            btype: other.to_string(),
+        },
+    };
+
+    let fee = match take_nat(&mut tx, "fee").map_err(invalid)? {
+        Some(fee) => Some(fee),
+        None => block_fee,
+    };
+
+    Ok(Icrc3Block {
+        index,
+        timestamp,
+        operation,
+        fee,
+        memo: take_blob(&mut tx, "memo").map_err(invalid)?,
+        created_at_time: take_u64(&mut tx, "ts").map_err(invalid)?,
+    })
+}
+
+type ValueMap = Vec<(String, Box<Icrc3Value>)>;
+
+fn into_map(value: Icrc3Value) -> Result<ValueMap, String> {
+    match value {
+        Icrc3Value::Map(map) => Ok(map),
+        _ => Err("expected a map".to_string()),
+    }
+}
+
+fn take(map: &mut ValueMap, key: &str) -> Option<Icrc3Value> {
+    let position = map.iter().position(|(k, _)| k == key)?;
+    Some(*map.swap_remove(position).1)
+}
+
+fn take_nat(map: &mut ValueMap, key: &str) -> Result<Option<u128>, String> {
+    match take(map, key) {
+        None => Ok(None),
+        Some(Icrc3Value::Nat(nat)) => nat
+            .0
+            .to_u128()
+            .map(Some)
+            .ok_or_else(|| format!("{} value {} is out of range", key, nat)),
+        Some(_) => Err(format!("{} is not a nat", key)),
+    }
+}
+
+fn take_u64(map: &mut ValueMap, key: &str) -> Result<Option<u64>, String> {
+    take_nat(map, key)?
+        .map(|value| {
+            u64::try_from(value).map_err(|_| format!("{} value {} is out of range", key, value))
+        })
+        .transpose()
+}
+
+fn take_text(map: &mut ValueMap, key: &str) -> Result<Option<String>, String> {
+    match take(map, key) {
+        None => Ok(None),
+        Some(Icrc3Value::Text(text)) => Ok(Some(text)),
+        Some(_) => Err(format!("{} is not a text", key)),
+    }
+}
+
+fn take_blob(map: &mut ValueMap, key: &str) -> Result<Option<Vec<u8>>, String> {
+    match take(map, key) {
+        None => Ok(None),
+        Some(Icrc3Value::Blob(blob)) => Ok(Some(blob.into_vec())),
+        Some(_) => Err(format!("{} is not a blob", key)),
+    }
+}
+
+/// Accounts are encoded as `[owner]` or `[owner, subaccount]` blobs
+fn take_account(map: &mut ValueMap, key: &str) -> Result<Option<Account>, String> {
+    let parts = match take(map, key) {
+        None => return Ok(None),
+        Some(Icrc3Value::Array(parts)) => parts,
+        Some(_) => return Err(format!("{} is not an account", key)),
+    };
+
+    let mut blobs = parts.into_iter().map(|part| match *part {
+        Icrc3Value::Blob(blob) => Ok(blob.into_vec()),
+        _ => Err(format!("{} contains a non-blob value", key)),
+    });
+
+    let owner = blobs
+        .next()
+        .ok_or_else(|| format!("{} has no owner", key))??;
+    let subaccount = blobs.next().transpose()?;
+
+    Ok(Some(Account {
+        owner: Principal::try_from_slice(&owner)
+            .map_err(|e| format!("{} owner is not a principal: {}", key, e))?,
+        subaccount,
+    }))
+}
+
+fn nat_to_u64(nat: &candid::Nat, field: &str) -> Result<u64, CurrencyError> {
+    nat.0
+        .to_u64()
+        .ok_or_else(|| CurrencyError::GetBlockError(format!("{} {} is out of range", field, nat)))
+}
+
+impl CurrencyManager {
+    /// Read blocks from the ICRC-3 ledger of a currency, see [`get_blocks`]
+    pub async fn get_blocks(
+        &self,
+        currency: &Currency,
+        start: u64,
+        length: u64,
+    ) -> Result<Icrc3BlockRange, CurrencyError> {
+        if *currency == Currency::ICP {
+            return Err(CurrencyError::OperationNotSupported(
+                "The ICP ledger does not implement ICRC-3".to_string(),
+            ));
+        }
+
+        get_blocks(self.get_ledger_id(currency)?, start, length).await
+    }
+}
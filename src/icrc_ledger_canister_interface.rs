@@ -1,3 +1,5 @@
+// Candid interface of the ICRC ledger canister, shared by ckBTC, ckETH, ckERC20
+// and generic ICRC-1 tokens.
 // This is an experimental feature to generate Rust binding from Candid.
 // You may want to manually adjust some of the types.
 #![allow(dead_code, unused_imports)]
@@ -24,7 +26,7 @@ pub enum MetadataValue {
     Text(String),
 }
 
-#[derive(CandidType, Deserialize)]
+#[derive(CandidType, Deserialize, Clone)]
 pub struct Account {
     pub owner: Principal,
     pub subaccount: Option<serde_bytes::ByteBuf>,
@@ -404,7 +406,7 @@ pub enum Result2 {
     Err(ApproveError),
 }
 
-#[derive(CandidType, Deserialize)]
+#[derive(CandidType, Deserialize, Clone)]
 pub struct TransferFromArgs {
     pub to: Account,
     pub fee: Option<candid::Nat>,
@@ -0,0 +1,461 @@
+//! Per-account transaction history read from index canisters.
+//!
+//! ICRC ledgers are served by the ICRC index-ng canister, ICP by the ICP index
+//! canister. Both return the newest transactions first; pass
+//! [`AccountHistory::next_start`] as `start` to read the next (older) page.
+
+use candid::{CandidType, Nat, Principal};
+use num_traits::ToPrimitive;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    currency_error::{call_error, CurrencyError},
+    icrc1_types::Account,
+    icrc_ledger_canister_interface::Transaction,
+    retry::{retry_policy, retry_query},
+    types::{
+        canister_wallets::icrc1_token_wallet::GenericICRC1TokenWallet,
+        currency_manager::CurrencyManager,
+    },
+    Currency,
+};
+
+/// Largest page requested from an index canister
+pub const MAX_HISTORY_PAGE_SIZE: u64 = 500;
+
+/// An account as reported by an index canister.
+/// The ICP index only reports hex encoded account identifiers.
+#[derive(Debug, Clone, Serialize, Deserialize, CandidType, PartialEq, Eq)]
+pub enum HistoryAccount {
+    Account(Account),
+    AccountIdentifier(String),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, CandidType, PartialEq, Eq)]
+pub enum HistoryOperation {
+    Mint {
+        to: HistoryAccount,
+        amount: u128,
+    },
+    Burn {
+        from: HistoryAccount,
+        spender: Option<HistoryAccount>,
+        amount: u128,
+    },
+    Transfer {
+        from: HistoryAccount,
+        to: HistoryAccount,
+        spender: Option<HistoryAccount>,
+        amount: u128,
+        fee: Option<u128>,
+    },
+    Approve {
+        from: HistoryAccount,
+        spender: HistoryAccount,
+        amount: u128,
+        expected_allowance: Option<u128>,
+        expires_at: Option<u64>,
+        fee: Option<u128>,
+    },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, CandidType, PartialEq, Eq)]
+pub struct HistoryTransaction {
+    /// Block index of the transaction on the ledger
+    pub id: u64,
+    pub timestamp: u64,
+    pub operation: HistoryOperation,
+    /// ICRC-1 memo, or the big-endian bytes of a legacy ICP memo
+    pub memo: Option<Vec<u8>>,
+    pub created_at_time: Option<u64>,
+}
+
+/// One page of an account's history, newest transaction first
+#[derive(Debug, Clone, Serialize, Deserialize, CandidType, PartialEq, Eq)]
+pub struct AccountHistory {
+    pub balance: u128,
+    pub transactions: Vec<HistoryTransaction>,
+    /// Id of the oldest transaction of the account
+    pub oldest_tx_id: Option<u64>,
+    /// `start` for the next page, `None` once the oldest transaction was returned
+    pub next_start: Option<u64>,
+}
+
+impl AccountHistory {
+    fn new(
+        balance: u128,
+        transactions: Vec<HistoryTransaction>,
+        oldest_tx_id: Option<u64>,
+    ) -> Self {
+        let next_start = match (transactions.last(), oldest_tx_id) {
+            (Some(last), Some(oldest)) if last.id > oldest => Some(last.id - 1),
+            _ => None,
+        };
+
+        Self {
+            balance,
+            transactions,
+            oldest_tx_id,
+            next_start,
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize)]
+struct GetAccountTransactionsArgs {
+    account: Account,
+    start: Option<Nat>,
+    max_results: Nat,
+}
+
+#[derive(CandidType, Deserialize)]
+struct GetTransactionsErr {
+    message: String,
+}
+
+#[derive(CandidType, Deserialize)]
+struct IcrcTransactionWithId {
+    id: Nat,
+    transaction: Transaction,
+}
+
+#[derive(CandidType, Deserialize)]
+struct IcrcGetTransactions {
+    balance: Nat,
+    transactions: Vec<IcrcTransactionWithId>,
+    oldest_tx_id: Option<Nat>,
+}
+
+#[derive(CandidType, Deserialize)]
+struct Tokens {
+    e8s: u64,
+}
+
+#[derive(CandidType, Deserialize)]
+struct TimeStamp {
+    timestamp_nanos: u64,
+}
+
+#[derive(CandidType, Deserialize)]
+enum IcpOperation {
+    Mint {
+        to: String,
+        amount: Tokens,
+    },
+    Burn {
+        from: String,
+        spender: Option<String>,
+        amount: Tokens,
+    },
+    Transfer {
+        from: String,
+        to: String,
+        spender: Option<String>,
+        amount: Tokens,
+        fee: Tokens,
+    },
+    Approve {
+        from: String,
+        spender: String,
+        allowance: Tokens,
+        expected_allowance: Option<Tokens>,
+        expires_at: Option<TimeStamp>,
+        fee: Tokens,
+    },
+}
+
+#[derive(CandidType, Deserialize)]
+struct IcpTransaction {
+    memo: u64,
+    icrc1_memo: Option<serde_bytes::ByteBuf>,
+    operation: IcpOperation,
+    created_at_time: Option<TimeStamp>,
+    timestamp: Option<TimeStamp>,
+}
+
+#[derive(CandidType, Deserialize)]
+struct IcpTransactionWithId {
+    id: u64,
+    transaction: IcpTransaction,
+}
+
+#[derive(CandidType, Deserialize)]
+struct IcpGetTransactions {
+    balance: u64,
+    transactions: Vec<IcpTransactionWithId>,
+    oldest_tx_id: Option<u64>,
+}
+
+fn history_args(
+    account: Account,
+    start: Option<u64>,
+    max_results: u64,
+) -> GetAccountTransactionsArgs {
+    GetAccountTransactionsArgs {
+        account,
+        start: start.map(Nat::from),
+        max_results: Nat::from(max_results.min(MAX_HISTORY_PAGE_SIZE)),
+    }
+}
+
+fn nat_to_u128(value: &Nat) -> Result<u128, CurrencyError> {
+    value
+        .0
+        .to_u128()
+        .ok_or_else(|| CurrencyError::QueryError(format!("Value {} is out of range", value)))
+}
+
+pub(crate) fn nat_to_u64(value: &Nat) -> Result<u64, CurrencyError> {
+    value
+        .0
+        .to_u64()
+        .ok_or_else(|| CurrencyError::QueryError(format!("Value {} is out of range", value)))
+}
+
+/// Read a page of an account's history from an ICRC index-ng canister
+pub async fn get_icrc_account_transactions(
+    index: Principal,
+    account: Account,
+    start: Option<u64>,
+    max_results: u64,
+) -> Result<AccountHistory, CurrencyError> {
+    let args = history_args(account, start, max_results);
+    let (result,): (Result<IcrcGetTransactions, GetTransactionsErr>,) = retry_query(&retry_policy(), |_| async {
+        ic_cdk::call(index, "get_account_transactions", (&args,))
+            .await
+            .map_err(call_error(index, "get_account_transactions"))
+    })
+    .await?;
+
+    let response = result.map_err(|e| CurrencyError::QueryError(e.message))?;
+
+    let transactions = response
+        .transactions
+        .into_iter()
+        .map(|tx| icrc_history_transaction(nat_to_u64(&tx.id)?, tx.transaction))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(AccountHistory::new(
+        nat_to_u128(&response.balance)?,
+        transactions,
+        response.oldest_tx_id.as_ref().map(nat_to_u64).transpose()?,
+    ))
+}
+
+pub(crate) fn icrc_history_transaction(
+    id: u64,
+    tx: Transaction,
+) -> Result<HistoryTransaction, CurrencyError> {
+    let (operation, memo, created_at_time) = if let Some(mint) = tx.mint {
+        (
+            HistoryOperation::Mint {
+                to: HistoryAccount::Account(icrc_account(mint.to)),
+                amount: nat_to_u128(&mint.amount)?,
+            },
+            mint.memo,
+            mint.created_at_time,
+        )
+    } else if let Some(burn) = tx.burn {
+        (
+            HistoryOperation::Burn {
+                from: HistoryAccount::Account(icrc_account(burn.from)),
+                spender: burn
+                    .spender
+                    .map(|s| HistoryAccount::Account(icrc_account(s))),
+                amount: nat_to_u128(&burn.amount)?,
+            },
+            burn.memo,
+            burn.created_at_time,
+        )
+    } else if let Some(transfer) = tx.transfer {
+        (
+            HistoryOperation::Transfer {
+                from: HistoryAccount::Account(icrc_account(transfer.from)),
+                to: HistoryAccount::Account(icrc_account(transfer.to)),
+                spender: transfer
+                    .spender
+                    .map(|s| HistoryAccount::Account(icrc_account(s))),
+                amount: nat_to_u128(&transfer.amount)?,
+                fee: transfer.fee.as_ref().map(nat_to_u128).transpose()?,
+            },
+            transfer.memo,
+            transfer.created_at_time,
+        )
+    } else if let Some(approve) = tx.approve {
+        (
+            HistoryOperation::Approve {
+                from: HistoryAccount::Account(icrc_account(approve.from)),
+                spender: HistoryAccount::Account(icrc_account(approve.spender)),
+                amount: nat_to_u128(&approve.amount)?,
+                expected_allowance: approve
+                    .expected_allowance
+                    .as_ref()
+                    .map(nat_to_u128)
+                    .transpose()?,
+                expires_at: approve.expires_at,
+                fee: approve.fee.as_ref().map(nat_to_u128).transpose()?,
+            },
+            approve.memo,
+            approve.created_at_time,
+        )
+    } else {
+        return Err(CurrencyError::InvalidTransactionType);
+    };
+
+    Ok(HistoryTransaction {
+        id,
+        timestamp: tx.timestamp,
+        operation,
+        memo: memo.map(|memo| memo.into_vec()),
+        created_at_time,
+    })
+}
+
+fn icrc_account(account: crate::icrc_ledger_canister_interface::Account) -> Account {
+    Account {
+        owner: account.owner,
+        subaccount: account.subaccount.map(|subaccount| subaccount.into_vec()),
+    }
+}
+
+/// Read a page of an account's history from the ICP index canister
+pub async fn get_icp_account_transactions(
+    index: Principal,
+    account: Account,
+    start: Option<u64>,
+    max_results: u64,
+) -> Result<AccountHistory, CurrencyError> {
+    let args = history_args(account, start, max_results);
+    let (result,): (Result<IcpGetTransactions, GetTransactionsErr>,) = retry_query(&retry_policy(), |_| async {
+        ic_cdk::call(index, "get_account_transactions", (&args,))
+            .await
+            .map_err(call_error(index, "get_account_transactions"))
+    })
+    .await?;
+
+    let response = result.map_err(|e| CurrencyError::QueryError(e.message))?;
+
+    let transactions = response
+        .transactions
+        .into_iter()
+        .map(|tx| icp_history_transaction(tx.id, tx.transaction))
+        .collect();
+
+    Ok(AccountHistory::new(
+        response.balance as u128,
+        transactions,
+        response.oldest_tx_id,
+    ))
+}
+
+fn icp_history_transaction(id: u64, tx: IcpTransaction) -> HistoryTransaction {
+    let operation = match tx.operation {
+        IcpOperation::Mint { to, amount } => HistoryOperation::Mint {
+            to: HistoryAccount::AccountIdentifier(to),
+            amount: amount.e8s as u128,
+        },
+        IcpOperation::Burn {
+            from,
+            spender,
+            amount,
+        } => HistoryOperation::Burn {
+            from: HistoryAccount::AccountIdentifier(from),
+            spender: spender.map(HistoryAccount::AccountIdentifier),
+            amount: amount.e8s as u128,
+        },
+        IcpOperation::Transfer {
+            from,
+            to,
+            spender,
+            amount,
+            fee,
+        } => HistoryOperation::Transfer {
+            from: HistoryAccount::AccountIdentifier(from),
+            to: HistoryAccount::AccountIdentifier(to),
+            spender: spender.map(HistoryAccount::AccountIdentifier),
+            amount: amount.e8s as u128,
+            fee: Some(fee.e8s as u128),
+        },
+        IcpOperation::Approve {
+            from,
+            spender,
+            allowance,
+            expected_allowance,
+            expires_at,
+            fee,
+        } => HistoryOperation::Approve {
+            from: HistoryAccount::AccountIdentifier(from),
+            spender: HistoryAccount::AccountIdentifier(spender),
+            amount: allowance.e8s as u128,
+            expected_allowance: expected_allowance.map(|tokens| tokens.e8s as u128),
+            expires_at: expires_at.map(|ts| ts.timestamp_nanos),
+            fee: Some(fee.e8s as u128),
+        },
+    };
+
+    let memo = match tx.icrc1_memo {
+        Some(memo) => Some(memo.into_vec()),
+        None if tx.memo != 0 => Some(tx.memo.to_be_bytes().to_vec()),
+        None => None,
+    };
+
+    HistoryTransaction {
+        id,
+        timestamp: tx
+            .timestamp
+            .map(|ts| ts.timestamp_nanos)
+            .unwrap_or_default(),
+        operation,
+        memo,
+        created_at_time: tx.created_at_time.map(|ts| ts.timestamp_nanos),
+    }
+}
+
+impl CurrencyManager {
+    /// Index canister of a currency.
+    ///
+    /// The network config takes precedence, then the index advertised in the
+    /// registered token metadata, then the index advertised by the ledger itself.
+    pub async fn get_index_canister(
+        &self,
+        currency: &Currency,
+    ) -> Result<Principal, CurrencyError> {
+        let ledger_id = self.get_ledger_id(currency)?;
+
+        if let Some(index) = self.network_config().index_canister(&ledger_id) {
+            return Ok(index);
+        }
+
+        if let Some(index) = self
+            .registered_metadata(&ledger_id)
+            .and_then(|metadata| metadata.index_canister_id)
+        {
+            return Ok(index);
+        }
+
+        if *currency != Currency::ICP {
+            let metadata = GenericICRC1TokenWallet::query_token_metadata(ledger_id).await?;
+            if let Some(index) = metadata.index_canister_id {
+                return Ok(index);
+            }
+        }
+
+        Err(CurrencyError::IndexCanisterNotFound(currency.to_string()))
+    }
+
+    /// Read a page of an account's history for a currency, newest transaction first
+    pub async fn get_account_transactions(
+        &self,
+        currency: &Currency,
+        account: Account,
+        start: Option<u64>,
+        max_results: u64,
+    ) -> Result<AccountHistory, CurrencyError> {
+        let index = self.get_index_canister(currency).await?;
+
+        match currency {
+            Currency::ICP => get_icp_account_transactions(index, account, start, max_results).await,
+            _ => get_icrc_account_transactions(index, account, start, max_results).await,
+        }
+    }
+}
@@ -0,0 +1,595 @@
+//! Boundary between the crate's flows and the canisters they call.
+//!
+//! Deposits, withdrawals, approvals and allowance checks are written against [`LedgerClient`],
+//! ckETH and ckBTC minter calls against [`MinterClient`]. On the IC they run over
+//! [`IcLedgerClient`] and [`IcMinterClient`], which call the canisters with the
+//! crate's retry policy. Tests substitute in-memory implementations.
+//!
+//! The flows take the current time as an argument instead of reading it from the
+//! IC, so they run outside a canister as well.
+
+use candid::{CandidType, Nat, Principal};
+use ic_ledger_types::DEFAULT_FEE;
+use num_traits::ToPrimitive;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    ckbtc_minter_canister_interface::{UpdateBalanceArg, UpdateBalanceError, UpdateBalanceRet},
+    cketh_minter_canister_interface::{
+        GetEventsArg, GetEventsRet, RetrieveErc20Request, WithdrawErc20Arg, WithdrawErc20Ret,
+        WithdrawalDetail, WithdrawalSearchParameter,
+    },
+    currency_error::{call_error, CurrencyError, LedgerRejection},
+    icrc1_types::{
+        Account, Allowance, AllowanceArgs, ApproveArg, TransferArg, TransferErrorIcrc1,
+        TransferFromArg, TransferFromError,
+    },
+    icrc3::{self, Icrc3BlockRange},
+    icrc_ledger_canister_interface::{ApproveArgs, Result2 as ApproveResult},
+    retry::{retry_policy, retry_query, retry_update, Idempotency},
+};
+
+/// An ICRC-1 ledger, with the ICRC-2 and ICRC-3 methods the crate uses.
+///
+/// Ledger answers other than success are returned as [`CurrencyError::LedgerRejected`].
+/// A `Duplicate` answer is returned as an error too, unless it answers a retry made by
+/// the client itself, in which case it is the block index of the original transaction.
+#[allow(async_fn_in_trait)]
+pub trait LedgerClient {
+    fn ledger_id(&self) -> Principal;
+
+    async fn fee(&self) -> Result<u128, CurrencyError>;
+
+    async fn balance_of(&self, account: &Account) -> Result<u128, CurrencyError>;
+
+    async fn transfer(&self, args: TransferArg) -> Result<u128, CurrencyError>;
+
+    async fn allowance(
+        &self,
+        account: &Account,
+        spender: &Account,
+    ) -> Result<Allowance, CurrencyError>;
+
+    async fn transfer_from(&self, args: TransferFromArg) -> Result<u128, CurrencyError>;
+
+    /// Set the allowance of `args.spender` on the caller's account
+    async fn approve(&self, args: ApproveArg) -> Result<u128, CurrencyError>;
+
+    /// Read up to `length` ICRC-3 blocks starting at `start`
+    async fn get_blocks(&self, start: u64, length: u64) -> Result<Icrc3BlockRange, CurrencyError>;
+}
+
+/// The ckETH and ckBTC minter methods the crate uses.
+///
+/// Minter rejections are returned as [`CurrencyError::MinterRejected`], except for
+/// `update_balance` whose answer is returned as is, since `NoNewUtxos` carries the
+/// UTXOs still waiting for confirmations.
+#[allow(async_fn_in_trait)]
+pub trait MinterClient {
+    fn minter_id(&self) -> Principal;
+
+    async fn withdraw_erc20(
+        &self,
+        args: WithdrawErc20Arg,
+    ) -> Result<RetrieveErc20Request, CurrencyError>;
+
+    async fn withdrawal_status(
+        &self,
+        withdrawal_id: u64,
+    ) -> Result<Vec<WithdrawalDetail>, CurrencyError>;
+
+    async fn is_address_blocked(&self, address: &str) -> Result<bool, CurrencyError>;
+
+    /// Read up to `length` ckETH minter events starting at `start`
+    async fn get_events(&self, start: u64, length: u64) -> Result<GetEventsRet, CurrencyError>;
+
+    async fn get_btc_address(&self, subaccount: Option<Vec<u8>>) -> Result<String, CurrencyError>;
+
+    async fn update_balance(
+        &self,
+        subaccount: Option<Vec<u8>>,
+    ) -> Result<UpdateBalanceRet, CurrencyError>;
+}
+
+fn nat_to_u128(value: Nat, field: &str) -> Result<u128, CurrencyError> {
+    value
+        .0
+        .to_u128()
+        .ok_or_else(|| CurrencyError::LedgerError(format!("{} {} is out of range", field, value)))
+}
+
+/// [`LedgerClient`] calling a ledger canister
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IcLedgerClient {
+    pub ledger_id: Principal,
+}
+
+impl IcLedgerClient {
+    pub fn new(ledger_id: Principal) -> Self {
+        Self { ledger_id }
+    }
+
+    async fn transfer_once(&self, args: TransferArg) -> Result<u128, CurrencyError> {
+        let ledger_id = self.ledger_id;
+        let (result,): (Result<u128, TransferErrorIcrc1>,) =
+            ic_cdk::call(ledger_id, "icrc1_transfer", (args,))
+                .await
+                .map_err(call_error(ledger_id, "icrc1_transfer"))?;
+
+        result.map_err(|e| CurrencyError::LedgerRejected(e.into()))
+    }
+
+    async fn transfer_from_once(&self, args: TransferFromArg) -> Result<u128, CurrencyError> {
+        let ledger_id = self.ledger_id;
+        let (result,): (Result<u128, TransferFromError>,) =
+            ic_cdk::call(ledger_id, "icrc2_transfer_from", (args,))
+                .await
+                .map_err(call_error(ledger_id, "icrc2_transfer_from"))?;
+
+        result.map_err(|e| CurrencyError::LedgerRejected(e.into()))
+    }
+
+    async fn approve_once(&self, args: ApproveArg) -> Result<u128, CurrencyError> {
+        let ledger_id = self.ledger_id;
+        let (result,): (ApproveResult,) =
+            ic_cdk::call(ledger_id, "icrc2_approve", (ApproveArgs::from(args),))
+                .await
+                .map_err(call_error(ledger_id, "icrc2_approve"))?;
+
+        match result {
+            ApproveResult::Ok(block_index) => nat_to_u128(block_index, "block index"),
+            ApproveResult::Err(e) => Err(CurrencyError::LedgerRejected(e.try_into()?)),
+        }
+    }
+}
+
+/// `Duplicate` answers to a retry are the transaction the first attempt created
+fn accept_replayed_duplicate(
+    attempt: u32,
+    result: Result<u128, CurrencyError>,
+) -> Result<u128, CurrencyError> {
+    match result {
+        Err(CurrencyError::LedgerRejected(LedgerRejection::Duplicate { duplicate_of }))
+            if attempt > 0 =>
+        {
+            Ok(duplicate_of)
+        }
+        result => result,
+    }
+}
+
+impl LedgerClient for IcLedgerClient {
+    fn ledger_id(&self) -> Principal {
+        self.ledger_id
+    }
+
+    async fn fee(&self) -> Result<u128, CurrencyError> {
+        let ledger_id = self.ledger_id;
+        let (fee,): (Nat,) = retry_query(&retry_policy(), |_| async {
+            ic_cdk::call(ledger_id, "icrc1_fee", ())
+                .await
+                .map_err(call_error(ledger_id, "icrc1_fee"))
+        })
+        .await?;
+        nat_to_u128(fee, "fee")
+    }
+
+    async fn balance_of(&self, account: &Account) -> Result<u128, CurrencyError> {
+        let ledger_id = self.ledger_id;
+        let (balance,): (Nat,) = retry_query(&retry_policy(), |_| async {
+            ic_cdk::call(ledger_id, "icrc1_balance_of", (account,))
+                .await
+                .map_err(call_error(ledger_id, "icrc1_balance_of"))
+        })
+        .await?;
+        nat_to_u128(balance, "balance")
+    }
+
+    /// Transfers with a `created_at_time` are retried, others are sent once
+    async fn transfer(&self, args: TransferArg) -> Result<u128, CurrencyError> {
+        let idempotency = match args.created_at_time {
+            Some(created_at_time) => Idempotency::CreatedAt(created_at_time),
+            None => return self.transfer_once(args).await,
+        };
+
+        retry_update(&retry_policy(), idempotency, |attempt| {
+            let args = args.clone();
+            async move { accept_replayed_duplicate(attempt, self.transfer_once(args).await) }
+        })
+        .await
+    }
+
+    async fn allowance(
+        &self,
+        account: &Account,
+        spender: &Account,
+    ) -> Result<Allowance, CurrencyError> {
+        let ledger_id = self.ledger_id;
+        let args = AllowanceArgs {
+            account: account.clone(),
+            spender: spender.clone(),
+        };
+        let (allowance,): (Allowance,) = retry_query(&retry_policy(), |_| async {
+            ic_cdk::call(ledger_id, "icrc2_allowance", (&args,))
+                .await
+                .map_err(call_error(ledger_id, "icrc2_allowance"))
+        })
+        .await?;
+        Ok(allowance)
+    }
+
+    /// Transfers with a `created_at_time` are retried, others are sent once
+    async fn transfer_from(&self, args: TransferFromArg) -> Result<u128, CurrencyError> {
+        let idempotency = match args.created_at_time {
+            Some(created_at_time) => Idempotency::CreatedAt(created_at_time),
+            None => return self.transfer_from_once(args).await,
+        };
+
+        retry_update(&retry_policy(), idempotency, |attempt| {
+            let args = args.clone();
+            async move { accept_replayed_duplicate(attempt, self.transfer_from_once(args).await) }
+        })
+        .await
+    }
+
+    /// Approvals with a `created_at_time` are retried, others are sent once
+    async fn approve(&self, args: ApproveArg) -> Result<u128, CurrencyError> {
+        let idempotency = match args.created_at_time {
+            Some(created_at_time) => Idempotency::CreatedAt(created_at_time),
+            None => return self.approve_once(args).await,
+        };
+
+        retry_update(&retry_policy(), idempotency, |attempt| {
+            let args = args.clone();
+            async move { accept_replayed_duplicate(attempt, self.approve_once(args).await) }
+        })
+        .await
+    }
+
+    async fn get_blocks(&self, start: u64, length: u64) -> Result<Icrc3BlockRange, CurrencyError> {
+        icrc3::get_blocks(self.ledger_id, start, length).await
+    }
+}
+
+/// [`MinterClient`] calling a ckETH or ckBTC minter canister
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IcMinterClient {
+    pub minter_id: Principal,
+}
+
+impl IcMinterClient {
+    pub fn new(minter_id: Principal) -> Self {
+        Self { minter_id }
+    }
+}
+
+impl MinterClient for IcMinterClient {
+    fn minter_id(&self) -> Principal {
+        self.minter_id
+    }
+
+    /// Not retried: the minter has no deduplication, so a second call would burn
+    /// the tokens again.
+    async fn withdraw_erc20(
+        &self,
+        args: WithdrawErc20Arg,
+    ) -> Result<RetrieveErc20Request, CurrencyError> {
+        let (result,): (WithdrawErc20Ret,) =
+            ic_cdk::call(self.minter_id, "withdraw_erc20", (args,))
+                .await
+                .map_err(call_error(self.minter_id, "withdraw_erc20"))?;
+
+        match result {
+            WithdrawErc20Ret::Ok(request) => Ok(request),
+            WithdrawErc20Ret::Err(e) => Err(CurrencyError::MinterRejected(e.try_into()?)),
+        }
+    }
+
+    async fn withdrawal_status(
+        &self,
+        withdrawal_id: u64,
+    ) -> Result<Vec<WithdrawalDetail>, CurrencyError> {
+        let minter_id = self.minter_id;
+        let (status,): (Vec<WithdrawalDetail>,) = retry_query(&retry_policy(), |_| async {
+            ic_cdk::call(
+                minter_id,
+                "withdrawal_status",
+                (WithdrawalSearchParameter::ByWithdrawalId(withdrawal_id),),
+            )
+            .await
+            .map_err(call_error(minter_id, "withdrawal_status"))
+        })
+        .await?;
+        Ok(status)
+    }
+
+    async fn is_address_blocked(&self, address: &str) -> Result<bool, CurrencyError> {
+        let minter_id = self.minter_id;
+        let (blocked,): (bool,) = retry_query(&retry_policy(), |_| async {
+            ic_cdk::call(minter_id, "is_address_blocked", (address.to_string(),))
+                .await
+                .map_err(call_error(minter_id, "is_address_blocked"))
+        })
+        .await?;
+        Ok(blocked)
+    }
+
+    async fn get_events(&self, start: u64, length: u64) -> Result<GetEventsRet, CurrencyError> {
+        let minter_id = self.minter_id;
+        let (events,): (GetEventsRet,) = retry_query(&retry_policy(), |_| async {
+            ic_cdk::call(minter_id, "get_events", (GetEventsArg { start, length },))
+                .await
+                .map_err(call_error(minter_id, "get_events"))
+        })
+        .await?;
+        Ok(events)
+    }
+
+    async fn get_btc_address(&self, subaccount: Option<Vec<u8>>) -> Result<String, CurrencyError> {
+        let minter_id = self.minter_id;
+        let arg = crate::ckbtc_minter_canister_interface::GetBtcAddressArg {
+            owner: None,
+            subaccount: subaccount.map(serde_bytes::ByteBuf::from),
+        };
+        let (address,): (String,) = retry_query(&retry_policy(), |_| async {
+            ic_cdk::call(minter_id, "get_btc_address", (&arg,))
+                .await
+                .map_err(call_error(minter_id, "get_btc_address"))
+        })
+        .await?;
+        Ok(address)
+    }
+
+    /// The minter only mints each UTXO once, so the call is retried like a query
+    async fn update_balance(
+        &self,
+        subaccount: Option<Vec<u8>>,
+    ) -> Result<UpdateBalanceRet, CurrencyError> {
+        let minter_id = self.minter_id;
+        let args = UpdateBalanceArg {
+            owner: None,
+            subaccount: subaccount.map(serde_bytes::ByteBuf::from),
+        };
+
+        retry_update(&retry_policy(), Idempotency::Inherent, |_| async {
+            let (result,): (UpdateBalanceRet,) =
+                ic_cdk::call(minter_id, "update_balance", (&args,))
+                    .await
+                    .map_err(call_error(minter_id, "update_balance"))?;
+
+            match result {
+                UpdateBalanceRet::Err(
+                    e @ (UpdateBalanceError::TemporarilyUnavailable(_)
+                    | UpdateBalanceError::AlreadyProcessing),
+                ) => Err(CurrencyError::MinterRejected(e.into())),
+                result => Ok(result),
+            }
+        })
+        .await
+    }
+}
+
+/// Check that `account` allows `spender` to move at least `amount` at time `now`
+pub async fn check_allowance(
+    ledger: &impl LedgerClient,
+    account: &Account,
+    spender: &Account,
+    amount: u128,
+    now: u64,
+) -> Result<Allowance, CurrencyError> {
+    let allowance = ledger.allowance(account, spender).await?;
+
+    if allowance.allowance < amount {
+        return Err(CurrencyError::InsufficientAllowance);
+    }
+    if allowance
+        .expires_at
+        .is_some_and(|expires_at| expires_at < now)
+    {
+        return Err(CurrencyError::InsufficientAllowance);
+    }
+
+    Ok(allowance)
+}
+
+/// What a deposit needs from the depositor, and whether they have it
+#[derive(Debug, Clone, Serialize, Deserialize, CandidType, PartialEq, Eq)]
+pub struct DepositCheck {
+    pub amount: u128,
+    pub fee: u128,
+    /// The allowance granted to the canister, zero once it expired
+    pub allowance: u128,
+    pub expires_at: Option<u64>,
+    /// Allowance the deposit consumes, `amount` plus the `transfer_from` fee.
+    /// An approval replaces the allowance, so this is the amount to approve.
+    pub required_allowance: u128,
+    pub balance: u128,
+    /// Balance the deposit consumes, plus the approval fee if an approval is missing
+    pub required_balance: u128,
+    pub will_succeed: bool,
+}
+
+/// Check what a deposit of `amount` from `from` to `to` needs at time `now`.
+///
+/// `fee` defaults to the ledger's fee.
+pub async fn deposit_check(
+    ledger: &impl LedgerClient,
+    from: &Account,
+    to: &Account,
+    amount: u128,
+    fee: Option<u128>,
+    now: u64,
+) -> Result<DepositCheck, CurrencyError> {
+    let fee = match fee {
+        Some(fee) => fee,
+        None => ledger.fee().await?,
+    };
+    let allowance = ledger.allowance(from, to).await?;
+    let balance = ledger.balance_of(from).await?;
+
+    let live_allowance = match allowance.expires_at {
+        Some(expires_at) if expires_at < now => 0,
+        _ => allowance.allowance,
+    };
+    let required_allowance = amount.saturating_add(fee);
+    let approved = live_allowance >= required_allowance;
+    let required_balance = if approved {
+        required_allowance
+    } else {
+        required_allowance.saturating_add(fee)
+    };
+
+    Ok(DepositCheck {
+        amount,
+        fee,
+        allowance: live_allowance,
+        expires_at: allowance.expires_at,
+        required_allowance,
+        balance,
+        required_balance,
+        will_succeed: approved && balance >= required_balance,
+    })
+}
+
+/// Move `amount` from `from` to `to` using the allowance `from` granted to `to`.
+///
+/// The allowance is checked first, for the amount and the fee the ledger takes out
+/// of it, so a missing allowance fails without paying a fee. `fee` defaults to the
+/// ledger's fee. `now` is used as the `created_at_time`. Returns the block index.
+pub async fn deposit_from(
+    ledger: &impl LedgerClient,
+    from: Account,
+    to: Account,
+    amount: u128,
+    fee: Option<u128>,
+    now: u64,
+) -> Result<u128, CurrencyError> {
+    let allowance_fee = match fee {
+        Some(fee) => fee,
+        None => ledger.fee().await?,
+    };
+    check_allowance(ledger, &from, &to, amount.saturating_add(allowance_fee), now).await?;
+
+    ledger
+        .transfer_from(TransferFromArg {
+            spender_subaccount: to.subaccount.clone(),
+            from,
+            to,
+            amount,
+            fee,
+            memo: None,
+            created_at_time: Some(now),
+        })
+        .await
+}
+
+/// Allow `args.spender` to move up to `args.amount` out of the caller's account.
+///
+/// The new allowance replaces the current one. An `expires_at` that is not in the
+/// future is rejected before any fee is paid. `now` is the `created_at_time` unless
+/// `args` sets one. Returns the block index.
+pub async fn approve_spender(
+    ledger: &impl LedgerClient,
+    args: ApproveArg,
+    now: u64,
+) -> Result<u128, CurrencyError> {
+    if args.expires_at.is_some_and(|expires_at| expires_at <= now) {
+        return Err(CurrencyError::LedgerRejected(LedgerRejection::Expired {
+            ledger_time: now,
+        }));
+    }
+
+    ledger
+        .approve(ApproveArg {
+            created_at_time: args.created_at_time.or(Some(now)),
+            ..args
+        })
+        .await
+}
+
+/// Set the allowance `owner` granted to `spender` back to zero.
+///
+/// Returns the block index, or `None` without paying a fee if there was no live
+/// allowance to revoke.
+pub async fn revoke_approval(
+    ledger: &impl LedgerClient,
+    owner: &Account,
+    spender: Account,
+    now: u64,
+) -> Result<Option<u128>, CurrencyError> {
+    let allowance = ledger.allowance(owner, &spender).await?;
+    if allowance.allowance == 0
+        || allowance
+            .expires_at
+            .is_some_and(|expires_at| expires_at < now)
+    {
+        return Ok(None);
+    }
+
+    let args = ApproveArg {
+        from_subaccount: owner.subaccount.clone(),
+        ..ApproveArg::new(spender, 0)
+    };
+    approve_spender(ledger, args, now).await.map(Some)
+}
+
+/// What arrives of `amount` once `fee` is deducted, `InsufficientFunds` if the fee is larger
+pub fn net_of_fee(amount: u128, fee: u128) -> Result<u128, CurrencyError> {
+    amount
+        .checked_sub(fee)
+        .ok_or(CurrencyError::InsufficientFunds)
+}
+
+/// Pay `amount` out of `from_subaccount` to `to`, the fee is deducted from `amount`.
+///
+/// `fee` defaults to the ICP fee for the deduction and to the ledger's fee for the
+/// transfer. A `Duplicate` answer is returned as an error: for a new `created_at_time`
+/// it means an identical transfer was already made, not that this one was executed.
+/// Use [`replay_withdrawal_to`] to repeat a transfer whose outcome is unknown.
+pub async fn withdraw_to(
+    ledger: &impl LedgerClient,
+    from_subaccount: Option<Vec<u8>>,
+    to: Account,
+    amount: u128,
+    fee: Option<u128>,
+    created_at_time: u64,
+) -> Result<u128, CurrencyError> {
+    let net_amount = net_of_fee(amount, fee.unwrap_or(DEFAULT_FEE.e8s() as u128))?;
+
+    ledger
+        .transfer(TransferArg {
+            to,
+            fee,
+            memo: None,
+            from_subaccount,
+            created_at_time: Some(created_at_time),
+            amount: net_amount,
+        })
+        .await
+}
+
+/// Repeat a [`withdraw_to`] with the `created_at_time` persisted for the first attempt.
+///
+/// A transfer the ledger already executed is returned as its original block index,
+/// so the call can be repeated safely.
+pub async fn replay_withdrawal_to(
+    ledger: &impl LedgerClient,
+    from_subaccount: Option<Vec<u8>>,
+    to: Account,
+    amount: u128,
+    fee: Option<u128>,
+    created_at_time: u64,
+) -> Result<u128, CurrencyError> {
+    accept_duplicate(withdraw_to(ledger, from_subaccount, to, amount, fee, created_at_time).await)
+}
+
+/// A `Duplicate` answer to a replayed transfer means it was already executed, return
+/// its block index. Only for a `created_at_time` persisted before the first attempt.
+pub(crate) fn accept_duplicate(result: Result<u128, CurrencyError>) -> Result<u128, CurrencyError> {
+    match result {
+        Err(CurrencyError::LedgerRejected(LedgerRejection::Duplicate { duplicate_of })) => {
+            Ok(duplicate_of)
+        }
+        result => result,
+    }
+}
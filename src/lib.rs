@@ -1,12 +1,24 @@
-pub mod ckbtc_ledger_canister_interface;
+pub mod address;
 pub mod ckbtc_minter_canister_interface;
 pub mod cketh_minter_canister_interface;
-pub mod ckusdc_canister_interface;
+pub mod cketh_deposit;
 pub mod currency_error;
+pub mod deposit_subaccount;
+pub mod deposit_watcher;
+pub mod guard;
 pub mod icrc1_types;
+pub mod icrc_ledger_canister_interface;
+pub mod icrc3;
+pub mod index;
+pub mod ledger_client;
+pub mod minter_events;
+pub mod outbox;
 pub mod query_btc;
 pub mod query;
 pub mod rake_constants;
+pub mod reimbursement;
+pub mod retry;
+pub mod stable_storage;
 pub mod state;
 pub mod transfer;
 pub mod types;
@@ -14,3 +26,7 @@ pub mod utils;
 
 // For ease of use, re-export the types in the top-level module
 pub use types::currency::Currency;
+
+// Every ICRC ledger shares one interface, these are its former per-token names
+pub use icrc_ledger_canister_interface as ckbtc_ledger_canister_interface;
+pub use icrc_ledger_canister_interface as ckusdc_canister_interface;
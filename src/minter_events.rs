@@ -0,0 +1,803 @@
+//! Typed stream of ckETH and ckBTC minter events.
+//!
+//! Both minters keep an append-only event log. The stream reads the new events of
+//! every minter used by the `CurrencyManager` from a cursor kept in stable memory,
+//! converts them into [`MinterEvent`]s and hands them to the subscribers of their
+//! [`MinterEventCategory`].
+//!
+//! Events are delivered exactly once: subscribers run and the cursor advances in the
+//! same message, after the last `await`. Run a single stream per canister, several
+//! streams would share the cursors and each see only part of the events.
+
+use std::{cell::RefCell, rc::Rc, time::Duration};
+
+use candid::{CandidType, Principal};
+use ic_cdk_timers::TimerId;
+use ic_stable_structures::{memory_manager::VirtualMemory, DefaultMemoryImpl, StableBTreeMap};
+use num_traits::ToPrimitive;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    ckbtc_minter_canister_interface::{self as ckbtc, ReimbursementReason, SuspendedReason},
+    cketh_minter_canister_interface::{self as cketh, EventPayload, ReimbursementIndex},
+    currency_error::{call_error, CurrencyError},
+    icrc1_types::Account,
+    retry::{retry_policy, retry_query},
+    types::{canister_wallets::btc_token_wallet::BtcUtxo, currency_manager::CurrencyManager},
+};
+
+pub type MinterEventMemory = VirtualMemory<DefaultMemoryImpl>;
+
+/// Events requested per call from the ckETH minter, which caps pages at 100
+const CKETH_PAGE_SIZE: u64 = 100;
+/// Events requested per call from the ckBTC minter
+const CKBTC_PAGE_SIZE: u64 = 1_000;
+/// Pages read per minter and run
+const MAX_PAGES_PER_RUN: u64 = 20;
+
+/// Which minter interface an event log follows
+#[derive(
+    Debug, Clone, Copy, Serialize, Deserialize, CandidType, PartialEq, Eq, PartialOrd, Ord, Hash,
+)]
+pub enum MinterKind {
+    CkEth,
+    CkBtc,
+}
+
+/// Ethereum log that caused a ckETH/ckERC20 deposit
+#[derive(Debug, Clone, Serialize, Deserialize, CandidType, PartialEq, Eq, Hash)]
+pub struct EthEventSource {
+    pub transaction_hash: String,
+    pub log_index: u128,
+}
+
+impl EthEventSource {
+    /// Lowercase `hash:log_index`, identifies a deposit across events
+    pub fn key(&self) -> String {
+        format!(
+            "{}:{}",
+            self.transaction_hash.to_lowercase(),
+            self.log_index
+        )
+    }
+}
+
+impl TryFrom<cketh::EventSource> for EthEventSource {
+    type Error = CurrencyError;
+
+    fn try_from(source: cketh::EventSource) -> Result<Self, CurrencyError> {
+        Ok(Self {
+            transaction_hash: source.transaction_hash,
+            log_index: nat_to_u128(&source.log_index)?,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, CandidType, PartialEq, Eq, Hash)]
+pub enum MinterEventCategory {
+    Deposit,
+    Withdrawal,
+    Reimbursement,
+    Quarantine,
+}
+
+/// Minter events relevant to deposits, withdrawals and their failures.
+///
+/// Amounts are in the smallest unit of the token, block indices refer to the
+/// token's ledger. Withdrawal ids are the ckETH ledger burn index of the withdrawal.
+#[derive(Debug, Clone, Serialize, Deserialize, CandidType, PartialEq, Eq)]
+pub enum MinterEvent {
+    /// The minter saw an ETH (`erc20_contract_address` is `None`) or ERC-20 deposit
+    EthDepositAccepted {
+        source: EthEventSource,
+        principal: Principal,
+        subaccount: Option<Vec<u8>>,
+        amount: u128,
+        from_address: String,
+        erc20_contract_address: Option<String>,
+    },
+    /// ckETH (`ckerc20_token_symbol` is `None`) or ckERC20 was minted for a deposit
+    EthDepositMinted {
+        source: EthEventSource,
+        mint_block_index: u128,
+        ckerc20_token_symbol: Option<String>,
+    },
+    EthDepositQuarantined {
+        source: EthEventSource,
+    },
+    EthDepositInvalid {
+        source: EthEventSource,
+        reason: String,
+    },
+    /// A withdrawal burned tokens; `ledger_id` is `None` for ckETH withdrawals
+    EthWithdrawalAccepted {
+        withdrawal_id: u128,
+        ledger_id: Option<Principal>,
+        burn_index: u128,
+        from: Principal,
+        from_subaccount: Option<Vec<u8>>,
+        amount: u128,
+        destination: String,
+    },
+    /// A ckERC20 withdrawal failed before being sent, the ckETH fee is reimbursed
+    EthWithdrawalFailed {
+        withdrawal_id: u128,
+        to: Principal,
+        to_subaccount: Option<Vec<u8>>,
+        reimbursed_amount: u128,
+    },
+    /// Tokens of a failed withdrawal were minted back to the sender
+    EthWithdrawalReimbursed {
+        withdrawal_id: u128,
+        ledger_id: Option<Principal>,
+        burn_index: u128,
+        reimbursed_amount: u128,
+        reimbursed_in_block: u128,
+        transaction_hash: Option<String>,
+    },
+    EthReimbursementQuarantined {
+        withdrawal_id: u128,
+        ledger_id: Option<Principal>,
+        burn_index: u128,
+    },
+    /// New UTXOs were credited to `account`; `mint_block_index` is `None` when nothing was minted.
+    /// `utxo_value` is the sum of the UTXO values, the minted amount is lower by the
+    /// minter's check fee.
+    BtcUtxosReceived {
+        account: Account,
+        mint_block_index: Option<u64>,
+        utxo_value: u64,
+        utxos: Vec<BtcUtxo>,
+    },
+    /// A UTXO was too small or quarantined and will not be minted for now
+    BtcUtxoSuspended {
+        account: Account,
+        utxo: BtcUtxo,
+        quarantined: bool,
+    },
+    BtcDepositReimbursementScheduled {
+        burn_block_index: u64,
+        account: Account,
+        amount: u64,
+        reason: String,
+    },
+    BtcDepositReimbursed {
+        burn_block_index: u64,
+        mint_block_index: u64,
+    },
+    BtcWithdrawalAccepted {
+        block_index: u64,
+        amount: u64,
+        reimbursement_account: Option<Account>,
+    },
+    /// The destination of a withdrawal failed the KYT check
+    BtcWithdrawalKytFailed {
+        block_index: u64,
+        owner: Principal,
+        amount: u64,
+    },
+}
+
+fn nat_to_u128(value: &candid::Nat) -> Result<u128, CurrencyError> {
+    value.0.to_u128().ok_or_else(|| {
+        CurrencyError::QueryError(format!("Minter event value {} is too large", value))
+    })
+}
+
+fn btc_account(account: ckbtc::Account) -> Account {
+    Account {
+        owner: account.owner,
+        subaccount: account.subaccount.map(|s| s.into_vec()),
+    }
+}
+
+impl MinterEvent {
+    pub fn category(&self) -> MinterEventCategory {
+        match self {
+            MinterEvent::EthDepositAccepted { .. }
+            | MinterEvent::EthDepositMinted { .. }
+            | MinterEvent::BtcUtxosReceived { .. } => MinterEventCategory::Deposit,
+            MinterEvent::EthWithdrawalAccepted { .. }
+            | MinterEvent::BtcWithdrawalAccepted { .. } => MinterEventCategory::Withdrawal,
+            MinterEvent::EthWithdrawalFailed { .. }
+            | MinterEvent::EthWithdrawalReimbursed { .. }
+            | MinterEvent::BtcDepositReimbursementScheduled { .. }
+            | MinterEvent::BtcDepositReimbursed { .. }
+            | MinterEvent::BtcWithdrawalKytFailed { .. } => MinterEventCategory::Reimbursement,
+            MinterEvent::EthDepositQuarantined { .. }
+            | MinterEvent::EthDepositInvalid { .. }
+            | MinterEvent::EthReimbursementQuarantined { .. }
+            | MinterEvent::BtcUtxoSuspended { .. } => MinterEventCategory::Quarantine,
+        }
+    }
+
+    /// Convert a ckETH minter event, `None` for internal bookkeeping events.
+    /// Fails on amounts or indices that do not fit a `u128`.
+    pub fn from_cketh(payload: EventPayload) -> Result<Option<Self>, CurrencyError> {
+        let event = match payload {
+            EventPayload::AcceptedDeposit {
+                principal,
+                transaction_hash,
+                value,
+                log_index,
+                subaccount,
+                from_address,
+                ..
+            } => MinterEvent::EthDepositAccepted {
+                source: EthEventSource {
+                    transaction_hash,
+                    log_index: nat_to_u128(&log_index)?,
+                },
+                principal,
+                subaccount: subaccount.map(|s| s.into_vec()),
+                amount: nat_to_u128(&value)?,
+                from_address,
+                erc20_contract_address: None,
+            },
+            EventPayload::AcceptedErc20Deposit {
+                principal,
+                transaction_hash,
+                value,
+                log_index,
+                subaccount,
+                erc20_contract_address,
+                from_address,
+                ..
+            } => MinterEvent::EthDepositAccepted {
+                source: EthEventSource {
+                    transaction_hash,
+                    log_index: nat_to_u128(&log_index)?,
+                },
+                principal,
+                subaccount: subaccount.map(|s| s.into_vec()),
+                amount: nat_to_u128(&value)?,
+                from_address,
+                erc20_contract_address: Some(erc20_contract_address),
+            },
+            EventPayload::MintedCkEth {
+                event_source,
+                mint_block_index,
+            } => MinterEvent::EthDepositMinted {
+                source: event_source.try_into()?,
+                mint_block_index: nat_to_u128(&mint_block_index)?,
+                ckerc20_token_symbol: None,
+            },
+            EventPayload::MintedCkErc20 {
+                event_source,
+                mint_block_index,
+                ckerc20_token_symbol,
+                ..
+            } => MinterEvent::EthDepositMinted {
+                source: event_source.try_into()?,
+                mint_block_index: nat_to_u128(&mint_block_index)?,
+                ckerc20_token_symbol: Some(ckerc20_token_symbol),
+            },
+            EventPayload::QuarantinedDeposit { event_source } => {
+                MinterEvent::EthDepositQuarantined {
+                    source: event_source.try_into()?,
+                }
+            }
+            EventPayload::InvalidDeposit {
+                event_source,
+                reason,
+            } => MinterEvent::EthDepositInvalid {
+                source: event_source.try_into()?,
+                reason,
+            },
+            EventPayload::AcceptedEthWithdrawalRequest {
+                ledger_burn_index,
+                destination,
+                withdrawal_amount,
+                from,
+                from_subaccount,
+                ..
+            } => MinterEvent::EthWithdrawalAccepted {
+                withdrawal_id: nat_to_u128(&ledger_burn_index)?,
+                ledger_id: None,
+                burn_index: nat_to_u128(&ledger_burn_index)?,
+                from,
+                from_subaccount: from_subaccount.map(|s| s.into_vec()),
+                amount: nat_to_u128(&withdrawal_amount)?,
+                destination,
+            },
+            EventPayload::AcceptedErc20WithdrawalRequest {
+                cketh_ledger_burn_index,
+                destination,
+                ckerc20_ledger_id,
+                withdrawal_amount,
+                from,
+                from_subaccount,
+                ckerc20_ledger_burn_index,
+                ..
+            } => MinterEvent::EthWithdrawalAccepted {
+                withdrawal_id: nat_to_u128(&cketh_ledger_burn_index)?,
+                ledger_id: Some(ckerc20_ledger_id),
+                burn_index: nat_to_u128(&ckerc20_ledger_burn_index)?,
+                from,
+                from_subaccount: from_subaccount.map(|s| s.into_vec()),
+                amount: nat_to_u128(&withdrawal_amount)?,
+                destination,
+            },
+            EventPayload::FailedErc20WithdrawalRequest {
+                to,
+                withdrawal_id,
+                reimbursed_amount,
+                to_subaccount,
+            } => MinterEvent::EthWithdrawalFailed {
+                withdrawal_id: nat_to_u128(&withdrawal_id)?,
+                to,
+                to_subaccount: to_subaccount.map(|s| s.into_vec()),
+                reimbursed_amount: nat_to_u128(&reimbursed_amount)?,
+            },
+            EventPayload::ReimbursedEthWithdrawal {
+                transaction_hash,
+                withdrawal_id,
+                reimbursed_amount,
+                reimbursed_in_block,
+            } => MinterEvent::EthWithdrawalReimbursed {
+                withdrawal_id: nat_to_u128(&withdrawal_id)?,
+                ledger_id: None,
+                burn_index: nat_to_u128(&withdrawal_id)?,
+                reimbursed_amount: nat_to_u128(&reimbursed_amount)?,
+                reimbursed_in_block: nat_to_u128(&reimbursed_in_block)?,
+                transaction_hash,
+            },
+            EventPayload::ReimbursedErc20Withdrawal {
+                burn_in_block,
+                transaction_hash,
+                withdrawal_id,
+                reimbursed_amount,
+                ledger_id,
+                reimbursed_in_block,
+            } => MinterEvent::EthWithdrawalReimbursed {
+                withdrawal_id: nat_to_u128(&withdrawal_id)?,
+                ledger_id: Some(ledger_id),
+                burn_index: nat_to_u128(&burn_in_block)?,
+                reimbursed_amount: nat_to_u128(&reimbursed_amount)?,
+                reimbursed_in_block: nat_to_u128(&reimbursed_in_block)?,
+                transaction_hash,
+            },
+            EventPayload::QuarantinedReimbursement { index } => match index {
+                ReimbursementIndex::CkEth { ledger_burn_index } => {
+                    MinterEvent::EthReimbursementQuarantined {
+                        withdrawal_id: nat_to_u128(&ledger_burn_index)?,
+                        ledger_id: None,
+                        burn_index: nat_to_u128(&ledger_burn_index)?,
+                    }
+                }
+                ReimbursementIndex::CkErc20 {
+                    cketh_ledger_burn_index,
+                    ledger_id,
+                    ckerc20_ledger_burn_index,
+                } => MinterEvent::EthReimbursementQuarantined {
+                    withdrawal_id: nat_to_u128(&cketh_ledger_burn_index)?,
+                    ledger_id: Some(ledger_id),
+                    burn_index: nat_to_u128(&ckerc20_ledger_burn_index)?,
+                },
+            },
+            _ => return Ok(None),
+        };
+        Ok(Some(event))
+    }
+
+    /// Convert a ckBTC minter event, `None` for internal bookkeeping events
+    pub fn from_ckbtc(event: ckbtc::Event) -> Option<Self> {
+        let event = match event {
+            ckbtc::Event::ReceivedUtxos {
+                to_account,
+                mint_txid,
+                utxos,
+            } => MinterEvent::BtcUtxosReceived {
+                account: btc_account(to_account),
+                mint_block_index: mint_txid,
+                utxo_value: utxos.iter().map(|utxo| utxo.value).sum(),
+                utxos: utxos.into_iter().map(BtcUtxo::from).collect(),
+            },
+            ckbtc::Event::SuspendedUtxo {
+                utxo,
+                account,
+                reason,
+            } => MinterEvent::BtcUtxoSuspended {
+                account: btc_account(account),
+                utxo: utxo.into(),
+                quarantined: matches!(reason, SuspendedReason::Quarantined),
+            },
+            ckbtc::Event::ScheduleDepositReimbursement {
+                burn_block_index,
+                account,
+                amount,
+                reason,
+            } => MinterEvent::BtcDepositReimbursementScheduled {
+                burn_block_index,
+                account: btc_account(account),
+                amount,
+                reason: match reason {
+                    ReimbursementReason::CallFailed => "Call failed".to_string(),
+                    ReimbursementReason::TaintedDestination { .. } => {
+                        "Tainted destination".to_string()
+                    }
+                },
+            },
+            ckbtc::Event::ReimbursedFailedDeposit {
+                burn_block_index,
+                mint_block_index,
+            } => MinterEvent::BtcDepositReimbursed {
+                burn_block_index,
+                mint_block_index,
+            },
+            ckbtc::Event::AcceptedRetrieveBtcRequest {
+                block_index,
+                reimbursement_account,
+                amount,
+                ..
+            } => MinterEvent::BtcWithdrawalAccepted {
+                block_index,
+                amount,
+                reimbursement_account: reimbursement_account.map(btc_account),
+            },
+            ckbtc::Event::RetrieveBtcKytFailed {
+                block_index,
+                owner,
+                amount,
+                ..
+            } => MinterEvent::BtcWithdrawalKytFailed {
+                block_index,
+                owner,
+                amount,
+            },
+            _ => return None,
+        };
+        Some(event)
+    }
+}
+
+/// A minter event with its position in the minter's log
+#[derive(Debug, Clone, Serialize, Deserialize, CandidType, PartialEq, Eq)]
+pub struct MinterEventRecord {
+    pub minter_id: Principal,
+    pub kind: MinterKind,
+    pub index: u64,
+    /// Nanoseconds since the epoch, the ckBTC minter does not timestamp its events
+    pub timestamp: Option<u64>,
+    pub event: MinterEvent,
+}
+
+/// One page of a minter's event log
+pub struct MinterEventPage {
+    pub records: Vec<MinterEventRecord>,
+    /// Index of the event after the page
+    pub next: u64,
+    /// Whether the log has more events after `next`
+    pub has_more: bool,
+}
+
+/// Read the events of a minter starting at `start`
+pub async fn read_minter_events(
+    minter_id: Principal,
+    kind: MinterKind,
+    start: u64,
+) -> Result<MinterEventPage, CurrencyError> {
+    match kind {
+        MinterKind::CkEth => {
+            let (page,): (cketh::GetEventsRet,) = retry_query(&retry_policy(), |_| async {
+                ic_cdk::call(
+                    minter_id,
+                    "get_events",
+                    (cketh::GetEventsArg {
+                        start,
+                        length: CKETH_PAGE_SIZE,
+                    },),
+                )
+                .await
+                .map_err(call_error(minter_id, "get_events"))
+            })
+            .await?;
+
+            let next = start + page.events.len() as u64;
+            let mut records = Vec::new();
+            for (offset, event) in page.events.into_iter().enumerate() {
+                if let Some(minter_event) = MinterEvent::from_cketh(event.payload)? {
+                    records.push(MinterEventRecord {
+                        minter_id,
+                        kind,
+                        index: start + offset as u64,
+                        timestamp: Some(event.timestamp),
+                        event: minter_event,
+                    });
+                }
+            }
+            Ok(MinterEventPage {
+                records,
+                next,
+                has_more: next > start && next < page.total_event_count,
+            })
+        }
+        MinterKind::CkBtc => {
+            let (events,): (Vec<ckbtc::Event>,) = retry_query(&retry_policy(), |_| async {
+                ic_cdk::call(
+                    minter_id,
+                    "get_events",
+                    (ckbtc::GetEventsArg {
+                        start,
+                        length: CKBTC_PAGE_SIZE,
+                    },),
+                )
+                .await
+                .map_err(call_error(minter_id, "get_events"))
+            })
+            .await?;
+
+            // The ckBTC minter does not report its log length, a full page means there may be more
+            let read = events.len() as u64;
+            Ok(MinterEventPage {
+                records: events
+                    .into_iter()
+                    .enumerate()
+                    .filter_map(|(offset, event)| {
+                        Some(MinterEventRecord {
+                            minter_id,
+                            kind,
+                            index: start + offset as u64,
+                            timestamp: None,
+                            event: MinterEvent::from_ckbtc(event)?,
+                        })
+                    })
+                    .collect(),
+                next: start + read,
+                has_more: read == CKBTC_PAGE_SIZE,
+            })
+        }
+    }
+}
+
+/// Next event to read per minter, stored in a dedicated virtual memory
+pub struct MinterEventStream {
+    cursors: StableBTreeMap<Principal, u64, MinterEventMemory>,
+}
+
+impl MinterEventStream {
+    pub fn init(memory: MinterEventMemory) -> Self {
+        Self {
+            cursors: StableBTreeMap::init(memory),
+        }
+    }
+
+    /// Index of the next event to read, `None` for a minter that was never read
+    pub fn cursor(&self, minter_id: &Principal) -> Option<u64> {
+        self.cursors.get(minter_id)
+    }
+
+    /// Continue reading a minter's log at `next_event`, e.g. 0 to replay its whole history
+    pub fn set_cursor(&mut self, minter_id: Principal, next_event: u64) {
+        self.cursors.insert(minter_id, next_event);
+    }
+}
+
+thread_local! {
+    static STREAM: RefCell<Option<MinterEventStream>> = const { RefCell::new(None) };
+    static POLLING: RefCell<bool> = const { RefCell::new(false) };
+}
+
+/// Initialise the stream on a memory obtained from the canister's `MemoryManager`.
+/// Must be called from both `init` and `post_upgrade`.
+pub fn init_minter_event_stream(memory: MinterEventMemory) {
+    STREAM.with(|stream| *stream.borrow_mut() = Some(MinterEventStream::init(memory)));
+}
+
+/// Run a closure against the stream
+pub fn with_minter_event_stream<R>(
+    f: impl FnOnce(&mut MinterEventStream) -> R,
+) -> Result<R, CurrencyError> {
+    STREAM.with(|stream| match stream.borrow_mut().as_mut() {
+        Some(stream) => Ok(f(stream)),
+        None => Err(CurrencyError::MinterEventStreamNotInitialized),
+    })
+}
+
+type MinterEventHandler = Box<dyn Fn(&CurrencyManager, &MinterEventRecord)>;
+
+/// Handlers of minter events, each for a set of categories
+#[derive(Default)]
+pub struct MinterEventSubscribers {
+    handlers: Vec<(Vec<MinterEventCategory>, MinterEventHandler)>,
+}
+
+impl MinterEventSubscribers {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Call `handler` for every event in one of `categories`
+    pub fn subscribe(
+        mut self,
+        categories: &[MinterEventCategory],
+        handler: impl Fn(&CurrencyManager, &MinterEventRecord) + 'static,
+    ) -> Self {
+        self.handlers.push((categories.to_vec(), Box::new(handler)));
+        self
+    }
+
+    /// Call `handler` for every event
+    pub fn subscribe_all(
+        self,
+        handler: impl Fn(&CurrencyManager, &MinterEventRecord) + 'static,
+    ) -> Self {
+        self.subscribe(
+            &[
+                MinterEventCategory::Deposit,
+                MinterEventCategory::Withdrawal,
+                MinterEventCategory::Reimbursement,
+                MinterEventCategory::Quarantine,
+            ],
+            handler,
+        )
+    }
+
+    pub fn dispatch(&self, manager: &CurrencyManager, record: &MinterEventRecord) {
+        let category = record.event.category();
+        for (categories, handler) in self.handlers.iter() {
+            if categories.contains(&category) {
+                handler(manager, record);
+            }
+        }
+    }
+}
+
+/// Minters used by the manager's ckETH/ckERC20 and ckBTC wallets
+pub fn minters(manager: &CurrencyManager) -> Vec<(Principal, MinterKind)> {
+    let mut minters: Vec<(Principal, MinterKind)> = manager
+        .ckerc20_tokens
+        .iter()
+        .map(|wallet| (wallet.config.minter_id, MinterKind::CkEth))
+        .collect();
+    // ckETH and the ckERC20 tokens share a minter
+    minters.sort();
+    minters.dedup();
+
+    if let Some(btc) = &manager.btc {
+        minters.push((btc.config.minter_id, MinterKind::CkBtc));
+    }
+    minters
+}
+
+/// Number of events in a minter's log
+pub async fn minter_log_length(
+    minter_id: Principal,
+    kind: MinterKind,
+) -> Result<u64, CurrencyError> {
+    match kind {
+        MinterKind::CkEth => {
+            let (page,): (cketh::GetEventsRet,) = retry_query(&retry_policy(), |_| async {
+                ic_cdk::call(
+                    minter_id,
+                    "get_events",
+                    (cketh::GetEventsArg {
+                        start: 0,
+                        length: 0,
+                    },),
+                )
+                .await
+                .map_err(call_error(minter_id, "get_events"))
+            })
+            .await?;
+            Ok(page.total_event_count)
+        }
+        MinterKind::CkBtc => {
+            // The ckBTC minter does not report its log length, search for the last event
+            let exists = |index: u64| async move {
+                let (events,): (Vec<ckbtc::Event>,) = retry_query(&retry_policy(), |_| async {
+                    ic_cdk::call(
+                        minter_id,
+                        "get_events",
+                        (ckbtc::GetEventsArg {
+                            start: index,
+                            length: 1,
+                        },),
+                    )
+                    .await
+                    .map_err(call_error(minter_id, "get_events"))
+                })
+                .await?;
+                Ok::<_, CurrencyError>(!events.is_empty())
+            };
+
+            if !exists(0).await? {
+                return Ok(0);
+            }
+            let (mut last, mut missing) = (0, 1);
+            while exists(missing).await? {
+                last = missing;
+                missing = missing.saturating_mul(2);
+            }
+            while missing - last > 1 {
+                let middle = last + (missing - last) / 2;
+                if exists(middle).await? {
+                    last = middle;
+                } else {
+                    missing = middle;
+                }
+            }
+            Ok(last + 1)
+        }
+    }
+}
+
+/// Read the new events of one minter and dispatch them.
+///
+/// A minter without a cursor starts at the current end of its log, earlier events
+/// are not dispatched; set its cursor with [`MinterEventStream::set_cursor`] to
+/// start elsewhere. Returns the number of events dispatched.
+pub async fn poll_minter(
+    manager: &CurrencyManager,
+    minter_id: Principal,
+    kind: MinterKind,
+    subscribers: &MinterEventSubscribers,
+) -> Result<usize, CurrencyError> {
+    let mut dispatched = 0;
+
+    let Some(mut start) = with_minter_event_stream(|stream| stream.cursor(&minter_id))? else {
+        let end = minter_log_length(minter_id, kind).await?;
+        with_minter_event_stream(|stream| stream.set_cursor(minter_id, end))?;
+        return Ok(0);
+    };
+
+    for _ in 0..MAX_PAGES_PER_RUN {
+        let page = read_minter_events(minter_id, kind, start).await?;
+
+        // No await from here on: the subscribers and the cursor update commit together.
+        for record in page.records.iter() {
+            subscribers.dispatch(manager, record);
+        }
+        with_minter_event_stream(|stream| stream.set_cursor(minter_id, page.next))?;
+        dispatched += page.records.len();
+        start = page.next;
+
+        if !page.has_more {
+            break;
+        }
+    }
+
+    Ok(dispatched)
+}
+
+/// Clears the polling flag when dropped, also when a run traps
+struct PollGuard;
+
+impl Drop for PollGuard {
+    fn drop(&mut self) {
+        POLLING.with(|flag| *flag.borrow_mut() = false);
+    }
+}
+
+/// Start a timer that periodically reads the events of every minter used by the manager.
+///
+/// `manager` provides the current `CurrencyManager` for every run, `None` skips the run.
+pub fn start_minter_event_stream(
+    interval: Duration,
+    manager: impl Fn() -> Option<CurrencyManager> + 'static,
+    subscribers: MinterEventSubscribers,
+) -> TimerId {
+    let manager = Rc::new(manager);
+    let subscribers = Rc::new(subscribers);
+
+    ic_cdk_timers::set_timer_interval(interval, move || {
+        if POLLING.with(|flag| flag.replace(true)) {
+            return;
+        }
+
+        let manager = manager.clone();
+        let subscribers = subscribers.clone();
+        ic_cdk::futures::spawn(async move {
+            let _guard = PollGuard;
+            let Some(manager) = manager() else {
+                return;
+            };
+
+            for (minter_id, kind) in minters(&manager) {
+                if let Err(e) = poll_minter(&manager, minter_id, kind, &subscribers).await {
+                    ic_cdk::println!("Failed to read events of minter {}: {:?}", minter_id, e);
+                }
+            }
+        });
+    })
+}
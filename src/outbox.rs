@@ -0,0 +1,421 @@
+//! Stable-memory outbox for withdrawals.
+//!
+//! Every withdrawal sent through the outbox is recorded as an intent before the
+//! ledger is called and marked as completed once the ledger answered. An entry
+//! that is still pending after a trap or an upgrade is resolved by the reconciler,
+//! which looks for the transfer on the ledger by its `created_at_time`. Only a
+//! transfer that is not there is replayed, with the same `created_at_time`, so the
+//! ledger still deduplicates it should it have landed after the search.
+
+use std::{borrow::Cow, cell::RefCell, time::Duration};
+
+use candid::{CandidType, Decode, Encode, Principal};
+use ic_cdk_timers::TimerId;
+use ic_ledger_types::{AccountIdentifier, DEFAULT_SUBACCOUNT};
+use ic_stable_structures::{
+    memory_manager::VirtualMemory, storable::Bound, DefaultMemoryImpl, StableBTreeMap, StableCell, Storable,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    currency_error::CurrencyError,
+    guard::OperationGuard,
+    icrc1_types::Account,
+    index::{HistoryAccount, HistoryOperation, HistoryTransaction},
+    query::LedgerQuery,
+    retry::DEDUP_WINDOW_NANOS,
+    types::{currency_manager::CurrencyManager, withdrawal_batch::WithdrawalRequest},
+    Currency,
+};
+
+pub type OutboxMemory = VirtualMemory<DefaultMemoryImpl>;
+
+/// Replays that fail this many times leave the entry for manual review
+const MAX_RECONCILE_ATTEMPTS: u32 = 5;
+
+/// Ledgers reject transactions created further than this ahead of their own time
+const PERMITTED_DRIFT_NANOS: u64 = 60 * 1_000_000_000;
+
+/// Blocks read per call when looking for a pending withdrawal on the ledger
+const LOOKUP_PAGE_SIZE: u64 = 100;
+
+/// Blocks searched per run before a lookup gives up
+const MAX_LOOKUP_BLOCKS: u64 = 10_000;
+
+#[derive(Debug, Clone, Serialize, Deserialize, CandidType, PartialEq, Eq)]
+pub enum OutboxStatus {
+    /// The intent is recorded; the transfer may or may not have reached the ledger
+    Pending,
+    /// The ledger executed the transfer
+    Completed { block_index: u128 },
+    /// The withdrawal returned an error to its caller
+    Failed { error: CurrencyError },
+    /// The outcome could not be determined automatically and needs manual review
+    Unresolved { reason: String },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, CandidType, PartialEq, Eq)]
+pub struct OutboxEntry {
+    pub id: u64,
+    pub request: WithdrawalRequest,
+    pub created_at_time: u64,
+    pub status: OutboxStatus,
+    pub attempts: u32,
+    pub updated_at: u64,
+}
+
+impl Storable for OutboxEntry {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).expect("Failed to encode outbox entry"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        // An entry that cannot be decoded must never be dropped silently, it may be
+        // the only record of a payout.
+        Decode!(bytes.as_ref(), Self).expect("Failed to decode outbox entry")
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+/// Outbox entries keyed by id, stored in a dedicated virtual memory
+pub struct WithdrawalOutbox {
+    entries: StableBTreeMap<u64, OutboxEntry, OutboxMemory>,
+    /// Id of the next entry, kept apart so pruned ids are never handed out again
+    next_id: StableCell<u64, OutboxMemory>,
+}
+
+impl WithdrawalOutbox {
+    pub fn init(entry_memory: OutboxMemory, counter_memory: OutboxMemory) -> Self {
+        Self {
+            entries: StableBTreeMap::init(entry_memory),
+            next_id: StableCell::init(counter_memory, 0).expect("Failed to init outbox id counter"),
+        }
+    }
+
+    /// Record the intent to withdraw, returns the new entry
+    pub fn record_intent(&mut self, request: WithdrawalRequest, created_at_time: u64) -> OutboxEntry {
+        let id = *self.next_id.get();
+        self.next_id
+            .set(id + 1)
+            .expect("Failed to update outbox id counter");
+
+        let entry = OutboxEntry {
+            id,
+            request,
+            created_at_time,
+            status: OutboxStatus::Pending,
+            attempts: 1,
+            updated_at: ic_cdk::api::time(),
+        };
+        self.entries.insert(id, entry.clone());
+        entry
+    }
+
+    pub fn set_status(&mut self, id: u64, status: OutboxStatus) -> Option<OutboxEntry> {
+        let mut entry = self.entries.get(&id)?;
+        entry.status = status;
+        entry.updated_at = ic_cdk::api::time();
+        self.entries.insert(id, entry.clone());
+        Some(entry)
+    }
+
+    /// Record another attempt at resolving an entry
+    pub fn record_attempt(&mut self, id: u64, status: OutboxStatus) -> Option<OutboxEntry> {
+        let mut entry = self.entries.get(&id)?;
+        entry.status = status;
+        entry.attempts += 1;
+        entry.updated_at = ic_cdk::api::time();
+        self.entries.insert(id, entry.clone());
+        Some(entry)
+    }
+
+    pub fn get(&self, id: u64) -> Option<OutboxEntry> {
+        self.entries.get(&id)
+    }
+
+    pub fn entries(&self) -> Vec<OutboxEntry> {
+        self.entries.iter().map(|(_, entry)| entry).collect()
+    }
+
+    pub fn pending(&self) -> Vec<OutboxEntry> {
+        self.entries
+            .iter()
+            .map(|(_, entry)| entry)
+            .filter(|entry| entry.status == OutboxStatus::Pending)
+            .collect()
+    }
+
+    /// Remove completed and failed entries last updated before `before`
+    pub fn prune(&mut self, before: u64) -> usize {
+        let stale: Vec<u64> = self
+            .entries
+            .iter()
+            .filter(|(_, entry)| {
+                entry.updated_at < before
+                    && matches!(
+                        entry.status,
+                        OutboxStatus::Completed { .. } | OutboxStatus::Failed { .. }
+                    )
+            })
+            .map(|(id, _)| id)
+            .collect();
+
+        for id in stale.iter() {
+            self.entries.remove(id);
+        }
+        stale.len()
+    }
+}
+
+thread_local! {
+    static OUTBOX: RefCell<Option<WithdrawalOutbox>> = const { RefCell::new(None) };
+    static RECONCILING: RefCell<bool> = const { RefCell::new(false) };
+}
+
+/// Initialise the outbox on two memories obtained from the canister's `MemoryManager`,
+/// one for the entries and one for the id counter.
+/// Must be called from both `init` and `post_upgrade`.
+pub fn init_withdrawal_outbox(entry_memory: OutboxMemory, counter_memory: OutboxMemory) {
+    OUTBOX.with(|outbox| {
+        *outbox.borrow_mut() = Some(WithdrawalOutbox::init(entry_memory, counter_memory))
+    });
+}
+
+/// Run a closure against the outbox
+pub fn with_outbox<R>(f: impl FnOnce(&mut WithdrawalOutbox) -> R) -> Result<R, CurrencyError> {
+    OUTBOX.with(|outbox| match outbox.borrow_mut().as_mut() {
+        Some(outbox) => Ok(f(outbox)),
+        None => Err(CurrencyError::OutboxNotInitialized),
+    })
+}
+
+impl CurrencyManager {
+    /// Withdraw through the outbox.
+    ///
+    /// The intent is persisted before the ledger is called, so a trap or an upgrade
+    /// between the call and the caller's own bookkeeping leaves a pending entry that
+    /// the reconciler resolves later. Returns the outbox entry id with the block index.
+    ///
+    /// A [`CurrencyError::CallOutcomeUnknown`] error also leaves the entry pending:
+    /// the transfer may have happened, so it is not a failure to report to the user.
+    pub async fn withdraw_with_outbox(
+        &self,
+        currency: &Currency,
+        wallet_principal_id: Principal,
+        amount: u64,
+    ) -> Result<(u64, u128), CurrencyError> {
+        let _guard = OperationGuard::new(wallet_principal_id, *currency)?;
+
+        let created_at_time = ic_cdk::api::time();
+        let entry = with_outbox(|outbox| {
+            outbox.record_intent(
+                WithdrawalRequest {
+                    currency: *currency,
+                    recipient: wallet_principal_id,
+                    amount,
+                },
+                created_at_time,
+            )
+        })?;
+
+        let result = self
+            .withdraw_at(currency, wallet_principal_id, amount, created_at_time)
+            .await;
+
+        let status = match &result {
+            Ok(block_index) => OutboxStatus::Completed {
+                block_index: *block_index,
+            },
+            Err(error) if error.is_outcome_unknown() => OutboxStatus::Pending,
+            Err(error) => OutboxStatus::Failed {
+                error: error.clone(),
+            },
+        };
+        with_outbox(|outbox| outbox.set_status(entry.id, status))?;
+
+        result.map(|block_index| (entry.id, block_index))
+    }
+}
+
+/// An account's default subaccount as the currency's ledger reports it
+fn history_account(query: &LedgerQuery, owner: Principal) -> HistoryAccount {
+    if query.is_icp {
+        HistoryAccount::AccountIdentifier(AccountIdentifier::new(&owner, &DEFAULT_SUBACCOUNT).to_hex())
+    } else {
+        HistoryAccount::Account(Account::from(owner))
+    }
+}
+
+/// Search the ledger backwards from its tip for the transfer of `entry`.
+///
+/// The withdrawal is the transfer from the canister to the recipient with the entry's
+/// `created_at_time`. A ledger only accepts a transaction up to the permitted drift
+/// after its own time, so the search stops at the first block timestamped before
+/// `created_at_time` minus that drift: the transfer cannot be any older.
+async fn find_withdrawal(
+    query: &LedgerQuery,
+    entry: &OutboxEntry,
+) -> Result<Option<u128>, CurrencyError> {
+    let from = history_account(query, ic_cdk::api::canister_self());
+    let to = history_account(query, entry.request.recipient);
+    let is_withdrawal = |transaction: &HistoryTransaction| {
+        transaction.created_at_time == Some(entry.created_at_time)
+            && matches!(
+                &transaction.operation,
+                HistoryOperation::Transfer {
+                    from: sender,
+                    to: receiver,
+                    spender: None,
+                    ..
+                } if *sender == from && *receiver == to
+            )
+    };
+    let oldest_timestamp = entry.created_at_time.saturating_sub(PERMITTED_DRIFT_NANOS);
+
+    let mut end = query.log_length().await?;
+    let lowest = end.saturating_sub(MAX_LOOKUP_BLOCKS);
+    while end > lowest {
+        let start = end.saturating_sub(LOOKUP_PAGE_SIZE).max(lowest);
+        let page = query.transactions(start, end - start).await?;
+        // A page missing its newest blocks could hide the transfer
+        if page.transactions.last().map(|transaction| transaction.id + 1) != Some(end) {
+            return Err(CurrencyError::QueryError(format!(
+                "Ledger returned an incomplete range for blocks {}..{}",
+                start, end
+            )));
+        }
+
+        if let Some(transaction) = page.transactions.iter().find(|t| is_withdrawal(t)) {
+            return Ok(Some(transaction.id as u128));
+        }
+        if start == 0
+            || page
+                .transactions
+                .iter()
+                .any(|transaction| transaction.timestamp < oldest_timestamp)
+        {
+            return Ok(None);
+        }
+        end = start;
+    }
+
+    Err(CurrencyError::QueryError(format!(
+        "Withdrawal not found within the last {} blocks",
+        MAX_LOOKUP_BLOCKS
+    )))
+}
+
+async fn reconcile_entry(manager: &CurrencyManager, entry: &OutboxEntry) -> OutboxStatus {
+    let request = &entry.request;
+    let attempt_failed = |error: CurrencyError| {
+        // A failed lookup or replay says nothing about the original transfer,
+        // keep the entry pending until the retries are exhausted.
+        if entry.attempts + 1 >= MAX_RECONCILE_ATTEMPTS {
+            OutboxStatus::Unresolved {
+                reason: format!("Reconciliation failed after {} attempts: {}", entry.attempts + 1, error),
+            }
+        } else {
+            OutboxStatus::Pending
+        }
+    };
+
+    let query = match manager.ledger_query(&request.currency) {
+        Ok(query) => query,
+        Err(error) => return attempt_failed(error),
+    };
+    match find_withdrawal(&query, entry).await {
+        Ok(Some(block_index)) => return OutboxStatus::Completed { block_index },
+        Ok(None) => {}
+        Err(error) => return attempt_failed(error),
+    }
+
+    // Not on the ledger: replay it, unless the ledger could no longer deduplicate
+    // a transfer that lands between the search and the replay
+    if ic_cdk::api::time().saturating_sub(entry.created_at_time) > DEDUP_WINDOW_NANOS {
+        return OutboxStatus::Unresolved {
+            reason: "Ledger deduplication window has passed".to_string(),
+        };
+    }
+    match manager
+        .withdraw_at(
+            &request.currency,
+            request.recipient,
+            request.amount,
+            entry.created_at_time,
+        )
+        .await
+    {
+        Ok(block_index) => OutboxStatus::Completed { block_index },
+        Err(error) if error.is_outcome_unknown() => attempt_failed(error),
+        Err(error) => OutboxStatus::Failed { error },
+    }
+}
+
+/// Resolve pending outbox entries older than `min_age`.
+///
+/// `on_resolved` is called for every entry that left the pending state, so the
+/// caller can update its own books.
+pub async fn reconcile_outbox(
+    manager: &CurrencyManager,
+    min_age: Duration,
+    on_resolved: &dyn Fn(&OutboxEntry),
+) -> Result<usize, CurrencyError> {
+    let cutoff = ic_cdk::api::time().saturating_sub(min_age.as_nanos() as u64);
+    let pending: Vec<OutboxEntry> = with_outbox(|outbox| outbox.pending())?
+        .into_iter()
+        .filter(|entry| entry.updated_at <= cutoff)
+        .collect();
+
+    let mut resolved = 0;
+    for entry in pending {
+        let status = reconcile_entry(manager, &entry).await;
+        let updated = with_outbox(|outbox| outbox.record_attempt(entry.id, status))?;
+
+        if let Some(updated) = updated.filter(|e| e.status != OutboxStatus::Pending) {
+            resolved += 1;
+            on_resolved(&updated);
+        }
+    }
+
+    Ok(resolved)
+}
+
+/// Clears the reconciling flag when dropped, also when a run traps
+struct ReconcileGuard;
+
+impl Drop for ReconcileGuard {
+    fn drop(&mut self) {
+        RECONCILING.with(|flag| *flag.borrow_mut() = false);
+    }
+}
+
+/// Start a timer that periodically reconciles the outbox.
+///
+/// `manager` provides the current `CurrencyManager` for every run, `None` skips the run.
+pub fn start_outbox_reconciler(
+    interval: Duration,
+    min_age: Duration,
+    manager: impl Fn() -> Option<CurrencyManager> + 'static,
+    on_resolved: impl Fn(&OutboxEntry) + 'static,
+) -> TimerId {
+    let manager = std::rc::Rc::new(manager);
+    let on_resolved = std::rc::Rc::new(on_resolved);
+
+    ic_cdk_timers::set_timer_interval(interval, move || {
+        if RECONCILING.with(|flag| flag.replace(true)) {
+            return;
+        }
+
+        let manager = manager.clone();
+        let on_resolved = on_resolved.clone();
+        ic_cdk::futures::spawn(async move {
+            let _guard = ReconcileGuard;
+            if let Some(manager) = manager() {
+                if let Err(e) = reconcile_outbox(&manager, min_age, on_resolved.as_ref()).await {
+                    ic_cdk::println!("Outbox reconciliation failed: {:?}", e);
+                }
+            }
+        });
+    })
+}
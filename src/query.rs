@@ -1,103 +1,431 @@
-use candid::Principal;
-use ic_ledger_types::{query_archived_blocks, query_blocks, Block, BlockIndex, GetBlocksArgs};
+//! Block, archive, balance and allowance queries for the ledger of any currency.
+//!
+//! ICRC ledgers serve their transactions through `get_transactions`, the ICP ledger
+//! its blocks through `query_blocks`. [`LedgerQuery`] reads both, follows the
+//! archive callbacks and returns [`HistoryTransaction`]s whatever the currency.
+//! [`query_block_range`] returns the raw ICP blocks, for callers matching the legacy
+//! account identifiers.
+
+use candid::{CandidType, Func, Nat, Principal};
+use ic_ledger_types::{
+    query_archived_blocks, query_blocks, AccountIdentifier, Block, BlockIndex, GetBlocksArgs,
+    Operation, QueryArchiveFn,
+};
+use serde::{Deserialize, Serialize};
 
 use crate::{
-    ckusdc_canister_interface::{GetBlocksRequest, GetTransactionsResponse, Transaction},
-    currency_error::CurrencyError,
+    currency_error::{call_error, CurrencyError},
+    icrc1_types::{Account, Allowance},
+    icrc_ledger_canister_interface::{
+        ArchiveInfo, ArchivedRange1Callback, GetBlocksRequest, GetTransactionsResponse,
+        Transaction, TransactionRange,
+    },
+    index::{
+        icrc_history_transaction, nat_to_u64, HistoryAccount, HistoryOperation, HistoryTransaction,
+    },
+    ledger_client::{IcLedgerClient, LedgerClient},
+    retry::{retry_policy, retry_query},
+    types::currency_manager::CurrencyManager,
+    Currency,
 };
 
-pub async fn get_one_block(
-    ledger: Principal,
-    block_index: u64,
-) -> Result<Option<Transaction>, CurrencyError> {
-    for i in 0..3 {
-        let args = GetBlocksRequest {
-            start: candid::Nat::from(block_index),
-            length: candid::Nat::from(1u64),
-        };
+/// Transactions read from a ledger, ordered by block index
+#[derive(Debug, Clone, Serialize, Deserialize, CandidType, PartialEq, Eq)]
+pub struct LedgerTransactions {
+    /// Number of blocks of the ledger, archived ones included
+    pub log_length: u64,
+    pub transactions: Vec<HistoryTransaction>,
+}
+
+/// An archive canister of a ledger.
+/// The ICP ledger does not report which blocks its archives hold.
+#[derive(Debug, Clone, Serialize, Deserialize, CandidType, PartialEq, Eq)]
+pub struct LedgerArchive {
+    pub canister_id: Principal,
+    pub block_range_start: Option<u64>,
+    pub block_range_end: Option<u64>,
+}
 
-        let result: Result<(GetTransactionsResponse,), _> =
-            ic_cdk::call(ledger, "get_transactions", (args,)).await;
-
-        match result {
-            Ok((response,)) => return Ok(response.transactions.into_iter().next()),
-            Err(e) => {
-                if i == 2 {
-                    // Last attempt
-                    return Err(CurrencyError::QueryError(format!(
-                        "Error querying transactions: {:?}",
-                        e
-                    )));
-                }
-                // On failure, continue to next iteration
-                continue;
-            }
+#[derive(CandidType, Deserialize)]
+struct IcpArchive {
+    canister_id: Principal,
+}
+
+#[derive(CandidType, Deserialize)]
+struct IcpArchives {
+    archives: Vec<IcpArchive>,
+}
+
+/// Read access to the ledger of a currency
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LedgerQuery {
+    pub ledger_id: Principal,
+    /// The ICP ledger serves blocks through `query_blocks` instead of `get_transactions`
+    pub is_icp: bool,
+}
+
+impl LedgerQuery {
+    pub fn new(currency: &Currency, ledger_id: Principal) -> Self {
+        Self {
+            ledger_id,
+            is_icp: *currency == Currency::ICP,
         }
     }
-    Ok(None)
+
+    /// Read up to `length` transactions starting at block `start`, archived ones included
+    pub async fn transactions(
+        &self,
+        start: u64,
+        length: u64,
+    ) -> Result<LedgerTransactions, CurrencyError> {
+        if !self.is_icp {
+            return icrc_transactions(self.ledger_id, start, length).await;
+        }
+
+        let (log_length, blocks) = query_block_range(self.ledger_id, start, length).await?;
+        Ok(LedgerTransactions {
+            log_length,
+            transactions: blocks
+                .into_iter()
+                .filter_map(|(index, block)| icp_block_transaction(index, block))
+                .collect(),
+        })
+    }
+
+    /// The transaction of block `index`, `None` if the ledger has no such block
+    pub async fn transaction(
+        &self,
+        index: u64,
+    ) -> Result<Option<HistoryTransaction>, CurrencyError> {
+        Ok(self
+            .transactions(index, 1)
+            .await?
+            .transactions
+            .into_iter()
+            .find(|transaction| transaction.id == index))
+    }
+
+    /// Number of blocks of the ledger, archived ones included
+    pub async fn log_length(&self) -> Result<u64, CurrencyError> {
+        Ok(self.transactions(0, 0).await?.log_length)
+    }
+
+    pub async fn archives(&self) -> Result<Vec<LedgerArchive>, CurrencyError> {
+        let ledger_id = self.ledger_id;
+
+        if self.is_icp {
+            let (archives,): (IcpArchives,) = retry_query(&retry_policy(), |_| async {
+                ic_cdk::call(ledger_id, "archives", ())
+                    .await
+                    .map_err(call_error(ledger_id, "archives"))
+            })
+            .await?;
+            return Ok(archives
+                .archives
+                .into_iter()
+                .map(|archive| LedgerArchive {
+                    canister_id: archive.canister_id,
+                    block_range_start: None,
+                    block_range_end: None,
+                })
+                .collect());
+        }
+
+        let (archives,): (Vec<ArchiveInfo>,) = retry_query(&retry_policy(), |_| async {
+            ic_cdk::call(ledger_id, "archives", ())
+                .await
+                .map_err(call_error(ledger_id, "archives"))
+        })
+        .await?;
+        archives
+            .into_iter()
+            .map(|archive| {
+                Ok(LedgerArchive {
+                    canister_id: archive.canister_id,
+                    block_range_start: Some(nat_to_u64(&archive.block_range_start)?),
+                    block_range_end: Some(nat_to_u64(&archive.block_range_end)?),
+                })
+            })
+            .collect()
+    }
+
+    pub async fn balance_of(&self, account: &Account) -> Result<u128, CurrencyError> {
+        IcLedgerClient::new(self.ledger_id)
+            .balance_of(account)
+            .await
+    }
+
+    pub async fn allowance(
+        &self,
+        account: &Account,
+        spender: &Account,
+    ) -> Result<Allowance, CurrencyError> {
+        IcLedgerClient::new(self.ledger_id)
+            .allowance(account, spender)
+            .await
+    }
 }
 
-pub async fn query_one_block(
+async fn icrc_transactions(
     ledger: Principal,
-    block_index: BlockIndex,
-) -> Result<Option<Block>, CurrencyError> {
-    let args = GetBlocksArgs {
-        start: block_index,
-        length: 1,
+    start: u64,
+    length: u64,
+) -> Result<LedgerTransactions, CurrencyError> {
+    let (log_length, raw) = icrc_raw_transactions(ledger, start, length).await?;
+    Ok(LedgerTransactions {
+        log_length,
+        transactions: raw
+            .into_iter()
+            .map(|(index, transaction)| icrc_history_transaction(index, transaction))
+            .collect::<Result<_, _>>()?,
+    })
+}
+
+/// The ledger's `get_transactions` answer with the archived transactions read,
+/// every transaction numbered with its block index
+async fn icrc_raw_transactions(
+    ledger: Principal,
+    start: u64,
+    length: u64,
+) -> Result<(u64, Vec<(u64, Transaction)>), CurrencyError> {
+    let args = GetBlocksRequest {
+        start: Nat::from(start),
+        length: Nat::from(length),
     };
+    let (response,): (GetTransactionsResponse,) = retry_query(&retry_policy(), |_| async {
+        ic_cdk::call(ledger, "get_transactions", (&args,))
+            .await
+            .map_err(call_error(ledger, "get_transactions"))
+    })
+    .await?;
 
-    for i in 0..3 {
-        let blocks_result = match query_blocks(ledger, &args).await {
-            Ok(blocks) => blocks,
-            Err(e) => {
-                if i == 2 {
-                    return Err(CurrencyError::QueryError(format!(
-                        "Error querying blocks: {:?}",
-                        e
-                    )));
-                }
-                continue;
-            }
+    let mut transactions = Vec::new();
+    for archived in response.archived_transactions {
+        let archived_start = nat_to_u64(&archived.start)?;
+        let args = GetBlocksRequest {
+            start: archived.start,
+            length: archived.length,
         };
-
-        if !blocks_result.blocks.is_empty() {
-            debug_assert_eq!(blocks_result.first_block_index, block_index);
-            return Ok(blocks_result.blocks.into_iter().next());
+        let range = archived_transactions(&archived.callback, &args).await?;
+        for (offset, transaction) in range.into_iter().enumerate() {
+            transactions.push((archived_start + offset as u64, transaction));
         }
+    }
 
-        if let Some(func) = blocks_result.archived_blocks.into_iter().find_map(|b| {
-            (b.start <= block_index && (block_index - b.start) < b.length).then_some(b.callback)
-        }) {
-            match query_archived_blocks(&func, &args).await {
-                Ok(blocks) => match blocks {
-                    Ok(range) => return Ok(range.blocks.into_iter().next()),
-                    Err(e) => {
-                        return Err(CurrencyError::QueryError(format!(
-                            "Error querying archived blocks: {:?}",
-                            e
-                        )))
-                    }
-                },
-                Err(e) => {
-                    return Err(CurrencyError::QueryError(format!(
-                        "Error querying archived blocks: {:?}",
-                        e
-                    )))
-                }
-            }
-        }
+    let first_index = nat_to_u64(&response.first_index)?;
+    for (offset, transaction) in response.transactions.into_iter().enumerate() {
+        transactions.push((first_index + offset as u64, transaction));
+    }
+
+    Ok((nat_to_u64(&response.log_length)?, transactions))
+}
+
+/// Read `args` from the archive canister `callback` points to
+async fn archived_transactions(
+    callback: &ArchivedRange1Callback,
+    args: &GetBlocksRequest,
+) -> Result<Vec<Transaction>, CurrencyError> {
+    let archive = &callback.0;
+    let (range,): (TransactionRange,) = retry_query(&retry_policy(), |_| async {
+        ic_cdk::call(archive.principal, &archive.method, (args,))
+            .await
+            .map_err(call_error(archive.principal, &archive.method))
+    })
+    .await?;
+    Ok(range.transactions)
+}
+
+/// Read `args` from the archive canister `func` points to
+async fn archived_blocks(
+    func: &QueryArchiveFn,
+    args: &GetBlocksArgs,
+) -> Result<Vec<Block>, CurrencyError> {
+    let archive = Func::from(func.clone());
+    retry_query(&retry_policy(), |_| async {
+        query_archived_blocks(func, args)
+            .await
+            .map_err(call_error(archive.principal, &archive.method))?
+            .map(|range| range.blocks)
+            .map_err(|e| {
+                CurrencyError::QueryError(format!("Error querying archived blocks: {:?}", e))
+            })
+    })
+    .await
+}
+
+/// Read up to `length` ICP blocks starting at `start`, including archived ones.
+/// Returns the chain length together with the blocks and their indices.
+///
+/// Only the contiguous run of blocks starting at `start` is returned, so an archive
+/// answering with fewer blocks than it was asked for cannot leave a gap.
+pub async fn query_block_range(
+    ledger: Principal,
+    start: BlockIndex,
+    length: u64,
+) -> Result<(u64, Vec<(BlockIndex, Block)>), CurrencyError> {
+    let args = GetBlocksArgs { start, length };
+    let response = retry_query(&retry_policy(), |_| async {
+        query_blocks(ledger, &args)
+            .await
+            .map_err(call_error(ledger, "query_blocks"))
+    })
+    .await?;
+
+    let mut ranges = Vec::new();
+    for archived in response.archived_blocks.iter() {
+        let archived_args = GetBlocksArgs {
+            start: archived.start,
+            length: archived.length,
+        };
+        let range = archived_blocks(&archived.callback, &archived_args).await?;
+        ranges.push((archived.start, range));
     }
+    ranges.push((response.first_block_index, response.blocks));
+
+    Ok((response.chain_length, contiguous_blocks(start, ranges)))
+}
+
+/// Number the blocks of `ranges`, each given with the index of its first block, and
+/// keep the run of consecutive indices starting at `start`
+pub fn contiguous_blocks<T>(
+    start: u64,
+    ranges: impl IntoIterator<Item = (u64, Vec<T>)>,
+) -> Vec<(u64, T)> {
+    let mut blocks: Vec<(u64, T)> = ranges
+        .into_iter()
+        .flat_map(|(first, range)| {
+            range
+                .into_iter()
+                .enumerate()
+                .map(move |(offset, block)| (first + offset as u64, block))
+        })
+        .filter(|(index, _)| *index >= start)
+        .collect();
+    blocks.sort_by_key(|(index, _)| *index);
+    blocks.dedup_by_key(|(index, _)| *index);
+
+    let contiguous = blocks
+        .iter()
+        .zip(start..)
+        .take_while(|((index, _), expected)| index == expected)
+        .count();
+    blocks.truncate(contiguous);
+    blocks
+}
+
+fn icp_account(account: AccountIdentifier) -> HistoryAccount {
+    HistoryAccount::AccountIdentifier(account.to_hex())
+}
+
+/// A block without an operation has nothing to report
+fn icp_block_transaction(id: BlockIndex, block: Block) -> Option<HistoryTransaction> {
+    let transaction = block.transaction;
+    let operation = match transaction.operation? {
+        Operation::Mint { to, amount } => HistoryOperation::Mint {
+            to: icp_account(to),
+            amount: amount.e8s() as u128,
+        },
+        Operation::Burn { from, amount } => HistoryOperation::Burn {
+            from: icp_account(from),
+            spender: None,
+            amount: amount.e8s() as u128,
+        },
+        Operation::Transfer {
+            from,
+            to,
+            amount,
+            fee,
+        } => HistoryOperation::Transfer {
+            from: icp_account(from),
+            to: icp_account(to),
+            spender: None,
+            amount: amount.e8s() as u128,
+            fee: Some(fee.e8s() as u128),
+        },
+        Operation::TransferFrom {
+            from,
+            to,
+            spender,
+            amount,
+            fee,
+        } => HistoryOperation::Transfer {
+            from: icp_account(from),
+            to: icp_account(to),
+            spender: Some(icp_account(spender)),
+            amount: amount.e8s() as u128,
+            fee: Some(fee.e8s() as u128),
+        },
+        // `query_blocks` does not return the approved amount
+        Operation::Approve {
+            from,
+            spender,
+            expires_at,
+            fee,
+        } => HistoryOperation::Approve {
+            from: icp_account(from),
+            spender: icp_account(spender),
+            amount: 0,
+            expected_allowance: None,
+            expires_at: expires_at.map(|ts| ts.timestamp_nanos),
+            fee: Some(fee.e8s() as u128),
+        },
+    };
+
+    let memo = match transaction.icrc1_memo {
+        Some(memo) => Some(memo.into_vec()),
+        None if transaction.memo.0 != 0 => Some(transaction.memo.0.to_be_bytes().to_vec()),
+        None => None,
+    };
+
+    Some(HistoryTransaction {
+        id,
+        timestamp: block.timestamp.timestamp_nanos,
+        operation,
+        memo,
+        created_at_time: Some(transaction.created_at_time.timestamp_nanos),
+    })
+}
+
+/// The ICRC transaction of block `block_index`
+#[deprecated(note = "use `LedgerQuery::transaction` instead")]
+pub async fn get_one_block(
+    ledger: Principal,
+    block_index: u64,
+) -> Result<Option<Transaction>, CurrencyError> {
+    let (_, transactions) = icrc_raw_transactions(ledger, block_index, 1).await?;
+    Ok(transactions
+        .into_iter()
+        .find(|(index, _)| *index == block_index)
+        .map(|(_, transaction)| transaction))
+}
 
-    Ok(None)
+/// The ICP block `block_index`
+#[deprecated(note = "use `LedgerQuery::transaction` or `query_block_range` instead")]
+pub async fn query_one_block(
+    ledger: Principal,
+    block_index: BlockIndex,
+) -> Result<Option<Block>, CurrencyError> {
+    let (_, blocks) = query_block_range(ledger, block_index, 1).await?;
+    Ok(blocks.into_iter().next().map(|(_, block)| block))
 }
 
+#[deprecated(note = "use `LedgerQuery::balance_of` instead")]
 pub async fn get_balance(
     ledger: &Principal,
     owner: &Principal,
     subaccount: Option<Vec<u8>>,
 ) -> Result<u128, CurrencyError> {
-    let (res,): (u128,) = ic_cdk::call(*ledger, "icrc1_balance_of", (owner, subaccount))
+    IcLedgerClient::new(*ledger)
+        .balance_of(&Account {
+            owner: *owner,
+            subaccount,
+        })
         .await
-        .map_err(|e| CurrencyError::LedgerError(format!("{:?}", e)))?;
-    Ok(res)
+}
+
+impl CurrencyManager {
+    /// Blocks, archives, balances and allowances of a currency's ledger
+    pub fn ledger_query(&self, currency: &Currency) -> Result<LedgerQuery, CurrencyError> {
+        Ok(LedgerQuery::new(currency, self.get_ledger_id(currency)?))
+    }
 }
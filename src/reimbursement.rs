@@ -0,0 +1,408 @@
+//! Reimbursements of failed withdrawals to Ethereum and Bitcoin.
+//!
+//! When a withdrawal through a minter fails, the minter mints the tokens back to the
+//! canister. Withdrawals made through [`CurrencyManager::withdraw_to_eth_address`]
+//! (or registered with [`track_withdrawal`]) are remembered together with the user
+//! who made them. A minter event stream subscriber matches the minter's reimbursement
+//! events to these withdrawals, records every reimbursement in an audit log and
+//! reports it once, so the caller can credit the user's internal balance.
+
+use std::{borrow::Cow, cell::RefCell};
+
+use candid::{CandidType, Decode, Encode, Principal};
+use ic_stable_structures::{
+    memory_manager::VirtualMemory, storable::Bound, DefaultMemoryImpl, StableBTreeMap, Storable,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    currency_error::CurrencyError,
+    guard::OperationGuard,
+    minter_events::{MinterEvent, MinterEventCategory, MinterEventRecord, MinterEventSubscribers},
+    types::currency::CKTokenSymbol,
+    types::currency_manager::CurrencyManager,
+    Currency,
+};
+
+pub type ReimbursementMemory = VirtualMemory<DefaultMemoryImpl>;
+
+#[derive(Debug, Clone, Serialize, Deserialize, CandidType, PartialEq, Eq)]
+pub enum TrackedWithdrawalStatus {
+    /// Sent to the minter, no failure seen
+    Pending,
+    /// The minter will mint `amount` back
+    ReimbursementScheduled {
+        amount: u128,
+    },
+    Reimbursed {
+        amount: u128,
+        block_index: u128,
+    },
+    /// The minter could not reimburse automatically, the tokens need manual recovery
+    ReimbursementQuarantined,
+}
+
+/// A withdrawal through a minter, kept until it is pruned
+#[derive(Debug, Clone, Serialize, Deserialize, CandidType, PartialEq, Eq)]
+pub struct TrackedWithdrawal {
+    pub minter_id: Principal,
+    /// ckETH withdrawal id, or the ckBTC ledger burn index
+    pub withdrawal_id: u128,
+    pub currency: Currency,
+    pub user: Principal,
+    pub amount: u128,
+    pub destination: String,
+    pub created_at: u64,
+    pub status: TrackedWithdrawalStatus,
+}
+
+impl Storable for TrackedWithdrawal {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).expect("Failed to encode tracked withdrawal"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).expect("Failed to decode tracked withdrawal")
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, CandidType, PartialEq, Eq)]
+pub enum ReimbursementKind {
+    /// The withdrawn tokens came back, credit `amount` to the user
+    Withdrawal,
+    /// The ckETH burned for the gas of a failed ckERC20 withdrawal came back to the canister
+    WithdrawalFee,
+    /// The reimbursement is stuck at the minter, nothing came back
+    Quarantined,
+}
+
+/// Audit log entry of a reimbursement
+#[derive(Debug, Clone, Serialize, Deserialize, CandidType, PartialEq, Eq)]
+pub struct ReimbursementRecord {
+    pub id: u64,
+    pub kind: ReimbursementKind,
+    pub currency: Currency,
+    pub user: Principal,
+    pub withdrawal_id: u128,
+    pub amount: u128,
+    /// Ledger block of the reimbursement mint, `None` for quarantined reimbursements
+    pub block_index: Option<u128>,
+    pub minter_id: Principal,
+    /// Index of the minter event that caused the entry
+    pub event_index: u64,
+    pub recorded_at: u64,
+}
+
+impl Storable for ReimbursementRecord {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).expect("Failed to encode reimbursement record"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        // Audit entries must never be dropped silently
+        Decode!(bytes.as_ref(), Self).expect("Failed to decode reimbursement record")
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+fn withdrawal_key(minter_id: &Principal, withdrawal_id: u128) -> String {
+    format!("{}:{}", minter_id.to_text(), withdrawal_id)
+}
+
+/// Tracked withdrawals and the reimbursement audit log, stored in two dedicated virtual memories
+pub struct ReimbursementTracker {
+    withdrawals: StableBTreeMap<String, TrackedWithdrawal, ReimbursementMemory>,
+    records: StableBTreeMap<u64, ReimbursementRecord, ReimbursementMemory>,
+}
+
+impl ReimbursementTracker {
+    pub fn init(
+        withdrawal_memory: ReimbursementMemory,
+        record_memory: ReimbursementMemory,
+    ) -> Self {
+        Self {
+            withdrawals: StableBTreeMap::init(withdrawal_memory),
+            records: StableBTreeMap::init(record_memory),
+        }
+    }
+
+    pub fn track(&mut self, withdrawal: TrackedWithdrawal) {
+        self.withdrawals.insert(
+            withdrawal_key(&withdrawal.minter_id, withdrawal.withdrawal_id),
+            withdrawal,
+        );
+    }
+
+    pub fn withdrawal(
+        &self,
+        minter_id: &Principal,
+        withdrawal_id: u128,
+    ) -> Option<TrackedWithdrawal> {
+        self.withdrawals
+            .get(&withdrawal_key(minter_id, withdrawal_id))
+    }
+
+    pub fn withdrawals(&self) -> Vec<TrackedWithdrawal> {
+        self.withdrawals.iter().map(|(_, w)| w).collect()
+    }
+
+    pub fn records(&self) -> Vec<ReimbursementRecord> {
+        self.records.iter().map(|(_, record)| record).collect()
+    }
+
+    /// Reimbursement records of one user
+    pub fn records_of(&self, user: &Principal) -> Vec<ReimbursementRecord> {
+        self.records
+            .iter()
+            .map(|(_, record)| record)
+            .filter(|record| record.user == *user)
+            .collect()
+    }
+
+    /// Stop tracking withdrawals created before `before` that saw no failure.
+    /// The audit log is never pruned.
+    pub fn prune(&mut self, before: u64) -> usize {
+        let stale: Vec<String> = self
+            .withdrawals
+            .iter()
+            .filter(|(_, w)| w.created_at < before && w.status == TrackedWithdrawalStatus::Pending)
+            .map(|(key, _)| key)
+            .collect();
+
+        for key in stale.iter() {
+            self.withdrawals.remove(key);
+        }
+        stale.len()
+    }
+
+    fn set_status(&mut self, withdrawal: &TrackedWithdrawal, status: TrackedWithdrawalStatus) {
+        let mut updated = withdrawal.clone();
+        updated.status = status;
+        self.track(updated);
+    }
+
+    fn record(
+        &mut self,
+        event: &MinterEventRecord,
+        withdrawal: &TrackedWithdrawal,
+        kind: ReimbursementKind,
+        currency: Currency,
+        amount: u128,
+        block_index: Option<u128>,
+    ) -> ReimbursementRecord {
+        let id = self
+            .records
+            .last_key_value()
+            .map(|(id, _)| id + 1)
+            .unwrap_or(0);
+
+        let record = ReimbursementRecord {
+            id,
+            kind,
+            currency,
+            user: withdrawal.user,
+            withdrawal_id: withdrawal.withdrawal_id,
+            amount,
+            block_index,
+            minter_id: event.minter_id,
+            event_index: event.index,
+            recorded_at: ic_cdk::api::time(),
+        };
+        self.records.insert(id, record.clone());
+        record
+    }
+
+    /// Apply one minter event, returns the audit entry if the event concerned a tracked withdrawal
+    fn apply(&mut self, event: &MinterEventRecord) -> Option<ReimbursementRecord> {
+        match &event.event {
+            MinterEvent::EthWithdrawalReimbursed {
+                withdrawal_id,
+                reimbursed_amount,
+                reimbursed_in_block,
+                ..
+            } => {
+                let withdrawal = self.withdrawal(&event.minter_id, *withdrawal_id)?;
+                self.set_status(
+                    &withdrawal,
+                    TrackedWithdrawalStatus::Reimbursed {
+                        amount: *reimbursed_amount,
+                        block_index: *reimbursed_in_block,
+                    },
+                );
+                Some(self.record(
+                    event,
+                    &withdrawal,
+                    ReimbursementKind::Withdrawal,
+                    withdrawal.currency,
+                    *reimbursed_amount,
+                    Some(*reimbursed_in_block),
+                ))
+            }
+            MinterEvent::EthWithdrawalFailed {
+                withdrawal_id,
+                reimbursed_amount,
+                ..
+            } => {
+                let withdrawal = self.withdrawal(&event.minter_id, *withdrawal_id)?;
+                Some(self.record(
+                    event,
+                    &withdrawal,
+                    ReimbursementKind::WithdrawalFee,
+                    Currency::CKETHToken(CKTokenSymbol::ETH),
+                    *reimbursed_amount,
+                    None,
+                ))
+            }
+            MinterEvent::EthReimbursementQuarantined { withdrawal_id, .. } => {
+                let withdrawal = self.withdrawal(&event.minter_id, *withdrawal_id)?;
+                self.set_status(
+                    &withdrawal,
+                    TrackedWithdrawalStatus::ReimbursementQuarantined,
+                );
+                Some(self.record(
+                    event,
+                    &withdrawal,
+                    ReimbursementKind::Quarantined,
+                    withdrawal.currency,
+                    0,
+                    None,
+                ))
+            }
+            MinterEvent::BtcDepositReimbursementScheduled {
+                burn_block_index,
+                amount,
+                ..
+            } => {
+                let withdrawal = self.withdrawal(&event.minter_id, *burn_block_index as u128)?;
+                self.set_status(
+                    &withdrawal,
+                    TrackedWithdrawalStatus::ReimbursementScheduled {
+                        amount: *amount as u128,
+                    },
+                );
+                None
+            }
+            MinterEvent::BtcDepositReimbursed {
+                burn_block_index,
+                mint_block_index,
+            } => {
+                let withdrawal = self.withdrawal(&event.minter_id, *burn_block_index as u128)?;
+                let amount = match withdrawal.status {
+                    TrackedWithdrawalStatus::ReimbursementScheduled { amount } => amount,
+                    _ => withdrawal.amount,
+                };
+                self.set_status(
+                    &withdrawal,
+                    TrackedWithdrawalStatus::Reimbursed {
+                        amount,
+                        block_index: *mint_block_index as u128,
+                    },
+                );
+                Some(self.record(
+                    event,
+                    &withdrawal,
+                    ReimbursementKind::Withdrawal,
+                    withdrawal.currency,
+                    amount,
+                    Some(*mint_block_index as u128),
+                ))
+            }
+            _ => None,
+        }
+    }
+}
+
+thread_local! {
+    static TRACKER: RefCell<Option<ReimbursementTracker>> = const { RefCell::new(None) };
+}
+
+/// Initialise the tracker on two memories obtained from the canister's `MemoryManager`.
+/// Must be called from both `init` and `post_upgrade`.
+pub fn init_reimbursement_tracker(
+    withdrawal_memory: ReimbursementMemory,
+    record_memory: ReimbursementMemory,
+) {
+    TRACKER.with(|tracker| {
+        *tracker.borrow_mut() = Some(ReimbursementTracker::init(withdrawal_memory, record_memory))
+    });
+}
+
+/// Run a closure against the tracker
+pub fn with_reimbursement_tracker<R>(
+    f: impl FnOnce(&mut ReimbursementTracker) -> R,
+) -> Result<R, CurrencyError> {
+    TRACKER.with(|tracker| match tracker.borrow_mut().as_mut() {
+        Some(tracker) => Ok(f(tracker)),
+        None => Err(CurrencyError::ReimbursementTrackerNotInitialized),
+    })
+}
+
+/// Remember a withdrawal made outside this crate so its reimbursement is matched,
+/// e.g. a `retrieve_btc_with_approval` whose burn index is the `withdrawal_id`
+pub fn track_withdrawal(withdrawal: TrackedWithdrawal) -> Result<(), CurrencyError> {
+    with_reimbursement_tracker(|tracker| tracker.track(withdrawal))
+}
+
+/// Subscribe `on_reimbursed` to the reimbursements of tracked withdrawals.
+///
+/// Every audit entry is reported once; credit the user for `ReimbursementKind::Withdrawal`
+/// entries only.
+pub fn subscribe_reimbursements(
+    subscribers: MinterEventSubscribers,
+    on_reimbursed: impl Fn(&ReimbursementRecord) + 'static,
+) -> MinterEventSubscribers {
+    subscribers.subscribe(
+        &[
+            MinterEventCategory::Reimbursement,
+            MinterEventCategory::Quarantine,
+        ],
+        move |_, event| match with_reimbursement_tracker(|tracker| tracker.apply(event)) {
+            Ok(Some(record)) => on_reimbursed(&record),
+            Ok(None) => {}
+            // Trap so the cursor is not advanced past the event
+            Err(e) => ic_cdk::trap(e.to_string()),
+        },
+    )
+}
+
+impl CurrencyManager {
+    /// Withdraw a user's ckETH/ckERC20 tokens to an Ethereum address and track the
+    /// withdrawal, so a reimbursement by the minter is credited back to the user
+    pub async fn withdraw_to_eth_address(
+        &self,
+        currency: &Currency,
+        user: Principal,
+        eth_address: String,
+        amount: u64,
+    ) -> Result<TrackedWithdrawal, CurrencyError> {
+        let _guard = OperationGuard::new(user, *currency)?;
+        // Fail before withdrawing rather than losing track of a withdrawal
+        with_reimbursement_tracker(|_| ())?;
+
+        let wallet = self
+            .ckerc20_tokens
+            .iter()
+            .find(|w| w.config.token_symbol == *currency)
+            .ok_or(CurrencyError::WalletNotSet)?;
+
+        let withdrawal_id = wallet
+            .request_eth_withdrawal(eth_address.clone(), amount)
+            .await?;
+
+        let withdrawal = TrackedWithdrawal {
+            minter_id: wallet.config.minter_id,
+            withdrawal_id,
+            currency: *currency,
+            user,
+            amount: amount as u128,
+            destination: eth_address,
+            created_at: ic_cdk::api::time(),
+            status: TrackedWithdrawalStatus::Pending,
+        };
+        track_withdrawal(withdrawal.clone())?;
+        Ok(withdrawal)
+    }
+}
@@ -0,0 +1,151 @@
+//! Retry policy for inter-canister calls.
+//!
+//! Only errors classified as retryable by [`CurrencyError::is_retryable`] are retried,
+//! waiting on a one-shot timer between attempts. Read-only calls go through
+//! [`retry_query`]. Update calls go through [`retry_update`], which requires the
+//! caller to state why sending the call again cannot execute it twice.
+
+use std::{cell::RefCell, future::Future, time::Duration};
+
+use futures::channel::oneshot;
+
+use crate::currency_error::CurrencyError;
+
+/// Ledgers only deduplicate transactions created within the last 24 hours.
+/// Replaying closer than this to the edge of the window is not attempted.
+pub(crate) const DEDUP_WINDOW_NANOS: u64 = 23 * 60 * 60 * 1_000_000_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Total number of attempts, including the first one
+    pub max_attempts: u32,
+    /// Wait before the first retry, doubled for every further retry
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl RetryPolicy {
+    pub const fn new(max_attempts: u32, initial_backoff: Duration, max_backoff: Duration) -> Self {
+        Self {
+            max_attempts,
+            initial_backoff,
+            max_backoff,
+        }
+    }
+
+    /// Make every call exactly once
+    pub const fn no_retry() -> Self {
+        Self::new(1, Duration::ZERO, Duration::ZERO)
+    }
+
+    /// Wait before retry number `retry`, starting at 1
+    pub fn backoff(&self, retry: u32) -> Duration {
+        let factor = 1u32 << retry.saturating_sub(1).min(16);
+        self.initial_backoff
+            .saturating_mul(factor)
+            .min(self.max_backoff)
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self::new(3, Duration::from_millis(500), Duration::from_secs(5))
+    }
+}
+
+/// Why an update call can safely be sent more than once
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Idempotency {
+    /// The ledger deduplicates transactions with this `created_at_time`, as long as
+    /// every attempt carries it and is made within the dedup window
+    CreatedAt(u64),
+    /// Calling the method again has no further effect, e.g. the minter's `update_balance`
+    Inherent,
+}
+
+thread_local! {
+    static RETRY_POLICY: RefCell<RetryPolicy> = RefCell::new(RetryPolicy::default());
+}
+
+/// Set the policy used by the queries, transfers and wallets of this crate
+pub fn set_retry_policy(policy: RetryPolicy) {
+    RETRY_POLICY.with(|p| *p.borrow_mut() = policy);
+}
+
+pub fn retry_policy() -> RetryPolicy {
+    RETRY_POLICY.with(|p| *p.borrow())
+}
+
+/// Resolve after `delay`, using a one-shot timer
+async fn sleep(delay: Duration) {
+    let (sender, receiver) = oneshot::channel();
+    ic_cdk_timers::set_timer(delay, move || {
+        let _ = sender.send(());
+    });
+    let _ = receiver.await;
+}
+
+async fn run<F, Fut, T>(
+    policy: &RetryPolicy,
+    may_retry: impl Fn() -> bool,
+    call: F,
+) -> Result<T, CurrencyError>
+where
+    F: Fn(u32) -> Fut,
+    Fut: Future<Output = Result<T, CurrencyError>>,
+{
+    let mut attempt = 0;
+    let mut outcome_unknown = false;
+    loop {
+        match call(attempt).await {
+            Err(e) if e.is_retryable() && attempt + 1 < policy.max_attempts && may_retry() => {
+                outcome_unknown |= e.is_outcome_unknown();
+                attempt += 1;
+                ic_cdk::println!("Retrying call (attempt {}) after: {}", attempt + 1, e);
+                let delay = policy.backoff(attempt);
+                if !delay.is_zero() {
+                    sleep(delay).await;
+                }
+            }
+            // A clean reject of the last attempt says nothing about an earlier one
+            Err(CurrencyError::CallRejected(e)) if outcome_unknown => {
+                return Err(CurrencyError::CallOutcomeUnknown(e))
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Run a read-only call, retrying it on retryable errors.
+///
+/// `call` receives the attempt number, starting at 0.
+pub async fn retry_query<F, Fut, T>(policy: &RetryPolicy, call: F) -> Result<T, CurrencyError>
+where
+    F: Fn(u32) -> Fut,
+    Fut: Future<Output = Result<T, CurrencyError>>,
+{
+    run(policy, || true, call).await
+}
+
+/// Run an update call, retrying it on retryable errors.
+///
+/// `call` receives the attempt number, starting at 0. With [`Idempotency::CreatedAt`]
+/// every attempt must send that `created_at_time`, and retries stop once the ledger
+/// would no longer deduplicate it.
+pub async fn retry_update<F, Fut, T>(
+    policy: &RetryPolicy,
+    idempotency: Idempotency,
+    call: F,
+) -> Result<T, CurrencyError>
+where
+    F: Fn(u32) -> Fut,
+    Fut: Future<Output = Result<T, CurrencyError>>,
+{
+    let may_retry = || match idempotency {
+        Idempotency::CreatedAt(created_at_time) => {
+            ic_cdk::api::time().saturating_sub(created_at_time) < DEDUP_WINDOW_NANOS
+        }
+        Idempotency::Inherent => true,
+    };
+    run(policy, may_retry, call).await
+}
@@ -0,0 +1,268 @@
+//! Versioned stable-memory encoding for the crate's persistent types.
+//!
+//! Every value is written as a single version byte followed by its candid encoding.
+//! Values written before versioning was introduced start with the candid magic
+//! bytes (`DIDL`) and are read as version 0. Decoding never falls back to a default:
+//! a value that cannot be read is reported as an error so the upgrade can be aborted
+//! instead of silently wiping the configuration.
+
+use candid::{CandidType, Decode, Encode};
+use ic_stable_structures::{
+    memory_manager::{MemoryId, MemoryManager},
+    DefaultMemoryImpl, Memory,
+};
+use serde::de::DeserializeOwned;
+
+use crate::{
+    currency_error::CurrencyError,
+    cketh_deposit::init_eth_deposit_watcher,
+    deposit_subaccount::init_sweep_log,
+    deposit_watcher::init_deposit_watcher,
+    minter_events::init_minter_event_stream,
+    outbox::init_withdrawal_outbox,
+    reimbursement::init_reimbursement_tracker,
+    state::TransactionState,
+    types::{
+        currency_manager::CurrencyManager,
+        token_registry::ICRC1TokenRegistry,
+    },
+};
+
+const CANDID_MAGIC: &[u8; 4] = b"DIDL";
+const WASM_PAGE_SIZE: u64 = 65536;
+const LENGTH_PREFIX_SIZE: u64 = 8;
+
+/// Version of unversioned candid data written by earlier releases
+pub const LEGACY_VERSION: u8 = 0;
+
+/// A type stored in stable memory with an explicit schema version.
+///
+/// Bump `VERSION` whenever the candid shape of the type changes in a way that
+/// candid subtyping cannot absorb, and handle the previous version in `migrate`.
+/// Versions must stay below `0x44` so they cannot be mistaken for legacy data.
+pub trait VersionedStorable: CandidType + DeserializeOwned + Sized {
+    const VERSION: u8;
+
+    /// Name used in error messages
+    const NAME: &'static str;
+
+    /// Decode `bytes` written with an older (or the current) `version`
+    fn migrate(version: u8, bytes: &[u8]) -> Result<Self, CurrencyError> {
+        match version {
+            v if v <= Self::VERSION => decode_candid(Self::NAME, bytes),
+            v => Err(CurrencyError::SerializationError(format!(
+                "{} was written with version {} but this build only supports up to {}",
+                Self::NAME,
+                v,
+                Self::VERSION
+            ))),
+        }
+    }
+}
+
+fn decode_candid<T: CandidType + DeserializeOwned>(
+    name: &str,
+    bytes: &[u8],
+) -> Result<T, CurrencyError> {
+    Decode!(bytes, T).map_err(|e| {
+        CurrencyError::SerializationError(format!("Failed to decode {}: {}", name, e))
+    })
+}
+
+/// Encode a value as `[version][candid]`
+pub fn encode_versioned<T: VersionedStorable>(value: &T) -> Result<Vec<u8>, CurrencyError> {
+    let candid = Encode!(value).map_err(|e| {
+        CurrencyError::SerializationError(format!("Failed to encode {}: {}", T::NAME, e))
+    })?;
+
+    let mut bytes = Vec::with_capacity(candid.len() + 1);
+    bytes.push(T::VERSION);
+    bytes.extend_from_slice(&candid);
+    Ok(bytes)
+}
+
+/// Decode a value written by [`encode_versioned`] or by a pre-versioning release
+pub fn decode_versioned<T: VersionedStorable>(bytes: &[u8]) -> Result<T, CurrencyError> {
+    if bytes.starts_with(CANDID_MAGIC) {
+        return T::migrate(LEGACY_VERSION, bytes);
+    }
+
+    match bytes.split_first() {
+        Some((version, candid)) => T::migrate(*version, candid),
+        None => Err(CurrencyError::SerializationError(format!(
+            "{} is empty",
+            T::NAME
+        ))),
+    }
+}
+
+/// Write a value to a dedicated memory as `[length][version][candid]`
+pub fn save<T: VersionedStorable, M: Memory>(memory: &M, value: &T) -> Result<(), CurrencyError> {
+    let bytes = encode_versioned(value)?;
+    let required = LENGTH_PREFIX_SIZE + bytes.len() as u64;
+    let available = memory.size() * WASM_PAGE_SIZE;
+
+    if required > available {
+        let missing_pages = (required - available).div_ceil(WASM_PAGE_SIZE);
+        if memory.grow(missing_pages) < 0 {
+            return Err(CurrencyError::SerializationError(format!(
+                "Failed to grow stable memory for {}",
+                T::NAME
+            )));
+        }
+    }
+
+    memory.write(0, &(bytes.len() as u64).to_le_bytes());
+    memory.write(LENGTH_PREFIX_SIZE, &bytes);
+    Ok(())
+}
+
+/// Read a value written by [`save`], `None` if nothing was saved yet
+pub fn load<T: VersionedStorable, M: Memory>(memory: &M) -> Result<Option<T>, CurrencyError> {
+    if memory.size() == 0 {
+        return Ok(None);
+    }
+
+    let mut length = [0u8; LENGTH_PREFIX_SIZE as usize];
+    memory.read(0, &mut length);
+    let length = u64::from_le_bytes(length);
+    if length == 0 {
+        return Ok(None);
+    }
+
+    if LENGTH_PREFIX_SIZE + length > memory.size() * WASM_PAGE_SIZE {
+        return Err(CurrencyError::SerializationError(format!(
+            "Stored {} length {} exceeds its memory",
+            T::NAME,
+            length
+        )));
+    }
+
+    let mut bytes = vec![0u8; length as usize];
+    memory.read(LENGTH_PREFIX_SIZE, &mut bytes);
+    decode_versioned(&bytes).map(Some)
+}
+
+impl VersionedStorable for CurrencyManager {
+    const VERSION: u8 = 1;
+    const NAME: &'static str = "CurrencyManager";
+}
+
+impl VersionedStorable for TransactionState {
+    const VERSION: u8 = 1;
+    const NAME: &'static str = "TransactionState";
+}
+
+impl VersionedStorable for ICRC1TokenRegistry {
+    const VERSION: u8 = 1;
+    const NAME: &'static str = "ICRC1TokenRegistry";
+}
+
+/// Memory ids used for the crate's state inside the canister's `MemoryManager`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CurrencyMemoryIds {
+    pub currency_manager: MemoryId,
+    pub transaction_state: MemoryId,
+    pub token_registry: MemoryId,
+    pub withdrawal_outbox: MemoryId,
+    pub deposit_watcher: MemoryId,
+    pub minter_event_cursors: MemoryId,
+    pub pending_eth_deposits: MemoryId,
+    pub tracked_withdrawals: MemoryId,
+    pub reimbursements: MemoryId,
+    pub outbox_next_id: MemoryId,
+    pub pending_sweeps: MemoryId,
+}
+
+impl CurrencyMemoryIds {
+    /// Use eleven consecutive memory ids starting at `first`
+    pub const fn starting_at(first: u8) -> Self {
+        Self {
+            currency_manager: MemoryId::new(first),
+            transaction_state: MemoryId::new(first + 1),
+            token_registry: MemoryId::new(first + 2),
+            withdrawal_outbox: MemoryId::new(first + 3),
+            deposit_watcher: MemoryId::new(first + 4),
+            minter_event_cursors: MemoryId::new(first + 5),
+            pending_eth_deposits: MemoryId::new(first + 6),
+            tracked_withdrawals: MemoryId::new(first + 7),
+            reimbursements: MemoryId::new(first + 8),
+            outbox_next_id: MemoryId::new(first + 9),
+            pending_sweeps: MemoryId::new(first + 10),
+        }
+    }
+}
+
+/// The crate's state as saved in `pre_upgrade` and restored in `post_upgrade`.
+/// The manager's token registry is saved to its own memory.
+#[derive(Debug, Clone)]
+pub struct CurrencyStableState {
+    pub currency_manager: CurrencyManager,
+    pub transaction_state: TransactionState,
+}
+
+impl CurrencyStableState {
+    /// Save all values, call from `pre_upgrade`
+    pub fn save(
+        &self,
+        memory_manager: &MemoryManager<DefaultMemoryImpl>,
+        ids: CurrencyMemoryIds,
+    ) -> Result<(), CurrencyError> {
+        let currency_manager = CurrencyManager {
+            token_registry: None,
+            ..self.currency_manager.clone()
+        };
+        save(&memory_manager.get(ids.currency_manager), &currency_manager)?;
+        save(&memory_manager.get(ids.transaction_state), &self.transaction_state)?;
+        save(
+            &memory_manager.get(ids.token_registry),
+            &self.currency_manager.token_registry(),
+        )
+    }
+
+    /// Load all values and initialise the stable structures, call from `post_upgrade`.
+    ///
+    /// Values that were never saved start from their defaults; values that fail to decode
+    /// return an error, which the upgrade hook should turn into a trap so the
+    /// upgrade is rolled back.
+    pub fn load(
+        memory_manager: &MemoryManager<DefaultMemoryImpl>,
+        ids: CurrencyMemoryIds,
+    ) -> Result<Self, CurrencyError> {
+        let mut currency_manager: CurrencyManager =
+            load(&memory_manager.get(ids.currency_manager))?.unwrap_or_default();
+        if let Some(token_registry) = load(&memory_manager.get(ids.token_registry))? {
+            currency_manager.token_registry = Some(token_registry);
+        }
+
+        let state = Self {
+            currency_manager,
+            transaction_state: load(&memory_manager.get(ids.transaction_state))?
+                .unwrap_or_default(),
+        };
+
+        init_stable_structures(memory_manager, ids);
+        Ok(state)
+    }
+}
+
+/// Initialise the withdrawal outbox, the deposit watchers, the minter event stream,
+/// the reimbursement tracker and the sweep log on their memories,
+/// call from `init` and `post_upgrade`
+pub fn init_stable_structures(
+    memory_manager: &MemoryManager<DefaultMemoryImpl>,
+    ids: CurrencyMemoryIds,
+) {
+    init_withdrawal_outbox(
+        memory_manager.get(ids.withdrawal_outbox),
+        memory_manager.get(ids.outbox_next_id),
+    );
+    init_deposit_watcher(memory_manager.get(ids.deposit_watcher));
+    init_minter_event_stream(memory_manager.get(ids.minter_event_cursors));
+    init_eth_deposit_watcher(memory_manager.get(ids.pending_eth_deposits));
+    init_reimbursement_tracker(
+        memory_manager.get(ids.tracked_withdrawals),
+        memory_manager.get(ids.reimbursements),
+    );
+    init_sweep_log(memory_manager.get(ids.pending_sweeps));
+}
@@ -1,10 +1,14 @@
-use candid::{CandidType, Decode, Encode};
+use candid::CandidType;
 use ic_stable_structures::{storable::Bound, Storable};
 use serde::{Deserialize, Serialize};
 use std::{borrow::Cow, collections::HashSet};
 
+use crate::stable_storage::{decode_versioned, encode_versioned};
+
 const REMOVE_PERCENTAGE: usize = 20;
 const MAX_VALUE_SIZE_TRANSACTION_STATE: u32 = 2_000_000;
+/// Transaction ids kept before the oldest are evicted
+pub const MAX_TRANSACTIONS: usize = MAX_VALUE_SIZE_TRANSACTION_STATE as usize / 100;
 
 #[derive(Debug, Clone, PartialEq, CandidType, Deserialize, Serialize)]
 pub struct TransactionState {
@@ -24,22 +28,24 @@ impl TransactionState {
         }
     }
 
+    /// Record a processed transaction id.
+    ///
+    /// Once `MAX_TRANSACTIONS` ids are stored, the oldest fifth is evicted first, ordered
+    /// by the timestamp after the id's last hyphen. Ids without a timestamp count as the
+    /// oldest; they are evicted within that fifth rather than all at once.
     pub fn add_transaction(&mut self, transaction_id: String) {
-        if self.processed_transactions.len() >= MAX_VALUE_SIZE_TRANSACTION_STATE as usize / 100 {
+        if self.processed_transactions.len() >= MAX_TRANSACTIONS {
             // Extract timestamps and sort
             let mut transactions: Vec<(i64, String)> = self
                 .processed_transactions
                 .iter()
-                .filter_map(|tx| {
-                    // Split by last hyphen to get timestamp
-                    let parts: Vec<&str> = tx.rsplitn(2, '-').collect();
-                    if parts.len() == 2 {
-                        // Parse timestamp
-                        if let Ok(timestamp) = parts[0].parse::<i64>() {
-                            return Some((timestamp, tx.to_string()));
-                        }
-                    }
-                    None
+                .map(|tx| {
+                    // Split by last hyphen to get timestamp, ids without one count as oldest
+                    let timestamp = tx
+                        .rsplit_once('-')
+                        .and_then(|(_, timestamp)| timestamp.parse::<i64>().ok())
+                        .unwrap_or(i64::MIN);
+                    (timestamp, tx.to_string())
                 })
                 .collect();
 
@@ -66,25 +72,19 @@ impl TransactionState {
 }
 
 impl Storable for TransactionState {
-    /// Serializes the struct into a byte array.
-    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
-        match Encode!(self) {
-            Ok(bytes) => Cow::Owned(bytes),
-            Err(e) => {
-                ic_cdk::println!("Serialization error: {:?}", e);
-                // Return empty state bytes rather than empty vec
-                let empty_state = TransactionState::new();
-                Cow::Owned(Encode!(&empty_state).unwrap_or_else(|_| vec![]))
-            }
-        }
+    /// Serializes the struct into a versioned byte array.
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(
+            encode_versioned(self)
+                .unwrap_or_else(|e| panic!("TransactionState serialization error: {}", e)),
+        )
     }
 
-    /// Deserializes the struct from a byte array.
+    /// Deserializes the struct from a byte array, trapping on failure so the
+    /// record of processed transactions is never silently reset.
     fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
-        Decode!(bytes.as_ref(), Self).unwrap_or_else(|e| {
-            ic_cdk::println!("Deserialization error: {:?}", e);
-            TransactionState::default()
-        })
+        decode_versioned(bytes.as_ref())
+            .unwrap_or_else(|e| panic!("TransactionState deserialization error: {}", e))
     }
 
     const BOUND: Bound = Bound::Bounded {
@@ -2,8 +2,13 @@ use candid::Principal;
 use ic_ledger_types::{AccountIdentifier, Subaccount, MAINNET_LEDGER_CANISTER_ID};
 
 use crate::{
-    currency_error::CurrencyError,
-    icrc1_types::{Account, TransferArg, TransferErrorIcrc1},
+    currency_error::{call_error, CurrencyError},
+    icrc1_types::{Account, TransferArg},
+    ledger_client::{
+        accept_duplicate, net_of_fee, replay_withdrawal_to, withdraw_to, IcLedgerClient,
+        LedgerClient,
+    },
+    retry::{retry_policy, retry_update, Idempotency},
 };
 
 pub async fn transfer_icp(
@@ -11,40 +16,82 @@ pub async fn transfer_icp(
     default_subaccount: Subaccount,
     to: Principal,
 ) -> Result<(), CurrencyError> {
-    let transfer_result = ic_ledger_types::transfer(
-        MAINNET_LEDGER_CANISTER_ID,
-    &ic_ledger_types::TransferArgs {
-            memo: ic_ledger_types::Memo(0), // Use an appropriate memo
-            amount: ic_ledger_types::Tokens::from_e8s(amount - ic_ledger_types::DEFAULT_FEE.e8s()),
-            fee: ic_ledger_types::DEFAULT_FEE,
-            from_subaccount: Some(default_subaccount),
-            to: AccountIdentifier::new(&to, &ic_ledger_types::DEFAULT_SUBACCOUNT),
-            created_at_time: None, // Optionally specify a time
-        },
-    )
-    .await;
+    send_icp(amount, default_subaccount, to, ic_cdk::api::time(), false).await?;
+    Ok(())
+}
+
+/// Transfers ICP with a caller-chosen `created_at_time`.
+///
+/// Retrying with the same `created_at_time` within the ledger's dedup window
+/// does not move funds twice; the ledger reports the original block instead,
+/// which is returned as a success. `created_at_time` must therefore be persisted
+/// before the first attempt.
+///
+/// Uses the ICP ledger's `transfer` endpoint rather than [`LedgerClient`], so the
+/// transactions keep the account identifiers and memo of earlier releases.
+pub async fn transfer_icp_at(
+    amount: u64,
+    default_subaccount: Subaccount,
+    to: Principal,
+    created_at_time: u64,
+) -> Result<u64, CurrencyError> {
+    send_icp(amount, default_subaccount, to, created_at_time, true).await
+}
+
+/// A `TxDuplicate` answer is the original block for a retry or a `replayed` timestamp,
+/// otherwise an identical transfer made earlier and returned as an error
+async fn send_icp(
+    amount: u64,
+    default_subaccount: Subaccount,
+    to: Principal,
+    created_at_time: u64,
+    replayed: bool,
+) -> Result<u64, CurrencyError> {
+    let net_amount = net_of_fee(amount as u128, ic_ledger_types::DEFAULT_FEE.e8s() as u128)?;
+    let args = ic_ledger_types::TransferArgs {
+        memo: ic_ledger_types::Memo(0), // Use an appropriate memo
+        amount: ic_ledger_types::Tokens::from_e8s(net_amount as u64),
+        fee: ic_ledger_types::DEFAULT_FEE,
+        from_subaccount: Some(default_subaccount),
+        to: AccountIdentifier::new(&to, &ic_ledger_types::DEFAULT_SUBACCOUNT),
+        created_at_time: Some(ic_ledger_types::Timestamp {
+            timestamp_nanos: created_at_time,
+        }),
+    };
+
+    retry_update(
+        &retry_policy(),
+        Idempotency::CreatedAt(created_at_time),
+        |attempt| {
+            let args = &args;
+            async move {
+                let result = ic_ledger_types::transfer(MAINNET_LEDGER_CANISTER_ID, args)
+                    .await
+                    .map_err(call_error(MAINNET_LEDGER_CANISTER_ID, "transfer"))?;
 
-    match transfer_result {
-        Ok(result) => match result {
-            Ok(block_index) => ic_cdk::api::print(format!(
-                "Transfer successful with block index {}",
-                block_index
-            )),
-            Err(e) => {
-                return Err(CurrencyError::LedgerError(format!(
-                    "Transfer failed: {:?}",
-                    e
-                )))
+                match result {
+                    Ok(block_index) => {
+                        ic_cdk::api::print(format!(
+                            "Transfer successful with block index {}",
+                            block_index
+                        ));
+                        Ok(block_index)
+                    }
+                    Err(ic_ledger_types::TransferError::TxDuplicate { duplicate_of })
+                        if replayed || attempt > 0 =>
+                    {
+                        ic_cdk::api::print(format!(
+                            "Transfer already executed in block index {}",
+                            duplicate_of
+                        ));
+                        Ok(duplicate_of)
+                    }
+                    Err(e) => Err(CurrencyError::LedgerRejected(e.into())),
+                }
             }
         },
-        Err(e) => {
-            return Err(CurrencyError::LedgerError(format!(
-                "ICDK call error: {:?}",
-                e
-            )))
-        }
-    }
-    Ok(())
+    )
+    .await
 }
 
 // Adjusted transfer_icrc1 function
@@ -61,39 +108,76 @@ pub async fn transfer_icrc1(
         &to_account,
     );
 
-    let transfer_args = TransferArg {
-        to: Account {
+    withdraw_to(
+        &IcLedgerClient::new(ledger_canister_id),
+        None,
+        Account {
             owner: to_account,
             subaccount: Some(default_subaccount),
         },
+        amount as u128,
         fee,
-        amount: (amount as u128 - fee.unwrap_or(ic_ledger_types::DEFAULT_FEE.e8s().into())).into(),
-        memo: None,
-        from_subaccount: None,
-        created_at_time: ic_cdk::api::time().into(),
-    };
+        ic_cdk::api::time(),
+    )
+    .await
+}
 
-    // Call the icrc1_transfer method
-    let transfer_result: Result<(Result<u128, TransferErrorIcrc1>,), _> =
-        ic_cdk::call(ledger_canister_id, "icrc1_transfer", (transfer_args,)).await;
+/// Transfers ICRC-1 tokens with a caller-chosen `created_at_time`.
+///
+/// Like [`transfer_icp_at`], a `Duplicate` answer from the ledger means the
+/// transfer was already executed and is returned as the original block index,
+/// so `created_at_time` must be persisted before the first attempt.
+pub async fn transfer_icrc1_at(
+    ledger_canister_id: Principal,
+    amount: u64,
+    default_subaccount: Vec<u8>,
+    to_account: Principal,
+    fee: Option<u128>,
+    created_at_time: u64,
+) -> Result<u128, CurrencyError> {
+    ic_cdk::println!(
+        "Transferring {} tokens to account {:?}",
+        amount,
+        &to_account,
+    );
 
-    ic_cdk::println!("Transfer result: {:?}", transfer_result);
+    replay_withdrawal_to(
+        &IcLedgerClient::new(ledger_canister_id),
+        None,
+        Account {
+            owner: to_account,
+            subaccount: Some(default_subaccount),
+        },
+        amount as u128,
+        fee,
+        created_at_time,
+    )
+    .await
+}
 
-    match transfer_result {
-        Ok((Ok(block_index),)) => {
-            ic_cdk::api::print(format!(
-                "Transfer successful with block index {}",
-                block_index
-            ));
-            Ok(block_index)
-        }
-        Ok((Err(e),)) => Err(CurrencyError::LedgerError(format!(
-            "Ledger transfer error: {:?}",
-            e
-        ))),
-        Err((rejection_code, message)) => Err(CurrencyError::LedgerError(format!(
-            "Failed to call ledger: {:?} {}",
-            rejection_code, message
-        ))),
-    }
+/// Transfers `amount` (fee paid on top) from one of the canister's subaccounts.
+///
+/// Like [`transfer_icrc1_at`], a duplicate of an earlier transfer is returned as a success.
+pub async fn transfer_icrc1_from_subaccount_at(
+    ledger_canister_id: Principal,
+    amount: u128,
+    from_subaccount: [u8; 32],
+    to: Account,
+    fee: Option<u128>,
+    created_at_time: u64,
+) -> Result<u128, CurrencyError> {
+    let transfer_args = TransferArg {
+        to,
+        fee,
+        amount,
+        memo: None,
+        from_subaccount: Some(from_subaccount.to_vec()),
+        created_at_time: Some(created_at_time),
+    };
+
+    accept_duplicate(
+        IcLedgerClient::new(ledger_canister_id)
+            .transfer(transfer_args)
+            .await,
+    )
 }
@@ -1,6 +1,6 @@
 use candid::Principal;
 
-use crate::{currency_error::CurrencyError, state::TransactionState};
+use crate::{currency_error::CurrencyError, ledger_client::DepositCheck, state::TransactionState};
 
 use super::canister_wallets::{
     ckerc20_token_wallet::CKERC20TokenWallet, icp_canister_wallet::ICPCanisterWallet,
@@ -20,13 +20,20 @@ pub(crate) trait CanisterWallet: Send + Sync {
         amount: u64,
     ) -> Result<(), CurrencyError>;
 
-    /** Validate the allowance granted by a user to this canister */
+    /** Validate the allowance granted by a user to this canister, covering the amount and the fee */
     async fn validate_allowance(
         &self,
         from_principal: Principal,
         amount: u64,
     ) -> Result<(), CurrencyError>;
 
+    /** Report what a deposit needs from the user: allowance, balance and fee */
+    async fn check_deposit(
+        &self,
+        from_principal: Principal,
+        amount: u64,
+    ) -> Result<DepositCheck, CurrencyError>;
+
     /** Withdraw from the canisters wallet to a given address */
     async fn withdraw(
         &self,
@@ -34,6 +41,14 @@ pub(crate) trait CanisterWallet: Send + Sync {
         amount: u64,
     ) -> Result<(), CurrencyError>;
 
+    /** Withdraw with a fixed created_at_time so retries are deduplicated by the ledger, returns the block index */
+    async fn withdraw_at(
+        &self,
+        wallet_principal_id: Principal,
+        amount: u64,
+        created_at_time: u64,
+    ) -> Result<u128, CurrencyError>;
+
     /** Get the balance */
     async fn get_balance(&self, principal_id: Principal) -> Result<u128, CurrencyError>;
 }
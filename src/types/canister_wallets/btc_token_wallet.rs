@@ -1,10 +1,14 @@
 use crate::{
-    ckbtc_ledger_canister_interface::{
-        Account, Allowance, AllowanceArgs, TransferFromArgs, TransferFromError,
-    },
     ckbtc_minter_canister_interface::{UpdateBalanceError, UpdateBalanceRet},
     currency_error::CurrencyError,
-    transfer::transfer_icrc1,
+    deposit_subaccount::{deposit_subaccount, SweepResult},
+    icrc1_types::{Account, Allowance, TransferFromArg},
+    ledger_client::{
+        check_allowance, deposit_check, deposit_from, DepositCheck, IcLedgerClient,
+        IcMinterClient, LedgerClient,
+        MinterClient,
+    },
+    transfer::{transfer_icrc1, transfer_icrc1_at},
 };
 use crate::{
     state::TransactionState,
@@ -19,7 +23,7 @@ use candid::{CandidType, Principal};
 use serde::{Deserialize, Serialize};
 
 // Import the generated interfaces
-use crate::ckbtc_minter_canister_interface::{GetBtcAddressArg, UpdateBalanceArg, UtxoStatus};
+use crate::ckbtc_minter_canister_interface::{Utxo, UtxoStatus};
 
 #[derive(Debug, Clone, Serialize, Deserialize, CandidType)]
 pub struct CKBTCTokenWallet {
@@ -44,18 +48,22 @@ impl CKBTCTokenWallet {
         Self { config }
     }
 
-    /// Gets the Bitcoin deposit address for this canister
-    pub async fn get_deposit_address(&self) -> Result<String, CurrencyError> {
-        let arg = GetBtcAddressArg {
-            owner: Some(ic_cdk::api::id()),
-            subaccount: None,
-        };
+    /// Create a wallet for a ckBTC ledger and minter other than the mainnet ones
+    pub fn from_config(config: CKTokenConfig) -> Self {
+        Self { config }
+    }
 
-        let (address,): (String,) = ic_cdk::call(self.config.minter_id, "get_btc_address", (arg,))
-            .await
-            .map_err(|e| CurrencyError::CanisterCallFailed(format!("{:?}", e)))?;
+    fn ledger(&self) -> IcLedgerClient {
+        IcLedgerClient::new(self.config.ledger_id)
+    }
+
+    fn minter(&self) -> IcMinterClient {
+        IcMinterClient::new(self.config.minter_id)
+    }
 
-        Ok(address)
+    /// Gets the Bitcoin deposit address for this canister
+    pub async fn get_deposit_address(&self) -> Result<String, CurrencyError> {
+        self.minter().get_btc_address(None).await
     }
 
     /// Check the allowance granted by a user to this canister
@@ -63,23 +71,12 @@ impl CKBTCTokenWallet {
         &self,
         from_principal: Principal,
     ) -> Result<Allowance, CurrencyError> {
-        let args = AllowanceArgs {
-            account: Account {
-                owner: from_principal,
-                subaccount: None,
-            },
-            spender: Account {
-                owner: ic_cdk::api::id(),
-                subaccount: None,
-            },
-        };
-
-        let (allowance,): (Allowance,) =
-            ic_cdk::call(self.config.ledger_id, "icrc2_allowance", (args,))
-                .await
-                .map_err(|e| CurrencyError::AllowanceCheckFailed(format!("{:?}", e)))?;
-
-        Ok(allowance)
+        self.ledger()
+            .allowance(
+                &Account::from(from_principal),
+                &Account::from(ic_cdk::api::canister_self()),
+            )
+            .await
     }
 
     /// Transfer tokens from a user's account to this canister using ICRC-2 transfer_from
@@ -88,137 +85,117 @@ impl CKBTCTokenWallet {
         from_principal: Principal,
         amount: u64,
     ) -> Result<u128, CurrencyError> {
-        let canister_account = Account {
-            owner: ic_cdk::api::id(),
-            subaccount: None,
-        };
-
-        let from_account = Account {
-            owner: from_principal,
-            subaccount: None,
-        };
-
-        let args = TransferFromArgs {
+        let args = TransferFromArg {
             spender_subaccount: None,
-            from: from_account,
-            to: canister_account,
+            from: Account::from(from_principal),
+            to: Account::from(ic_cdk::api::canister_self()),
             amount: amount.into(),
-            fee: Some(self.config.fee.into()),
+            fee: Some(self.config.fee),
             memo: None,
             created_at_time: Some(ic_cdk::api::time()),
         };
 
-        let (result,): (Result<u128, TransferFromError>,) =
-            ic_cdk::call(self.config.ledger_id, "icrc2_transfer_from", (args,))
-                .await
-                .map_err(|e| CurrencyError::TransferFromFailed(format!("{:?}", e)))?;
+        self.ledger().transfer_from(args).await
+    }
 
-        match result {
-            Ok(block_index) => Ok(block_index),
-            Err(e) => match e {
-                TransferFromError::InsufficientAllowance { .. } => {
-                    Err(CurrencyError::InsufficientAllowance)
-                }
-                _ => match e {
-                    TransferFromError::BadFee { expected_fee } => {
-                        Err(CurrencyError::TransferFromFailed(format!(
-                            "Bad fee: Expected {}, got {}",
-                            expected_fee,
-                            ic_ledger_types::DEFAULT_FEE.e8s()
-                        )))
-                    }
-                    TransferFromError::BadBurn { min_burn_amount } => {
-                        Err(CurrencyError::TransferFromFailed(format!(
-                            "Bad burn: Minimum burn amount is {}",
-                            min_burn_amount
-                        )))
-                    }
-                    TransferFromError::InsufficientFunds { balance } => {
-                        Err(CurrencyError::TransferFromFailed(format!(
-                            "Insufficient funds: Balance is {}",
-                            balance
-                        )))
-                    }
-                    TransferFromError::TooOld => Err(CurrencyError::TransferFromFailed(
-                        "Transaction is too old".to_string(),
-                    )),
-                    TransferFromError::CreatedInFuture { ledger_time } => {
-                        Err(CurrencyError::TransferFromFailed(format!(
-                            "Transaction created in the future: {}",
-                            ledger_time
-                        )))
-                    }
-                    TransferFromError::GenericError {
-                        message,
-                        error_code,
-                    } => Err(CurrencyError::TransferFromFailed(format!(
-                        "Error code {}: {}",
-                        error_code, message
-                    ))),
-                    TransferFromError::Duplicate { duplicate_of } => {
-                        Err(CurrencyError::TransferFromFailed(format!(
-                            "Duplicate transaction: {}",
-                            duplicate_of
-                        )))
-                    }
-                    TransferFromError::TemporarilyUnavailable => {
-                        Err(CurrencyError::TransferFromFailed(
-                            "Service temporarily unavailable".to_string(),
-                        ))
-                    }
-                    TransferFromError::InsufficientAllowance { allowance } => {
-                        Err(CurrencyError::TransferFromFailed(format!(
-                            "Insufficient allowance: {}",
-                            allowance
-                        )))
-                    }
-                },
-            },
-        }
+    /// Gets the Bitcoin deposit address of a user, backed by the user's deposit subaccount
+    pub async fn get_deposit_address_for(
+        &self,
+        user: Principal,
+        namespace: Option<u16>,
+    ) -> Result<String, CurrencyError> {
+        self.minter()
+            .get_btc_address(Some(deposit_subaccount(&user, namespace).to_vec()))
+            .await
+    }
+
+    async fn call_update_balance(
+        &self,
+        subaccount: Option<[u8; 32]>,
+    ) -> Result<UpdateBalanceRet, CurrencyError> {
+        self.minter()
+            .update_balance(subaccount.map(|subaccount| subaccount.to_vec()))
+            .await
     }
 
     /// Updates the balance by checking for new UTXOs
     async fn update_balance(&self) -> Result<Vec<UtxoStatus>, CurrencyError> {
-        let args = UpdateBalanceArg {
-            owner: Some(ic_cdk::api::id()),
-            subaccount: None,
-        };
+        match self.call_update_balance(None).await? {
+            UpdateBalanceRet::Ok(statuses) => Ok(statuses),
+            UpdateBalanceRet::Err(e) => Err(CurrencyError::MinterRejected(e.into())),
+        }
+    }
 
-        let (result,): (UpdateBalanceRet,) =
-            ic_cdk::call(self.config.minter_id, "update_balance", (args,))
-                .await
-                .map_err(|e| CurrencyError::CanisterCallFailed(format!("{:?}", e)))?;
+    /// Ask the minter to mint ckBTC for new UTXOs sent to a user's deposit address.
+    ///
+    /// Minted ckBTC arrives in the user's deposit subaccount. UTXOs still waiting for
+    /// confirmations are reported as pending rather than as an error.
+    pub async fn update_balance_for(
+        &self,
+        user: Principal,
+        namespace: Option<u16>,
+    ) -> Result<BtcDepositUpdate, CurrencyError> {
+        update_deposit_balance(&self.minter(), user, namespace).await
+    }
+}
 
-        match result {
-            UpdateBalanceRet::Ok(statuses) => Ok(statuses),
-            UpdateBalanceRet::Err(e) => {
-                let error_msg = match e {
-                    UpdateBalanceError::GenericError {
-                        error_message,
-                        error_code,
-                    } => format!("Error code {}: {}", error_code, error_message),
-                    UpdateBalanceError::TemporarilyUnavailable(msg) => {
-                        format!("Service temporarily unavailable: {}", msg)
-                    }
-                    UpdateBalanceError::AlreadyProcessing => {
-                        "Already processing balance update".to_string()
-                    }
-                    UpdateBalanceError::NoNewUtxos {
-                        required_confirmations,
-                        current_confirmations,
-                        ..
-                    } => format!(
-                        "No new UTXOs available. Required confirmations: {}, Current: {}",
-                        required_confirmations,
-                        current_confirmations.unwrap_or(0)
-                    ),
-                };
-                Err(CurrencyError::LedgerError(error_msg))
-            }
+#[derive(Debug, Clone, Serialize, Deserialize, CandidType, PartialEq, Eq)]
+pub struct BtcUtxo {
+    /// Transaction id as returned by the minter
+    pub txid: Vec<u8>,
+    pub vout: u32,
+    pub value: u64,
+    pub height: u32,
+}
+
+impl From<Utxo> for BtcUtxo {
+    fn from(utxo: Utxo) -> Self {
+        Self {
+            txid: utxo.outpoint.txid.into_vec(),
+            vout: utxo.outpoint.vout,
+            value: utxo.value,
+            height: utxo.height,
         }
     }
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize, CandidType, PartialEq, Eq)]
+pub struct BtcMintedUtxo {
+    pub utxo: BtcUtxo,
+    pub minted_amount: u64,
+    pub block_index: u64,
+}
+
+/// A UTXO seen by the minter that does not have enough confirmations yet
+#[derive(Debug, Clone, Serialize, Deserialize, CandidType, PartialEq, Eq)]
+pub struct BtcPendingUtxo {
+    pub txid: Vec<u8>,
+    pub vout: u32,
+    pub value: u64,
+    pub confirmations: u32,
+}
+
+/// Outcome of checking a user's BTC deposit address
+#[derive(Debug, Clone, Serialize, Deserialize, CandidType, PartialEq, Eq)]
+pub struct BtcDepositUpdate {
+    pub user: Principal,
+    /// UTXOs converted to ckBTC in this call
+    pub minted: Vec<BtcMintedUtxo>,
+    /// UTXOs that passed the checks but were not minted yet, minted by a later call
+    pub checked: Vec<BtcUtxo>,
+    /// UTXOs below the minter's minimum, never minted
+    pub value_too_small: Vec<BtcUtxo>,
+    /// UTXOs rejected by the minter's checks, never minted
+    pub tainted: Vec<BtcUtxo>,
+    /// UTXOs waiting for confirmations
+    pub pending: Vec<BtcPendingUtxo>,
+    pub required_confirmations: Option<u32>,
+    /// Sum of `minted`
+    pub minted_amount: u128,
+    /// Sweep of the deposit subaccount into the main account, the amount to credit the user
+    pub sweep: Option<SweepResult>,
+}
+
 impl CanisterWallet for CKBTCTokenWallet {
     async fn deposit(
         &self,
@@ -226,22 +203,16 @@ impl CanisterWallet for CKBTCTokenWallet {
         from_principal: Principal,
         amount: u64,
     ) -> Result<(), CurrencyError> {
-        // First check the allowance to make sure it's sufficient
-        let allowance = self.check_allowance(from_principal).await?;
-
-        if allowance.allowance < amount as u128 {
-            return Err(CurrencyError::InsufficientAllowance);
-        }
-
-        // Check if the allowance is expired
-        if let Some(expires_at) = allowance.expires_at {
-            if expires_at < ic_cdk::api::time() {
-                return Err(CurrencyError::InsufficientAllowance);
-            }
-        }
-
-        // Transfer the tokens using the allowance
-        let block_index = self.transfer_from(from_principal, amount).await?;
+        // Transfer the tokens using the allowance, once it is known to be sufficient
+        let block_index = deposit_from(
+            &self.ledger(),
+            Account::from(from_principal),
+            Account::from(ic_cdk::api::canister_self()),
+            amount as u128,
+            Some(self.config.fee),
+            ic_cdk::api::time(),
+        )
+        .await?;
 
         // Record the transaction
         let tx_id = format!(
@@ -265,23 +236,34 @@ impl CanisterWallet for CKBTCTokenWallet {
         from_principal: Principal, 
         amount: u64
     ) -> Result<(), CurrencyError> {
-        // Check the allowance to make sure it's sufficient
-        let allowance = self.check_allowance(from_principal).await?;
-        
-        if allowance.allowance < amount as u128 {
-            return Err(CurrencyError::InsufficientAllowance);
-        }
-        
-        // Check if the allowance is expired
-        if let Some(expires_at) = allowance.expires_at {
-            if expires_at < ic_cdk::api::time() {
-                return Err(CurrencyError::InsufficientAllowance);
-            }
-        }
-        
+        check_allowance(
+            &self.ledger(),
+            &Account::from(from_principal),
+            &Account::from(ic_cdk::api::canister_self()),
+            (amount as u128).saturating_add(self.config.fee),
+            ic_cdk::api::time(),
+        )
+        .await?;
+
         Ok(())
     }
 
+    async fn check_deposit(
+        &self,
+        from_principal: Principal,
+        amount: u64,
+    ) -> Result<DepositCheck, CurrencyError> {
+        deposit_check(
+            &self.ledger(),
+            &Account::from(from_principal),
+            &Account::from(ic_cdk::api::canister_self()),
+            amount as u128,
+            Some(self.config.fee),
+            ic_cdk::api::time(),
+        )
+        .await
+    }
+
     async fn withdraw(
         &self,
         wallet_principal_id: Principal,
@@ -301,32 +283,93 @@ impl CanisterWallet for CKBTCTokenWallet {
         Ok(())
     }
 
-    async fn get_balance(&self, principal_id: Principal) -> Result<u128, CurrencyError> {
-        let account = crate::ckbtc_ledger_canister_interface::Account {
-            owner: principal_id,
-            subaccount: None,
-        };
-        
-        let (balance,): (candid::Nat,) = ic_cdk::call(
+    async fn withdraw_at(
+        &self,
+        wallet_principal_id: Principal,
+        amount: u64,
+        created_at_time: u64,
+    ) -> Result<u128, CurrencyError> {
+        let default_subaccount = get_canister_state().default_subaccount.0;
+
+        transfer_icrc1_at(
             self.config.ledger_id,
-            "icrc1_balance_of", 
-            (account,)
+            amount,
+            default_subaccount.to_vec(),
+            wallet_principal_id,
+            Some(self.config.fee),
+            created_at_time,
         )
         .await
-        .map_err(|e| CurrencyError::LedgerError(
-            format!("Failed to query ckBTC balance: {:?}", e)
-        ))?;
-        
-        // Convert the candid::Nat to u128
-        let balance_str = balance.0.to_string();
-        let balance_u128 = match balance_str.parse::<u128>() {
-            Ok(val) => val,
-            Err(_) => {
-                return Err(CurrencyError::LedgerError(
-                    format!("Failed to convert balance '{}' to u128", balance_str)
-                ));
+        .map_err(|e| CurrencyError::WithdrawalFailed(e.to_string()))
+    }
+
+    async fn get_balance(&self, principal_id: Principal) -> Result<u128, CurrencyError> {
+        self.ledger().balance_of(&Account::from(principal_id)).await
+    }
+}
+
+/// Ask `minter` to mint ckBTC for new UTXOs sent to a user's deposit address
+pub async fn update_deposit_balance(
+    minter: &impl MinterClient,
+    user: Principal,
+    namespace: Option<u16>,
+) -> Result<BtcDepositUpdate, CurrencyError> {
+    let mut update = BtcDepositUpdate {
+        user,
+        minted: Vec::new(),
+        checked: Vec::new(),
+        value_too_small: Vec::new(),
+        tainted: Vec::new(),
+        pending: Vec::new(),
+        required_confirmations: None,
+        minted_amount: 0,
+        sweep: None,
+    };
+
+    match minter
+        .update_balance(Some(deposit_subaccount(&user, namespace).to_vec()))
+        .await?
+    {
+        UpdateBalanceRet::Ok(statuses) => {
+            for status in statuses {
+                match status {
+                    UtxoStatus::Minted {
+                        minted_amount,
+                        block_index,
+                        utxo,
+                    } => {
+                        update.minted_amount += minted_amount as u128;
+                        update.minted.push(BtcMintedUtxo {
+                            utxo: utxo.into(),
+                            minted_amount,
+                            block_index,
+                        });
+                    }
+                    UtxoStatus::Checked(utxo) => update.checked.push(utxo.into()),
+                    UtxoStatus::ValueTooSmall(utxo) => update.value_too_small.push(utxo.into()),
+                    UtxoStatus::Tainted(utxo) => update.tainted.push(utxo.into()),
+                }
             }
-        };
-        Ok(balance_u128)
+        }
+        UpdateBalanceRet::Err(UpdateBalanceError::NoNewUtxos {
+            required_confirmations,
+            pending_utxos,
+            ..
+        }) => {
+            update.required_confirmations = Some(required_confirmations);
+            update.pending = pending_utxos
+                .unwrap_or_default()
+                .into_iter()
+                .map(|utxo| BtcPendingUtxo {
+                    txid: utxo.outpoint.txid.into_vec(),
+                    vout: utxo.outpoint.vout,
+                    value: utxo.value,
+                    confirmations: utxo.confirmations,
+                })
+                .collect();
+        }
+        UpdateBalanceRet::Err(e) => return Err(CurrencyError::MinterRejected(e.into())),
     }
+
+    Ok(update)
 }
@@ -1,14 +1,20 @@
 use crate::{
+    address::check_eth_address,
     cketh_minter_canister_interface::{
-        EventPayload, GetEventsArg, GetEventsRet, LedgerError, MinterInfo, TxFinalizedStatus,
-        WithdrawErc20Arg, WithdrawErc20Error, WithdrawErc20Ret, WithdrawalDetail,
-        WithdrawalSearchParameter, WithdrawalStatus,
+        EventPayload, MinterInfo, TxFinalizedStatus, WithdrawErc20Arg, WithdrawalStatus,
     },
-    currency_error::CurrencyError,
-    icrc1_types::{Account, Allowance, AllowanceArgs, TransferFromArg, TransferFromError},
-    transfer::transfer_icrc1,
+    currency_error::{call_error, CurrencyError},
+    icrc1_types::{Account, Allowance, TransferFromArg},
+    ledger_client::{
+        check_allowance, deposit_check, deposit_from, DepositCheck, IcLedgerClient,
+        IcMinterClient, LedgerClient,
+        MinterClient,
+    },
+    retry::{retry_policy, retry_query},
+    transfer::{transfer_icrc1, transfer_icrc1_at},
 };
-use candid::{CandidType, Principal};
+use candid::{CandidType, Nat, Principal};
+use num_traits::ToPrimitive;
 use serde::{Deserialize, Serialize};
 
 use crate::{
@@ -37,6 +43,16 @@ pub enum CKTokenWithdrawalStatus {
         effective_fee: Option<u64>,
     },
     Failed(String),
+    /// The transaction failed and the tokens were minted back to the canister
+    Reimbursed {
+        transaction_hash: String,
+        reimbursed_amount: u128,
+        reimbursed_in_block: u128,
+    },
+    /// The transaction failed, the minter has yet to mint the tokens back
+    PendingReimbursement {
+        transaction_hash: String,
+    },
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, CandidType)]
@@ -72,12 +88,23 @@ impl CKERC20TokenWallet {
         Self { config }
     }
 
+    /// Create a wallet for a ckERC20 ledger and minter other than the mainnet ones
+    pub fn from_config(config: CKTokenConfig) -> Self {
+        Self { config }
+    }
+
+    fn minter(&self) -> IcMinterClient {
+        IcMinterClient::new(self.config.minter_id)
+    }
+
     pub async fn get_deposit_address(&self) -> Result<Option<String>, CurrencyError> {
         // Call the minter's smart_contract_address function directly
-        let (deposit_address,): (Option<String>,) =
+        let (deposit_address,): (Option<String>,) = retry_query(&retry_policy(), |_| async {
             ic_cdk::call(self.config.minter_id, "smart_contract_address", ())
                 .await
-                .map_err(|e| CurrencyError::CanisterCallFailed(format!("{:?}", e)))?;
+                .map_err(call_error(self.config.minter_id, "smart_contract_address"))
+        })
+        .await?;
 
         Ok(deposit_address)
     }
@@ -95,56 +122,26 @@ impl CKERC20TokenWallet {
     /// - Error if the helper contract address is not configured in the minter
     pub async fn get_deposit_address_for_principal(&self) -> Result<String, CurrencyError> {
         // Get the deposit with subaccount helper contract address
-        let (minter_info,): (MinterInfo,) =
+        let (minter_info,): (MinterInfo,) = retry_query(&retry_policy(), |_| async {
             ic_cdk::call(self.config.minter_id, "get_minter_info", ())
                 .await
-                .map_err(|e| CurrencyError::CanisterCallFailed(format!("{:?}", e)))?;
+                .map_err(call_error(self.config.minter_id, "get_minter_info"))
+        })
+        .await?;
 
         minter_info
             .deposit_with_subaccount_helper_contract_address
             .ok_or(CurrencyError::NoDepositAddress)
     }
 
-    /// Monitors for a ckToken minting event from a specific Ethereum transaction
-    /// Returns the block number where the ckTokens were minted once found.
+    /// Finds the ckToken minting event of a specific Ethereum transaction
+    /// Returns the ledger block index of the mint, or `TransactionNotFound` if it was not minted yet.
     /// Should be used after sending tokens to the address recieved from `get_deposit_address_for_principal`
     pub async fn get_mint_block_number(
         &self,
         eth_transaction_hash: String,
     ) -> Result<u64, CurrencyError> {
-        // We'll try a few times since there might be a delay
-        for _ in 0..10 {
-            let events_arg = GetEventsArg {
-                // Look at last 100 events - might need adjustment
-                start: 0,
-                length: 100,
-            };
-
-            let (events,): (GetEventsRet,) =
-                ic_cdk::call(self.config.minter_id, "get_events", (events_arg,))
-                    .await
-                    .map_err(|e| CurrencyError::CanisterCallFailed(format!("{:?}", e)))?;
-
-            // Look for MintedCkErc20 event with matching transaction hash
-            for event in events.events {
-                if let EventPayload::MintedCkErc20 {
-                    event_source,
-                    mint_block_index,
-                    ..
-                } = event.payload
-                {
-                    if event_source.transaction_hash == eth_transaction_hash {
-                        return mint_block_index.0.try_into().map_err(|_| {
-                            CurrencyError::QueryError("Block number too large".to_string())
-                        });
-                    }
-                }
-            }
-
-            // Wait a bit before trying again
-        }
-
-        Err(CurrencyError::TransactionNotFound)
+        find_mint_block(&self.minter(), &eth_transaction_hash).await
     }
 
     pub async fn withdraw_icrc1_token_to_eth_address(
@@ -152,121 +149,19 @@ impl CKERC20TokenWallet {
         eth_address: String,
         amount: u64,
     ) -> Result<(), CurrencyError> {
-        // First create withdrawal args for the minter
-        let withdraw_arg = WithdrawErc20Arg {
-            amount: amount.into(),
-            ckerc20_ledger_id: self.config.ledger_id,
-            recipient: eth_address,      // This needs to be an ETH address
-            from_cketh_subaccount: None, // For gas fees
-            from_ckerc20_subaccount: None,
-        };
+        self.request_eth_withdrawal(eth_address, amount)
+            .await
+            .map(|_| ())
+    }
 
-        // Call minter to initiate withdrawal
-        let (result,): (WithdrawErc20Ret,) =
-            ic_cdk::call(self.config.minter_id, "withdraw_erc20", (withdraw_arg,))
-                .await
-                .map_err(|e| CurrencyError::CanisterCallFailed(format!("{:?}", e)))?;
-
-        match result {
-            WithdrawErc20Ret::Ok(_) => Ok(()),
-            WithdrawErc20Ret::Err(e) => {
-                let error_msg = match e {
-                    WithdrawErc20Error::TokenNotSupported { supported_tokens } => {
-                        format!(
-                            "Token not supported. Supported tokens: {:?}",
-                            supported_tokens
-                                .iter()
-                                .map(|t| t.ckerc20_token_symbol.clone())
-                                .collect::<Vec<_>>()
-                        )
-                    }
-                    WithdrawErc20Error::TemporarilyUnavailable(msg) => {
-                        format!("Service temporarily unavailable: {}", msg)
-                    }
-                    WithdrawErc20Error::CkErc20LedgerError {
-                        error,
-                        cketh_block_index: _,
-                    } => match error {
-                        LedgerError::TemporarilyUnavailable(msg) => {
-                            format!("Ledger temporarily unavailable: {}", msg)
-                        }
-                        LedgerError::InsufficientAllowance {
-                            token_symbol,
-                            allowance,
-                            failed_burn_amount,
-                            ..
-                        } => {
-                            format!(
-                                "Insufficient allowance for {}: have {} need {}",
-                                token_symbol, allowance, failed_burn_amount
-                            )
-                        }
-                        LedgerError::AmountTooLow {
-                            minimum_burn_amount,
-                            token_symbol,
-                            failed_burn_amount,
-                            ..
-                        } => {
-                            format!(
-                                "Amount too low for {}: minimum {} got {}",
-                                token_symbol, minimum_burn_amount, failed_burn_amount
-                            )
-                        }
-                        LedgerError::InsufficientFunds {
-                            balance,
-                            token_symbol,
-                            failed_burn_amount,
-                            ..
-                        } => {
-                            format!(
-                                "Insufficient funds for {}: have {} need {}",
-                                token_symbol, balance, failed_burn_amount
-                            )
-                        }
-                    },
-                    WithdrawErc20Error::CkEthLedgerError { error } => match error {
-                        LedgerError::TemporarilyUnavailable(msg) => {
-                            format!("ckETH ledger temporarily unavailable: {}", msg)
-                        }
-                        LedgerError::InsufficientAllowance {
-                            token_symbol,
-                            allowance,
-                            failed_burn_amount,
-                            ..
-                        } => {
-                            format!(
-                                "Insufficient {} allowance: have {} need {}",
-                                token_symbol, allowance, failed_burn_amount
-                            )
-                        }
-                        LedgerError::AmountTooLow {
-                            minimum_burn_amount,
-                            failed_burn_amount,
-                            ..
-                        } => {
-                            format!(
-                                "ckETH amount too low: minimum {} got {}",
-                                minimum_burn_amount, failed_burn_amount
-                            )
-                        }
-                        LedgerError::InsufficientFunds {
-                            balance,
-                            failed_burn_amount,
-                            ..
-                        } => {
-                            format!(
-                                "Insufficient ckETH funds: have {} need {}",
-                                balance, failed_burn_amount
-                            )
-                        }
-                    },
-                    WithdrawErc20Error::RecipientAddressBlocked { address } => {
-                        format!("Recipient address blocked: {}", address)
-                    }
-                };
-                Err(CurrencyError::WithdrawalFailed(error_msg))
-            }
-        }
+    /// Withdraw to an Ethereum address, returns the minter's withdrawal id
+    /// (the ckETH ledger burn index) that reimbursements refer to
+    pub async fn request_eth_withdrawal(
+        &self,
+        eth_address: String,
+        amount: u64,
+    ) -> Result<u128, CurrencyError> {
+        withdraw_to_eth(&self.minter(), self.config.ledger_id, eth_address, amount).await
     }
 
     /// Check the status of a withdrawal after it's been initiated
@@ -274,48 +169,7 @@ impl CKERC20TokenWallet {
         &self,
         withdrawal_id: u64,
     ) -> Result<CKTokenWithdrawalStatus, CurrencyError> {
-        let (status,): (Vec<WithdrawalDetail>,) = ic_cdk::call(
-            self.config.minter_id,
-            "withdrawal_status",
-            (WithdrawalSearchParameter::ByWithdrawalId(withdrawal_id),),
-        )
-        .await
-        .map_err(|e| CurrencyError::CanisterCallFailed(format!("{:?}", e)))?;
-
-        let detail = status.first().ok_or(CurrencyError::WithdrawalFailed(
-            "Withdrawal not found".to_string(),
-        ))?;
-
-        Ok(match &detail.status {
-            WithdrawalStatus::Pending => CKTokenWithdrawalStatus::Pending,
-            WithdrawalStatus::TxCreated => CKTokenWithdrawalStatus::TxCreated,
-            WithdrawalStatus::TxSent(tx) => CKTokenWithdrawalStatus::TxSent {
-                transaction_hash: tx.transaction_hash.clone(),
-            },
-            WithdrawalStatus::TxFinalized(status) => match status {
-                TxFinalizedStatus::Success {
-                    transaction_hash,
-                    effective_transaction_fee,
-                } => CKTokenWithdrawalStatus::TxFinalized {
-                    transaction_hash: transaction_hash.clone(),
-                    effective_fee: effective_transaction_fee
-                        .as_ref()
-                        .map(|f| f.clone().0.try_into().unwrap_or(0)),
-                },
-                TxFinalizedStatus::Reimbursed {
-                    transaction_hash, ..
-                } => CKTokenWithdrawalStatus::Failed(format!(
-                    "Transaction failed and was reimbursed. Tx hash: {}",
-                    transaction_hash
-                )),
-                TxFinalizedStatus::PendingReimbursement(tx) => {
-                    CKTokenWithdrawalStatus::Failed(format!(
-                        "Transaction failed, reimbursement pending. Tx hash: {}",
-                        tx.transaction_hash
-                    ))
-                }
-            },
-        })
+        withdrawal_status(&self.minter(), withdrawal_id).await
     }
 
     // Helper functions for implementations
@@ -325,13 +179,7 @@ impl CKERC20TokenWallet {
         account: Account,
         spender: Account,
     ) -> Result<Allowance, CurrencyError> {
-        let args = AllowanceArgs { account, spender };
-
-        let (allowance,): (Allowance,) = ic_cdk::call(ledger, "icrc2_allowance", (args,))
-            .await
-            .map_err(|e| CurrencyError::AllowanceCheckFailed(format!("{:?}", e)))?;
-
-        Ok(allowance)
+        IcLedgerClient::new(ledger).allowance(&account, &spender).await
     }
 
     pub async fn transfer_from(
@@ -351,22 +199,8 @@ impl CKERC20TokenWallet {
             created_at_time: Some(ic_cdk::api::time()),
         };
 
-        let (result,): (Result<u128, TransferFromError>,) =
-            ic_cdk::call(ledger, "icrc2_transfer_from", (args,))
-                .await
-                .map_err(|e| CurrencyError::TransferFromFailed(format!("{:?}", e)))?;
-
-        match result {
-            Ok(block_index) => Ok(block_index),
-            Err(e) => match e {
-                TransferFromError::InsufficientAllowance { .. } => {
-                    Err(CurrencyError::InsufficientAllowance)
-                }
-                _ => Err(CurrencyError::TransferFromFailed(format!("{:?}", e))),
-            },
-        }
+        IcLedgerClient::new(ledger).transfer_from(args).await
     }
-
 }
 
 impl CanisterWallet for CKERC20TokenWallet {
@@ -378,38 +212,14 @@ impl CanisterWallet for CKERC20TokenWallet {
     ) -> Result<(), CurrencyError> {
         let canister_state = get_canister_state();
 
-        // Check allowance
-        let from_account = Account {
-            owner: from_principal,
-            subaccount: None,
-        };
-        let spender_account = Account {
-            owner: canister_state.owner,
-            subaccount: None,
-        };
-
-        let allowance =
-            self.check_allowance(self.config.ledger_id, from_account, spender_account).await?;
-
-        if allowance.allowance < amount as u128 {
-            return Err(CurrencyError::InsufficientAllowance);
-        }
-
-        let from_account = Account {
-            owner: from_principal,
-            subaccount: None,
-        };
-        let spender_account = Account {
-            owner: canister_state.owner,
-            subaccount: None,
-        };
-
-        // Transfer tokens using allowance
-        let block_index = self.transfer_from(
-            self.config.ledger_id,
-            from_account,
-            spender_account,
+        // Transfer tokens using the allowance, once it is known to be sufficient
+        let block_index = deposit_from(
+            &IcLedgerClient::new(self.config.ledger_id),
+            Account::from(from_principal),
+            Account::from(canister_state.owner),
             amount.into(),
+            Some(self.config.fee),
+            ic_cdk::api::time(),
         )
         .await?;
 
@@ -432,31 +242,36 @@ impl CanisterWallet for CKERC20TokenWallet {
     ) -> Result<(), CurrencyError> {
         let canister_state = get_canister_state();
 
-        let from_account = Account {
-            owner: from_principal,
-            subaccount: None,
-        };
-        let spender_account = Account {
-            owner: canister_state.owner,
-            subaccount: None,
-        };
-        // Check the allowance to make sure it's sufficient
-        let allowance = self.check_allowance(self.config.ledger_id, from_account, spender_account).await?;
-        
-        if allowance.allowance < amount as u128 {
-            return Err(CurrencyError::InsufficientAllowance);
-        }
-        
-        // Check if the allowance is expired
-        if let Some(expires_at) = allowance.expires_at {
-            if expires_at < ic_cdk::api::time() {
-                return Err(CurrencyError::InsufficientAllowance);
-            }
-        }
-        
+        check_allowance(
+            &IcLedgerClient::new(self.config.ledger_id),
+            &Account::from(from_principal),
+            &Account::from(canister_state.owner),
+            (amount as u128).saturating_add(self.config.fee),
+            ic_cdk::api::time(),
+        )
+        .await?;
+
         Ok(())
     }
 
+    async fn check_deposit(
+        &self,
+        from_principal: Principal,
+        amount: u64,
+    ) -> Result<DepositCheck, CurrencyError> {
+        let canister_state = get_canister_state();
+
+        deposit_check(
+            &IcLedgerClient::new(self.config.ledger_id),
+            &Account::from(from_principal),
+            &Account::from(canister_state.owner),
+            amount as u128,
+            Some(self.config.fee),
+            ic_cdk::api::time(),
+        )
+        .await
+    }
+
     async fn withdraw(
         &self,
         wallet_principal_id: Principal,
@@ -478,32 +293,173 @@ impl CanisterWallet for CKERC20TokenWallet {
         Ok(())
     }
 
-    async fn get_balance(&self, principal_id: Principal) -> Result<u128, CurrencyError> {
-        let account = Account {
-            owner: principal_id,
-            subaccount: None,
-        };
-        
-        let (balance,): (candid::Nat,) = ic_cdk::call(
+    async fn withdraw_at(
+        &self,
+        wallet_principal_id: Principal,
+        amount: u64,
+        created_at_time: u64,
+    ) -> Result<u128, CurrencyError> {
+        let default_subaccount = get_canister_state().default_subaccount.0;
+
+        transfer_icrc1_at(
             self.config.ledger_id,
-            "icrc1_balance_of", 
-            (account,)
+            amount,
+            default_subaccount.to_vec(),
+            wallet_principal_id,
+            Some(self.config.fee),
+            created_at_time,
         )
         .await
-        .map_err(|e| CurrencyError::LedgerError(
-            format!("Failed to query {:?} balance: {:?}", self.config.token_symbol, e)
-        ))?;
-        
-        // Convert the candid::Nat to u128
-        let balance_str = balance.0.to_string();
-        let balance_u128 = match balance_str.parse::<u128>() {
-            Ok(val) => val,
-            Err(_) => {
-                return Err(CurrencyError::LedgerError(
-                    format!("Failed to convert balance '{}' to u128", balance_str)
-                ));
+    }
+
+    async fn get_balance(&self, principal_id: Principal) -> Result<u128, CurrencyError> {
+        IcLedgerClient::new(self.config.ledger_id)
+            .balance_of(&Account::from(principal_id))
+            .await
+    }
+}
+
+/// Events read per `get_events` call, the most the ckETH minter returns
+pub const MINT_EVENTS_PAGE_SIZE: u64 = 100;
+
+/// Number of most recent minter events [`find_mint_block`] looks through
+pub const MAX_MINT_EVENTS_SCANNED: u64 = 5_000;
+
+/// Find the mint of an Ethereum deposit among the last [`MAX_MINT_EVENTS_SCANNED`]
+/// events of `minter`'s log, returns its ledger block index or `TransactionNotFound`
+/// if it was not minted yet or too long ago
+pub async fn find_mint_block(
+    minter: &impl MinterClient,
+    eth_transaction_hash: &str,
+) -> Result<u64, CurrencyError> {
+    let hash = eth_transaction_hash.to_lowercase();
+
+    // Mints are recent, so read the event log backwards from its end
+    let latest = minter.get_events(0, 0).await?;
+    let oldest = latest
+        .total_event_count
+        .saturating_sub(MAX_MINT_EVENTS_SCANNED);
+
+    let mut end = latest.total_event_count;
+    while end > oldest {
+        let start = end.saturating_sub(MINT_EVENTS_PAGE_SIZE).max(oldest);
+        let events = minter.get_events(start, end - start).await?;
+
+        if events.events.is_empty() {
+            break;
+        }
+
+        // Look for a mint event with matching transaction hash
+        for event in events.events.iter() {
+            let (event_source, mint_block_index) = match &event.payload {
+                EventPayload::MintedCkErc20 {
+                    event_source,
+                    mint_block_index,
+                    ..
+                }
+                | EventPayload::MintedCkEth {
+                    event_source,
+                    mint_block_index,
+                } => (event_source, mint_block_index),
+                _ => continue,
+            };
+
+            if event_source.transaction_hash.to_lowercase() == hash {
+                return mint_block_index.0.clone().try_into().map_err(|_| {
+                    CurrencyError::QueryError("Block number too large".to_string())
+                });
             }
-        };
-        Ok(balance_u128)
+        }
+
+        end = start;
     }
+
+    Err(CurrencyError::TransactionNotFound)
+}
+
+/// Withdraw `amount` of the ckERC20 token of `ledger_id` to an Ethereum address,
+/// returns the minter's withdrawal id (the ckETH ledger burn index)
+pub async fn withdraw_to_eth(
+    minter: &impl MinterClient,
+    ledger_id: Principal,
+    eth_address: String,
+    amount: u64,
+) -> Result<u128, CurrencyError> {
+    check_eth_address(minter, &eth_address).await?;
+
+    // First create withdrawal args for the minter
+    let withdraw_arg = WithdrawErc20Arg {
+        amount: amount.into(),
+        ckerc20_ledger_id: ledger_id,
+        recipient: eth_address,      // This needs to be an ETH address
+        from_cketh_subaccount: None, // For gas fees
+        from_ckerc20_subaccount: None,
+    };
+
+    // Call minter to initiate withdrawal, never retried since the minter
+    // does not deduplicate withdrawals
+    minter
+        .withdraw_erc20(withdraw_arg)
+        .await?
+        .cketh_block_index
+        .0
+        .to_u128()
+        .ok_or_else(|| CurrencyError::QueryError("Withdrawal id too large".to_string()))
+}
+
+fn nat_to_u128(value: &Nat, field: &str) -> Result<u128, CurrencyError> {
+    value
+        .0
+        .to_u128()
+        .ok_or_else(|| CurrencyError::QueryError(format!("{} {} is too large", field, value)))
+}
+
+/// Status of a withdrawal made through `minter`
+pub async fn withdrawal_status(
+    minter: &impl MinterClient,
+    withdrawal_id: u64,
+) -> Result<CKTokenWithdrawalStatus, CurrencyError> {
+    let status = minter.withdrawal_status(withdrawal_id).await?;
+
+    let detail = status.first().ok_or(CurrencyError::WithdrawalFailed(
+        "Withdrawal not found".to_string(),
+    ))?;
+
+    Ok(match &detail.status {
+        WithdrawalStatus::Pending => CKTokenWithdrawalStatus::Pending,
+        WithdrawalStatus::TxCreated => CKTokenWithdrawalStatus::TxCreated,
+        WithdrawalStatus::TxSent(tx) => CKTokenWithdrawalStatus::TxSent {
+            transaction_hash: tx.transaction_hash.clone(),
+        },
+        WithdrawalStatus::TxFinalized(status) => match status {
+            TxFinalizedStatus::Success {
+                transaction_hash,
+                effective_transaction_fee,
+            } => CKTokenWithdrawalStatus::TxFinalized {
+                transaction_hash: transaction_hash.clone(),
+                effective_fee: effective_transaction_fee
+                    .as_ref()
+                    .map(|fee| {
+                        fee.0.to_u64().ok_or_else(|| {
+                            CurrencyError::QueryError(format!("Effective fee {} is too large", fee))
+                        })
+                    })
+                    .transpose()?,
+            },
+            TxFinalizedStatus::Reimbursed {
+                transaction_hash,
+                reimbursed_amount,
+                reimbursed_in_block,
+            } => CKTokenWithdrawalStatus::Reimbursed {
+                transaction_hash: transaction_hash.clone(),
+                reimbursed_amount: nat_to_u128(reimbursed_amount, "Reimbursed amount")?,
+                reimbursed_in_block: nat_to_u128(reimbursed_in_block, "Reimbursement block")?,
+            },
+            TxFinalizedStatus::PendingReimbursement(tx) => {
+                CKTokenWithdrawalStatus::PendingReimbursement {
+                    transaction_hash: tx.transaction_hash.clone(),
+                }
+            }
+        },
+    })
 }
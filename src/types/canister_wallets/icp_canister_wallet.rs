@@ -1,7 +1,10 @@
 use crate::{
     currency_error::CurrencyError,
-    icrc1_types::{Account, Allowance, AllowanceArgs, TransferFromArg, TransferFromError},
-    transfer::transfer_icp,
+    icrc1_types::{Account, Allowance, TransferFromArg},
+    ledger_client::{
+        check_allowance, deposit_check, deposit_from, DepositCheck, IcLedgerClient, LedgerClient,
+    },
+    transfer::{transfer_icp, transfer_icp_at},
 };
 use candid::{CandidType, Principal};
 use ic_ledger_types::MAINNET_LEDGER_CANISTER_ID;
@@ -15,28 +18,21 @@ use crate::{
 pub struct ICPCanisterWallet;
 
 impl ICPCanisterWallet {
+    fn ledger(&self) -> IcLedgerClient {
+        IcLedgerClient::new(MAINNET_LEDGER_CANISTER_ID)
+    }
+
     /// Check the allowance granted by a user to this canister
     pub async fn check_allowance(
         &self,
         from_principal: Principal,
     ) -> Result<Allowance, CurrencyError> {
-        let args = AllowanceArgs {
-            account: Account {
-                owner: from_principal,
-                subaccount: None,
-            },
-            spender: Account {
-                owner: ic_cdk::api::id(),
-                subaccount: None,
-            },
-        };
-
-        let (allowance,): (Allowance,) =
-            ic_cdk::call(MAINNET_LEDGER_CANISTER_ID, "icrc2_allowance", (args,))
-                .await
-                .map_err(|e| CurrencyError::AllowanceCheckFailed(format!("{:?}", e)))?;
-
-        Ok(allowance)
+        self.ledger()
+            .allowance(
+                &Account::from(from_principal),
+                &Account::from(ic_cdk::api::canister_self()),
+            )
+            .await
     }
 
     /// Transfer tokens from a user's account to this canister using ICRC-2 transfer_from
@@ -45,40 +41,17 @@ impl ICPCanisterWallet {
         from_principal: Principal,
         amount: u64,
     ) -> Result<u128, CurrencyError> {
-        let canister_account = Account {
-            owner: ic_cdk::api::id(),
-            subaccount: None,
-        };
-
-        let from_account = Account {
-            owner: from_principal,
-            subaccount: None,
-        };
-
         let args = TransferFromArg {
             spender_subaccount: None,
-            from: from_account,
-            to: canister_account,
+            from: Account::from(from_principal),
+            to: Account::from(ic_cdk::api::canister_self()),
             amount: amount.into(),
             fee: Some(ic_ledger_types::DEFAULT_FEE.e8s().into()),
             memo: None,
             created_at_time: Some(ic_cdk::api::time()),
         };
 
-        let (result,): (Result<u128, TransferFromError>,) =
-            ic_cdk::call(MAINNET_LEDGER_CANISTER_ID, "icrc2_transfer_from", (args,))
-                .await
-                .map_err(|e| CurrencyError::TransferFromFailed(format!("{:?}", e)))?;
-
-        match result {
-            Ok(block_index) => Ok(block_index),
-            Err(e) => match e {
-                TransferFromError::InsufficientAllowance { .. } => {
-                    Err(CurrencyError::InsufficientAllowance)
-                }
-                _ => Err(CurrencyError::TransferFromFailed(format!("{:?}", e))),
-            },
-        }
+        self.ledger().transfer_from(args).await
     }
 }
 
@@ -89,22 +62,16 @@ impl CanisterWallet for ICPCanisterWallet {
         from_principal: Principal,
         amount: u64,
     ) -> Result<(), CurrencyError> {
-        // First check the allowance to make sure it's sufficient
-        let allowance = self.check_allowance(from_principal).await?;
-
-        if allowance.allowance < amount as u128 {
-            return Err(CurrencyError::InsufficientAllowance);
-        }
-
-        // Check if the allowance is expired
-        if let Some(expires_at) = allowance.expires_at {
-            if expires_at < ic_cdk::api::time() {
-                return Err(CurrencyError::InsufficientAllowance);
-            }
-        }
-
-        // Transfer the tokens using the allowance
-        let block_index = self.transfer_from(from_principal, amount).await?;
+        // Transfer the tokens using the allowance, once it is known to be sufficient
+        let block_index = deposit_from(
+            &self.ledger(),
+            Account::from(from_principal),
+            Account::from(ic_cdk::api::canister_self()),
+            amount as u128,
+            Some(ic_ledger_types::DEFAULT_FEE.e8s().into()),
+            ic_cdk::api::time(),
+        )
+        .await?;
 
         // Record the transaction
         let tx_id = format!(
@@ -124,23 +91,34 @@ impl CanisterWallet for ICPCanisterWallet {
         from_principal: Principal, 
         amount: u64
     ) -> Result<(), CurrencyError> {
-        // Check the allowance to make sure it's sufficient
-        let allowance = self.check_allowance(from_principal).await?;
-        
-        if allowance.allowance < amount as u128 {
-            return Err(CurrencyError::InsufficientAllowance);
-        }
-        
-        // Check if the allowance is expired
-        if let Some(expires_at) = allowance.expires_at {
-            if expires_at < ic_cdk::api::time() {
-                return Err(CurrencyError::InsufficientAllowance);
-            }
-        }
-        
+        check_allowance(
+            &self.ledger(),
+            &Account::from(from_principal),
+            &Account::from(ic_cdk::api::canister_self()),
+            amount as u128 + ic_ledger_types::DEFAULT_FEE.e8s() as u128,
+            ic_cdk::api::time(),
+        )
+        .await?;
+
         Ok(())
     }
 
+    async fn check_deposit(
+        &self,
+        from_principal: Principal,
+        amount: u64,
+    ) -> Result<DepositCheck, CurrencyError> {
+        deposit_check(
+            &self.ledger(),
+            &Account::from(from_principal),
+            &Account::from(ic_cdk::api::canister_self()),
+            amount as u128,
+            Some(ic_ledger_types::DEFAULT_FEE.e8s().into()),
+            ic_cdk::api::time(),
+        )
+        .await
+    }
+
     async fn withdraw(
         &self,
         wallet_principal_id: Principal,
@@ -155,31 +133,21 @@ impl CanisterWallet for ICPCanisterWallet {
         Ok(())
     }
 
-    async fn get_balance(&self, principal_id: Principal) -> Result<u128, CurrencyError> {
-        let account = Account {
-            owner: principal_id,
-            subaccount: None,
-        };
-        
-        let (balance,): (candid::Nat,) = ic_cdk::call(
-            MAINNET_LEDGER_CANISTER_ID,
-            "icrc1_balance_of", 
-            (account,)
-        )
-        .await
-        .map_err(|e| CurrencyError::LedgerError(format!("Failed to query ICP balance: {:?}", e)))?;
-        
-        // Convert candid::Nat to u64, ensuring it doesn't overflow
-        let balance_str = balance.0.to_string();
-        let balance_u128 = match balance_str.parse::<u128>() {
-            Ok(val) => val,
-            Err(_) => {
-                return Err(CurrencyError::LedgerError(
-                    format!("Failed to convert balance '{}' to u128", balance_str)
-                ));
-            }
-        };
+    async fn withdraw_at(
+        &self,
+        wallet_principal_id: Principal,
+        amount: u64,
+        created_at_time: u64,
+    ) -> Result<u128, CurrencyError> {
+        let default_subaccount = get_canister_state().default_subaccount;
+
+        let block_index =
+            transfer_icp_at(amount, default_subaccount, wallet_principal_id, created_at_time)
+                .await?;
+        Ok(block_index as u128)
+    }
 
-        Ok(balance_u128)
+    async fn get_balance(&self, principal_id: Principal) -> Result<u128, CurrencyError> {
+        self.ledger().balance_of(&Account::from(principal_id)).await
     }
 }
@@ -1,8 +1,13 @@
 use crate::{
-    currency_error::CurrencyError,
-    icrc1_types::{Account, Allowance, AllowanceArgs, TransferFromArg, TransferFromError},
+    currency_error::{call_error, CurrencyError},
+    icrc1_types::{Account, Allowance, TransferFromArg},
+    icrc_ledger_canister_interface::MetadataValue,
+    ledger_client::{
+        check_allowance, deposit_check, deposit_from, DepositCheck, IcLedgerClient, LedgerClient,
+    },
     state::TransactionState,
-    transfer::transfer_icrc1,
+    retry::{retry_policy, retry_query},
+    transfer::{transfer_icrc1, transfer_icrc1_at},
     types::canister_wallet::CanisterWallet,
     utils::get_canister_state,
 };
@@ -25,6 +30,96 @@ pub struct ICRC1TokenMetadata {
     pub decimals: u8,
     pub fee: u128,
     pub supported_standards: Vec<StandardRecord>,
+    /// Token logo, usually a data URL
+    pub logo: Option<String>,
+    pub max_memo_length: Option<u16>,
+    pub minting_account: Option<Account>,
+    /// Index canister advertised by the ledger (ICRC-106)
+    pub index_canister_id: Option<Principal>,
+}
+
+impl ICRC1TokenMetadata {
+    /// Build the metadata from the entries returned by `icrc1_metadata`.
+    ///
+    /// Name, symbol, decimals and fee are required; a missing or unparseable
+    /// value is reported as an error instead of being replaced by a default.
+    pub fn from_metadata_entries(
+        entries: Vec<(String, MetadataValue)>,
+        supported_standards: Vec<StandardRecord>,
+        minting_account: Option<Account>,
+    ) -> Result<Self, CurrencyError> {
+        let mut name = None;
+        let mut symbol = None;
+        let mut decimals = None;
+        let mut fee = None;
+        let mut logo = None;
+        let mut max_memo_length = None;
+        let mut index_canister_id = None;
+
+        for (key, value) in entries {
+            match key.as_str() {
+                "icrc1:name" => name = Some(metadata_text(&key, value)?),
+                "icrc1:symbol" => symbol = Some(metadata_text(&key, value)?),
+                "icrc1:decimals" => decimals = Some(metadata_nat(&key, value)?),
+                "icrc1:fee" => fee = Some(metadata_nat(&key, value)?),
+                "icrc1:logo" => logo = Some(metadata_text(&key, value)?),
+                "icrc1:max_memo_length" => max_memo_length = Some(metadata_nat(&key, value)?),
+                "icrc106:index_principal" => {
+                    let text = metadata_text(&key, value)?;
+                    index_canister_id = Some(Principal::from_text(&text).map_err(|e| {
+                        CurrencyError::InvalidTokenMetadata(format!(
+                            "{} is not a principal: {}",
+                            key, e
+                        ))
+                    })?);
+                }
+                _ => {}
+            }
+        }
+
+        let missing = |key: &str| CurrencyError::InvalidTokenMetadata(format!("{} is missing", key));
+
+        Ok(Self {
+            name: name.ok_or_else(|| missing("icrc1:name"))?,
+            symbol: symbol.ok_or_else(|| missing("icrc1:symbol"))?,
+            decimals: decimals.ok_or_else(|| missing("icrc1:decimals"))?,
+            fee: fee.ok_or_else(|| missing("icrc1:fee"))?,
+            supported_standards,
+            logo,
+            max_memo_length,
+            minting_account,
+            index_canister_id,
+        })
+    }
+}
+
+fn metadata_text(key: &str, value: MetadataValue) -> Result<String, CurrencyError> {
+    match value {
+        MetadataValue::Text(text) => Ok(text),
+        _ => Err(CurrencyError::InvalidTokenMetadata(format!(
+            "{} is not a text value",
+            key
+        ))),
+    }
+}
+
+fn metadata_nat<T: TryFrom<u128>>(key: &str, value: MetadataValue) -> Result<T, CurrencyError> {
+    let nat = match value {
+        MetadataValue::Nat(nat) => nat,
+        _ => {
+            return Err(CurrencyError::InvalidTokenMetadata(format!(
+                "{} is not a nat value",
+                key
+            )))
+        }
+    };
+
+    nat.0
+        .to_u128()
+        .and_then(|value| T::try_from(value).ok())
+        .ok_or_else(|| {
+            CurrencyError::InvalidTokenMetadata(format!("{} value {} is out of range", key, nat))
+        })
 }
 
 /// Generic wallet for any ICRC-1 token
@@ -46,42 +141,50 @@ impl GenericICRC1TokenWallet {
         })
     }
     
-    /// Query token metadata from the ledger canister
+    /// Create a wallet from metadata that was already queried, e.g. from the token registry
+    pub fn from_metadata(ledger_id: Principal, metadata: ICRC1TokenMetadata) -> Self {
+        Self {
+            ledger_id,
+            metadata,
+        }
+    }
+
+    /// Query token metadata from the ledger canister.
+    ///
+    /// `icrc1_metadata`, `icrc1_supported_standards` and `icrc1_minting_account`
+    /// are queried concurrently.
     pub async fn query_token_metadata(ledger_id: Principal) -> Result<ICRC1TokenMetadata, CurrencyError> {
-        // Query name
-        let (name,): (String,) = ic_cdk::call(ledger_id, "icrc1_name", ())
-            .await
-            .map_err(|e| CurrencyError::CanisterCallFailed(format!("Failed to query token name: {:?}", e)))?;
-        
-        // Query symbol
-        let (symbol,): (String,) = ic_cdk::call(ledger_id, "icrc1_symbol", ())
-            .await
-            .map_err(|e| CurrencyError::CanisterCallFailed(format!("Failed to query token symbol: {:?}", e)))?;
-        
-        // Query decimals
-        let (decimals,): (u8,) = ic_cdk::call(ledger_id, "icrc1_decimals", ())
-            .await
-            .map_err(|e| CurrencyError::CanisterCallFailed(format!("Failed to query token decimals: {:?}", e)))?;
-        
-        // Query fee
-        let (fee,): (candid::Nat,) = ic_cdk::call(ledger_id, "icrc1_fee", ())
-            .await
-            .map_err(|e| CurrencyError::CanisterCallFailed(format!("Failed to query token fee: {:?}", e)))?;
-        
-        // Query supported standards
-        let (standards,): (Vec<StandardRecord>,) = ic_cdk::call(ledger_id, "icrc1_supported_standards", ())
-            .await
-            .map_err(|e| CurrencyError::CanisterCallFailed(format!("Failed to query supported standards: {:?}", e)))?;
-        
-        Ok(ICRC1TokenMetadata {
-            name,
-            symbol,
-            decimals,
-            fee: fee.0.to_u128().unwrap_or(10000), // Default fee if conversion fails
-            supported_standards: standards,
-        })
+        let policy = retry_policy();
+        let metadata_call = retry_query(&policy, |_| async {
+            ic_cdk::call::<(), (Vec<(String, MetadataValue)>,)>(ledger_id, "icrc1_metadata", ())
+                .await
+                .map_err(call_error(ledger_id, "icrc1_metadata"))
+        });
+        let standards_call = retry_query(&policy, |_| async {
+            ic_cdk::call::<(), (Vec<StandardRecord>,)>(ledger_id, "icrc1_supported_standards", ())
+                .await
+                .map_err(call_error(ledger_id, "icrc1_supported_standards"))
+        });
+        let minting_account_call = retry_query(&policy, |_| async {
+            ic_cdk::call::<(), (Option<Account>,)>(ledger_id, "icrc1_minting_account", ())
+                .await
+                .map_err(call_error(ledger_id, "icrc1_minting_account"))
+        });
+
+        let (metadata, standards, minting_account) =
+            futures::join!(metadata_call, standards_call, minting_account_call);
+
+        let (entries,) = metadata?;
+        let (standards,) = standards?;
+        let (minting_account,) = minting_account?;
+
+        ICRC1TokenMetadata::from_metadata_entries(entries, standards, minting_account)
     }
     
+    fn ledger(&self) -> IcLedgerClient {
+        IcLedgerClient::new(self.ledger_id)
+    }
+
     /// Check if the token supports ICRC-2 standard (which includes approve and transfer_from)
     pub fn supports_icrc2(&self) -> bool {
         self.metadata.supported_standards.iter().any(|std| std.name == "ICRC-2")
@@ -98,23 +201,12 @@ impl GenericICRC1TokenWallet {
             ));
         }
         
-        let args = AllowanceArgs {
-            account: Account {
-                owner: from_principal,
-                subaccount: None,
-            },
-            spender: Account {
-                owner: ic_cdk::api::id(),
-                subaccount: None,
-            },
-        };
-
-        let (allowance,): (Allowance,) =
-            ic_cdk::call(self.ledger_id, "icrc2_allowance", (args,))
-                .await
-                .map_err(|e| CurrencyError::AllowanceCheckFailed(format!("{:?}", e)))?;
-
-        Ok(allowance)
+        self.ledger()
+            .allowance(
+                &Account::from(from_principal),
+                &Account::from(ic_cdk::api::canister_self()),
+            )
+            .await
     }
 
     /// Transfer tokens from a user's account to this canister using ICRC-2 transfer_from
@@ -129,40 +221,17 @@ impl GenericICRC1TokenWallet {
             ));
         }
         
-        let canister_account = Account {
-            owner: ic_cdk::api::id(),
-            subaccount: None,
-        };
-
-        let from_account = Account {
-            owner: from_principal,
-            subaccount: None,
-        };
-
         let args = TransferFromArg {
             spender_subaccount: None,
-            from: from_account,
-            to: canister_account,
+            from: Account::from(from_principal),
+            to: Account::from(ic_cdk::api::canister_self()),
             amount: amount.into(),
             fee: Some(self.metadata.fee),
             memo: None,
             created_at_time: Some(ic_cdk::api::time()),
         };
 
-        let (result,): (Result<u128, TransferFromError>,) =
-            ic_cdk::call(self.ledger_id, "icrc2_transfer_from", (args,))
-                .await
-                .map_err(|e| CurrencyError::TransferFromFailed(format!("{:?}", e)))?;
-
-        match result {
-            Ok(block_index) => Ok(block_index),
-            Err(e) => match e {
-                TransferFromError::InsufficientAllowance { .. } => {
-                    Err(CurrencyError::InsufficientAllowance)
-                }
-                _ => Err(CurrencyError::TransferFromFailed(format!("{:?}", e))),
-            },
-        }
+        self.ledger().transfer_from(args).await
     }
 }
 
@@ -180,22 +249,16 @@ impl CanisterWallet for GenericICRC1TokenWallet {
             ));
         }
         
-        // Check the allowance to make sure it's sufficient
-        let allowance = self.check_allowance(from_principal).await?;
-
-        if allowance.allowance < amount as u128 {
-            return Err(CurrencyError::InsufficientAllowance);
-        }
-
-        // Check if the allowance is expired
-        if let Some(expires_at) = allowance.expires_at {
-            if expires_at < ic_cdk::api::time() {
-                return Err(CurrencyError::InsufficientAllowance);
-            }
-        }
-
-        // Transfer the tokens using the allowance
-        let block_index = self.transfer_from(from_principal, amount).await?;
+        // Transfer the tokens using the allowance, once it is known to be sufficient
+        let block_index = deposit_from(
+            &self.ledger(),
+            Account::from(from_principal),
+            Account::from(ic_cdk::api::canister_self()),
+            amount as u128,
+            Some(self.metadata.fee),
+            ic_cdk::api::time(),
+        )
+        .await?;
 
         // Record the transaction
         let tx_id = format!(
@@ -223,23 +286,41 @@ impl CanisterWallet for GenericICRC1TokenWallet {
             ));
         }
         
-        // Check the allowance to make sure it's sufficient
-        let allowance = self.check_allowance(from_principal).await?;
-        
-        if allowance.allowance < amount as u128 {
-            return Err(CurrencyError::InsufficientAllowance);
-        }
-        
-        // Check if the allowance is expired
-        if let Some(expires_at) = allowance.expires_at {
-            if expires_at < ic_cdk::api::time() {
-                return Err(CurrencyError::InsufficientAllowance);
-            }
-        }
-        
+        check_allowance(
+            &self.ledger(),
+            &Account::from(from_principal),
+            &Account::from(ic_cdk::api::canister_self()),
+            (amount as u128).saturating_add(self.metadata.fee),
+            ic_cdk::api::time(),
+        )
+        .await?;
+
         Ok(())
     }
 
+    async fn check_deposit(
+        &self,
+        from_principal: Principal,
+        amount: u64,
+    ) -> Result<DepositCheck, CurrencyError> {
+        if !self.supports_icrc2() {
+            return Err(CurrencyError::OperationNotSupported(format!(
+                "Token {} does not support ICRC-2 (allowance) operations",
+                self.metadata.symbol
+            )));
+        }
+
+        deposit_check(
+            &self.ledger(),
+            &Account::from(from_principal),
+            &Account::from(ic_cdk::api::canister_self()),
+            amount as u128,
+            Some(self.metadata.fee),
+            ic_cdk::api::time(),
+        )
+        .await
+    }
+
     async fn withdraw(
         &self,
         wallet_principal_id: Principal,
@@ -261,33 +342,26 @@ impl CanisterWallet for GenericICRC1TokenWallet {
         Ok(())
     }
 
-    async fn get_balance(&self, principal_id: Principal) -> Result<u128, CurrencyError> {
-        let account = Account {
-            owner: principal_id,
-            subaccount: None,
-        };
-        
-        let (balance,): (candid::Nat,) = ic_cdk::call(
+    async fn withdraw_at(
+        &self,
+        wallet_principal_id: Principal,
+        amount: u64,
+        created_at_time: u64,
+    ) -> Result<u128, CurrencyError> {
+        let default_subaccount = get_canister_state().default_subaccount.0.to_vec();
+
+        transfer_icrc1_at(
             self.ledger_id,
-            "icrc1_balance_of", 
-            (account,)
+            amount,
+            default_subaccount,
+            wallet_principal_id,
+            Some(self.metadata.fee),
+            created_at_time,
         )
         .await
-        .map_err(|e| CurrencyError::LedgerError(
-            format!("Failed to query {} balance: {:?}", self.metadata.symbol, e)
-        ))?;
-        
-        // Convert candid::Nat to u128
-        let balance_str = balance.0.to_string();
-        let balance_u128 = match balance_str.parse::<u128>() {
-            Ok(val) => val,
-            Err(_) => {
-                return Err(CurrencyError::LedgerError(
-                    format!("Failed to convert balance '{}' to u128", balance_str)
-                ));
-            }
-        };
+    }
 
-        Ok(balance_u128)
+    async fn get_balance(&self, principal_id: Principal) -> Result<u128, CurrencyError> {
+        self.ledger().balance_of(&Account::from(principal_id)).await
     }
 }
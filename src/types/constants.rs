@@ -13,3 +13,9 @@ pub const ETH_DECIMALS: u8 = 18;
 pub const BTC_MINTER_CANISTER_ID: &str = "mqygn-kiaaa-aaaar-qaadq-cai";
 pub const BTC_LEDGER_CANISTER_ID: &str = "mxzaz-hqaaa-aaaar-qaada-cai";
 pub const BTC_DECIMALS: u8 = 8;
+
+pub const ICP_INDEX_CANISTER_ID: &str = "qhbym-qaaaa-aaaaa-aaafq-cai";
+pub const BTC_INDEX_CANISTER_ID: &str = "n5wcd-faaaa-aaaar-qaaea-cai";
+pub const ETH_INDEX_CANISTER_ID: &str = "s3zol-vqaaa-aaaar-qacpa-cai";
+pub const USDC_INDEX_CANISTER_ID: &str = "xrs4b-hiaaa-aaaar-qafoa-cai";
+pub const USDT_INDEX_CANISTER_ID: &str = "cefgz-dyaaa-aaaar-qag5a-cai";
@@ -1,12 +1,16 @@
 use std::borrow::Cow;
 
-use candid::{CandidType, Decode, Encode, Principal};
-use ic_ledger_types::DEFAULT_FEE;
+use candid::{CandidType, Principal};
+use ic_ledger_types::{DEFAULT_FEE, MAINNET_LEDGER_CANISTER_ID};
 use ic_stable_structures::{storable::Bound, Storable};
 use serde::{Deserialize, Serialize};
 
 use crate::{
     currency_error::CurrencyError,
+    guard::OperationGuard,
+    icrc1_types::{Account, ApproveArg},
+    ledger_client::{approve_spender, revoke_approval, DepositCheck, IcLedgerClient},
+    stable_storage::{decode_versioned, encode_versioned},
     state::TransactionState,
     types::{
         canister_wallet::CanisterWallet,
@@ -17,35 +21,33 @@ use crate::{
     Currency,
 };
 
-use super::canister_wallets::{btc_token_wallet::CKBTCTokenWallet, icrc1_token_wallet::GenericICRC1TokenWallet};
-
-const MAX_VALUE_SIZE_CURRENCY_MANAGER: u32 = 100000; // Adjust based on your needs
+use super::{
+    canister_wallets::{
+        btc_token_wallet::{BtcDepositUpdate, CKBTCTokenWallet},
+        icrc1_token_wallet::{GenericICRC1TokenWallet, ICRC1TokenMetadata, StandardRecord},
+    },
+    currency::Token,
+    network_config::NetworkConfig,
+    token_registry::ICRC1TokenRegistry,
+    withdrawal_batch::{WithdrawalBatch, WithdrawalBatchReport},
+};
 
 impl Storable for CurrencyManager {
-    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
-        Cow::Owned(Encode!(self).unwrap_or_else(|e| {
-            ic_cdk::println!("CurrencyManager serialization error: {:?}", e);
-            vec![]
-        }))
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(
+            encode_versioned(self)
+                .unwrap_or_else(|e| panic!("CurrencyManager serialization error: {}", e)),
+        )
     }
 
+    /// Traps instead of returning an empty manager, so a value that cannot be read
+    /// aborts the upgrade rather than wiping the configuration.
     fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
-        Decode!(bytes.as_ref(), Self).unwrap_or_else(|e| {
-            ic_cdk::println!("CurrencyManager deserialization error: {:?}", e);
-            // Return empty CurrencyManager as fallback
-            CurrencyManager {
-                icp: None,
-                ckerc20_tokens: vec![],
-                btc: None,
-                generic_icrc1_tokens: vec![],
-            }
-        })
+        decode_versioned(bytes.as_ref())
+            .unwrap_or_else(|e| panic!("CurrencyManager deserialization error: {}", e))
     }
 
-    const BOUND: Bound = Bound::Bounded {
-        max_size: MAX_VALUE_SIZE_CURRENCY_MANAGER,
-        is_fixed_size: false,
-    };
+    const BOUND: Bound = Bound::Unbounded;
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, CandidType)]
@@ -54,6 +56,25 @@ pub struct CurrencyManager {
     pub ckerc20_tokens: Vec<CKERC20TokenWallet>,
     pub btc: Option<CKBTCTokenWallet>,
     pub generic_icrc1_tokens: Vec<GenericICRC1TokenWallet>,
+    /// Source of truth for the metadata of the generic ICRC-1 tokens, `None` for a manager
+    /// stored before the registry existed; it is then seeded from the wallets on first use
+    pub token_registry: Option<ICRC1TokenRegistry>,
+    /// Deployment specific canister ids, `None` uses [`NetworkConfig::mainnet`]
+    pub network: Option<NetworkConfig>,
+}
+
+/// A currency the manager can handle, with the metadata a frontend needs to display it
+#[derive(Debug, Clone, Serialize, Deserialize, CandidType)]
+pub struct SupportedCurrency {
+    pub currency: Currency,
+    pub ledger_id: Principal,
+    pub metadata: ICRC1TokenMetadata,
+}
+
+impl Default for CurrencyManager {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl CurrencyManager {
@@ -63,9 +84,30 @@ impl CurrencyManager {
             ckerc20_tokens: Vec::new(),
             btc: Some(CKBTCTokenWallet::new()),
             generic_icrc1_tokens: Vec::new(),
+            token_registry: Some(ICRC1TokenRegistry::new()),
+            network: None,
         }
     }
 
+    pub fn network_config(&self) -> NetworkConfig {
+        self.network.clone().unwrap_or_default()
+    }
+
+    /// Set the network and point the ckBTC and ckERC20 wallets at its canisters
+    pub fn set_network_config(&mut self, network: NetworkConfig) {
+        for wallet in self.ckerc20_tokens.iter_mut() {
+            if let Some(config) = network.ck_token_config(&wallet.config.token_symbol) {
+                wallet.config = config;
+            }
+        }
+        if let Some(wallet) = self.btc.as_mut() {
+            if let Some(config) = network.ck_token_config(&Currency::BTC) {
+                wallet.config = config;
+            }
+        }
+        self.network = Some(network);
+    }
+
     pub async fn add_currency(&mut self, currency: Currency) -> Result<(), CurrencyError> {
         match currency {
             Currency::ICP => {
@@ -80,12 +122,20 @@ impl CurrencyManager {
                     .iter()
                     .any(|w: &CKERC20TokenWallet| w.config.token_symbol == Currency::CKETHToken(token))
                 {
-                    self.ckerc20_tokens.push(CKERC20TokenWallet::new(token));
+                    let wallet = match self.network_config().ck_token_config(&currency) {
+                        Some(config) => CKERC20TokenWallet::from_config(config),
+                        None => CKERC20TokenWallet::new(token),
+                    };
+                    self.ckerc20_tokens.push(wallet);
                 }
             }
             Currency::BTC => {
                 if self.btc.is_none() {
-                    self.btc = Some(CKBTCTokenWallet::new());
+                    let wallet = match self.network_config().ck_token_config(&currency) {
+                        Some(config) => CKBTCTokenWallet::from_config(config),
+                        None => CKBTCTokenWallet::new(),
+                    };
+                    self.btc = Some(wallet);
                 }
             }
             Currency::GenericICRC1(token) => {
@@ -95,7 +145,9 @@ impl CurrencyManager {
                     .iter()
                     .any(|w: &GenericICRC1TokenWallet| w.metadata.symbol == token.symbol_to_string())
                 {
-                    self.generic_icrc1_tokens.push(GenericICRC1TokenWallet::new(token.ledger_id).await?);
+                    let metadata = self.token_registry_mut().register_token(token.ledger_id).await?;
+                    self.generic_icrc1_tokens
+                        .push(GenericICRC1TokenWallet::from_metadata(token.ledger_id, metadata));
                 }
             }
         }
@@ -117,10 +169,164 @@ impl CurrencyManager {
             Currency::GenericICRC1(token) => {
                 self.generic_icrc1_tokens
                     .retain(|w| w.metadata.symbol != token.symbol_to_string());
+                self.token_registry_mut().deregister_token(&token.ledger_id);
             }
         }
     }
 
+    /// Copy of the token registry, e.g. for [`start_metadata_refresh`](super::token_registry::start_metadata_refresh)
+    pub fn token_registry(&self) -> ICRC1TokenRegistry {
+        match &self.token_registry {
+            Some(registry) => registry.clone(),
+            None => {
+                // Seed the registry with the metadata the wallets already carry
+                let mut registry = ICRC1TokenRegistry::new();
+                for wallet in self.generic_icrc1_tokens.iter() {
+                    registry.register_metadata(wallet.ledger_id, wallet.metadata.clone());
+                }
+                registry
+            }
+        }
+    }
+
+    fn token_registry_mut(&mut self) -> &mut ICRC1TokenRegistry {
+        if self.token_registry.is_none() {
+            self.token_registry = Some(self.token_registry());
+        }
+        self.token_registry.get_or_insert_with(ICRC1TokenRegistry::new)
+    }
+
+    /// Metadata of a token from the registry
+    pub(crate) fn registered_metadata(&self, ledger_id: &Principal) -> Option<ICRC1TokenMetadata> {
+        match &self.token_registry {
+            Some(registry) => registry.get_token_metadata(ledger_id),
+            None => self
+                .generic_icrc1_tokens
+                .iter()
+                .find(|wallet| wallet.ledger_id == *ledger_id)
+                .map(|wallet| wallet.metadata.clone()),
+        }
+    }
+
+    /// Store refreshed token metadata in the registry and the matching wallets.
+    ///
+    /// Pair with [`ICRC1TokenRegistry::fetch_all_metadata`] on a copy of the registry,
+    /// so no borrow of the manager is held across the ledger calls.
+    pub fn apply_token_metadata(&mut self, updates: Vec<(Principal, ICRC1TokenMetadata)>) {
+        for (ledger_id, metadata) in updates {
+            if !self.token_registry_mut().is_token_registered(&ledger_id) {
+                // Removed while the refresh was running
+                continue;
+            }
+
+            if let Some(wallet) = self
+                .generic_icrc1_tokens
+                .iter_mut()
+                .find(|w| w.ledger_id == ledger_id)
+            {
+                wallet.metadata = metadata.clone();
+            }
+            self.token_registry_mut().register_metadata(ledger_id, metadata);
+        }
+    }
+
+    /// List every configured currency with its metadata
+    pub fn supported_currencies(&self) -> Vec<SupportedCurrency> {
+        let mut currencies = Vec::new();
+
+        if self.icp.is_some() {
+            currencies.push(SupportedCurrency {
+                currency: Currency::ICP,
+                ledger_id: MAINNET_LEDGER_CANISTER_ID,
+                metadata: self.known_metadata(
+                    MAINNET_LEDGER_CANISTER_ID,
+                    "Internet Computer",
+                    "ICP",
+                    8,
+                    DEFAULT_FEE.e8s() as u128,
+                ),
+            });
+        }
+
+        for wallet in self.ckerc20_tokens.iter() {
+            let symbol = format!("ck{}", wallet.config.token_symbol);
+            currencies.push(SupportedCurrency {
+                currency: wallet.config.token_symbol,
+                ledger_id: wallet.config.ledger_id,
+                metadata: self.known_metadata(
+                    wallet.config.ledger_id,
+                    &symbol,
+                    &symbol,
+                    wallet.config.decimals,
+                    wallet.config.fee,
+                ),
+            });
+        }
+
+        if let Some(wallet) = &self.btc {
+            currencies.push(SupportedCurrency {
+                currency: Currency::BTC,
+                ledger_id: wallet.config.ledger_id,
+                metadata: self.known_metadata(
+                    wallet.config.ledger_id,
+                    "ckBTC",
+                    "ckBTC",
+                    wallet.config.decimals,
+                    wallet.config.fee,
+                ),
+            });
+        }
+
+        for wallet in self.generic_icrc1_tokens.iter() {
+            let metadata = self
+                .registered_metadata(&wallet.ledger_id)
+                .unwrap_or_else(|| wallet.metadata.clone());
+            currencies.push(SupportedCurrency {
+                currency: Currency::GenericICRC1(Token::from_string(
+                    wallet.ledger_id,
+                    &metadata.symbol,
+                    metadata.decimals,
+                )),
+                ledger_id: wallet.ledger_id,
+                metadata,
+            });
+        }
+
+        currencies
+    }
+
+    /// Registry metadata for a built-in currency, or the static defaults if it was never registered
+    fn known_metadata(
+        &self,
+        ledger_id: Principal,
+        name: &str,
+        symbol: &str,
+        decimals: u8,
+        fee: u128,
+    ) -> ICRC1TokenMetadata {
+        self.registered_metadata(&ledger_id)
+            .unwrap_or_else(|| ICRC1TokenMetadata {
+                name: name.to_string(),
+                symbol: symbol.to_string(),
+                decimals,
+                fee,
+                supported_standards: vec![
+                    StandardRecord {
+                        name: "ICRC-1".to_string(),
+                        url: "https://github.com/dfinity/ICRC-1/tree/main/standards/ICRC-1".to_string(),
+                    },
+                    StandardRecord {
+                        name: "ICRC-2".to_string(),
+                        url: "https://github.com/dfinity/ICRC-1/tree/main/standards/ICRC-2".to_string(),
+                    },
+                ],
+                logo: None,
+                max_memo_length: None,
+                minting_account: None,
+                index_canister_id: None,
+            })
+    }
+
     pub async fn deposit(
         &self,
         transaction_state: &mut TransactionState,
@@ -128,6 +334,8 @@ impl CurrencyManager {
         from_principal: Principal,
         amount: u64,
     ) -> Result<(), CurrencyError> {
+        let _guard = OperationGuard::new(from_principal, *currency)?;
+
         match currency {
             Currency::ICP => match &self.icp {
                 Some(icp) => icp.deposit(transaction_state, from_principal, amount).await,
@@ -198,12 +406,49 @@ impl CurrencyManager {
         }
     }
 
+    /// What a deposit of `amount` needs from `from_principal`, and whether it would succeed
+    pub async fn check_deposit(
+        &self,
+        currency: &Currency,
+        from_principal: Principal,
+        amount: u64,
+    ) -> Result<DepositCheck, CurrencyError> {
+        match currency {
+            Currency::ICP => match &self.icp {
+                Some(wallet) => wallet.check_deposit(from_principal, amount).await,
+                None => Err(CurrencyError::WalletNotSet),
+            },
+            Currency::CKETHToken(token) => {
+                let wallet = self
+                    .ckerc20_tokens
+                    .iter()
+                    .find(|w| w.config.token_symbol == Currency::CKETHToken(*token))
+                    .ok_or(CurrencyError::WalletNotSet)?;
+                wallet.check_deposit(from_principal, amount).await
+            }
+            Currency::BTC => match &self.btc {
+                Some(wallet) => wallet.check_deposit(from_principal, amount).await,
+                None => Err(CurrencyError::WalletNotSet),
+            },
+            Currency::GenericICRC1(token) => {
+                let wallet = self
+                    .generic_icrc1_tokens
+                    .iter()
+                    .find(|w| w.metadata.symbol == token.symbol_to_string())
+                    .ok_or(CurrencyError::WalletNotSet)?;
+                wallet.check_deposit(from_principal, amount).await
+            }
+        }
+    }
+
     pub async fn withdraw(
         &self,
         currency: &Currency,
         wallet_principal_id: Principal,
         amount: u64,
     ) -> Result<(), CurrencyError> {
+        let _guard = OperationGuard::new(wallet_principal_id, *currency)?;
+
         match currency {
             Currency::ICP => match &self.icp {
                 Some(wallet) => wallet.withdraw(wallet_principal_id, amount).await,
@@ -232,12 +477,97 @@ impl CurrencyManager {
         }
     }
 
+    /// Withdraw with a fixed `created_at_time`, returning the ledger block index.
+    ///
+    /// Retrying with the same `created_at_time` is safe: the ledger deduplicates
+    /// the transfer and the original block index is returned. This does not take the
+    /// per-principal guard, so batches can pay the same recipient more than once.
+    pub async fn withdraw_at(
+        &self,
+        currency: &Currency,
+        wallet_principal_id: Principal,
+        amount: u64,
+        created_at_time: u64,
+    ) -> Result<u128, CurrencyError> {
+        match currency {
+            Currency::ICP => match &self.icp {
+                Some(wallet) => {
+                    wallet
+                        .withdraw_at(wallet_principal_id, amount, created_at_time)
+                        .await
+                }
+                None => Err(CurrencyError::WalletNotSet),
+            },
+            Currency::CKETHToken(token) => {
+                let wallet = self
+                    .ckerc20_tokens
+                    .iter()
+                    .find(|w| w.config.token_symbol == Currency::CKETHToken(*token))
+                    .ok_or(CurrencyError::WalletNotSet)?;
+                wallet
+                    .withdraw_at(wallet_principal_id, amount, created_at_time)
+                    .await
+            }
+            Currency::BTC => match &self.btc {
+                Some(wallet) => {
+                    wallet
+                        .withdraw_at(wallet_principal_id, amount, created_at_time)
+                        .await
+                }
+                None => Err(CurrencyError::WalletNotSet),
+            },
+            Currency::GenericICRC1(token) => {
+                let wallet = self
+                    .generic_icrc1_tokens
+                    .iter()
+                    .find(|w| w.metadata.symbol == token.symbol_to_string())
+                    .ok_or(CurrencyError::WalletNotSet)?;
+                wallet
+                    .withdraw_at(wallet_principal_id, amount, created_at_time)
+                    .await
+            }
+        }
+    }
+
+    /// Execute the outstanding items of a withdrawal batch, at most `max_concurrency` at a time.
+    ///
+    /// `persist` is called with the updated batch before every round of transfers is sent and
+    /// after its results are recorded, so the caller can write it to stable memory. If the
+    /// canister traps or is upgraded mid-batch, calling this again with the persisted batch
+    /// resumes it: items that were in flight are retried with their original `created_at_time`
+    /// and are therefore deduplicated by the ledger instead of being paid twice.
+    pub async fn process_withdrawal_batch(
+        &self,
+        batch: &mut WithdrawalBatch,
+        max_concurrency: usize,
+        persist: impl FnMut(&WithdrawalBatch),
+    ) -> WithdrawalBatchReport {
+        batch
+            .process(
+                max_concurrency,
+                ic_cdk::api::time,
+                persist,
+                |request, created_at_time| async move {
+                    self.withdraw_at(
+                        &request.currency,
+                        request.recipient,
+                        request.amount,
+                        created_at_time,
+                    )
+                    .await
+                },
+            )
+            .await
+    }
+
     pub async fn withdraw_rake(
         &self,
         currency: &Currency,
         wallet_principal_id: Principal,
         amount: u64,
     ) -> Result<(), CurrencyError> {
+        let _guard = OperationGuard::new(wallet_principal_id, *currency)?;
+
         match currency {
             Currency::ICP => match &self.icp {
                 Some(wallet) => wallet.withdraw(wallet_principal_id, amount).await,
@@ -295,6 +625,36 @@ impl CurrencyManager {
         }
     }
 
+    /// Get the ledger canister id backing a currency
+    pub fn get_ledger_id(&self, currency: &Currency) -> Result<Principal, CurrencyError> {
+        match currency {
+            Currency::ICP => match &self.icp {
+                Some(_) => Ok(MAINNET_LEDGER_CANISTER_ID),
+                None => Err(CurrencyError::WalletNotSet),
+            },
+            Currency::CKETHToken(token) => {
+                let wallet = self
+                    .ckerc20_tokens
+                    .iter()
+                    .find(|w| w.config.token_symbol == Currency::CKETHToken(*token))
+                    .ok_or(CurrencyError::WalletNotSet)?;
+                Ok(wallet.config.ledger_id)
+            }
+            Currency::BTC => match &self.btc {
+                Some(wallet) => Ok(wallet.config.ledger_id),
+                None => Err(CurrencyError::WalletNotSet),
+            },
+            Currency::GenericICRC1(token) => {
+                let wallet = self
+                    .generic_icrc1_tokens
+                    .iter()
+                    .find(|w| w.metadata.symbol == token.symbol_to_string())
+                    .ok_or(CurrencyError::WalletNotSet)?;
+                Ok(wallet.ledger_id)
+            }
+        }
+    }
+
     pub async fn get_fee(&self, currency: &Currency) -> Result<u128, CurrencyError> {
         match currency {
             Currency::ICP => match &self.icp {
@@ -323,4 +683,58 @@ impl CurrencyManager {
             }
         }
     }
+
+    /// Allow `args.spender` to move up to `args.amount` of a currency out of the
+    /// canister's account, see [`approve_spender`]. Returns the block index.
+    pub async fn approve(
+        &self,
+        currency: &Currency,
+        args: ApproveArg,
+    ) -> Result<u128, CurrencyError> {
+        let ledger = IcLedgerClient::new(self.get_ledger_id(currency)?);
+        approve_spender(&ledger, args, ic_cdk::api::time()).await
+    }
+
+    /// Revoke what the canister's `from_subaccount` approved `spender` for, see
+    /// [`revoke_approval`]. `None` if there was no allowance left.
+    pub async fn revoke_approval(
+        &self,
+        currency: &Currency,
+        from_subaccount: Option<Vec<u8>>,
+        spender: Account,
+    ) -> Result<Option<u128>, CurrencyError> {
+        let ledger = IcLedgerClient::new(self.get_ledger_id(currency)?);
+        let owner = Account {
+            owner: ic_cdk::api::canister_self(),
+            subaccount: from_subaccount,
+        };
+        revoke_approval(&ledger, &owner, spender, ic_cdk::api::time()).await
+    }
+
+    /// Bitcoin address a user sends BTC to, see [`CKBTCTokenWallet::get_deposit_address_for`]
+    pub async fn get_btc_deposit_address(
+        &self,
+        user: Principal,
+        namespace: Option<u16>,
+    ) -> Result<String, CurrencyError> {
+        match &self.btc {
+            Some(btc) => btc.get_deposit_address_for(user, namespace).await,
+            None => Err(CurrencyError::WalletNotSet),
+        }
+    }
+
+    /// Mint ckBTC for a user's new BTC deposits and sweep it into the main account.
+    ///
+    /// The amount to credit the user is `sweep.amount`. A sweep that failed earlier is
+    /// retried by the next call, so nothing minted is ever lost.
+    pub async fn update_btc_deposits(
+        &self,
+        user: Principal,
+        namespace: Option<u16>,
+    ) -> Result<BtcDepositUpdate, CurrencyError> {
+        let btc = self.btc.as_ref().ok_or(CurrencyError::WalletNotSet)?;
+        let mut update = btc.update_balance_for(user, namespace).await?;
+        update.sweep = self.sweep_deposit(&Currency::BTC, user, namespace).await?;
+        Ok(update)
+    }
 }
@@ -3,4 +3,6 @@ pub mod canister_wallets;
 pub mod constants;
 pub mod currency;
 pub mod currency_manager;
+pub mod network_config;
 pub mod token_registry;
+pub mod withdrawal_batch;
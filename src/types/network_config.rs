@@ -0,0 +1,119 @@
+use std::collections::HashMap;
+
+use candid::{CandidType, Principal};
+use ic_ledger_types::MAINNET_LEDGER_CANISTER_ID;
+use serde::{Deserialize, Serialize};
+
+use crate::{address::BitcoinNetwork, Currency};
+
+use super::{
+    canister_wallets::{btc_token_wallet::CKBTCTokenWallet, ckerc20_token_wallet::CKERC20TokenWallet},
+    currency::CKTokenConfig,
+};
+use super::constants::{
+    BTC_INDEX_CANISTER_ID, BTC_LEDGER_CANISTER_ID, ETH_INDEX_CANISTER_ID, ETH_LEDGER_CANISTER_ID,
+    ICP_INDEX_CANISTER_ID, USDC_INDEX_CANISTER_ID, USDC_LEDGER_CANISTER_ID, USDT_INDEX_CANISTER_ID,
+    USDT_LEDGER_CANISTER_ID,
+};
+
+/// Canister ids that differ between mainnet and local or test deployments
+#[derive(Debug, Clone, Serialize, Deserialize, CandidType, PartialEq, Eq)]
+pub struct NetworkConfig {
+    /// Index canister per ledger. Takes precedence over the index advertised
+    /// in the ledger metadata.
+    pub index_canisters: HashMap<Principal, Principal>,
+    /// Network BTC withdrawal addresses are validated for, `None` means mainnet
+    pub bitcoin_network: Option<BitcoinNetwork>,
+    /// Ledger and minter of ckBTC and the ckERC20 tokens, replacing the mainnet
+    /// canisters. The ICP ledger is always expected at its mainnet id.
+    pub ck_tokens: Option<Vec<CKTokenConfig>>,
+}
+
+impl NetworkConfig {
+    /// Configuration without any known canister ids
+    pub fn empty() -> Self {
+        Self {
+            index_canisters: HashMap::new(),
+            bitcoin_network: None,
+            ck_tokens: None,
+        }
+    }
+
+    /// Index canisters of the ledgers supported out of the box on mainnet
+    pub fn mainnet() -> Self {
+        let pairs = [
+            (BTC_LEDGER_CANISTER_ID, BTC_INDEX_CANISTER_ID),
+            (ETH_LEDGER_CANISTER_ID, ETH_INDEX_CANISTER_ID),
+            (USDC_LEDGER_CANISTER_ID, USDC_INDEX_CANISTER_ID),
+            (USDT_LEDGER_CANISTER_ID, USDT_INDEX_CANISTER_ID),
+        ];
+
+        let mut index_canisters: HashMap<Principal, Principal> = pairs
+            .iter()
+            .map(|(ledger, index)| {
+                (
+                    Principal::from_text(ledger).unwrap(),
+                    Principal::from_text(index).unwrap(),
+                )
+            })
+            .collect();
+        index_canisters.insert(
+            MAINNET_LEDGER_CANISTER_ID,
+            Principal::from_text(ICP_INDEX_CANISTER_ID).unwrap(),
+        );
+
+        Self {
+            index_canisters,
+            bitcoin_network: None,
+            ck_tokens: None,
+        }
+    }
+
+    pub fn with_index_canister(mut self, ledger_id: Principal, index_id: Principal) -> Self {
+        self.index_canisters.insert(ledger_id, index_id);
+        self
+    }
+
+    pub fn with_bitcoin_network(mut self, network: BitcoinNetwork) -> Self {
+        self.bitcoin_network = Some(network);
+        self
+    }
+
+    /// Use `config` for the ckBTC or ckERC20 token named by `config.token_symbol`
+    pub fn with_ck_token(mut self, config: CKTokenConfig) -> Self {
+        let ck_tokens = self.ck_tokens.get_or_insert_with(Vec::new);
+        ck_tokens.retain(|c| c.token_symbol != config.token_symbol);
+        ck_tokens.push(config);
+        self
+    }
+
+    pub fn bitcoin_network(&self) -> BitcoinNetwork {
+        self.bitcoin_network.unwrap_or(BitcoinNetwork::Mainnet)
+    }
+
+    pub fn index_canister(&self, ledger_id: &Principal) -> Option<Principal> {
+        self.index_canisters.get(ledger_id).copied()
+    }
+
+    /// Ledger and minter of a ckBTC or ckERC20 currency, the mainnet ones unless overridden
+    pub fn ck_token_config(&self, currency: &Currency) -> Option<CKTokenConfig> {
+        let configured = self
+            .ck_tokens
+            .iter()
+            .flatten()
+            .find(|c| c.token_symbol == *currency)
+            .copied();
+
+        configured.or(match currency {
+            Currency::BTC => Some(CKBTCTokenWallet::new().config),
+            Currency::CKETHToken(token) => Some(CKERC20TokenWallet::new(*token).config),
+            _ => None,
+        })
+    }
+}
+
+impl Default for NetworkConfig {
+    fn default() -> Self {
+        Self::mainnet()
+    }
+}
@@ -1,11 +1,13 @@
 use std::collections::HashMap;
-use candid::{CandidType, Decode, Encode, Principal};
+use candid::{CandidType, Principal};
 use serde::{Deserialize, Serialize};
 use ic_stable_structures::{Storable, storable::Bound};
-use std::borrow::Cow;
+use std::{borrow::Cow, rc::Rc, time::Duration};
+use ic_cdk_timers::TimerId;
 
 use crate::{
-    currency_error::CurrencyError, 
+    currency_error::CurrencyError,
+    stable_storage::{decode_versioned, encode_versioned},
     types::currency::{Currency, CKTokenSymbol},
 };
 
@@ -56,6 +58,39 @@ impl ICRC1TokenRegistry {
         Ok(metadata)
     }
     
+    /// Register a token with metadata that was already queried
+    pub fn register_metadata(&mut self, ledger_id: Principal, metadata: ICRC1TokenMetadata) {
+        let ledger_id_str = ledger_id.to_string();
+
+        // Drop the previous symbol mapping in case the symbol changed
+        if let Some(previous) = self.tokens.get(&ledger_id_str) {
+            self.symbol_to_canister.remove(&previous.symbol);
+        }
+
+        self.symbol_to_canister.insert(metadata.symbol.clone(), ledger_id_str.clone());
+        self.tokens.insert(ledger_id_str, metadata);
+    }
+
+    /// Remove a token, returns its metadata if it was registered
+    pub fn deregister_token(&mut self, ledger_id: &Principal) -> Option<ICRC1TokenMetadata> {
+        let metadata = self.tokens.remove(&ledger_id.to_string())?;
+        self.symbol_to_canister.remove(&metadata.symbol);
+        Some(metadata)
+    }
+
+    /// Query the current metadata of every registered token.
+    ///
+    /// This only reads `self`, so it can run on a copy of the registry while the
+    /// results are applied afterwards with [`Self::register_metadata`].
+    pub async fn fetch_all_metadata(&self) -> Vec<(Principal, Result<ICRC1TokenMetadata, CurrencyError>)> {
+        let mut results = Vec::with_capacity(self.tokens.len());
+        for (ledger_id, _) in self.get_all_tokens() {
+            let metadata = GenericICRC1TokenWallet::query_token_metadata(ledger_id).await;
+            results.push((ledger_id, metadata));
+        }
+        results
+    }
+
     /// Check if a token is already registered by ledger ID
     pub fn is_token_registered(&self, ledger_id: &Principal) -> bool {
         self.tokens.contains_key(&ledger_id.to_string())
@@ -105,19 +140,50 @@ impl ICRC1TokenRegistry {
     }
 }
 
+/// Start a timer that periodically refreshes the metadata of all registered tokens.
+///
+/// `registry` provides a copy of the current registry for every run (`None` skips the run)
+/// and `on_refreshed` receives the metadata that could be fetched, typically to pass it to
+/// [`CurrencyManager::apply_token_metadata`](super::currency_manager::CurrencyManager::apply_token_metadata).
+pub fn start_metadata_refresh(
+    interval: Duration,
+    registry: impl Fn() -> Option<ICRC1TokenRegistry> + 'static,
+    on_refreshed: impl Fn(Vec<(Principal, ICRC1TokenMetadata)>) + 'static,
+) -> TimerId {
+    let registry = Rc::new(registry);
+    let on_refreshed = Rc::new(on_refreshed);
+
+    ic_cdk_timers::set_timer_interval(interval, move || {
+        let registry = registry.clone();
+        let on_refreshed = on_refreshed.clone();
+        ic_cdk::futures::spawn(async move {
+            let Some(registry) = registry() else {
+                return;
+            };
+
+            let mut updates = Vec::new();
+            for (ledger_id, result) in registry.fetch_all_metadata().await {
+                match result {
+                    Ok(metadata) => updates.push((ledger_id, metadata)),
+                    Err(e) => ic_cdk::println!("Failed to refresh metadata of {}: {:?}", ledger_id, e),
+                }
+            }
+            on_refreshed(updates);
+        });
+    })
+}
+
 impl Storable for ICRC1TokenRegistry {
-    fn to_bytes(&self) -> Cow<[u8]> {
-        Cow::Owned(Encode!(self).unwrap_or_else(|e| {
-            ic_cdk::println!("TokenRegistry serialization error: {:?}", e);
-            vec![]
-        }))
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(
+            encode_versioned(self)
+                .unwrap_or_else(|e| panic!("TokenRegistry serialization error: {}", e)),
+        )
     }
 
     fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        Decode!(bytes.as_ref(), Self).unwrap_or_else(|e| {
-            ic_cdk::println!("TokenRegistry deserialization error: {:?}", e);
-            Self::new()
-        })
+        decode_versioned(bytes.as_ref())
+            .unwrap_or_else(|e| panic!("TokenRegistry deserialization error: {}", e))
     }
 
     const BOUND: Bound = Bound::Bounded {
@@ -0,0 +1,233 @@
+use std::{borrow::Cow, future::Future};
+
+use candid::{CandidType, Decode, Encode, Principal};
+use futures::future::join_all;
+use ic_stable_structures::{storable::Bound, Storable};
+use serde::{Deserialize, Serialize};
+
+use crate::{currency_error::CurrencyError, Currency};
+
+const MAX_VALUE_SIZE_WITHDRAWAL_BATCH: u32 = 2_000_000;
+
+/// Number of withdrawals executed concurrently when the caller has no preference
+pub const DEFAULT_BATCH_CONCURRENCY: usize = 10;
+
+/// A single payout requested as part of a batch
+#[derive(Debug, Clone, Serialize, Deserialize, CandidType, PartialEq, Eq)]
+pub struct WithdrawalRequest {
+    pub currency: Currency,
+    pub recipient: Principal,
+    pub amount: u64,
+}
+
+/// Progress of a single payout inside a batch.
+///
+/// Once an item has been sent it keeps its `created_at_time` forever, so every
+/// retry is deduplicated by the ledger and can never pay the recipient twice.
+#[derive(Debug, Clone, Serialize, Deserialize, CandidType, PartialEq, Eq)]
+pub enum WithdrawalItemStatus {
+    /// Not attempted yet
+    Pending,
+    /// Sent to the ledger, outcome not recorded yet (e.g. the canister trapped or was upgraded)
+    InFlight { created_at_time: u64 },
+    /// Executed by the ledger
+    Completed { block_index: u128 },
+    /// Rejected, can be retried with [`WithdrawalBatch::retry_failed`]
+    Failed {
+        error: CurrencyError,
+        created_at_time: u64,
+    },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, CandidType, PartialEq, Eq)]
+pub struct WithdrawalBatchItem {
+    pub request: WithdrawalRequest,
+    pub status: WithdrawalItemStatus,
+    pub attempts: u32,
+}
+
+impl WithdrawalBatchItem {
+    /// Whether the item still has to be sent to the ledger
+    pub fn is_outstanding(&self) -> bool {
+        matches!(
+            self.status,
+            WithdrawalItemStatus::Pending | WithdrawalItemStatus::InFlight { .. }
+        )
+    }
+}
+
+/// A set of payouts executed together, e.g. at the end of a tournament.
+///
+/// The batch is meant to be kept in stable memory by the caller and handed to
+/// [`CurrencyManager::process_withdrawal_batch`](super::currency_manager::CurrencyManager::process_withdrawal_batch)
+/// together with a persist callback. Calling it again after a trap or an upgrade
+/// resumes where the previous run stopped.
+#[derive(Debug, Clone, Serialize, Deserialize, CandidType, PartialEq, Eq)]
+pub struct WithdrawalBatch {
+    pub id: u64,
+    pub items: Vec<WithdrawalBatchItem>,
+}
+
+impl WithdrawalBatch {
+    pub fn new(id: u64, requests: Vec<WithdrawalRequest>) -> Self {
+        Self {
+            id,
+            items: requests
+                .into_iter()
+                .map(|request| WithdrawalBatchItem {
+                    request,
+                    status: WithdrawalItemStatus::Pending,
+                    attempts: 0,
+                })
+                .collect(),
+        }
+    }
+
+    /// True once every item is either completed or failed
+    pub fn is_finished(&self) -> bool {
+        !self.items.iter().any(|item| item.is_outstanding())
+    }
+
+    /// Schedule failed items for another attempt, reusing their original `created_at_time`
+    pub fn retry_failed(&mut self) {
+        for item in self.items.iter_mut() {
+            if let WithdrawalItemStatus::Failed {
+                created_at_time, ..
+            } = item.status
+            {
+                item.status = WithdrawalItemStatus::InFlight { created_at_time };
+            }
+        }
+    }
+
+    /// Execute the outstanding items, at most `max_concurrency` at a time, through
+    /// `withdraw_at`, which is given each request with its `created_at_time`.
+    ///
+    /// See [`CurrencyManager::process_withdrawal_batch`](super::currency_manager::CurrencyManager::process_withdrawal_batch),
+    /// which runs it against the manager's wallets.
+    pub async fn process<F, Fut>(
+        &mut self,
+        max_concurrency: usize,
+        now: impl Fn() -> u64,
+        mut persist: impl FnMut(&WithdrawalBatch),
+        withdraw_at: F,
+    ) -> WithdrawalBatchReport
+    where
+        F: Fn(WithdrawalRequest, u64) -> Fut,
+        Fut: Future<Output = Result<u128, CurrencyError>>,
+    {
+        let max_concurrency = max_concurrency.max(1);
+
+        loop {
+            let indices: Vec<usize> = self
+                .items
+                .iter()
+                .enumerate()
+                .filter(|(_, item)| item.is_outstanding())
+                .map(|(index, _)| index)
+                .take(max_concurrency)
+                .collect();
+
+            if indices.is_empty() {
+                break;
+            }
+
+            // Record the intent before any call is made. Each item gets a distinct
+            // created_at_time so identical payouts within a round are not deduplicated.
+            let now = now();
+            let mut round = Vec::with_capacity(indices.len());
+            for (offset, &index) in indices.iter().enumerate() {
+                let item = &mut self.items[index];
+                let created_at_time = match item.status {
+                    WithdrawalItemStatus::InFlight { created_at_time } => created_at_time,
+                    _ => now + offset as u64,
+                };
+                item.status = WithdrawalItemStatus::InFlight { created_at_time };
+                item.attempts += 1;
+                round.push((index, item.request.clone(), created_at_time));
+            }
+            persist(self);
+
+            let results = join_all(
+                round
+                    .iter()
+                    .map(|(_, request, created_at_time)| {
+                        withdraw_at(request.clone(), *created_at_time)
+                    }),
+            )
+            .await;
+
+            for ((index, _, created_at_time), result) in round.into_iter().zip(results) {
+                self.items[index].status = match result {
+                    Ok(block_index) => WithdrawalItemStatus::Completed { block_index },
+                    Err(error) => WithdrawalItemStatus::Failed {
+                        error,
+                        created_at_time,
+                    },
+                };
+            }
+            persist(self);
+        }
+
+        self.report()
+    }
+
+    pub fn report(&self) -> WithdrawalBatchReport {
+        let mut report = WithdrawalBatchReport {
+            batch_id: self.id,
+            items: Vec::with_capacity(self.items.len()),
+            completed: 0,
+            failed: 0,
+            outstanding: 0,
+        };
+
+        for (index, item) in self.items.iter().enumerate() {
+            match item.status {
+                WithdrawalItemStatus::Completed { .. } => report.completed += 1,
+                WithdrawalItemStatus::Failed { .. } => report.failed += 1,
+                _ => report.outstanding += 1,
+            }
+            report.items.push(WithdrawalItemReport {
+                index: index as u32,
+                request: item.request.clone(),
+                status: item.status.clone(),
+                attempts: item.attempts,
+            });
+        }
+
+        report
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, CandidType, PartialEq, Eq)]
+pub struct WithdrawalItemReport {
+    pub index: u32,
+    pub request: WithdrawalRequest,
+    pub status: WithdrawalItemStatus,
+    pub attempts: u32,
+}
+
+/// Per-item outcome of a batch
+#[derive(Debug, Clone, Serialize, Deserialize, CandidType, PartialEq, Eq)]
+pub struct WithdrawalBatchReport {
+    pub batch_id: u64,
+    pub items: Vec<WithdrawalItemReport>,
+    pub completed: u32,
+    pub failed: u32,
+    pub outstanding: u32,
+}
+
+impl Storable for WithdrawalBatch {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).expect("Failed to encode withdrawal batch"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).expect("Failed to decode withdrawal batch")
+    }
+
+    const BOUND: Bound = Bound::Bounded {
+        max_size: MAX_VALUE_SIZE_WITHDRAWAL_BATCH,
+        is_fixed_size: false,
+    };
+}
@@ -0,0 +1,321 @@
+use currency::address::{
+    eth_checksum_address, validate_btc_address, validate_eth_address, AddressError,
+    BitcoinAddressType, BitcoinNetwork,
+};
+
+// EIP-55 test vectors
+const EIP55_CHECKSUMMED: [&str; 4] = [
+    "0x5aAeb6053F3E94C9b9A09f33669435E7Ef1BeAed",
+    "0xfB6916095ca1df60bB79Ce92cE3Ea74c37c5d359",
+    "0xdbF03B407c01E7cD3CBea99509d93f8DDDC8C6FB",
+    "0xD1220A0cf47c7B9Be7A2E6BA89F429762e7b9aDb",
+];
+const EIP55_ALL_CAPS: [&str; 2] = [
+    "0x52908400098527886E0F7030069857D2E4169EE7",
+    "0x8617E340B3D01FA5F11F306F4090FD50E238070D",
+];
+const EIP55_ALL_LOWER: [&str; 2] = [
+    "0xde709f2102306220921060314715629080e2fb77",
+    "0x27b1fdb04752bbc536007a920d24acb045561c26",
+];
+
+#[test]
+fn eip55_vectors_are_checksummed() {
+    for address in EIP55_CHECKSUMMED
+        .iter()
+        .chain(EIP55_ALL_CAPS.iter())
+        .chain(EIP55_ALL_LOWER.iter())
+    {
+        let bytes = validate_eth_address(address).unwrap();
+        assert_eq!(
+            eth_checksum_address(&bytes).to_lowercase(),
+            address.to_lowercase()
+        );
+    }
+
+    for address in EIP55_CHECKSUMMED {
+        let bytes = validate_eth_address(address).unwrap();
+        assert_eq!(eth_checksum_address(&bytes), address);
+    }
+}
+
+#[test]
+fn single_letter_in_the_wrong_case_fails_the_checksum() {
+    for address in EIP55_CHECKSUMMED {
+        let last = address.rfind(|c: char| c.is_ascii_alphabetic()).unwrap();
+        let flipped = match address.as_bytes()[last] {
+            c if c.is_ascii_lowercase() => c.to_ascii_uppercase(),
+            c => c.to_ascii_lowercase(),
+        };
+        let address = format!(
+            "{}{}{}",
+            &address[..last],
+            flipped as char,
+            &address[last + 1..]
+        );
+
+        assert_eq!(
+            validate_eth_address(&address),
+            Err(AddressError::InvalidChecksum)
+        );
+    }
+}
+
+#[test]
+fn malformed_eth_addresses_are_rejected() {
+    assert_eq!(validate_eth_address(""), Err(AddressError::Empty));
+    assert!(matches!(
+        validate_eth_address("5aAeb6053F3E94C9b9A09f33669435E7Ef1BeAed"),
+        Err(AddressError::Malformed(_))
+    ));
+    assert!(matches!(
+        validate_eth_address("0x5aAeb6053F3E94C9b9A09f33669435E7Ef1BeAeg"),
+        Err(AddressError::Malformed(_))
+    ));
+    assert_eq!(
+        validate_eth_address("0x5aaeb6053f3e94c9b9a09f33669435e7ef1bea"),
+        Err(AddressError::InvalidLength {
+            expected: 20,
+            found: 19
+        })
+    );
+}
+
+#[test]
+fn bip173_and_bip350_valid_addresses() {
+    let valid = [
+        // BIP-173
+        (
+            "BC1QW508D6QEJXTDG4Y5R3ZARVARY0C5XW7KV8F3T4",
+            BitcoinNetwork::Mainnet,
+            BitcoinAddressType::P2wpkh,
+        ),
+        (
+            "tb1qrp33g0q5c5txsp9arysrx4k6zdkfs4nce4xj0gdcccefvpysxf3q0sl5k7",
+            BitcoinNetwork::Testnet,
+            BitcoinAddressType::P2wsh,
+        ),
+        (
+            "tb1qqqqqp399et2xygdj5xreqhjjvcmzhxw4aywxecjdzew6hylgvsesrxh6hy",
+            BitcoinNetwork::Testnet,
+            BitcoinAddressType::P2wsh,
+        ),
+        // BIP-350
+        (
+            "bc1p0xlxvlhemja6c4dqv22uapctqupfhlxm9h8z3k2e72q4k9hcz7vqzk5jj0",
+            BitcoinNetwork::Mainnet,
+            BitcoinAddressType::P2tr,
+        ),
+        (
+            "tb1pqqqqp399et2xygdj5xreqhjjvcmzhxw4aywxecjdzew6hylgvsesf3hn0c",
+            BitcoinNetwork::Testnet,
+            BitcoinAddressType::P2tr,
+        ),
+    ];
+
+    for (address, network, address_type) in valid {
+        assert_eq!(
+            validate_btc_address(address, network),
+            Ok(address_type),
+            "{}",
+            address
+        );
+    }
+}
+
+#[test]
+fn bip350_valid_addresses_of_unsupported_programs() {
+    for address in [
+        "bc1pw508d6qejxtdg4y5r3zarvary0c5xw7kw508d6qejxtdg4y5r3zarvary0c5xw7kt5nd6y",
+        "BC1SW50QGDZ25J",
+        "bc1zw508d6qejxtdg4y5r3zarvaryvaxxpcs",
+    ] {
+        assert!(
+            matches!(
+                validate_btc_address(address, BitcoinNetwork::Mainnet),
+                Err(AddressError::UnsupportedAddressType(_))
+            ),
+            "{}",
+            address
+        );
+    }
+}
+
+#[test]
+fn wrong_checksum_variant_for_the_witness_version_is_rejected() {
+    let wrong_variant = [
+        // Version 1 and up with a bech32 checksum
+        (
+            "bc1p0xlxvlhemja6c4dqv22uapctqupfhlxm9h8z3k2e72q4k9hcz7vqh2y7hd",
+            BitcoinNetwork::Mainnet,
+        ),
+        (
+            "tb1z0xlxvlhemja6c4dqv22uapctqupfhlxm9h8z3k2e72q4k9hcz7vqglt7rf",
+            BitcoinNetwork::Testnet,
+        ),
+        (
+            "BC1S0XLXVLHEMJA6C4DQV22UAPCTQUPFHLXM9H8Z3K2E72Q4K9HCZ7VQ54WELL",
+            BitcoinNetwork::Mainnet,
+        ),
+        // Version 0 with a bech32m checksum
+        (
+            "bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kemeawh",
+            BitcoinNetwork::Mainnet,
+        ),
+        (
+            "tb1q0xlxvlhemja6c4dqv22uapctqupfhlxm9h8z3k2e72q4k9hcz7vq24jc47",
+            BitcoinNetwork::Testnet,
+        ),
+    ];
+
+    for (address, network) in wrong_variant {
+        assert_eq!(
+            validate_btc_address(address, network),
+            Err(AddressError::InvalidChecksum),
+            "{}",
+            address
+        );
+    }
+}
+
+#[test]
+fn bip173_and_bip350_invalid_addresses() {
+    for address in [
+        // Invalid human readable part
+        "tc1p0xlxvlhemja6c4dqv22uapctqupfhlxm9h8z3k2e72q4k9hcz7vq5zuyut",
+        // Invalid character
+        "bc1p38j9r5y49hruaue7wxjce0updqjuyyx0kh56v8s25huc6995vvpql3jow4",
+        // Invalid witness version
+        "BC130XLXVLHEMJA6C4DQV22UAPCTQUPFHLXM9H8Z3K2E72Q4K9HCZ7VQ7ZWS8R",
+        // Invalid program lengths
+        "bc1pw5dgrnzv",
+        "BC1QR508D6QEJXTDG4Y5R3ZARVARYV98GJ9P",
+        // Mixed case
+        "tb1qrp33g0q5c5txsp9arysrx4k6zdkfs4nce4xj0gdcccefvpysxf3q0sL5k7",
+        // Empty data section
+        "bc1gmk9yu",
+    ] {
+        assert!(
+            validate_btc_address(address, BitcoinNetwork::Mainnet).is_err(),
+            "{}",
+            address
+        );
+    }
+}
+
+#[test]
+fn segwit_address_of_another_network_is_rejected() {
+    assert_eq!(
+        validate_btc_address(
+            "bc1p0xlxvlhemja6c4dqv22uapctqupfhlxm9h8z3k2e72q4k9hcz7vqzk5jj0",
+            BitcoinNetwork::Testnet
+        ),
+        Err(AddressError::WrongNetwork {
+            expected: BitcoinNetwork::Testnet,
+            found: BitcoinNetwork::Mainnet,
+        })
+    );
+}
+
+#[test]
+fn base58_addresses() {
+    let valid = [
+        (
+            "1BvBMSEYstWetqTFn5Au4m4GFg7xJaNVN2",
+            BitcoinNetwork::Mainnet,
+            BitcoinAddressType::P2pkh,
+        ),
+        (
+            "3J98t1WpEZ73CNmQviecrnyiWrnqRhWNLy",
+            BitcoinNetwork::Mainnet,
+            BitcoinAddressType::P2sh,
+        ),
+        (
+            "mipcBbFg9gMiCh81Kj8tqqdgoZub1ZJRfn",
+            BitcoinNetwork::Testnet,
+            BitcoinAddressType::P2pkh,
+        ),
+        (
+            "2MzQwSSnBHWHqSAqtTVQ6v47XtaisrJa1Vc",
+            BitcoinNetwork::Testnet,
+            BitcoinAddressType::P2sh,
+        ),
+    ];
+
+    for (address, network, address_type) in valid {
+        assert_eq!(
+            validate_btc_address(address, network),
+            Ok(address_type),
+            "{}",
+            address
+        );
+    }
+}
+
+#[test]
+fn base58_address_with_a_bad_checksum_is_rejected() {
+    for (address, network) in [
+        (
+            "1BvBMSEYstWetqTFn5Au4m4GFg7xJaNVN3",
+            BitcoinNetwork::Mainnet,
+        ),
+        (
+            "3J98t1WpEZ73CNmQviecrnyiWrnqRhWNLz",
+            BitcoinNetwork::Mainnet,
+        ),
+        (
+            "mipcBbFg9gMiCh81Kj8tqqdgoZub1ZJRfo",
+            BitcoinNetwork::Testnet,
+        ),
+    ] {
+        assert_eq!(
+            validate_btc_address(address, network),
+            Err(AddressError::InvalidChecksum),
+            "{}",
+            address
+        );
+    }
+}
+
+#[test]
+fn base58_address_of_another_network_is_rejected() {
+    assert_eq!(
+        validate_btc_address(
+            "1BvBMSEYstWetqTFn5Au4m4GFg7xJaNVN2",
+            BitcoinNetwork::Testnet
+        ),
+        Err(AddressError::WrongNetwork {
+            expected: BitcoinNetwork::Testnet,
+            found: BitcoinNetwork::Mainnet,
+        })
+    );
+    assert_eq!(
+        validate_btc_address(
+            "mipcBbFg9gMiCh81Kj8tqqdgoZub1ZJRfn",
+            BitcoinNetwork::Mainnet
+        ),
+        Err(AddressError::WrongNetwork {
+            expected: BitcoinNetwork::Mainnet,
+            found: BitcoinNetwork::Testnet,
+        })
+    );
+}
+
+#[test]
+fn base58_address_of_the_wrong_length_is_rejected() {
+    assert!(matches!(
+        validate_btc_address("1BvBMSEYstWetqTFn5Au4m4GFg7xJaNV", BitcoinNetwork::Mainnet),
+        Err(AddressError::InvalidLength { expected: 25, .. })
+    ));
+    assert!(matches!(
+        validate_btc_address(
+            "1BvBMSEYstWetqTFn5Au4m4GFg7xJaNVN0",
+            BitcoinNetwork::Mainnet
+        ),
+        Err(AddressError::Malformed(_))
+    ));
+    assert_eq!(
+        validate_btc_address("", BitcoinNetwork::Mainnet),
+        Err(AddressError::Empty)
+    );
+}
@@ -0,0 +1,476 @@
+//! In-memory ICRC-1/2/3 ledger.
+//!
+//! Follows the reference ledger closely enough for the crate's flows: fees are
+//! checked and burned, allowances expire and are debited by amount plus fee,
+//! transfers and approvals carrying a `created_at_time` are deduplicated for 24 hours and
+//! every operation is appended to an ICRC-3 block log.
+
+use std::{cell::RefCell, collections::HashMap};
+
+use candid::Principal;
+use currency::{
+    currency_error::{CallError, CallRejectCode, CurrencyError, LedgerRejection},
+    icrc1_types::{Account, Allowance, ApproveArg, TransferArg, TransferFromArg},
+    icrc3::{Icrc3Block, Icrc3BlockRange, Icrc3Operation},
+    ledger_client::LedgerClient,
+};
+
+pub const NANOS_PER_SEC: u64 = 1_000_000_000;
+pub const TX_WINDOW: u64 = 24 * 60 * 60 * NANOS_PER_SEC;
+pub const PERMITTED_DRIFT: u64 = 60 * NANOS_PER_SEC;
+
+type AccountKey = (Principal, [u8; 32]);
+
+fn key(account: &Account) -> AccountKey {
+    let mut subaccount = [0; 32];
+    if let Some(bytes) = &account.subaccount {
+        subaccount[..bytes.len()].copy_from_slice(bytes);
+    }
+    (account.owner, subaccount)
+}
+
+/// A transfer, with or without a spender. Two transactions are duplicates
+/// when they are equal and carry a `created_at_time`.
+struct Tx {
+    from: Account,
+    to: Account,
+    spender: Option<Account>,
+    amount: u128,
+    fee: Option<u128>,
+    memo: Option<Vec<u8>>,
+    created_at_time: Option<u64>,
+}
+
+impl Tx {
+    /// Compare accounts the way the ledger does, a missing subaccount is the zero one
+    fn same_as(&self, other: &Tx) -> bool {
+        key(&self.from) == key(&other.from)
+            && key(&self.to) == key(&other.to)
+            && self.spender.as_ref().map(key) == other.spender.as_ref().map(key)
+            && self.amount == other.amount
+            && self.fee == other.fee
+            && self.memo == other.memo
+            && self.created_at_time == other.created_at_time
+    }
+}
+
+#[derive(Default)]
+struct State {
+    time: u64,
+    balances: HashMap<AccountKey, u128>,
+    allowances: HashMap<(AccountKey, AccountKey), (u128, Option<u64>)>,
+    blocks: Vec<Icrc3Block>,
+    recent: Vec<(Tx, u64)>,
+    recent_approvals: Vec<(ApproveArg, u64)>,
+    lose_next_reply: bool,
+}
+
+pub struct MockLedger {
+    ledger_id: Principal,
+    /// The principal making the calls, the canister under test
+    caller: Principal,
+    fee: u128,
+    state: RefCell<State>,
+}
+
+impl MockLedger {
+    pub fn new(caller: Principal, fee: u128) -> Self {
+        Self {
+            ledger_id: Principal::from_slice(&[0xAA; 10]),
+            caller,
+            fee,
+            state: RefCell::new(State {
+                time: 1_700_000_000 * NANOS_PER_SEC,
+                ..Default::default()
+            }),
+        }
+    }
+
+    pub fn time(&self) -> u64 {
+        self.state.borrow().time
+    }
+
+    pub fn advance_time(&self, nanos: u64) {
+        self.state.borrow_mut().time += nanos;
+    }
+
+    pub fn balance(&self, account: &Account) -> u128 {
+        self.state
+            .borrow()
+            .balances
+            .get(&key(account))
+            .copied()
+            .unwrap_or(0)
+    }
+
+    /// Sum of all balances, minted tokens minus burned fees
+    pub fn total_supply(&self) -> u128 {
+        self.state.borrow().balances.values().sum()
+    }
+
+    pub fn blocks(&self) -> Vec<Icrc3Block> {
+        self.state.borrow().blocks.clone()
+    }
+
+    pub fn mint(&self, to: &Account, amount: u128) {
+        let mut state = self.state.borrow_mut();
+        *state.balances.entry(key(to)).or_default() += amount;
+        Self::push_block(
+            &mut state,
+            Icrc3Operation::Mint {
+                to: to.clone(),
+                amount,
+            },
+            None,
+            None,
+        );
+    }
+
+    /// `from` approves `spender`, paying the fee
+    pub fn approve(
+        &self,
+        from: &Account,
+        spender: &Account,
+        amount: u128,
+        expires_at: Option<u64>,
+    ) {
+        let mut state = self.state.borrow_mut();
+        let balance = state.balances.entry(key(from)).or_default();
+        *balance = balance
+            .checked_sub(self.fee)
+            .expect("approver cannot pay the fee");
+        state
+            .allowances
+            .insert((key(from), key(spender)), (amount, expires_at));
+        Self::push_block(
+            &mut state,
+            Icrc3Operation::Approve {
+                from: from.clone(),
+                spender: spender.clone(),
+                amount,
+                expected_allowance: None,
+                expires_at,
+            },
+            Some(self.fee),
+            None,
+        );
+    }
+
+    /// Execute the next transfer or approval but lose the reply, like a call timing out
+    pub fn lose_next_reply(&self) {
+        self.state.borrow_mut().lose_next_reply = true;
+    }
+
+    fn push_block(
+        state: &mut State,
+        operation: Icrc3Operation,
+        fee: Option<u128>,
+        created_at_time: Option<u64>,
+    ) -> u64 {
+        let index = state.blocks.len() as u64;
+        let timestamp = state.time;
+        state.blocks.push(Icrc3Block {
+            index,
+            timestamp,
+            operation,
+            fee,
+            memo: None,
+            created_at_time,
+        });
+        index
+    }
+
+    fn rejected(rejection: LedgerRejection) -> CurrencyError {
+        CurrencyError::LedgerRejected(rejection)
+    }
+
+    /// Checks shared by `icrc1_transfer` and `icrc2_transfer_from`, then the transfer itself
+    fn execute(&self, method: &str, tx: Tx) -> Result<u128, CurrencyError> {
+        let mut state = self.state.borrow_mut();
+        let now = state.time;
+
+        if tx.fee.is_some_and(|fee| fee != self.fee) {
+            return Err(Self::rejected(LedgerRejection::BadFee {
+                expected_fee: self.fee,
+            }));
+        }
+
+        if let Some(created_at_time) = tx.created_at_time {
+            Self::check_created_at(now, created_at_time)?;
+
+            state
+                .recent
+                .retain(|(recent, _)| !Self::left_window(now, recent.created_at_time));
+            if let Some((_, index)) = state.recent.iter().find(|(recent, _)| recent.same_as(&tx)) {
+                return Err(Self::rejected(LedgerRejection::Duplicate {
+                    duplicate_of: *index as u128,
+                }));
+            }
+        }
+
+        // The ledger works on unbounded naturals, no balance can cover more than u128::MAX
+        let Some(debit) = tx.amount.checked_add(self.fee) else {
+            let balance = state.balances.get(&key(&tx.from)).copied().unwrap_or(0);
+            return Err(Self::rejected(LedgerRejection::InsufficientFunds {
+                balance,
+            }));
+        };
+        let allowance_key = tx
+            .spender
+            .as_ref()
+            .map(|spender| (key(&tx.from), key(spender)));
+        if let Some(allowance_key) = &allowance_key {
+            let allowance = match state.allowances.get(allowance_key) {
+                Some((allowance, expires_at)) if expires_at.is_none_or(|e| e >= now) => *allowance,
+                _ => 0,
+            };
+            if allowance < debit {
+                return Err(Self::rejected(LedgerRejection::InsufficientAllowance {
+                    allowance,
+                }));
+            }
+        }
+
+        let balance = state.balances.get(&key(&tx.from)).copied().unwrap_or(0);
+        if balance < debit {
+            return Err(Self::rejected(LedgerRejection::InsufficientFunds {
+                balance,
+            }));
+        }
+
+        if let Some(allowance_key) = &allowance_key {
+            state
+                .allowances
+                .get_mut(allowance_key)
+                .expect("allowance checked above")
+                .0 -= debit;
+        }
+        *state
+            .balances
+            .get_mut(&key(&tx.from))
+            .expect("balance checked above") -= debit;
+        *state.balances.entry(key(&tx.to)).or_default() += tx.amount;
+
+        let index = Self::push_block(
+            &mut state,
+            Icrc3Operation::Transfer {
+                from: tx.from.clone(),
+                to: tx.to.clone(),
+                spender: tx.spender.clone(),
+                amount: tx.amount,
+            },
+            tx.fee,
+            tx.created_at_time,
+        );
+        if tx.created_at_time.is_some() {
+            state.recent.push((tx, index));
+        }
+
+        self.reply(&mut state, method, index)
+    }
+
+    /// `icrc2_approve` from one of the caller's accounts
+    fn execute_approve(&self, args: ApproveArg) -> Result<u128, CurrencyError> {
+        let mut state = self.state.borrow_mut();
+        let now = state.time;
+
+        if args.fee.is_some_and(|fee| fee != self.fee) {
+            return Err(Self::rejected(LedgerRejection::BadFee {
+                expected_fee: self.fee,
+            }));
+        }
+
+        if let Some(created_at_time) = args.created_at_time {
+            Self::check_created_at(now, created_at_time)?;
+
+            state
+                .recent_approvals
+                .retain(|(recent, _)| !Self::left_window(now, recent.created_at_time));
+            if let Some((_, index)) = state
+                .recent_approvals
+                .iter()
+                .find(|(recent, _)| *recent == args)
+            {
+                return Err(Self::rejected(LedgerRejection::Duplicate {
+                    duplicate_of: *index as u128,
+                }));
+            }
+        }
+
+        if args.expires_at.is_some_and(|expires_at| expires_at < now) {
+            return Err(Self::rejected(LedgerRejection::Expired {
+                ledger_time: now,
+            }));
+        }
+
+        let from = Account {
+            owner: self.caller,
+            subaccount: args.from_subaccount.clone(),
+        };
+        let allowance_key = (key(&from), key(&args.spender));
+        let current_allowance = match state.allowances.get(&allowance_key) {
+            Some((allowance, expires_at)) if expires_at.is_none_or(|e| e >= now) => *allowance,
+            _ => 0,
+        };
+        if args
+            .expected_allowance
+            .is_some_and(|expected| expected != current_allowance)
+        {
+            return Err(Self::rejected(LedgerRejection::AllowanceChanged {
+                current_allowance,
+            }));
+        }
+
+        let balance = state.balances.get(&key(&from)).copied().unwrap_or(0);
+        if balance < self.fee {
+            return Err(Self::rejected(LedgerRejection::InsufficientFunds {
+                balance,
+            }));
+        }
+
+        *state
+            .balances
+            .get_mut(&key(&from))
+            .expect("balance checked above") -= self.fee;
+        state
+            .allowances
+            .insert(allowance_key, (args.amount, args.expires_at));
+
+        let index = Self::push_block(
+            &mut state,
+            Icrc3Operation::Approve {
+                from,
+                spender: args.spender.clone(),
+                amount: args.amount,
+                expected_allowance: args.expected_allowance,
+                expires_at: args.expires_at,
+            },
+            args.fee,
+            args.created_at_time,
+        );
+        if args.created_at_time.is_some() {
+            state.recent_approvals.push((args, index));
+        }
+
+        self.reply(&mut state, "icrc2_approve", index)
+    }
+
+    fn check_created_at(now: u64, created_at_time: u64) -> Result<(), CurrencyError> {
+        if created_at_time.saturating_add(TX_WINDOW + PERMITTED_DRIFT) < now {
+            return Err(Self::rejected(LedgerRejection::TooOld));
+        }
+        if created_at_time > now.saturating_add(PERMITTED_DRIFT) {
+            return Err(Self::rejected(LedgerRejection::CreatedInFuture {
+                ledger_time: Some(now),
+            }));
+        }
+        Ok(())
+    }
+
+    /// Whether a transaction created at `created_at_time` can no longer be a duplicate
+    fn left_window(now: u64, created_at_time: Option<u64>) -> bool {
+        created_at_time
+            .unwrap_or(0)
+            .saturating_add(TX_WINDOW + PERMITTED_DRIFT)
+            < now
+    }
+
+    /// The answer for block `index`, unless the reply is to be lost
+    fn reply(&self, state: &mut State, method: &str, index: u64) -> Result<u128, CurrencyError> {
+        if std::mem::take(&mut state.lose_next_reply) {
+            return Err(CallError {
+                canister_id: self.ledger_id,
+                method: method.to_string(),
+                code: CallRejectCode::SysUnknown,
+                message: "reply lost".to_string(),
+            }
+            .into());
+        }
+
+        Ok(index as u128)
+    }
+}
+
+impl LedgerClient for MockLedger {
+    fn ledger_id(&self) -> Principal {
+        self.ledger_id
+    }
+
+    async fn fee(&self) -> Result<u128, CurrencyError> {
+        Ok(self.fee)
+    }
+
+    async fn balance_of(&self, account: &Account) -> Result<u128, CurrencyError> {
+        Ok(self.balance(account))
+    }
+
+    async fn transfer(&self, args: TransferArg) -> Result<u128, CurrencyError> {
+        let from = Account {
+            owner: self.caller,
+            subaccount: args.from_subaccount,
+        };
+        self.execute(
+            "icrc1_transfer",
+            Tx {
+                from,
+                to: args.to,
+                spender: None,
+                amount: args.amount,
+                fee: args.fee,
+                memo: args.memo,
+                created_at_time: args.created_at_time,
+            },
+        )
+    }
+
+    async fn allowance(
+        &self,
+        account: &Account,
+        spender: &Account,
+    ) -> Result<Allowance, CurrencyError> {
+        let state = self.state.borrow();
+        let (allowance, expires_at) = state
+            .allowances
+            .get(&(key(account), key(spender)))
+            .copied()
+            .unwrap_or((0, None));
+        Ok(Allowance {
+            allowance,
+            expires_at,
+        })
+    }
+
+    async fn transfer_from(&self, args: TransferFromArg) -> Result<u128, CurrencyError> {
+        let spender = Account {
+            owner: self.caller,
+            subaccount: args.spender_subaccount,
+        };
+        self.execute(
+            "icrc2_transfer_from",
+            Tx {
+                from: args.from,
+                to: args.to,
+                spender: Some(spender),
+                amount: args.amount,
+                fee: args.fee,
+                memo: args.memo,
+                created_at_time: args.created_at_time,
+            },
+        )
+    }
+
+    async fn approve(&self, args: ApproveArg) -> Result<u128, CurrencyError> {
+        self.execute_approve(args)
+    }
+
+    async fn get_blocks(&self, start: u64, length: u64) -> Result<Icrc3BlockRange, CurrencyError> {
+        let state = self.state.borrow();
+        let log_length = state.blocks.len() as u64;
+        let start = start.min(log_length) as usize;
+        let end = (start as u64).saturating_add(length).min(log_length) as usize;
+        Ok(Icrc3BlockRange {
+            log_length,
+            blocks: state.blocks[start..end].to_vec(),
+        })
+    }
+}
@@ -0,0 +1,517 @@
+//! In-memory ckETH and ckBTC minter.
+//!
+//! Answers with the candid types of the real minters. Nothing happens on its own:
+//! tests mint deposits, move withdrawals through their states and confirm UTXOs
+//! explicitly, so every run sees the same sequence of answers.
+//!
+//! Withdrawals follow the minter's state machine, a transition from the wrong
+//! state panics:
+//!
+//! ```text
+//! Pending -> TxCreated -> TxSent -> Finalized
+//!                                -> PendingReimbursement -> Reimbursed
+//! ```
+
+use std::cell::RefCell;
+
+use candid::{Nat, Principal};
+use currency::{
+    ckbtc_minter_canister_interface::{
+        PendingUtxo, PendingUtxoOutpoint, UpdateBalanceError, UpdateBalanceRet, Utxo, UtxoOutpoint,
+        UtxoStatus,
+    },
+    cketh_minter_canister_interface::{
+        EthTransaction, Event, EventPayload, EventSource, GetEventsRet, RetrieveErc20Request,
+        TxFinalizedStatus, WithdrawErc20Arg, WithdrawErc20Error, WithdrawalDetail,
+        WithdrawalStatus,
+    },
+    currency_error::CurrencyError,
+    ledger_client::MinterClient,
+};
+use serde_bytes::ByteBuf;
+
+/// The ckETH minter returns at most 100 events per call
+pub const EVENTS_PAGE_SIZE: u64 = 100;
+
+/// The minter's candid types are not `Clone`, so the state keeps its own copies
+/// and builds the answers from them.
+enum MockEvent {
+    Skipped,
+    MintedCkEth {
+        transaction_hash: String,
+        block: u64,
+    },
+    MintedCkErc20 {
+        transaction_hash: String,
+        symbol: String,
+        contract_address: String,
+        block: u64,
+    },
+}
+
+impl MockEvent {
+    fn payload(&self) -> EventPayload {
+        let source = |transaction_hash: &String| EventSource {
+            transaction_hash: transaction_hash.clone(),
+            log_index: Nat::from(0u8),
+        };
+        match self {
+            MockEvent::Skipped => EventPayload::SkippedBlock {
+                block_number: Nat::from(0u8),
+                contract_address: None,
+            },
+            MockEvent::MintedCkEth {
+                transaction_hash,
+                block,
+            } => EventPayload::MintedCkEth {
+                event_source: source(transaction_hash),
+                mint_block_index: Nat::from(*block),
+            },
+            MockEvent::MintedCkErc20 {
+                transaction_hash,
+                symbol,
+                contract_address,
+                block,
+            } => EventPayload::MintedCkErc20 {
+                event_source: source(transaction_hash),
+                erc20_contract_address: contract_address.clone(),
+                mint_block_index: Nat::from(*block),
+                ckerc20_token_symbol: symbol.clone(),
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MockWithdrawalStatus {
+    Pending,
+    TxCreated,
+    TxSent {
+        transaction_hash: String,
+    },
+    Finalized {
+        transaction_hash: String,
+        effective_fee: u64,
+    },
+    PendingReimbursement {
+        transaction_hash: String,
+    },
+    Reimbursed {
+        transaction_hash: String,
+        block: u64,
+    },
+}
+
+impl MockWithdrawalStatus {
+    fn sent_hash(&self) -> Option<String> {
+        match self {
+            MockWithdrawalStatus::TxSent { transaction_hash } => Some(transaction_hash.clone()),
+            _ => None,
+        }
+    }
+}
+
+struct MockWithdrawal {
+    id: u64,
+    amount: Nat,
+    recipient: String,
+    status: MockWithdrawalStatus,
+}
+
+impl MockWithdrawal {
+    fn detail(&self, from: Principal) -> WithdrawalDetail {
+        let tx = |transaction_hash: &String| EthTransaction {
+            transaction_hash: transaction_hash.clone(),
+        };
+        let status = match &self.status {
+            MockWithdrawalStatus::Pending => WithdrawalStatus::Pending,
+            MockWithdrawalStatus::TxCreated => WithdrawalStatus::TxCreated,
+            MockWithdrawalStatus::TxSent { transaction_hash } => {
+                WithdrawalStatus::TxSent(tx(transaction_hash))
+            }
+            MockWithdrawalStatus::Finalized {
+                transaction_hash,
+                effective_fee,
+            } => WithdrawalStatus::TxFinalized(TxFinalizedStatus::Success {
+                transaction_hash: transaction_hash.clone(),
+                effective_transaction_fee: Some(Nat::from(*effective_fee)),
+            }),
+            MockWithdrawalStatus::PendingReimbursement { transaction_hash } => {
+                WithdrawalStatus::TxFinalized(TxFinalizedStatus::PendingReimbursement(tx(
+                    transaction_hash,
+                )))
+            }
+            MockWithdrawalStatus::Reimbursed {
+                transaction_hash,
+                block,
+            } => WithdrawalStatus::TxFinalized(TxFinalizedStatus::Reimbursed {
+                transaction_hash: transaction_hash.clone(),
+                reimbursed_amount: self.amount.clone(),
+                reimbursed_in_block: Nat::from(*block),
+            }),
+        };
+        WithdrawalDetail {
+            status,
+            token_symbol: "ckERC20".to_string(),
+            withdrawal_amount: self.amount.clone(),
+            withdrawal_id: self.id,
+            from,
+            from_subaccount: None,
+            max_transaction_fee: None,
+            recipient_address: self.recipient.clone(),
+        }
+    }
+}
+
+struct MockUtxo {
+    subaccount: Option<Vec<u8>>,
+    vout: u32,
+    value: u64,
+    confirmations: u32,
+    minted: bool,
+}
+
+impl MockUtxo {
+    fn utxo(&self) -> Utxo {
+        Utxo {
+            height: 800_000,
+            value: self.value,
+            outpoint: UtxoOutpoint {
+                txid: ByteBuf::from(vec![0xCC; 32]),
+                vout: self.vout,
+            },
+        }
+    }
+}
+
+#[derive(Default)]
+struct State {
+    time: u64,
+    next_block: u64,
+    events: Vec<MockEvent>,
+    withdrawals: Vec<MockWithdrawal>,
+    blocked: Vec<String>,
+    next_withdraw_error: Option<WithdrawErc20Error>,
+    utxos: Vec<MockUtxo>,
+    required_confirmations: u32,
+    min_utxo_value: u64,
+    next_update_error: Option<UpdateBalanceError>,
+}
+
+impl State {
+    fn next_block(&mut self) -> u64 {
+        let block = self.next_block;
+        self.next_block += 1;
+        block
+    }
+}
+
+pub struct MockMinter {
+    minter_id: Principal,
+    /// The principal making the calls, the canister under test
+    caller: Principal,
+    state: RefCell<State>,
+}
+
+impl MockMinter {
+    pub fn new(caller: Principal) -> Self {
+        Self {
+            minter_id: Principal::from_slice(&[0xBB; 10]),
+            caller,
+            state: RefCell::new(State {
+                time: 1_700_000_000_000_000_000,
+                required_confirmations: 6,
+                ..State::default()
+            }),
+        }
+    }
+
+    /// Move withdrawal `id` to the status `f` returns for its current one
+    fn transition(
+        &self,
+        id: u64,
+        f: impl FnOnce(&MockWithdrawalStatus) -> Option<MockWithdrawalStatus>,
+    ) {
+        let mut state = self.state.borrow_mut();
+        let withdrawal = state
+            .withdrawals
+            .iter_mut()
+            .find(|w| w.id == id)
+            .unwrap_or_else(|| panic!("no withdrawal {}", id));
+        withdrawal.status = f(&withdrawal.status)
+            .unwrap_or_else(|| panic!("withdrawal {} cannot leave {:?}", id, withdrawal.status));
+    }
+
+    /// Log an event the crate does not look for
+    pub fn skip_block(&self) {
+        self.state.borrow_mut().events.push(MockEvent::Skipped);
+    }
+
+    /// Mint ckETH for an Ethereum deposit, returns the ledger block index
+    pub fn mint_eth(&self, transaction_hash: &str) -> u64 {
+        let mut state = self.state.borrow_mut();
+        let block = state.next_block();
+        state.events.push(MockEvent::MintedCkEth {
+            transaction_hash: transaction_hash.to_string(),
+            block,
+        });
+        block
+    }
+
+    /// Mint a ckERC20 token for an Ethereum deposit, returns the ledger block index
+    pub fn mint_erc20(&self, transaction_hash: &str, symbol: &str, contract_address: &str) -> u64 {
+        let mut state = self.state.borrow_mut();
+        let block = state.next_block();
+        state.events.push(MockEvent::MintedCkErc20 {
+            transaction_hash: transaction_hash.to_string(),
+            symbol: symbol.to_string(),
+            contract_address: contract_address.to_string(),
+            block,
+        });
+        block
+    }
+
+    pub fn block_address(&self, address: &str) {
+        self.state.borrow_mut().blocked.push(address.to_lowercase());
+    }
+
+    /// Reject the next `withdraw_erc20` with `error`
+    pub fn reject_next_withdrawal(&self, error: WithdrawErc20Error) {
+        self.state.borrow_mut().next_withdraw_error = Some(error);
+    }
+
+    pub fn withdrawal_count(&self) -> usize {
+        self.state.borrow().withdrawals.len()
+    }
+
+    pub fn create_transaction(&self, id: u64) {
+        self.transition(id, |status| {
+            (*status == MockWithdrawalStatus::Pending).then_some(MockWithdrawalStatus::TxCreated)
+        })
+    }
+
+    pub fn send_transaction(&self, id: u64, transaction_hash: &str) {
+        self.transition(id, |status| {
+            (*status == MockWithdrawalStatus::TxCreated).then(|| MockWithdrawalStatus::TxSent {
+                transaction_hash: transaction_hash.to_string(),
+            })
+        })
+    }
+
+    pub fn finalize(&self, id: u64, effective_fee: u64) {
+        self.transition(id, |status| {
+            status
+                .sent_hash()
+                .map(|transaction_hash| MockWithdrawalStatus::Finalized {
+                    transaction_hash,
+                    effective_fee,
+                })
+        })
+    }
+
+    /// The sent transaction failed on Ethereum
+    pub fn fail(&self, id: u64) {
+        self.transition(id, |status| {
+            status.sent_hash().map(
+                |transaction_hash| MockWithdrawalStatus::PendingReimbursement { transaction_hash },
+            )
+        })
+    }
+
+    /// Mint the amount of a failed withdrawal back, returns the ledger block index
+    pub fn reimburse(&self, id: u64) -> u64 {
+        let block = self.state.borrow_mut().next_block();
+        self.transition(id, |status| match status {
+            MockWithdrawalStatus::PendingReimbursement { transaction_hash } => {
+                Some(MockWithdrawalStatus::Reimbursed {
+                    transaction_hash: transaction_hash.clone(),
+                    block,
+                })
+            }
+            _ => None,
+        });
+        block
+    }
+
+    pub fn set_required_confirmations(&self, confirmations: u32) {
+        self.state.borrow_mut().required_confirmations = confirmations;
+    }
+
+    /// UTXOs below `value` are reported as too small and never minted
+    pub fn set_min_utxo_value(&self, value: u64) {
+        self.state.borrow_mut().min_utxo_value = value;
+    }
+
+    /// A Bitcoin transaction paying `value` to the deposit address of `subaccount`
+    pub fn add_utxo(&self, subaccount: Option<Vec<u8>>, value: u64, confirmations: u32) {
+        let mut state = self.state.borrow_mut();
+        let vout = state.utxos.len() as u32;
+        state.utxos.push(MockUtxo {
+            subaccount,
+            vout,
+            value,
+            confirmations,
+            minted: false,
+        });
+    }
+
+    /// Mine `blocks` Bitcoin blocks
+    pub fn confirm_utxos(&self, blocks: u32) {
+        for utxo in self.state.borrow_mut().utxos.iter_mut() {
+            utxo.confirmations += blocks;
+        }
+    }
+
+    /// Answer the next `update_balance` with `error`
+    pub fn reject_next_update(&self, error: UpdateBalanceError) {
+        self.state.borrow_mut().next_update_error = Some(error);
+    }
+}
+
+impl MinterClient for MockMinter {
+    fn minter_id(&self) -> Principal {
+        self.minter_id
+    }
+
+    async fn withdraw_erc20(
+        &self,
+        args: WithdrawErc20Arg,
+    ) -> Result<RetrieveErc20Request, CurrencyError> {
+        let mut state = self.state.borrow_mut();
+        if let Some(error) = state.next_withdraw_error.take() {
+            return Err(CurrencyError::MinterRejected(error.try_into()?));
+        }
+        if state.blocked.contains(&args.recipient.to_lowercase()) {
+            return Err(CurrencyError::MinterRejected(
+                WithdrawErc20Error::RecipientAddressBlocked {
+                    address: args.recipient,
+                }
+                .try_into()?,
+            ));
+        }
+
+        // The ckETH burn paying for gas gives the withdrawal its id
+        let cketh_block_index = state.next_block();
+        let ckerc20_block_index = state.next_block();
+        state.withdrawals.push(MockWithdrawal {
+            id: cketh_block_index,
+            amount: args.amount,
+            recipient: args.recipient,
+            status: MockWithdrawalStatus::Pending,
+        });
+        Ok(RetrieveErc20Request {
+            ckerc20_block_index: Nat::from(ckerc20_block_index),
+            cketh_block_index: Nat::from(cketh_block_index),
+        })
+    }
+
+    async fn withdrawal_status(
+        &self,
+        withdrawal_id: u64,
+    ) -> Result<Vec<WithdrawalDetail>, CurrencyError> {
+        Ok(self
+            .state
+            .borrow()
+            .withdrawals
+            .iter()
+            .filter(|w| w.id == withdrawal_id)
+            .map(|w| w.detail(self.caller))
+            .collect())
+    }
+
+    async fn is_address_blocked(&self, address: &str) -> Result<bool, CurrencyError> {
+        Ok(self
+            .state
+            .borrow()
+            .blocked
+            .contains(&address.to_lowercase()))
+    }
+
+    async fn get_events(&self, start: u64, length: u64) -> Result<GetEventsRet, CurrencyError> {
+        let state = self.state.borrow();
+        let total = state.events.len() as u64;
+        let start = start.min(total);
+        let end = (start + length.min(EVENTS_PAGE_SIZE)).min(total);
+        Ok(GetEventsRet {
+            total_event_count: total,
+            events: state.events[start as usize..end as usize]
+                .iter()
+                .map(|event| Event {
+                    timestamp: state.time,
+                    payload: event.payload(),
+                })
+                .collect(),
+        })
+    }
+
+    async fn get_btc_address(&self, subaccount: Option<Vec<u8>>) -> Result<String, CurrencyError> {
+        let suffix: String = subaccount
+            .unwrap_or_default()
+            .iter()
+            .take(8)
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        Ok(format!("bcrt1qmock{}", suffix))
+    }
+
+    async fn update_balance(
+        &self,
+        subaccount: Option<Vec<u8>>,
+    ) -> Result<UpdateBalanceRet, CurrencyError> {
+        let mut state = self.state.borrow_mut();
+        let state = &mut *state;
+        if let Some(error) = state.next_update_error.take() {
+            // Like IcMinterClient, transient errors are errors, the others answers
+            return match error {
+                UpdateBalanceError::TemporarilyUnavailable(_)
+                | UpdateBalanceError::AlreadyProcessing => {
+                    Err(CurrencyError::MinterRejected(error.into()))
+                }
+                error => Ok(UpdateBalanceRet::Err(error)),
+            };
+        }
+
+        let required = state.required_confirmations;
+        let min_value = state.min_utxo_value;
+        let mut statuses = Vec::new();
+        let mut pending = Vec::new();
+        for utxo in state
+            .utxos
+            .iter_mut()
+            .filter(|u| u.subaccount == subaccount && !u.minted)
+        {
+            if utxo.confirmations < required {
+                pending.push(PendingUtxo {
+                    confirmations: utxo.confirmations,
+                    value: utxo.value,
+                    outpoint: PendingUtxoOutpoint {
+                        txid: utxo.utxo().outpoint.txid,
+                        vout: utxo.vout,
+                    },
+                });
+                continue;
+            }
+            utxo.minted = true;
+            if utxo.value < min_value {
+                statuses.push(UtxoStatus::ValueTooSmall(utxo.utxo()));
+            } else {
+                statuses.push(UtxoStatus::Minted {
+                    minted_amount: utxo.value,
+                    block_index: state.next_block,
+                    utxo: utxo.utxo(),
+                });
+                state.next_block += 1;
+            }
+        }
+
+        if statuses.is_empty() {
+            return Ok(UpdateBalanceRet::Err(UpdateBalanceError::NoNewUtxos {
+                suspended_utxos: None,
+                required_confirmations: required,
+                current_confirmations: pending.iter().map(|u| u.confirmations).max(),
+                pending_utxos: Some(pending),
+            }));
+        }
+        Ok(UpdateBalanceRet::Ok(statuses))
+    }
+}
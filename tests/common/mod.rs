@@ -0,0 +1,5 @@
+// Each test crate uses only some of the mocks
+#![allow(dead_code)]
+
+pub mod mock_ledger;
+pub mod mock_minter;
@@ -0,0 +1,79 @@
+use candid::Principal;
+use currency::deposit_subaccount::{
+    deposit_subaccount, icrc1_account_text, user_from_deposit_subaccount,
+};
+
+// Owner of the ICRC-1 textual encoding examples
+const OWNER: &str = "k2t6j-2nvnp-4zjm3-25dtz-6xhaa-c7boj-5gayf-oj3xs-i43lp-teztq-6ae";
+
+fn owner() -> Principal {
+    Principal::from_text(OWNER).unwrap()
+}
+
+#[test]
+fn deposit_subaccount_round_trips() {
+    let users = [
+        Principal::anonymous(),
+        owner(),
+        Principal::from_slice(&[0xff; 29]),
+    ];
+
+    for user in users {
+        for namespace in [None, Some(0), Some(1), Some(u16::MAX)] {
+            let subaccount = deposit_subaccount(&user, namespace);
+            assert_eq!(
+                user_from_deposit_subaccount(&subaccount),
+                Some((user, namespace.unwrap_or(0)))
+            );
+        }
+    }
+}
+
+#[test]
+fn namespaces_give_distinct_subaccounts() {
+    assert_eq!(
+        deposit_subaccount(&owner(), None),
+        deposit_subaccount(&owner(), Some(0))
+    );
+    assert_ne!(
+        deposit_subaccount(&owner(), Some(1)),
+        deposit_subaccount(&owner(), Some(2))
+    );
+}
+
+#[test]
+fn other_subaccounts_are_not_deposit_subaccounts() {
+    let mut padded = deposit_subaccount(&Principal::from_slice(&[2; 10]), None);
+    padded[20] = 1;
+    let mut too_long = [0u8; 32];
+    too_long[0] = 30;
+
+    assert_eq!(user_from_deposit_subaccount(&[0u8; 32]), None);
+    assert_eq!(user_from_deposit_subaccount(&padded), None);
+    assert_eq!(user_from_deposit_subaccount(&too_long), None);
+    assert_eq!(user_from_deposit_subaccount(&[1u8; 31]), None);
+}
+
+#[test]
+fn icrc1_textual_encoding_vectors() {
+    let mut one = [0u8; 32];
+    one[31] = 1;
+    let mut counting = [0u8; 32];
+    for (i, byte) in counting.iter_mut().enumerate() {
+        *byte = i as u8 + 1;
+    }
+
+    assert_eq!(icrc1_account_text(&owner(), None), OWNER);
+    assert_eq!(icrc1_account_text(&owner(), Some(&[0u8; 32])), OWNER);
+    assert_eq!(
+        icrc1_account_text(&owner(), Some(&one)),
+        format!("{}-6cc627i.1", OWNER)
+    );
+    assert_eq!(
+        icrc1_account_text(&owner(), Some(&counting)),
+        format!(
+            "{}-dfxgiyy.102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f20",
+            OWNER
+        )
+    );
+}
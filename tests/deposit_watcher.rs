@@ -0,0 +1,149 @@
+use candid::Principal;
+use currency::{
+    deposit_watcher::{icp_deposits, icrc_deposits, DepositResolver},
+    icrc1_types::Account,
+    icrc3::{Icrc3Block, Icrc3Operation},
+    query::contiguous_blocks,
+    types::currency::CKTokenSymbol,
+    Currency,
+};
+use ic_ledger_types::{
+    AccountIdentifier, Block, Memo, Operation, Timestamp, Tokens, Transaction, DEFAULT_SUBACCOUNT,
+};
+
+const FEE: u64 = 10_000;
+
+fn canister() -> Principal {
+    Principal::from_slice(&[1; 10])
+}
+
+fn user() -> Principal {
+    Principal::from_slice(&[2; 10])
+}
+
+fn other_canister() -> Principal {
+    Principal::from_slice(&[3; 10])
+}
+
+struct EveryoneIsUser;
+
+impl DepositResolver for EveryoneIsUser {
+    fn resolve(&self, _subaccount: Option<&[u8]>, _memo: Option<&[u8]>) -> Option<Principal> {
+        Some(user())
+    }
+}
+
+fn icrc_block(index: u64, operation: Icrc3Operation) -> Icrc3Block {
+    Icrc3Block {
+        index,
+        timestamp: index,
+        operation,
+        fee: None,
+        memo: None,
+        created_at_time: None,
+    }
+}
+
+fn icrc_transfer(index: u64, spender: Option<Principal>) -> Icrc3Block {
+    icrc_block(
+        index,
+        Icrc3Operation::Transfer {
+            from: Account::from(user()),
+            to: Account::from(canister()),
+            spender: spender.map(Account::from),
+            amount: 1_000,
+        },
+    )
+}
+
+fn icp_account(owner: Principal) -> AccountIdentifier {
+    AccountIdentifier::new(&owner, &DEFAULT_SUBACCOUNT)
+}
+
+fn icp_block(operation: Operation) -> Block {
+    Block {
+        parent_hash: None,
+        transaction: Transaction {
+            memo: Memo(0),
+            operation: Some(operation),
+            created_at_time: Timestamp { timestamp_nanos: 0 },
+            icrc1_memo: None,
+        },
+        timestamp: Timestamp { timestamp_nanos: 0 },
+    }
+}
+
+#[test]
+fn short_archive_page_stops_the_range_at_the_gap() {
+    // The archive was asked for blocks 0..5 but only returned 0..3
+    let archived = (0, vec!["a", "b", "c"]);
+    let ledger = (5, vec!["f", "g"]);
+
+    let blocks = contiguous_blocks(0, vec![archived, ledger]);
+
+    assert_eq!(blocks, vec![(0, "a"), (1, "b"), (2, "c")]);
+}
+
+#[test]
+fn contiguous_range_starts_at_the_requested_block() {
+    let blocks = contiguous_blocks(3, vec![(4, vec!["e", "f"]), (0, vec!["a", "b", "c", "d"])]);
+
+    assert_eq!(blocks, vec![(3, "d"), (4, "e"), (5, "f")]);
+}
+
+#[test]
+fn range_missing_its_first_block_is_empty() {
+    let blocks = contiguous_blocks(2, vec![(3, vec!["d", "e"])]);
+
+    assert!(blocks.is_empty());
+}
+
+#[test]
+fn icrc_transfers_by_the_canister_as_spender_are_not_deposits() {
+    let blocks = vec![
+        icrc_transfer(0, None),
+        icrc_transfer(1, Some(canister())),
+        icrc_transfer(2, Some(other_canister())),
+    ];
+
+    let deposits = icrc_deposits(
+        Currency::CKETHToken(CKTokenSymbol::USDC),
+        canister(),
+        blocks,
+        &EveryoneIsUser,
+    );
+
+    let indices: Vec<u64> = deposits.iter().map(|deposit| deposit.block_index).collect();
+    assert_eq!(indices, vec![0, 2]);
+    assert!(deposits.iter().all(|deposit| deposit.user == Some(user())));
+}
+
+#[test]
+fn icp_transfer_from_by_the_canister_is_not_a_deposit() {
+    let transfer_from = |spender: Principal| Operation::TransferFrom {
+        from: icp_account(user()),
+        to: icp_account(canister()),
+        spender: icp_account(spender),
+        amount: Tokens::from_e8s(1_000),
+        fee: Tokens::from_e8s(FEE),
+    };
+    let blocks = vec![
+        (
+            0,
+            icp_block(Operation::Transfer {
+                from: icp_account(user()),
+                to: icp_account(canister()),
+                amount: Tokens::from_e8s(1_000),
+                fee: Tokens::from_e8s(FEE),
+            }),
+        ),
+        (1, icp_block(transfer_from(canister()))),
+        (2, icp_block(transfer_from(other_canister()))),
+    ];
+
+    let deposits = icp_deposits(canister(), blocks, &EveryoneIsUser);
+
+    let indices: Vec<u64> = deposits.iter().map(|deposit| deposit.block_index).collect();
+    assert_eq!(indices, vec![0, 2]);
+    assert!(deposits.iter().all(|deposit| deposit.amount == 1_000));
+}
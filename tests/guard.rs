@@ -0,0 +1,50 @@
+use candid::Principal;
+use currency::{
+    currency_error::CurrencyError,
+    guard::{is_locked, OperationGuard},
+    types::currency::CKTokenSymbol,
+    Currency,
+};
+
+fn user(i: u8) -> Principal {
+    Principal::from_slice(&[i; 10])
+}
+
+#[test]
+fn second_guard_for_the_same_user_and_currency_fails() {
+    let _guard = OperationGuard::new(user(1), Currency::ICP).unwrap();
+
+    assert!(matches!(
+        OperationGuard::new(user(1), Currency::ICP),
+        Err(CurrencyError::LockError)
+    ));
+    assert!(is_locked(user(1), Currency::ICP));
+}
+
+#[test]
+fn guards_for_other_currencies_or_users_are_independent() {
+    let _guard = OperationGuard::new(user(1), Currency::ICP).unwrap();
+
+    assert!(OperationGuard::new(user(1), Currency::BTC).is_ok());
+    assert!(OperationGuard::new(user(1), Currency::CKETHToken(CKTokenSymbol::USDC)).is_ok());
+    assert!(OperationGuard::new(user(2), Currency::ICP).is_ok());
+    assert!(!is_locked(user(2), Currency::ICP));
+}
+
+#[test]
+fn dropping_the_guard_releases_the_lock() {
+    let guard = OperationGuard::new(user(1), Currency::ICP).unwrap();
+    drop(guard);
+
+    assert!(!is_locked(user(1), Currency::ICP));
+    assert!(OperationGuard::new(user(1), Currency::ICP).is_ok());
+}
+
+#[test]
+fn failed_acquire_does_not_release_the_held_lock() {
+    let _guard = OperationGuard::new(user(1), Currency::ICP).unwrap();
+
+    let _ = OperationGuard::new(user(1), Currency::ICP);
+
+    assert!(is_locked(user(1), Currency::ICP));
+}
@@ -0,0 +1,202 @@
+use candid::{Nat, Principal};
+use currency::{
+    currency_error::CurrencyError,
+    icrc1_types::Account,
+    icrc3::{decode_block, Icrc3Block, Icrc3Operation},
+    icrc_ledger_canister_interface::Icrc3Value,
+};
+use serde_bytes::ByteBuf;
+
+fn user() -> Principal {
+    Principal::from_slice(&[2; 10])
+}
+
+fn canister() -> Principal {
+    Principal::from_slice(&[1; 10])
+}
+
+fn map(entries: Vec<(&str, Icrc3Value)>) -> Icrc3Value {
+    Icrc3Value::Map(
+        entries
+            .into_iter()
+            .map(|(key, value)| (key.to_string(), Box::new(value)))
+            .collect(),
+    )
+}
+
+fn nat(value: u64) -> Icrc3Value {
+    Icrc3Value::Nat(Nat::from(value))
+}
+
+fn text(value: &str) -> Icrc3Value {
+    Icrc3Value::Text(value.to_string())
+}
+
+fn blob(value: &[u8]) -> Icrc3Value {
+    Icrc3Value::Blob(ByteBuf::from(value.to_vec()))
+}
+
+fn account(owner: Principal, subaccount: Option<[u8; 32]>) -> Icrc3Value {
+    let mut parts = vec![Box::new(blob(owner.as_slice()))];
+    if let Some(subaccount) = subaccount {
+        parts.push(Box::new(blob(&subaccount)));
+    }
+    Icrc3Value::Array(parts)
+}
+
+#[test]
+fn decodes_the_legacy_layout() {
+    let block = map(vec![
+        ("ts", nat(2_000)),
+        (
+            "tx",
+            map(vec![
+                ("op", text("xfer")),
+                ("amt", nat(500)),
+                ("fee", nat(10)),
+                ("from", account(user(), None)),
+                ("to", account(canister(), Some([7; 32]))),
+                ("memo", blob(b"memo")),
+                ("ts", nat(1_000)),
+            ]),
+        ),
+    ]);
+
+    assert_eq!(
+        decode_block(4, block).unwrap(),
+        Icrc3Block {
+            index: 4,
+            timestamp: 2_000,
+            operation: Icrc3Operation::Transfer {
+                from: Account::from(user()),
+                to: Account {
+                    owner: canister(),
+                    subaccount: Some(vec![7; 32]),
+                },
+                spender: None,
+                amount: 500,
+            },
+            fee: Some(10),
+            memo: Some(b"memo".to_vec()),
+            created_at_time: Some(1_000),
+        }
+    );
+}
+
+#[test]
+fn decodes_the_typed_layout_with_the_block_fee() {
+    let block = map(vec![
+        ("btype", text("2xfer")),
+        ("ts", nat(2_000)),
+        ("fee", nat(10)),
+        (
+            "tx",
+            map(vec![
+                ("amt", nat(500)),
+                ("from", account(user(), None)),
+                ("to", account(canister(), None)),
+                ("spender", account(canister(), None)),
+            ]),
+        ),
+    ]);
+
+    let block = decode_block(5, block).unwrap();
+
+    assert_eq!(
+        block.operation,
+        Icrc3Operation::Transfer {
+            from: Account::from(user()),
+            to: Account::from(canister()),
+            spender: Some(Account::from(canister())),
+            amount: 500,
+        }
+    );
+    assert_eq!(block.fee, Some(10));
+    assert_eq!(block.created_at_time, None);
+}
+
+#[test]
+fn decodes_a_typed_approve() {
+    let block = map(vec![
+        ("btype", text("2approve")),
+        ("ts", nat(2_000)),
+        (
+            "tx",
+            map(vec![
+                ("amt", nat(800)),
+                ("from", account(user(), None)),
+                ("spender", account(canister(), None)),
+                ("expected_allowance", nat(0)),
+                ("expires_at", nat(9_000)),
+            ]),
+        ),
+    ]);
+
+    assert_eq!(
+        decode_block(6, block).unwrap().operation,
+        Icrc3Operation::Approve {
+            from: Account::from(user()),
+            spender: Account::from(canister()),
+            amount: 800,
+            expected_allowance: Some(0),
+            expires_at: Some(9_000),
+        }
+    );
+}
+
+#[test]
+fn unknown_block_type_is_decoded_as_unknown() {
+    let block = map(vec![
+        ("btype", text("107feecol")),
+        ("ts", nat(2_000)),
+        (
+            "tx",
+            map(vec![("fee_collector", account(canister(), None))]),
+        ),
+    ]);
+    let without_tx = map(vec![("btype", text("107feecol")), ("ts", nat(3_000))]);
+
+    assert!(matches!(
+        decode_block(8, without_tx).unwrap().operation,
+        Icrc3Operation::Unknown { .. }
+    ));
+    let block = decode_block(7, block).unwrap();
+
+    assert_eq!(block.index, 7);
+    assert_eq!(block.timestamp, 2_000);
+    assert_eq!(
+        block.operation,
+        Icrc3Operation::Unknown {
+            btype: "107feecol".to_string()
+        }
+    );
+}
+
+#[test]
+fn unknown_legacy_operation_is_decoded_as_unknown() {
+    let block = map(vec![
+        ("ts", nat(2_000)),
+        ("tx", map(vec![("op", text("freeze"))])),
+    ]);
+
+    assert_eq!(
+        decode_block(8, block).unwrap().operation,
+        Icrc3Operation::Unknown {
+            btype: "freeze".to_string()
+        }
+    );
+}
+
+#[test]
+fn malformed_known_block_is_an_error() {
+    let block = map(vec![
+        ("btype", text("1mint")),
+        ("ts", nat(2_000)),
+        ("tx", map(vec![("amt", nat(500))])),
+    ]);
+
+    assert!(matches!(
+        decode_block(9, block),
+        Err(CurrencyError::GetBlockError(_))
+    ));
+}
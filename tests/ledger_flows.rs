@@ -0,0 +1,499 @@
+mod common;
+
+use candid::Principal;
+use common::mock_ledger::{MockLedger, NANOS_PER_SEC};
+use currency::{
+    currency_error::{CurrencyError, LedgerRejection},
+    icrc1_types::{Account, ApproveArg},
+    icrc3::Icrc3Operation,
+    ledger_client::{
+        approve_spender, check_allowance, deposit_check, deposit_from, revoke_approval,
+        replay_withdrawal_to, withdraw_to, DepositCheck, LedgerClient,
+    },
+    rake_constants::RAKE_WALLET_ADDRESS_PRINCIPAL,
+};
+
+const FEE: u128 = 10_000;
+
+fn canister() -> Account {
+    Account::from(Principal::from_slice(&[1; 10]))
+}
+
+fn user() -> Account {
+    Account::from(Principal::from_slice(&[2; 10]))
+}
+
+fn ledger() -> MockLedger {
+    MockLedger::new(canister().owner, FEE)
+}
+
+#[tokio::test]
+async fn deposit_moves_tokens_within_allowance() {
+    let ledger = ledger();
+    ledger.mint(&user(), 1_000_000);
+    ledger.approve(&user(), &canister(), 600_000, None);
+
+    let block = deposit_from(
+        &ledger,
+        user(),
+        canister(),
+        500_000,
+        Some(FEE),
+        ledger.time(),
+    )
+    .await
+    .unwrap();
+
+    assert_eq!(ledger.balance(&canister()), 500_000);
+    assert_eq!(ledger.balance(&user()), 1_000_000 - FEE - 500_000 - FEE);
+
+    let allowance = ledger.allowance(&user(), &canister()).await.unwrap();
+    assert_eq!(allowance.allowance, 600_000 - 500_000 - FEE);
+
+    let range = ledger.get_blocks(block as u64, 10).await.unwrap();
+    assert_eq!(range.log_length, 3);
+    assert_eq!(
+        range.blocks[0].operation,
+        Icrc3Operation::Transfer {
+            from: user(),
+            to: canister(),
+            spender: Some(canister()),
+            amount: 500_000,
+        }
+    );
+}
+
+#[tokio::test]
+async fn deposit_above_allowance_is_rejected_before_transfer() {
+    let ledger = ledger();
+    ledger.mint(&user(), 1_000_000);
+    ledger.approve(&user(), &canister(), 100_000, None);
+
+    let result = deposit_from(
+        &ledger,
+        user(),
+        canister(),
+        500_000,
+        Some(FEE),
+        ledger.time(),
+    )
+    .await;
+
+    assert_eq!(result, Err(CurrencyError::InsufficientAllowance));
+    assert_eq!(ledger.blocks().len(), 2);
+    assert_eq!(ledger.balance(&user()), 1_000_000 - FEE);
+}
+
+#[tokio::test]
+async fn deposit_with_expired_allowance_is_rejected() {
+    let ledger = ledger();
+    ledger.mint(&user(), 1_000_000);
+    ledger.approve(
+        &user(),
+        &canister(),
+        600_000,
+        Some(ledger.time() + NANOS_PER_SEC),
+    );
+    ledger.advance_time(2 * NANOS_PER_SEC);
+
+    let now = ledger.time();
+    assert!(matches!(
+        check_allowance(&ledger, &user(), &canister(), 500_000, now).await,
+        Err(CurrencyError::InsufficientAllowance)
+    ));
+    assert_eq!(
+        deposit_from(&ledger, user(), canister(), 500_000, Some(FEE), now).await,
+        Err(CurrencyError::InsufficientAllowance)
+    );
+    assert_eq!(ledger.balance(&canister()), 0);
+}
+
+#[tokio::test]
+async fn deposit_needs_allowance_for_the_fee_too() {
+    let ledger = ledger();
+    ledger.mint(&user(), 1_000_000);
+    ledger.approve(&user(), &canister(), 500_000, None);
+
+    let result = deposit_from(
+        &ledger,
+        user(),
+        canister(),
+        500_000,
+        Some(FEE),
+        ledger.time(),
+    )
+    .await;
+
+    assert_eq!(result, Err(CurrencyError::InsufficientAllowance));
+    assert_eq!(ledger.blocks().len(), 2);
+}
+
+#[tokio::test]
+async fn deposit_check_reports_the_allowance_to_approve() {
+    let ledger = ledger();
+    ledger.mint(&user(), 1_000_000);
+    ledger.approve(&user(), &canister(), 500_000, None);
+
+    let check = deposit_check(
+        &ledger,
+        &user(),
+        &canister(),
+        500_000,
+        Some(FEE),
+        ledger.time(),
+    )
+    .await
+    .unwrap();
+
+    assert_eq!(
+        check,
+        DepositCheck {
+            amount: 500_000,
+            fee: FEE,
+            allowance: 500_000,
+            expires_at: None,
+            required_allowance: 500_000 + FEE,
+            balance: 1_000_000 - FEE,
+            // The missing approval costs a fee as well
+            required_balance: 500_000 + 2 * FEE,
+            will_succeed: false,
+        }
+    );
+
+    ledger.approve(&user(), &canister(), check.required_allowance, None);
+    let check = deposit_check(&ledger, &user(), &canister(), 500_000, None, ledger.time())
+        .await
+        .unwrap();
+    assert!(check.will_succeed);
+    assert_eq!(check.required_balance, 500_000 + FEE);
+    assert!(deposit_from(
+        &ledger,
+        user(),
+        canister(),
+        500_000,
+        Some(FEE),
+        ledger.time()
+    )
+    .await
+    .is_ok());
+}
+
+#[tokio::test]
+async fn deposit_check_counts_expired_allowance_as_zero() {
+    let ledger = ledger();
+    ledger.mint(&user(), 1_000_000);
+    let expires_at = ledger.time() + NANOS_PER_SEC;
+    ledger.approve(&user(), &canister(), 600_000, Some(expires_at));
+    ledger.advance_time(2 * NANOS_PER_SEC);
+
+    let check = deposit_check(
+        &ledger,
+        &user(),
+        &canister(),
+        500_000,
+        Some(FEE),
+        ledger.time(),
+    )
+    .await
+    .unwrap();
+
+    assert_eq!(check.allowance, 0);
+    assert_eq!(check.expires_at, Some(expires_at));
+    assert!(!check.will_succeed);
+}
+
+#[tokio::test]
+async fn deposit_check_reports_insufficient_balance() {
+    let ledger = ledger();
+    ledger.mint(&user(), 400_000);
+    ledger.approve(&user(), &canister(), 600_000, None);
+
+    let check = deposit_check(
+        &ledger,
+        &user(),
+        &canister(),
+        500_000,
+        Some(FEE),
+        ledger.time(),
+    )
+    .await
+    .unwrap();
+
+    assert_eq!(check.allowance, 600_000);
+    assert_eq!(check.balance, 400_000 - FEE);
+    assert_eq!(check.required_balance, 500_000 + FEE);
+    assert!(!check.will_succeed);
+}
+
+#[tokio::test]
+async fn withdraw_deducts_fee_from_amount() {
+    let ledger = ledger();
+    ledger.mint(&canister(), 1_000_000);
+
+    withdraw_to(&ledger, None, user(), 100_000, Some(FEE), ledger.time())
+        .await
+        .unwrap();
+
+    assert_eq!(ledger.balance(&user()), 100_000 - FEE);
+    assert_eq!(ledger.balance(&canister()), 1_000_000 - 100_000);
+}
+
+#[tokio::test]
+async fn replayed_withdraw_returns_original_block() {
+    let ledger = ledger();
+    ledger.mint(&canister(), 1_000_000);
+    let created_at_time = ledger.time();
+
+    ledger.lose_next_reply();
+    let lost = withdraw_to(&ledger, None, user(), 100_000, Some(FEE), created_at_time).await;
+    assert!(lost.unwrap_err().is_outcome_unknown());
+
+    ledger.advance_time(60 * NANOS_PER_SEC);
+    let block = replay_withdrawal_to(&ledger, None, user(), 100_000, Some(FEE), created_at_time)
+        .await
+        .unwrap();
+
+    assert_eq!(block, 1);
+    assert_eq!(ledger.blocks().len(), 2);
+    assert_eq!(ledger.balance(&user()), 100_000 - FEE);
+}
+
+#[tokio::test]
+async fn new_withdraw_matching_an_earlier_one_is_a_duplicate() {
+    let ledger = ledger();
+    ledger.mint(&canister(), 1_000_000);
+    let created_at_time = ledger.time();
+    withdraw_to(&ledger, None, user(), 100_000, Some(FEE), created_at_time)
+        .await
+        .unwrap();
+
+    let result = withdraw_to(&ledger, None, user(), 100_000, Some(FEE), created_at_time).await;
+
+    assert_eq!(
+        result,
+        Err(CurrencyError::LedgerRejected(LedgerRejection::Duplicate {
+            duplicate_of: 1
+        }))
+    );
+    assert_eq!(ledger.balance(&user()), 100_000 - FEE);
+}
+
+#[tokio::test]
+async fn withdraw_outside_dedup_window_is_too_old() {
+    let ledger = ledger();
+    ledger.mint(&canister(), 1_000_000);
+    let created_at_time = ledger.time();
+    ledger.advance_time(25 * 60 * 60 * NANOS_PER_SEC);
+
+    let result = withdraw_to(&ledger, None, user(), 100_000, Some(FEE), created_at_time).await;
+
+    assert_eq!(
+        result,
+        Err(CurrencyError::LedgerRejected(LedgerRejection::TooOld))
+    );
+    assert_eq!(ledger.balance(&canister()), 1_000_000);
+}
+
+#[tokio::test]
+async fn rake_is_paid_to_rake_wallet() {
+    let ledger = ledger();
+    ledger.mint(&canister(), 1_000_000);
+    let rake_wallet = Account::from(Principal::from_text(RAKE_WALLET_ADDRESS_PRINCIPAL).unwrap());
+
+    withdraw_to(
+        &ledger,
+        None,
+        rake_wallet.clone(),
+        50_000,
+        None,
+        ledger.time(),
+    )
+    .await
+    .unwrap();
+
+    assert_eq!(ledger.balance(&rake_wallet), 50_000 - FEE);
+}
+
+#[tokio::test]
+async fn withdraw_with_wrong_fee_is_rejected() {
+    let ledger = ledger();
+    ledger.mint(&canister(), 1_000_000);
+
+    let result = withdraw_to(&ledger, None, user(), 100_000, Some(FEE + 1), ledger.time()).await;
+
+    assert_eq!(
+        result,
+        Err(CurrencyError::LedgerRejected(LedgerRejection::BadFee {
+            expected_fee: FEE
+        }))
+    );
+}
+
+#[tokio::test]
+async fn withdraw_below_fee_is_not_sent() {
+    let ledger = ledger();
+    ledger.mint(&canister(), 1_000_000);
+
+    let result = withdraw_to(&ledger, None, user(), FEE - 1, Some(FEE), ledger.time()).await;
+
+    assert_eq!(result, Err(CurrencyError::InsufficientFunds));
+    assert_eq!(ledger.blocks().len(), 1);
+}
+
+/// A contract account the canister pays through an allowance
+fn pool() -> Account {
+    Account {
+        owner: Principal::from_slice(&[3; 10]),
+        subaccount: Some(vec![7; 32]),
+    }
+}
+
+#[tokio::test]
+async fn approve_grants_allowance_to_spender_subaccount() {
+    let ledger = ledger();
+    ledger.mint(&canister(), 1_000_000);
+    let expires_at = ledger.time() + NANOS_PER_SEC;
+
+    let args = ApproveArg {
+        expires_at: Some(expires_at),
+        ..ApproveArg::new(pool(), 300_000)
+    };
+    let block = approve_spender(&ledger, args, ledger.time()).await.unwrap();
+
+    let allowance = ledger.allowance(&canister(), &pool()).await.unwrap();
+    assert_eq!(allowance.allowance, 300_000);
+    assert_eq!(allowance.expires_at, Some(expires_at));
+    assert_eq!(ledger.balance(&canister()), 1_000_000 - FEE);
+    assert_eq!(
+        ledger.blocks()[block as usize].operation,
+        Icrc3Operation::Approve {
+            from: canister(),
+            spender: pool(),
+            amount: 300_000,
+            expected_allowance: None,
+            expires_at: Some(expires_at),
+        }
+    );
+
+    // The spender's default account was not approved
+    let default_account = Account::from(pool().owner);
+    let allowance = ledger
+        .allowance(&canister(), &default_account)
+        .await
+        .unwrap();
+    assert_eq!(allowance.allowance, 0);
+}
+
+#[tokio::test]
+async fn approve_with_stale_expected_allowance_is_rejected() {
+    let ledger = ledger();
+    ledger.mint(&canister(), 1_000_000);
+    approve_spender(&ledger, ApproveArg::new(pool(), 100_000), ledger.time())
+        .await
+        .unwrap();
+    ledger.advance_time(1);
+
+    let stale = ApproveArg {
+        expected_allowance: Some(50_000),
+        ..ApproveArg::new(pool(), 200_000)
+    };
+    let result = approve_spender(&ledger, stale, ledger.time()).await;
+    assert_eq!(
+        result,
+        Err(CurrencyError::LedgerRejected(
+            LedgerRejection::AllowanceChanged {
+                current_allowance: 100_000
+            }
+        ))
+    );
+    assert!(result.unwrap_err().is_user_error());
+    assert_eq!(ledger.balance(&canister()), 1_000_000 - FEE);
+
+    let current = ApproveArg {
+        expected_allowance: Some(100_000),
+        ..ApproveArg::new(pool(), 200_000)
+    };
+    approve_spender(&ledger, current, ledger.time())
+        .await
+        .unwrap();
+    let allowance = ledger.allowance(&canister(), &pool()).await.unwrap();
+    assert_eq!(allowance.allowance, 200_000);
+}
+
+#[tokio::test]
+async fn approve_expiring_in_the_past_is_not_sent() {
+    let ledger = ledger();
+    ledger.mint(&canister(), 1_000_000);
+
+    let args = ApproveArg {
+        expires_at: Some(ledger.time()),
+        ..ApproveArg::new(pool(), 100_000)
+    };
+    let result = approve_spender(&ledger, args, ledger.time()).await;
+
+    assert!(matches!(
+        result,
+        Err(CurrencyError::LedgerRejected(
+            LedgerRejection::Expired { .. }
+        ))
+    ));
+    assert_eq!(ledger.blocks().len(), 1);
+    assert_eq!(ledger.balance(&canister()), 1_000_000);
+}
+
+#[tokio::test]
+async fn replayed_approve_returns_original_block() {
+    let ledger = ledger();
+    ledger.mint(&canister(), 1_000_000);
+    let args = ApproveArg::new(pool(), 100_000);
+    let now = ledger.time();
+
+    let first = approve_spender(&ledger, args.clone(), now).await.unwrap();
+    let replay = approve_spender(&ledger, args, now).await;
+
+    assert_eq!(
+        replay,
+        Err(CurrencyError::LedgerRejected(LedgerRejection::Duplicate {
+            duplicate_of: first
+        }))
+    );
+    assert_eq!(ledger.balance(&canister()), 1_000_000 - FEE);
+}
+
+#[tokio::test]
+async fn revoke_sets_allowance_to_zero() {
+    let ledger = ledger();
+    ledger.mint(&canister(), 1_000_000);
+    approve_spender(&ledger, ApproveArg::new(pool(), 100_000), ledger.time())
+        .await
+        .unwrap();
+    ledger.advance_time(1);
+
+    let block = revoke_approval(&ledger, &canister(), pool(), ledger.time())
+        .await
+        .unwrap();
+
+    assert!(block.is_some());
+    let allowance = ledger.allowance(&canister(), &pool()).await.unwrap();
+    assert_eq!(allowance.allowance, 0);
+    assert_eq!(ledger.balance(&canister()), 1_000_000 - 2 * FEE);
+}
+
+#[tokio::test]
+async fn revoke_without_live_allowance_pays_no_fee() {
+    let ledger = ledger();
+    ledger.mint(&canister(), 1_000_000);
+    let args = ApproveArg {
+        expires_at: Some(ledger.time() + NANOS_PER_SEC),
+        ..ApproveArg::new(pool(), 100_000)
+    };
+    approve_spender(&ledger, args, ledger.time()).await.unwrap();
+    ledger.advance_time(2 * NANOS_PER_SEC);
+
+    let expired = revoke_approval(&ledger, &canister(), pool(), ledger.time()).await;
+    let never_approved = revoke_approval(&ledger, &canister(), user(), ledger.time()).await;
+
+    assert_eq!(expired, Ok(None));
+    assert_eq!(never_approved, Ok(None));
+    assert_eq!(ledger.balance(&canister()), 1_000_000 - FEE);
+}
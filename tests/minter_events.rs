@@ -0,0 +1,41 @@
+use candid::Nat;
+use currency::{
+    cketh_minter_canister_interface::{EventPayload, EventSource},
+    currency_error::CurrencyError,
+    minter_events::{EthEventSource, MinterEvent},
+};
+
+fn minted(mint_block_index: Nat) -> EventPayload {
+    EventPayload::MintedCkEth {
+        event_source: EventSource {
+            transaction_hash: "0xABC".to_string(),
+            log_index: Nat::from(3u64),
+        },
+        mint_block_index,
+    }
+}
+
+#[test]
+fn cketh_mint_is_converted() {
+    assert_eq!(
+        MinterEvent::from_cketh(minted(Nat::from(42u64))),
+        Ok(Some(MinterEvent::EthDepositMinted {
+            source: EthEventSource {
+                transaction_hash: "0xABC".to_string(),
+                log_index: 3,
+            },
+            mint_block_index: 42,
+            ckerc20_token_symbol: None,
+        }))
+    );
+}
+
+#[test]
+fn out_of_range_value_is_an_error() {
+    let too_large = Nat::from(u128::MAX) + Nat::from(1u64);
+
+    assert!(matches!(
+        MinterEvent::from_cketh(minted(too_large)),
+        Err(CurrencyError::QueryError(_))
+    ));
+}
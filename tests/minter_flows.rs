@@ -0,0 +1,262 @@
+mod common;
+
+use candid::Principal;
+use common::mock_minter::{MockMinter, EVENTS_PAGE_SIZE};
+use currency::{
+    address::AddressError,
+    ckbtc_minter_canister_interface::UpdateBalanceError,
+    cketh_minter_canister_interface::WithdrawErc20Error,
+    currency_error::{CurrencyError, MinterRejection},
+    deposit_subaccount::deposit_subaccount,
+    types::canister_wallets::{
+        btc_token_wallet::update_deposit_balance,
+        ckerc20_token_wallet::{
+            find_mint_block, withdraw_to_eth, withdrawal_status, CKTokenWithdrawalStatus,
+            MAX_MINT_EVENTS_SCANNED,
+        },
+    },
+};
+
+const RECIPIENT: &str = "0x2d39863d30716aaf2b7fffd85dd03dda2bfc2e38";
+
+fn canister() -> Principal {
+    Principal::from_slice(&[1; 10])
+}
+
+fn user() -> Principal {
+    Principal::from_slice(&[2; 10])
+}
+
+fn ledger_id() -> Principal {
+    Principal::from_slice(&[0xAA; 10])
+}
+
+#[tokio::test]
+async fn mint_block_is_found_whatever_the_hash_case() {
+    let minter = MockMinter::new(canister());
+    minter.skip_block();
+    let block = minter.mint_eth("0xABCDEF");
+    minter.mint_erc20("0x1234", "ckUSDC", "0xa0b8");
+
+    assert_eq!(find_mint_block(&minter, "0xabcdef").await, Ok(block));
+    assert_eq!(find_mint_block(&minter, "0x1234").await, Ok(block + 1));
+}
+
+#[tokio::test]
+async fn mint_block_is_found_pages_back() {
+    let minter = MockMinter::new(canister());
+    let block = minter.mint_erc20("0xold", "ckUSDC", "0xa0b8");
+    for _ in 0..2 * EVENTS_PAGE_SIZE {
+        minter.skip_block();
+    }
+
+    assert_eq!(find_mint_block(&minter, "0xold").await, Ok(block));
+}
+
+#[tokio::test]
+async fn mint_block_older_than_the_scanned_events_is_not_found() {
+    let minter = MockMinter::new(canister());
+    minter.mint_eth("0xold");
+    for _ in 0..MAX_MINT_EVENTS_SCANNED {
+        minter.skip_block();
+    }
+
+    assert_eq!(
+        find_mint_block(&minter, "0xold").await,
+        Err(CurrencyError::TransactionNotFound)
+    );
+
+    let block = minter.mint_eth("0xnew");
+    assert_eq!(find_mint_block(&minter, "0xnew").await, Ok(block));
+}
+
+#[tokio::test]
+async fn mint_block_of_an_unminted_deposit_is_not_found() {
+    let minter = MockMinter::new(canister());
+    assert_eq!(
+        find_mint_block(&minter, "0xabc").await,
+        Err(CurrencyError::TransactionNotFound)
+    );
+
+    minter.mint_eth("0xdef");
+    assert_eq!(
+        find_mint_block(&minter, "0xabc").await,
+        Err(CurrencyError::TransactionNotFound)
+    );
+}
+
+#[tokio::test]
+async fn withdrawal_goes_from_pending_to_finalized() {
+    let minter = MockMinter::new(canister());
+    let id = withdraw_to_eth(&minter, ledger_id(), RECIPIENT.to_string(), 1_000_000)
+        .await
+        .unwrap() as u64;
+
+    assert!(matches!(
+        withdrawal_status(&minter, id).await,
+        Ok(CKTokenWithdrawalStatus::Pending)
+    ));
+
+    minter.create_transaction(id);
+    assert!(matches!(
+        withdrawal_status(&minter, id).await,
+        Ok(CKTokenWithdrawalStatus::TxCreated)
+    ));
+
+    minter.send_transaction(id, "0xfeed");
+    assert!(matches!(
+        withdrawal_status(&minter, id).await,
+        Ok(CKTokenWithdrawalStatus::TxSent { transaction_hash }) if transaction_hash == "0xfeed"
+    ));
+
+    minter.finalize(id, 21_000);
+    assert!(matches!(
+        withdrawal_status(&minter, id).await,
+        Ok(CKTokenWithdrawalStatus::TxFinalized {
+            transaction_hash,
+            effective_fee: Some(21_000),
+        }) if transaction_hash == "0xfeed"
+    ));
+}
+
+#[tokio::test]
+async fn failed_withdrawal_is_reimbursed() {
+    let minter = MockMinter::new(canister());
+    let id = withdraw_to_eth(&minter, ledger_id(), RECIPIENT.to_string(), 1_000_000)
+        .await
+        .unwrap() as u64;
+    minter.create_transaction(id);
+    minter.send_transaction(id, "0xfeed");
+
+    minter.fail(id);
+    assert!(matches!(
+        withdrawal_status(&minter, id).await,
+        Ok(CKTokenWithdrawalStatus::PendingReimbursement { .. })
+    ));
+
+    let block = minter.reimburse(id);
+    assert!(matches!(
+        withdrawal_status(&minter, id).await,
+        Ok(CKTokenWithdrawalStatus::Reimbursed {
+            reimbursed_amount: 1_000_000,
+            reimbursed_in_block,
+            ..
+        }) if reimbursed_in_block == block as u128
+    ));
+}
+
+#[tokio::test]
+async fn unknown_withdrawal_is_reported() {
+    let minter = MockMinter::new(canister());
+    assert!(matches!(
+        withdrawal_status(&minter, 42).await,
+        Err(CurrencyError::WithdrawalFailed(_))
+    ));
+}
+
+#[tokio::test]
+async fn withdrawal_to_a_blocked_address_never_reaches_the_minter() {
+    let minter = MockMinter::new(canister());
+    minter.block_address(RECIPIENT);
+
+    let address = format!("0x{}", RECIPIENT[2..].to_uppercase());
+    let result = withdraw_to_eth(&minter, ledger_id(), address, 1_000).await;
+    assert!(matches!(
+        result,
+        Err(CurrencyError::InvalidAddress(AddressError::Blocked(_)))
+    ));
+    assert_eq!(minter.withdrawal_count(), 0);
+}
+
+#[tokio::test]
+async fn withdrawal_to_a_malformed_address_never_reaches_the_minter() {
+    let minter = MockMinter::new(canister());
+
+    let result = withdraw_to_eth(&minter, ledger_id(), "0x1234".to_string(), 1_000).await;
+    assert!(matches!(result, Err(CurrencyError::InvalidAddress(_))));
+    assert_eq!(minter.withdrawal_count(), 0);
+}
+
+#[tokio::test]
+async fn minter_rejection_is_typed() {
+    let minter = MockMinter::new(canister());
+    minter.reject_next_withdrawal(WithdrawErc20Error::TemporarilyUnavailable(
+        "upgrading".to_string(),
+    ));
+
+    let error = withdraw_to_eth(&minter, ledger_id(), RECIPIENT.to_string(), 1_000)
+        .await
+        .unwrap_err();
+    assert!(matches!(
+        error,
+        CurrencyError::MinterRejected(MinterRejection::TemporarilyUnavailable(_))
+    ));
+    assert!(error.is_retryable());
+    assert_eq!(minter.withdrawal_count(), 0);
+}
+
+#[tokio::test]
+async fn btc_deposit_is_minted_once_confirmed() {
+    let minter = MockMinter::new(canister());
+    let subaccount = deposit_subaccount(&user(), None).to_vec();
+    minter.add_utxo(Some(subaccount), 50_000, 2);
+
+    let update = update_deposit_balance(&minter, user(), None).await.unwrap();
+    assert_eq!(update.minted_amount, 0);
+    assert_eq!(update.pending.len(), 1);
+    assert_eq!(update.pending[0].confirmations, 2);
+    assert_eq!(update.required_confirmations, Some(6));
+
+    minter.confirm_utxos(4);
+    let update = update_deposit_balance(&minter, user(), None).await.unwrap();
+    assert_eq!(update.minted_amount, 50_000);
+    assert_eq!(update.minted.len(), 1);
+    assert!(update.pending.is_empty());
+
+    // A UTXO is only minted once
+    let update = update_deposit_balance(&minter, user(), None).await.unwrap();
+    assert_eq!(update.minted_amount, 0);
+    assert!(update.minted.is_empty());
+}
+
+#[tokio::test]
+async fn btc_deposits_of_other_users_are_not_minted() {
+    let minter = MockMinter::new(canister());
+    let other = Principal::from_slice(&[3; 10]);
+    minter.add_utxo(Some(deposit_subaccount(&other, None).to_vec()), 50_000, 6);
+
+    let update = update_deposit_balance(&minter, user(), None).await.unwrap();
+    assert_eq!(update.minted_amount, 0);
+    assert!(update.pending.is_empty());
+}
+
+#[tokio::test]
+async fn btc_dust_is_reported_not_minted() {
+    let minter = MockMinter::new(canister());
+    minter.set_min_utxo_value(10_000);
+    minter.add_utxo(Some(deposit_subaccount(&user(), None).to_vec()), 500, 6);
+
+    let update = update_deposit_balance(&minter, user(), None).await.unwrap();
+    assert_eq!(update.minted_amount, 0);
+    assert_eq!(update.value_too_small.len(), 1);
+}
+
+#[tokio::test]
+async fn btc_minter_errors_are_typed() {
+    let minter = MockMinter::new(canister());
+    minter.reject_next_update(UpdateBalanceError::AlreadyProcessing);
+    let error = update_deposit_balance(&minter, user(), None)
+        .await
+        .unwrap_err();
+    assert!(error.is_retryable());
+
+    minter.reject_next_update(UpdateBalanceError::GenericError {
+        error_message: "boom".to_string(),
+        error_code: 1,
+    });
+    let error = update_deposit_balance(&minter, user(), None)
+        .await
+        .unwrap_err();
+    assert!(matches!(error, CurrencyError::MinterRejected(_)));
+    assert!(!error.is_retryable());
+}
@@ -0,0 +1,333 @@
+mod common;
+
+use std::time::Duration;
+
+use candid::Principal;
+use common::mock_ledger::{MockLedger, NANOS_PER_SEC, TX_WINDOW};
+use currency::{
+    currency_error::{CurrencyError, LedgerRejection},
+    icrc1_types::Account,
+    icrc3::Icrc3Operation,
+    ledger_client::{deposit_from, net_of_fee, replay_withdrawal_to, withdraw_to},
+    rake_constants::RAKE_WALLET_ADDRESS_PRINCIPAL,
+    retry::{retry_update, Idempotency, RetryPolicy},
+    state::{TransactionState, MAX_TRANSACTIONS},
+};
+use futures::executor::block_on;
+use proptest::prelude::*;
+
+const FEE: u128 = 10_000;
+const USERS: usize = 3;
+const INITIAL_BALANCE: u128 = 100_000_000;
+
+fn canister() -> Account {
+    Account::from(Principal::from_slice(&[1; 10]))
+}
+
+fn user(n: usize) -> Account {
+    Account::from(Principal::from_slice(&[2, n as u8]))
+}
+
+fn rake_wallet() -> Account {
+    Account::from(Principal::from_text(RAKE_WALLET_ADDRESS_PRINCIPAL).unwrap())
+}
+
+/// A ledger where every user has approved the canister for their whole balance
+fn funded_ledger() -> MockLedger {
+    let ledger = MockLedger::new(canister().owner, FEE);
+    for n in 0..USERS {
+        ledger.mint(&user(n), INITIAL_BALANCE);
+        ledger.approve(&user(n), &canister(), INITIAL_BALANCE, None);
+    }
+    ledger
+}
+
+/// Blocks that burned a fee, every block but the mints
+fn fee_blocks(ledger: &MockLedger) -> u128 {
+    ledger
+        .blocks()
+        .iter()
+        .filter(|block| !matches!(block.operation, Icrc3Operation::Mint { .. }))
+        .count() as u128
+}
+
+fn transfer_blocks(ledger: &MockLedger) -> usize {
+    ledger
+        .blocks()
+        .iter()
+        .filter(|block| matches!(block.operation, Icrc3Operation::Transfer { .. }))
+        .count()
+}
+
+/// Withdraw like the canister does: a reply lost in transit is retried with the
+/// same `created_at_time`, which the ledger answers with the original block
+fn withdraw(
+    ledger: &MockLedger,
+    to: Account,
+    amount: u128,
+    lose_reply: bool,
+) -> Result<u128, CurrencyError> {
+    let created_at_time = ledger.time();
+    // A withdrawal that does not cover the fee never reaches the ledger
+    if lose_reply && amount >= FEE {
+        ledger.lose_next_reply();
+    }
+    // `Idempotency::CreatedAt` reads the canister clock, which only exists in a canister;
+    // the retry is made at once, well within the ledger's dedup window
+    let policy = RetryPolicy::new(2, Duration::ZERO, Duration::ZERO);
+    block_on(retry_update(&policy, Idempotency::Inherent, |attempt| {
+        let to = to.clone();
+        async move {
+            if attempt == 0 {
+                withdraw_to(ledger, None, to, amount, Some(FEE), created_at_time).await
+            } else {
+                replay_withdrawal_to(ledger, None, to, amount, Some(FEE), created_at_time).await
+            }
+        }
+    }))
+}
+
+#[derive(Debug, Clone)]
+enum Op {
+    Deposit {
+        user: usize,
+        amount: u128,
+    },
+    Withdraw {
+        user: usize,
+        amount: u128,
+        lose_reply: bool,
+    },
+    /// Move part of a user's credit to the rake
+    Rake {
+        user: usize,
+        amount: u128,
+    },
+    PayRake {
+        lose_reply: bool,
+    },
+    Wait {
+        nanos: u64,
+    },
+}
+
+fn op() -> impl Strategy<Value = Op> {
+    let amount = 0..3 * FEE + 5_000_000;
+    prop_oneof![
+        (0..USERS, amount.clone()).prop_map(|(user, amount)| Op::Deposit { user, amount }),
+        (0..USERS, amount.clone(), any::<bool>()).prop_map(|(user, amount, lose_reply)| {
+            Op::Withdraw {
+                user,
+                amount,
+                lose_reply,
+            }
+        }),
+        (0..USERS, amount).prop_map(|(user, amount)| Op::Rake { user, amount }),
+        any::<bool>().prop_map(|lose_reply| Op::PayRake { lose_reply }),
+        (0..TX_WINDOW).prop_map(|nanos| Op::Wait { nanos }),
+    ]
+}
+
+/// What the canister believes it holds for each user and for the rake
+#[derive(Default)]
+struct Books {
+    credits: [u128; USERS],
+    rake: u128,
+}
+
+proptest! {
+    #[test]
+    fn net_of_fee_never_underflows(amount in any::<u128>(), fee in any::<u128>()) {
+        match net_of_fee(amount, fee) {
+            Ok(net) => prop_assert_eq!(net + fee, amount),
+            Err(e) => {
+                prop_assert!(fee > amount);
+                prop_assert_eq!(e, CurrencyError::InsufficientFunds);
+            }
+        }
+    }
+
+    #[test]
+    fn withdraw_moves_all_or_nothing(amount in any::<u128>(), fee in prop::option::of(any::<u128>())) {
+        let ledger = MockLedger::new(canister().owner, FEE);
+        ledger.mint(&canister(), INITIAL_BALANCE);
+
+        let result = block_on(withdraw_to(&ledger, None, user(0), amount, fee, ledger.time()));
+
+        let paid = INITIAL_BALANCE - ledger.balance(&canister());
+        match result {
+            Ok(_) => {
+                prop_assert_eq!(paid, amount);
+                prop_assert_eq!(ledger.balance(&user(0)), amount - FEE);
+            }
+            Err(_) => {
+                prop_assert_eq!(paid, 0);
+                prop_assert_eq!(ledger.balance(&user(0)), 0);
+            }
+        }
+    }
+
+    #[test]
+    fn deposit_moves_all_or_nothing(amount in any::<u128>(), fee in prop::option::of(any::<u128>())) {
+        let ledger = funded_ledger();
+        let before = ledger.balance(&user(0));
+
+        let result = block_on(deposit_from(&ledger, user(0), canister(), amount, fee, ledger.time()));
+
+        let received = ledger.balance(&canister());
+        match result {
+            Ok(_) => {
+                prop_assert_eq!(received, amount);
+                prop_assert_eq!(ledger.balance(&user(0)), before - amount - FEE);
+            }
+            Err(_) => {
+                prop_assert_eq!(received, 0);
+                prop_assert_eq!(ledger.balance(&user(0)), before);
+            }
+        }
+    }
+
+    #[test]
+    fn funds_are_conserved(ops in prop::collection::vec(op(), 1..60)) {
+        let ledger = funded_ledger();
+        let minted = ledger.total_supply() + fee_blocks(&ledger) * FEE;
+        let mut books = Books::default();
+
+        for op in ops {
+            match op {
+                Op::Deposit { user: n, amount } => {
+                    let now = ledger.time();
+                    if block_on(deposit_from(&ledger, user(n), canister(), amount, Some(FEE), now)).is_ok() {
+                        books.credits[n] += amount;
+                    }
+                }
+                Op::Withdraw { user: n, amount, lose_reply } => {
+                    if books.credits[n] >= amount && withdraw(&ledger, user(n), amount, lose_reply).is_ok() {
+                        books.credits[n] -= amount;
+                    }
+                }
+                Op::Rake { user: n, amount } => {
+                    if books.credits[n] >= amount {
+                        books.credits[n] -= amount;
+                        books.rake += amount;
+                    }
+                }
+                Op::PayRake { lose_reply } => {
+                    if withdraw(&ledger, rake_wallet(), books.rake, lose_reply).is_ok() {
+                        books.rake = 0;
+                    }
+                }
+                Op::Wait { nanos } => ledger.advance_time(nanos),
+            }
+            // Messages never share a timestamp, so no two operations are duplicates
+            ledger.advance_time(1);
+
+            let held: u128 = books.credits.iter().sum::<u128>() + books.rake;
+            prop_assert_eq!(ledger.balance(&canister()), held);
+            prop_assert_eq!(ledger.total_supply() + fee_blocks(&ledger) * FEE, minted);
+        }
+    }
+
+    #[test]
+    fn replays_execute_each_withdrawal_once(
+        amounts in prop::collection::vec(FEE..10 * FEE, 1..8),
+        schedule in prop::collection::vec((any::<prop::sample::Index>(), any::<bool>(), 0..NANOS_PER_SEC), 1..40),
+    ) {
+        let ledger = MockLedger::new(canister().owner, FEE);
+        ledger.mint(&canister(), INITIAL_BALANCE);
+        let created_at: Vec<u64> = amounts
+            .iter()
+            .map(|_| {
+                ledger.advance_time(1);
+                ledger.time()
+            })
+            .collect();
+
+        let mut executed: Vec<Option<u128>> = vec![None; amounts.len()];
+        let mut paid = 0;
+        for (index, lose_reply, wait) in schedule {
+            let i = index.index(amounts.len());
+            if lose_reply {
+                ledger.lose_next_reply();
+            }
+            let result = block_on(replay_withdrawal_to(&ledger, None, user(i), amounts[i], Some(FEE), created_at[i]));
+            let block = ledger
+                .blocks()
+                .iter()
+                .rev()
+                .find(|block| matches!(&block.operation, Icrc3Operation::Transfer { to, .. } if *to == user(i)))
+                .map(|block| block.index as u128);
+
+            match result {
+                Ok(index) => {
+                    prop_assert_eq!(Some(index), block);
+                    prop_assert!(executed[i].is_none_or(|first| first == index));
+                }
+                Err(e) => prop_assert!(e.is_outcome_unknown()),
+            }
+            if executed[i].is_none() && block.is_some() {
+                paid += amounts[i];
+            }
+            executed[i] = executed[i].or(block);
+            ledger.advance_time(wait);
+        }
+
+        prop_assert_eq!(transfer_blocks(&ledger), executed.iter().flatten().count());
+        prop_assert_eq!(ledger.balance(&canister()), INITIAL_BALANCE - paid);
+    }
+
+    #[test]
+    fn replays_after_the_window_are_rejected(amount in FEE..10 * FEE, late in 1..NANOS_PER_SEC) {
+        let ledger = MockLedger::new(canister().owner, FEE);
+        ledger.mint(&canister(), INITIAL_BALANCE);
+        let created_at_time = ledger.time();
+        block_on(withdraw_to(&ledger, None, user(0), amount, Some(FEE), created_at_time)).unwrap();
+
+        ledger.advance_time(TX_WINDOW + 60 * NANOS_PER_SEC + late);
+        let replay = block_on(replay_withdrawal_to(&ledger, None, user(0), amount, Some(FEE), created_at_time));
+
+        prop_assert_eq!(replay, Err(CurrencyError::LedgerRejected(LedgerRejection::TooOld)));
+        prop_assert_eq!(ledger.balance(&canister()), INITIAL_BALANCE - amount);
+    }
+}
+
+/// Count the ids of `ids` the state still knows
+fn kept(state: &TransactionState, ids: &[String]) -> usize {
+    ids.iter().filter(|id| state.transaction_exists(id)).count()
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(8))]
+
+    #[test]
+    fn eviction_drops_only_the_oldest_fifth(
+        extra in 0..MAX_TRANSACTIONS,
+        untimed in prop::collection::vec(any::<bool>(), 0..64),
+    ) {
+        let mut state = TransactionState::new();
+        let mut ids = Vec::new();
+        let mut expected = 0;
+        for i in 0..MAX_TRANSACTIONS + extra {
+            // Some ids do not end with a timestamp, they are evicted first
+            let id = if untimed.get(i).copied().unwrap_or(false) {
+                format!("MANUAL-{}-x", i)
+            } else {
+                format!("DEPOSIT-{}-{}", i, 1_000 + i)
+            };
+            if expected >= MAX_TRANSACTIONS {
+                expected -= expected / 5;
+            }
+            state.add_transaction(id.clone());
+            expected += 1;
+            ids.push(id);
+
+            prop_assert!(state.transaction_exists(&ids[i]));
+        }
+
+        prop_assert_eq!(kept(&state, &ids), expected);
+        prop_assert!(expected <= MAX_TRANSACTIONS);
+        // An eviction keeps the newest four fifths
+        let newest = ids.len() - MAX_TRANSACTIONS * 4 / 5;
+        prop_assert_eq!(kept(&state, &ids[newest..]), MAX_TRANSACTIONS * 4 / 5);
+    }
+}
@@ -0,0 +1,195 @@
+use candid::{Encode, Principal};
+use currency::{
+    currency_error::CurrencyError,
+    stable_storage::{
+        decode_versioned, encode_versioned, load, save, CurrencyMemoryIds, CurrencyStableState,
+        VersionedStorable, LEGACY_VERSION,
+    },
+    state::TransactionState,
+    types::{
+        canister_wallets::icrc1_token_wallet::ICRC1TokenMetadata, currency_manager::CurrencyManager,
+    },
+};
+use ic_stable_structures::{
+    memory_manager::{MemoryId, MemoryManager},
+    DefaultMemoryImpl, Storable, VectorMemory,
+};
+
+/// `CurrencyManager::new()` with a ckUSDC wallet and a generic "TST" token
+/// (ledger `[5; 10]`), as encoded by the release before stable storage was versioned
+const BASELINE_CURRENCY_MANAGER: &str = "4449444c0f6c04d184ab0201d686c002088dc3d06e09db98e3870b0a6e026c01c2adc9be0c036c05c6fcb6027dc295a993017b9efeb9a40304f1f7fcf70668cb83d3c90f686b04b1bcc9017fb6bede017feffcdbef0305e89397870a076c03c295a993017bf1f7fcf70668d8def6f60e066d7b6b03f9c9d2017fddb2b9c3037feeb2b9c3037f6e7f6d026d0b6c02efcee780040cf1f7fcf706686c05c6fcb6027dc295a993017bcbe4fdc7047185ccbc9c0e0dd8def6f60e716d0e6c02efd6e40271cbe4fdc704710100010a0800010a00000000023000060101010a000000000230000701010101904e060301010a000000000230015b0101010a000000000230009c010101904e080454657374012168747470733a2f2f6769746875622e636f6d2f6466696e6974792f494352432d3106494352432d3103545354010a05050505050505050505";
+
+fn baseline_token() -> Principal {
+    Principal::from_slice(&[5; 10])
+}
+
+fn transaction_state(ids: &[&str]) -> TransactionState {
+    let mut state = TransactionState::new();
+    for id in ids {
+        state.add_transaction(id.to_string());
+    }
+    state
+}
+
+fn metadata(symbol: &str) -> ICRC1TokenMetadata {
+    ICRC1TokenMetadata {
+        name: symbol.to_string(),
+        symbol: symbol.to_string(),
+        decimals: 8,
+        fee: 10,
+        supported_standards: Vec::new(),
+        logo: None,
+        max_memo_length: None,
+        minting_account: None,
+        index_canister_id: None,
+    }
+}
+
+#[test]
+fn versioned_value_starts_with_its_version_and_round_trips() {
+    let state = transaction_state(&["a-1", "b-2"]);
+
+    let bytes = encode_versioned(&state).unwrap();
+
+    assert_eq!(bytes[0], TransactionState::VERSION);
+    assert_eq!(&bytes[1..5], b"DIDL");
+    assert_eq!(decode_versioned::<TransactionState>(&bytes).unwrap(), state);
+}
+
+#[test]
+fn unversioned_candid_is_read_as_the_legacy_version() {
+    let state = transaction_state(&["a-1"]);
+
+    let legacy = Encode!(&state).unwrap();
+
+    assert_eq!(
+        decode_versioned::<TransactionState>(&legacy).unwrap(),
+        state
+    );
+    assert_eq!(
+        TransactionState::migrate(LEGACY_VERSION, &legacy).unwrap(),
+        state
+    );
+}
+
+#[test]
+fn newer_or_empty_value_is_an_error() {
+    let mut bytes = encode_versioned(&transaction_state(&["a-1"])).unwrap();
+    bytes[0] = TransactionState::VERSION + 1;
+
+    assert!(matches!(
+        decode_versioned::<TransactionState>(&bytes),
+        Err(CurrencyError::SerializationError(_))
+    ));
+    assert!(matches!(
+        decode_versioned::<TransactionState>(&[]),
+        Err(CurrencyError::SerializationError(_))
+    ));
+}
+
+#[test]
+fn undecodable_value_is_an_error_instead_of_a_default() {
+    assert!(matches!(
+        decode_versioned::<TransactionState>(&[TransactionState::VERSION, 1, 2, 3]),
+        Err(CurrencyError::SerializationError(_))
+    ));
+}
+
+#[test]
+fn save_and_load_use_a_length_prefix() {
+    let memory = VectorMemory::default();
+    assert_eq!(load::<TransactionState, _>(&memory).unwrap(), None);
+
+    let long = transaction_state(&["a-1", "b-2", "c-3"]);
+    save(&memory, &long).unwrap();
+    assert_eq!(load(&memory).unwrap(), Some(long));
+
+    // A shorter value overwrites the start of the memory, the length keeps the rest out
+    let short = transaction_state(&["d-4"]);
+    save(&memory, &short).unwrap();
+    assert_eq!(load(&memory).unwrap(), Some(short));
+}
+
+#[test]
+fn baseline_currency_manager_is_decoded() {
+    let legacy = hex::decode(BASELINE_CURRENCY_MANAGER).unwrap();
+    let mut versioned = vec![CurrencyManager::VERSION];
+    versioned.extend_from_slice(&legacy);
+
+    for bytes in [legacy.clone(), versioned] {
+        let manager: CurrencyManager = decode_versioned(&bytes).unwrap();
+
+        assert!(manager.icp.is_some());
+        assert!(manager.btc.is_some());
+        assert_eq!(manager.ckerc20_tokens.len(), 1);
+        assert_eq!(manager.generic_icrc1_tokens[0].metadata.symbol, "TST");
+        assert!(manager.token_registry.is_none());
+        assert!(manager.network.is_none());
+        // The registry is seeded from the wallets
+        assert_eq!(
+            manager
+                .token_registry()
+                .get_token_metadata(&baseline_token())
+                .map(|metadata| metadata.symbol),
+            Some("TST".to_string())
+        );
+    }
+
+    let manager = CurrencyManager::from_bytes(legacy.into());
+    assert_eq!(manager.generic_icrc1_tokens.len(), 1);
+}
+
+#[test]
+fn memory_ids_keep_their_layout() {
+    let ids = CurrencyMemoryIds::starting_at(10);
+
+    assert_eq!(ids.currency_manager, MemoryId::new(10));
+    assert_eq!(ids.transaction_state, MemoryId::new(11));
+    assert_eq!(ids.token_registry, MemoryId::new(12));
+    assert_eq!(ids.withdrawal_outbox, MemoryId::new(13));
+    assert_eq!(ids.reimbursements, MemoryId::new(18));
+    assert_eq!(ids.outbox_next_id, MemoryId::new(19));
+    assert_eq!(ids.pending_sweeps, MemoryId::new(20));
+}
+
+#[test]
+fn stable_state_round_trips_through_the_memory_manager() {
+    let memory_manager = MemoryManager::init(DefaultMemoryImpl::default());
+    let ids = CurrencyMemoryIds::starting_at(0);
+
+    let mut currency_manager = CurrencyManager::new();
+    currency_manager
+        .token_registry
+        .as_mut()
+        .unwrap()
+        .register_metadata(baseline_token(), metadata("TST"));
+    let state = CurrencyStableState {
+        currency_manager,
+        transaction_state: transaction_state(&["a-1"]),
+    };
+
+    state.save(&memory_manager, ids).unwrap();
+    let loaded = CurrencyStableState::load(&memory_manager, ids).unwrap();
+
+    assert_eq!(loaded.transaction_state, state.transaction_state);
+    assert!(loaded
+        .currency_manager
+        .token_registry()
+        .is_token_registered(&baseline_token()));
+    // The registry lives in its own memory, not inside the saved manager
+    let saved_manager: CurrencyManager = load(&memory_manager.get(ids.currency_manager))
+        .unwrap()
+        .unwrap();
+    assert!(saved_manager.token_registry.is_none());
+}
+
+#[test]
+fn nothing_saved_loads_the_defaults() {
+    let memory_manager = MemoryManager::init(DefaultMemoryImpl::default());
+
+    let loaded =
+        CurrencyStableState::load(&memory_manager, CurrencyMemoryIds::starting_at(0)).unwrap();
+
+    assert_eq!(loaded.transaction_state, TransactionState::new());
+    assert!(loaded.currency_manager.icp.is_some());
+}
@@ -0,0 +1,168 @@
+use std::{borrow::Cow, cell::RefCell};
+
+use candid::Principal;
+use currency::{
+    currency_error::{CurrencyError, LedgerRejection},
+    types::withdrawal_batch::{WithdrawalBatch, WithdrawalItemStatus, WithdrawalRequest},
+    Currency,
+};
+use futures::executor::block_on;
+use ic_stable_structures::Storable;
+
+const NOW: u64 = 1_000;
+
+fn recipient(i: u8) -> Principal {
+    Principal::from_slice(&[i; 10])
+}
+
+fn request(i: u8, amount: u64) -> WithdrawalRequest {
+    WithdrawalRequest {
+        currency: Currency::ICP,
+        recipient: recipient(i),
+        amount,
+    }
+}
+
+fn insufficient_funds() -> CurrencyError {
+    CurrencyError::LedgerRejected(LedgerRejection::InsufficientFunds { balance: 0 })
+}
+
+/// Calls made to the ledger, as (recipient, created_at_time)
+type Calls = RefCell<Vec<(Principal, u64)>>;
+
+/// Withdraw through a fake ledger rejecting payouts to `rejected`
+fn run(
+    batch: &mut WithdrawalBatch,
+    max_concurrency: usize,
+    rejected: Option<Principal>,
+    calls: &Calls,
+    snapshots: &mut Vec<WithdrawalBatch>,
+) {
+    block_on(batch.process(
+        max_concurrency,
+        || NOW,
+        |batch| snapshots.push(batch.clone()),
+        |request, created_at_time| async move {
+            calls
+                .borrow_mut()
+                .push((request.recipient, created_at_time));
+            if Some(request.recipient) == rejected {
+                Err(insufficient_funds())
+            } else {
+                Ok(calls.borrow().len() as u128)
+            }
+        },
+    ));
+}
+
+#[test]
+fn report_lists_the_result_of_every_item() {
+    let mut batch =
+        WithdrawalBatch::new(7, vec![request(1, 100), request(2, 200), request(3, 300)]);
+    let calls = Calls::default();
+
+    run(&mut batch, 10, Some(recipient(2)), &calls, &mut Vec::new());
+    let report = batch.report();
+
+    assert_eq!(report.batch_id, 7);
+    assert_eq!(
+        (report.completed, report.failed, report.outstanding),
+        (2, 1, 0)
+    );
+    assert!(batch.is_finished());
+    assert!(matches!(
+        report.items[0].status,
+        WithdrawalItemStatus::Completed { .. }
+    ));
+    assert_eq!(
+        report.items[1].status,
+        WithdrawalItemStatus::Failed {
+            error: insufficient_funds(),
+            created_at_time: NOW + 1,
+        }
+    );
+    assert!(report.items.iter().all(|item| item.attempts == 1));
+    assert_eq!(report.items[2].request, request(3, 300));
+}
+
+#[test]
+fn items_of_a_round_get_distinct_created_at_times() {
+    let mut batch = WithdrawalBatch::new(1, vec![request(1, 100), request(1, 100)]);
+    let calls = Calls::default();
+
+    run(&mut batch, 10, None, &calls, &mut Vec::new());
+
+    assert_eq!(
+        calls.into_inner(),
+        vec![(recipient(1), NOW), (recipient(1), NOW + 1)]
+    );
+}
+
+#[test]
+fn batch_is_persisted_before_and_after_every_round() {
+    let mut batch =
+        WithdrawalBatch::new(1, vec![request(1, 100), request(2, 200), request(3, 300)]);
+    let calls = Calls::default();
+    let mut snapshots = Vec::new();
+
+    run(&mut batch, 2, None, &calls, &mut snapshots);
+
+    // Two rounds, each persisted once with its items in flight and once with their results
+    assert_eq!(snapshots.len(), 4);
+    assert!(matches!(
+        snapshots[0].items[1].status,
+        WithdrawalItemStatus::InFlight { .. }
+    ));
+    assert_eq!(snapshots[0].items[2].status, WithdrawalItemStatus::Pending);
+    assert_eq!(snapshots[3], batch);
+}
+
+#[test]
+fn resumed_batch_resends_in_flight_items_with_their_created_at_time() {
+    let mut batch =
+        WithdrawalBatch::new(1, vec![request(1, 100), request(2, 200), request(3, 300)]);
+    let mut snapshots = Vec::new();
+    run(&mut batch, 2, None, &Calls::default(), &mut snapshots);
+
+    // The canister trapped while the first round was in flight: resume from what was persisted
+    let mut resumed = WithdrawalBatch::from_bytes(snapshots[0].to_bytes());
+    let calls = Calls::default();
+    run(&mut resumed, 2, None, &calls, &mut Vec::new());
+
+    assert_eq!(
+        calls.into_inner(),
+        vec![
+            (recipient(1), NOW),
+            (recipient(2), NOW + 1),
+            (recipient(3), NOW)
+        ]
+    );
+    assert!(resumed.is_finished());
+    assert_eq!(resumed.items[0].attempts, 2);
+    assert_eq!(resumed.items[2].attempts, 1);
+}
+
+#[test]
+fn retried_failures_keep_their_created_at_time() {
+    let mut batch = WithdrawalBatch::new(1, vec![request(1, 100), request(2, 200)]);
+    run(
+        &mut batch,
+        10,
+        Some(recipient(2)),
+        &Calls::default(),
+        &mut Vec::new(),
+    );
+
+    batch.retry_failed();
+    let calls = Calls::default();
+    run(&mut batch, 10, None, &calls, &mut Vec::new());
+
+    assert_eq!(calls.into_inner(), vec![(recipient(2), NOW + 1)]);
+    assert_eq!(batch.report().completed, 2);
+}
+
+#[test]
+#[should_panic(expected = "Failed to decode withdrawal batch")]
+fn undecodable_batch_traps_instead_of_being_emptied() {
+    WithdrawalBatch::from_bytes(Cow::Borrowed(b"not a batch"));
+}